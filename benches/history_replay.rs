@@ -0,0 +1,103 @@
+//! How a join replay's cost splits between loading the room's history and
+//! turning it into frames, as the room grows, next to loading it whole.
+//!
+//! Run with `cargo bench --bench history_replay`.
+
+use backend::bench::{self, ReplayFilter, SavedMessage, Store};
+use chrono::{TimeDelta, Utc};
+use criterion::{BatchSize, BenchmarkId, Criterion, criterion_group, criterion_main};
+use std::hint::black_box;
+use tokio::runtime::Runtime;
+
+const ROOM: &str = "main";
+
+// Messages in the room for each run
+const SIZES: [usize; 4] = [100, 1_000, 10_000, 100_000];
+
+// --max-replay's default
+const MAX_REPLAY: usize = 1000;
+
+// A fresh database holding `n` messages in the room, a second apart, from a
+// few senders taking turns
+async fn seed_database(n: usize) -> Store {
+    let store = Store::new("sqlite::memory:".to_string());
+    store.create_tables().await.unwrap();
+
+    let senders = ["alice", "bob", "carol"];
+    let start = Utc::now() - TimeDelta::seconds(n as i64);
+    for i in 0..n {
+        let text = format!("Message number {} of the benchmark history", i);
+        let sent_at = start + TimeDelta::seconds(i as i64);
+        store
+            .save_message(ROOM, &text, senders[i % senders.len()], sent_at, None)
+            .await
+            .unwrap();
+    }
+    store
+}
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+fn get_messages(c: &mut Criterion) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group("get_messages");
+    group.sample_size(10);
+    for n in SIZES {
+        let store = runtime.block_on(seed_database(n));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &store, |b, store| {
+            b.iter(|| runtime.block_on(store.get_messages(ROOM)).unwrap());
+        });
+    }
+    group.finish();
+}
+
+// What a join loads: the newest messages, up to the server's maximum
+fn replay_messages(c: &mut Criterion) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group("replay_messages");
+    group.sample_size(10);
+    for n in SIZES {
+        let store = runtime.block_on(seed_database(n));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &store, |b, store| {
+            b.iter(|| {
+                runtime
+                    .block_on(store.replay_messages(ROOM, ReplayFilter::All, MAX_REPLAY))
+                    .unwrap()
+            });
+        });
+    }
+    group.finish();
+}
+
+// Everything after loading: grouping by day and encoding each frame, which
+// is all the outbox does before writing to the socket
+fn encode_replay(c: &mut Criterion) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group("encode_replay");
+    for n in SIZES {
+        let messages: Vec<SavedMessage> = runtime.block_on(async {
+            let store = seed_database(n).await;
+            let (messages, _) = store
+                .replay_messages(ROOM, ReplayFilter::All, MAX_REPLAY)
+                .await
+                .unwrap();
+            messages
+        });
+        group.bench_with_input(BenchmarkId::from_parameter(n), &messages, |b, messages| {
+            b.iter_batched(
+                || messages.clone(),
+                |messages| black_box(bench::encode_replay(messages, MAX_REPLAY)),
+                BatchSize::LargeInput,
+            );
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, get_messages, replay_messages, encode_replay);
+criterion_main!(benches);
@@ -0,0 +1,332 @@
+use crate::config::ServerConfig;
+use crate::db::{BACKUP_TABLES, SqliteStore, Store};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use lume::database::error::DatabaseError;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+// Rows read from, or inserted into, a table per query
+const BATCH: usize = 500;
+
+/// One line of a backup: a row of one namespace's table, keyed by column.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    namespace: String,
+    table: String,
+    row: Map<String, Value>,
+}
+
+/// Why a backup couldn't be written or read back.
+#[derive(Debug)]
+pub enum BackupError {
+    Io(io::Error),
+    Database(DatabaseError),
+    Json(serde_json::Error),
+    /// The backup or the databases it targets aren't what it needs, e.g. a
+    /// line names a namespace that isn't configured.
+    Invalid(String),
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackupError::Io(e) => write!(f, "I/O error: {}", e),
+            BackupError::Database(e) => write!(f, "database error: {}", e),
+            BackupError::Json(e) => write!(f, "malformed backup: {}", e),
+            BackupError::Invalid(problem) => f.write_str(problem),
+        }
+    }
+}
+
+impl std::error::Error for BackupError {}
+
+impl From<io::Error> for BackupError {
+    fn from(e: io::Error) -> Self {
+        BackupError::Io(e)
+    }
+}
+
+impl From<DatabaseError> for BackupError {
+    fn from(e: DatabaseError) -> Self {
+        BackupError::Database(e)
+    }
+}
+
+impl From<serde_json::Error> for BackupError {
+    fn from(e: serde_json::Error) -> Self {
+        BackupError::Json(e)
+    }
+}
+
+/// Writes every table of every SQLite-backed namespace to `path` as gzipped
+/// JSON lines, a page of rows at a time. Returns the number of rows written.
+/// Namespaces kept in memory have nothing to back up and are skipped.
+pub async fn export(config: &ServerConfig, path: &Path) -> Result<usize, BackupError> {
+    let mut namespaces: Vec<(String, SqliteStore)> = config
+        .namespaces()
+        .into_iter()
+        .filter_map(|(name, url)| match Store::new(url) {
+            Store::Sqlite(store) => Some((name, store)),
+            Store::Memory(_) => None,
+        })
+        .collect();
+    namespaces.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut out = GzEncoder::new(BufWriter::new(File::create(path)?), Compression::default());
+    let written = write_backup(&mut out, &namespaces).await?;
+    out.finish()?.flush()?;
+    Ok(written)
+}
+
+/// Loads a backup written by `export` into the configured namespaces, which
+/// must all be empty. Rows keep their ids. Returns the number of rows read.
+pub async fn import(config: &ServerConfig, path: &Path) -> Result<usize, BackupError> {
+    let mut stores = HashMap::new();
+    for (name, url) in config.namespaces() {
+        let Store::Sqlite(store) = Store::new(url) else {
+            continue;
+        };
+        store.create_tables().await?;
+        stores.insert(name, store);
+    }
+    for (name, store) in &stores {
+        ensure_empty(name, store).await?;
+    }
+
+    let input = BufReader::new(GzDecoder::new(File::open(path)?));
+    read_backup(input, &stores).await
+}
+
+async fn write_backup(
+    out: &mut impl Write,
+    namespaces: &[(String, SqliteStore)],
+) -> Result<usize, BackupError> {
+    let mut written = 0;
+    for (namespace, store) in namespaces {
+        for table in BACKUP_TABLES {
+            // Databases from older versions may lack newer tables
+            let columns = store.columns(table).await?;
+            if columns.is_empty() {
+                continue;
+            }
+
+            let mut after = 0;
+            loop {
+                let rows = store.export_rows(table, &columns, after, BATCH).await?;
+                for (rowid, row) in &rows {
+                    let record = Record {
+                        namespace: namespace.clone(),
+                        table: table.to_string(),
+                        row: serde_json::from_str(row)?,
+                    };
+                    serde_json::to_writer(&mut *out, &record)?;
+                    out.write_all(b"\n")?;
+                    after = *rowid;
+                }
+                written += rows.len();
+                if rows.len() < BATCH {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(written)
+}
+
+// Rows read but not yet inserted, all for one table and with the same
+// columns
+struct Pending {
+    namespace: String,
+    table: String,
+    columns: Vec<String>,
+    rows: Vec<Vec<Value>>,
+}
+
+async fn read_backup(
+    input: impl BufRead,
+    stores: &HashMap<String, SqliteStore>,
+) -> Result<usize, BackupError> {
+    let mut read = 0;
+    let mut pending: Option<Pending> = None;
+    for (number, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record = serde_json::from_str(&line)
+            .map_err(|e| BackupError::Invalid(format!("line {}: {}", number + 1, e)))?;
+        if !stores.contains_key(&record.namespace) {
+            return Err(BackupError::Invalid(format!(
+                "line {}: namespace {} isn't configured with an SQLite database",
+                number + 1,
+                record.namespace
+            )));
+        }
+        if !BACKUP_TABLES.contains(&record.table.as_str()) {
+            return Err(BackupError::Invalid(format!(
+                "line {}: unknown table {}",
+                number + 1,
+                record.table
+            )));
+        }
+
+        let columns: Vec<String> = record.row.keys().cloned().collect();
+        let same_batch = pending.as_ref().is_some_and(|pending| {
+            pending.namespace == record.namespace
+                && pending.table == record.table
+                && pending.columns == columns
+                && pending.rows.len() < BATCH
+        });
+        if !same_batch && let Some(full) = pending.take() {
+            flush(full, stores).await?;
+        }
+        let batch = pending.get_or_insert_with(|| Pending {
+            namespace: record.namespace,
+            table: record.table,
+            columns,
+            rows: Vec::new(),
+        });
+        batch.rows.push(record.row.into_values().collect());
+        read += 1;
+    }
+    if let Some(rest) = pending {
+        flush(rest, stores).await?;
+    }
+    Ok(read)
+}
+
+async fn flush(pending: Pending, stores: &HashMap<String, SqliteStore>) -> Result<(), BackupError> {
+    let Some(store) = stores.get(&pending.namespace) else {
+        return Ok(());
+    };
+    store
+        .import_rows(&pending.table, &pending.columns, &pending.rows)
+        .await?;
+    Ok(())
+}
+
+// Importing keeps row ids, which would clash with rows already there
+async fn ensure_empty(namespace: &str, store: &SqliteStore) -> Result<(), BackupError> {
+    for table in BACKUP_TABLES {
+        let columns = store.columns(table).await?;
+        if !store.export_rows(table, &columns, 0, 1).await?.is_empty() {
+            return Err(BackupError::Invalid(format!(
+                "namespace {} already has rows in {}; import into an empty database",
+                namespace, table
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    async fn store() -> SqliteStore {
+        let store = SqliteStore::new("sqlite::memory:".to_string());
+        store.create_tables().await.unwrap();
+        store
+    }
+
+    async fn backup(namespaces: &[(String, SqliteStore)]) -> Vec<u8> {
+        let mut out = GzEncoder::new(Vec::new(), Compression::default());
+        write_backup(&mut out, namespaces).await.unwrap();
+        out.finish().unwrap()
+    }
+
+    #[tokio::test]
+    async fn backups_round_trip() {
+        let source = store().await;
+        for n in 0..300 {
+            let sender = if n % 2 == 0 { "alice" } else { "bob" };
+            source
+                .save_message("main", &format!("message {}", n), sender, Utc::now(), None)
+                .await
+                .unwrap();
+        }
+        source.set_pinned("main", 7, true).await.unwrap();
+        source.load_user("alice").await.unwrap();
+        source
+            .save_name_change("bob", "robert", 1_700_000_000)
+            .await
+            .unwrap();
+        source
+            .record_audit("alice", "pin", "message 7")
+            .await
+            .unwrap();
+        source
+            .add_bookmark(
+                "main",
+                "https://example.com",
+                "It's here",
+                "alice",
+                Utc::now(),
+            )
+            .await
+            .unwrap();
+        source.add_keyword("alice", "deploy").await.unwrap();
+
+        let namespaces = [("default".to_string(), source)];
+        let written = backup(&namespaces).await;
+
+        let target = store().await;
+        let stores = HashMap::from([("default".to_string(), target.clone())]);
+        let input = BufReader::new(GzDecoder::new(written.as_slice()));
+        let read = read_backup(input, &stores).await.unwrap();
+        assert!(read > 300);
+
+        // Backing the copy up again gives the very same bytes
+        let restored = [("default".to_string(), target.clone())];
+        assert_eq!(backup(&restored).await, written);
+        let messages = Store::Sqlite(target.clone())
+            .get_messages("main")
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 300);
+        assert_eq!(messages[6].id, 7);
+        assert!(messages[6].pinned);
+        assert_eq!(target.keywords("alice").await.unwrap(), ["deploy"]);
+        assert_eq!(
+            target.bookmarks("main").await.unwrap()[0].title,
+            "It's here"
+        );
+        assert_eq!(target.previous_names("robert", 3).await.unwrap(), ["bob"]);
+
+        // Ids are kept, so a second import would clash
+        assert!(matches!(
+            ensure_empty("default", &target).await,
+            Err(BackupError::Invalid(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn unknown_namespaces_and_tables_are_refused() {
+        let stores = HashMap::from([("default".to_string(), store().await)]);
+        for (line, problem) in [
+            (
+                r#"{"namespace":"other","table":"User","row":{}}"#,
+                "line 1: namespace other isn't configured with an SQLite database",
+            ),
+            (
+                r#"{"namespace":"default","table":"sqlite_master","row":{}}"#,
+                "line 1: unknown table sqlite_master",
+            ),
+        ] {
+            let error = read_backup(line.as_bytes(), &stores).await.unwrap_err();
+            assert_eq!(error.to_string(), problem);
+        }
+
+        let line = r#"{"namespace":"default","table":"User","row":{"name; DROP":"x"}}"#;
+        let error = read_backup(line.as_bytes(), &stores).await.unwrap_err();
+        assert!(matches!(error, BackupError::Database(_)));
+    }
+}
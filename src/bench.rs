@@ -0,0 +1,22 @@
+//! Hooks for the benchmarks under `benches/`, which only see the crate's
+//! public API. Not meant for anything else, and free to change.
+
+use crate::history;
+use crate::protocol::{Frame, Protocol};
+
+pub use crate::db::{ReplayFilter, SavedMessage, Store};
+
+/// Builds and encodes the join replay of `messages`, as `replay_messages`
+/// loads them, the way the server does for a JSON client: the same frames
+/// and sequenced encoding the outbox writes out. Returns how many bytes the
+/// frames came to.
+pub fn encode_replay(messages: Vec<SavedMessage>, max_replay: usize) -> usize {
+    crate::replay_messages(&messages, history::utc_offset(0), max_replay, 0)
+        .iter()
+        .zip(1..)
+        .map(|(message, seq)| match Protocol::Json.encode(seq, message) {
+            Frame::Text(text) => text.len(),
+            Frame::Binary(bytes) => bytes.len(),
+        })
+        .sum()
+}
@@ -0,0 +1,287 @@
+use crate::cluster::Cluster;
+use crate::config::ServerConfig;
+use crate::db::Store;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Validates the configuration without starting the server, printing one line
+/// per check. Returns whether every check passed.
+pub async fn check_config(config: &ServerConfig) -> bool {
+    write_report(config, &mut io::stdout()).await
+}
+
+// Runs every check, writing its line to `out` as soon as it's known
+async fn write_report(config: &ServerConfig, out: &mut impl Write) -> bool {
+    let mut passed = true;
+    let mut report = |what: &str, result: Result<String, String>| match result {
+        Ok(detail) => {
+            let _ = writeln!(out, "ok    {}: {}", what, detail);
+        }
+        Err(problem) => {
+            let _ = writeln!(out, "FAIL  {}: {}", what, problem);
+            passed = false;
+        }
+    };
+
+    report("port", Ok(config.port.to_string()));
+
+    report(
+        "namespaces",
+        config
+            .validate_namespaces()
+            .map(|_| config.namespaces().len().to_string()),
+    );
+
+    for (name, database_url) in config.namespaces() {
+        let what = format!("namespace {}", name);
+        report(&what, check_database(&database_url).await);
+    }
+
+    report(
+        "wal checkpoints",
+        Ok(format!("every {}s", config.wal_checkpoint_interval)),
+    );
+
+    report(
+        "persistence lag",
+        Ok(format!(
+            "warn at {} unsaved or {}s behind{}",
+            config.persistence_lag_depth,
+            config.persistence_lag_secs,
+            if config.persistence_notices {
+                ", notifying admins"
+            } else {
+                ""
+            }
+        )),
+    );
+
+    report(
+        "file transfers",
+        Ok(format!(
+            "up to {} bytes, abandoned after {}s without a chunk",
+            config.max_transfer_size, config.transfer_stall_secs
+        )),
+    );
+
+    report(
+        "idle timeout",
+        Ok(format!("{}s without input", config.idle_timeout)),
+    );
+
+    report(
+        "naming timeout",
+        Ok(format!("{}s to choose a name", config.naming_timeout)),
+    );
+
+    report(
+        "max connections",
+        match config.max_connections {
+            Some(0) => Err("must be at least 1".to_string()),
+            Some(max) => Ok(max.to_string()),
+            None => Ok("unlimited".to_string()),
+        },
+    );
+
+    report(
+        "connect limit",
+        match config.connect_limit {
+            Some(limit) => Ok(format!("{} per {}s", limit, config.connect_window)),
+            None => Ok("unlimited".to_string()),
+        },
+    );
+
+    report(
+        "message quota",
+        match config.message_quota {
+            Some(quota) => Ok(format!("{} per 24h", quota)),
+            None => Ok("unlimited".to_string()),
+        },
+    );
+
+    report(
+        "upload quota",
+        match config.upload_quota {
+            Some(quota) => Ok(format!("{} bytes per 24h", quota)),
+            None => Ok("unlimited".to_string()),
+        },
+    );
+
+    report(
+        "admin password",
+        config
+            .validate_admin_password()
+            .map(|_| match config.admin_password {
+                Some(_) => "set".to_string(),
+                None => "admin login disabled".to_string(),
+            }),
+    );
+
+    report(
+        "motd",
+        match &config.motd_file {
+            // A missing file is created by the first `/motd set`
+            Some(path) if path.exists() && !path.is_file() => {
+                Err(format!("{} is not a file", path.display()))
+            }
+            Some(path) => Ok(path.display().to_string()),
+            None => Ok("disabled".to_string()),
+        },
+    );
+
+    report(
+        "greeting bot",
+        match &config.greeting_file {
+            Some(path) if !path.is_file() => Err(format!("{} is not a file", path.display())),
+            Some(path) => Ok(path.display().to_string()),
+            None => Ok("disabled".to_string()),
+        },
+    );
+
+    report(
+        "max message length",
+        match config.max_message_length {
+            Some(max) => Ok(format!("{} characters", max)),
+            None => Ok("unlimited".to_string()),
+        },
+    );
+
+    report("max replay", Ok(format!("{} messages", config.max_replay)));
+
+    report(
+        "feedback",
+        match &config.feedback_file {
+            // Created by the first `/feedback`
+            path if path.exists() && !path.is_file() => {
+                Err(format!("{} is not a file", path.display()))
+            }
+            path => Ok(path.display().to_string()),
+        },
+    );
+
+    report(
+        "handoff",
+        match &config.handoff_file {
+            // Written by the first shutdown
+            Some(path) if path.exists() && !path.is_file() => {
+                Err(format!("{} is not a file", path.display()))
+            }
+            Some(path) => Ok(format!(
+                "{}, restored within {}s",
+                path.display(),
+                config.handoff_max_age
+            )),
+            None => Ok("disabled".to_string()),
+        },
+    );
+
+    report(
+        "redis",
+        match &config.redis_url {
+            Some(url) => check_redis(url).await,
+            None => Ok("disabled".to_string()),
+        },
+    );
+
+    report(
+        "message tracing",
+        Ok(if config.trace_messages {
+            "enabled".to_string()
+        } else {
+            "disabled".to_string()
+        }),
+    );
+
+    report(
+        "webhook",
+        match &config.webhook_url {
+            Some(url) => check_webhook_url(url),
+            None => Ok("disabled".to_string()),
+        },
+    );
+
+    let verdict = if passed { "valid" } else { "invalid" };
+    let _ = writeln!(out, "Configuration is {}", verdict);
+    passed
+}
+
+async fn check_database(database_url: &str) -> Result<String, String> {
+    // SQLite databases must already exist; nothing creates the file
+    if let Some(path) = database_url.strip_prefix("sqlite://")
+        && !Path::new(path).is_file()
+    {
+        return Err(format!("{}: file {} does not exist", database_url, path));
+    }
+
+    Store::new(database_url.to_string())
+        .ping()
+        .await
+        .map(|_| database_url.to_string())
+        .map_err(|e| format!("{}: {}", database_url, e))
+}
+
+async fn check_redis(url: &str) -> Result<String, String> {
+    Cluster::connect(url)
+        .await
+        .map(|_| url.to_string())
+        .map_err(|e| format!("{}: {}", url, e))
+}
+
+fn check_webhook_url(url: &str) -> Result<String, String> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| format!("{}: {}", url, e))?;
+    match parsed.scheme() {
+        "http" | "https" => Ok(url.to_string()),
+        scheme => Err(format!("{}: unsupported scheme {}", url, scheme)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    async fn report(args: &[&str]) -> (bool, Vec<String>) {
+        let config = ServerConfig::try_parse_from(
+            ["backend", "--namespace", "main=memory://"]
+                .iter()
+                .chain(args),
+        )
+        .unwrap();
+        let mut out = Vec::new();
+        let passed = write_report(&config, &mut out).await;
+        let lines = String::from_utf8(out).unwrap();
+        (passed, lines.lines().map(str::to_string).collect())
+    }
+
+    #[tokio::test]
+    async fn a_working_config_passes_every_check() {
+        let (passed, lines) = report(&["--max-connections", "50"]).await;
+
+        assert!(passed);
+        assert!(lines.contains(&"ok    namespace main: memory://".to_string()));
+        assert!(lines.contains(&"ok    max connections: 50".to_string()));
+        assert!(!lines.iter().any(|line| line.starts_with("FAIL")));
+        assert_eq!(lines.last().unwrap(), "Configuration is valid");
+    }
+
+    #[tokio::test]
+    async fn a_broken_setting_fails_its_check_only() {
+        let (passed, lines) =
+            report(&["--max-connections", "0", "--webhook-url", "ftp://hooks"]).await;
+
+        assert!(!passed);
+        let failures: Vec<_> = lines
+            .iter()
+            .filter(|line| line.starts_with("FAIL"))
+            .collect();
+        assert_eq!(
+            failures,
+            [
+                "FAIL  max connections: must be at least 1",
+                "FAIL  webhook: ftp://hooks: unsupported scheme ftp",
+            ]
+        );
+        assert!(lines.contains(&"ok    namespace main: memory://".to_string()));
+        assert_eq!(lines.last().unwrap(), "Configuration is invalid");
+    }
+}
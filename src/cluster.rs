@@ -0,0 +1,113 @@
+use crate::error::ChatError;
+use crate::protocol::Message;
+use futures_util::StreamExt;
+use redis::AsyncCommands;
+use redis::aio::MultiplexedConnection;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::time::Duration;
+use tracing::{error, warn};
+
+// Pause before a dropped subscription is re-established
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// A broadcast as it travels between nodes.
+#[derive(Serialize, Deserialize)]
+struct Relay {
+    // Publishing node, so it can skip its own messages when they come back
+    node: u64,
+    message: Message,
+}
+
+/// Shares broadcasts with the other nodes of a cluster through Redis pub/sub.
+#[derive(Clone)]
+pub struct Cluster {
+    client: redis::Client,
+    publisher: MultiplexedConnection,
+    node: u64,
+}
+
+impl Cluster {
+    pub async fn connect(url: &str) -> redis::RedisResult<Self> {
+        let client = redis::Client::open(url)?;
+        let publisher = client.get_multiplexed_async_connection().await?;
+        Ok(Self {
+            client,
+            publisher,
+            node: rand::random(),
+        })
+    }
+
+    /// Hands a locally broadcast message to the other nodes. Publishing runs
+    /// in the background so Redis latency never delays local delivery.
+    pub fn publish(&self, namespace: &str, room: &str, message: &Message) {
+        let relay = Relay {
+            node: self.node,
+            message: message.clone(),
+        };
+        let payload = match serde_json::to_string(&relay) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("Failed to publish: {}", ChatError::from(e));
+                return;
+            }
+        };
+        let channel = channel(namespace, room);
+        let mut publisher = self.publisher.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = publisher.publish::<_, _, ()>(&channel, payload).await {
+                error!("Failed to publish to {}: {}", channel, e);
+            }
+        });
+    }
+
+    /// Spawns a task passing every message other nodes broadcast in the room
+    /// to `deliver`, resubscribing whenever the Redis connection drops.
+    pub fn subscribe<F, Fut>(&self, namespace: &str, room: &str, deliver: F)
+    where
+        F: Fn(Message) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let cluster = self.clone();
+        let channel = channel(namespace, room);
+
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = cluster.relay(&channel, &deliver).await {
+                    error!("Redis subscription to {} failed: {}", channel, e);
+                }
+                tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+            }
+        });
+    }
+
+    async fn relay<F, Fut>(&self, channel: &str, deliver: &F) -> redis::RedisResult<()>
+    where
+        F: Fn(Message) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let mut pubsub = self.client.get_async_pubsub().await?;
+        pubsub.subscribe(channel).await?;
+
+        let mut messages = pubsub.into_on_message();
+        while let Some(message) = messages.next().await {
+            let payload: String = message.get_payload()?;
+            match serde_json::from_str::<Relay>(&payload) {
+                Ok(relay) if relay.node == self.node => {}
+                Ok(relay) => deliver(relay.message).await,
+                Err(e) => warn!("Ignoring malformed message on {}: {}", channel, e),
+            }
+        }
+
+        Err(redis::RedisError::from((
+            redis::ErrorKind::IoError,
+            "subscription stream ended",
+        )))
+    }
+}
+
+// Redis channel carrying one room's broadcasts
+fn channel(namespace: &str, room: &str) -> String {
+    format!("chat:{}:{}", namespace, room)
+}
@@ -0,0 +1,2603 @@
+use crate::error::ChatError;
+use crate::keywords::{self, MAX_KEYWORD_LEN, MAX_KEYWORDS};
+use crate::outbox::Outbound;
+use crate::protocol::{CloseReason, ErrorCode, Message, MessageType, RoomInfo};
+use crate::util;
+use crate::{
+    ACTIVITY_WINDOW, DEFAULT_ROOM, MAX_TAILERS, NamespaceState, bookmark_list, broadcast,
+    close_with, connection_named, deliver_direct, drain_notice, enqueue, enter_room, greet_room,
+    join_room, post_chat, reconnect_delay, report, reserve_name, room_event, room_greeting, send,
+    send_history, send_off, stored_message,
+};
+use chrono::SubsecRound;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tracing::{error, info};
+use wynd::handle::ConnectionHandle;
+
+// Previous names shown by /whois
+const WHOIS_HISTORY: usize = 3;
+
+// Characters of each message /pin list and /purge --preview show
+const PIN_LIST_PREVIEW: usize = 100;
+
+// Messages /from returns by default, and at most
+const FROM_DEFAULT_LIMIT: usize = 20;
+const FROM_MAX_LIMIT: usize = 100;
+
+// Events /events returns by default, and at most
+const EVENTS_DEFAULT_LIMIT: usize = 20;
+const EVENTS_MAX_LIMIT: usize = 100;
+
+// Topic changes /topic history lists
+const TOPIC_HISTORY_LIMIT: usize = 10;
+
+// Messages one /purge deletes at most
+const PURGE_MAX_LIMIT: usize = 1000;
+
+const PURGE_USAGE: &str = "Usage: /purge <name> <count> [--preview]";
+
+// Entries /audit returns by default, and at most
+const AUDIT_DEFAULT_LIMIT: usize = 20;
+const AUDIT_MAX_LIMIT: usize = 100;
+
+// Senders /wordcount ranks by default, and at most
+const WORDCOUNT_DEFAULT_LIMIT: usize = 10;
+const WORDCOUNT_MAX_LIMIT: usize = 100;
+
+// Longest URL and title a bookmark may have
+const MAX_BOOKMARK_URL_LEN: usize = 2048;
+const MAX_BOOKMARK_TITLE_LEN: usize = 200;
+
+const BOOKMARK_USAGE: &str =
+    "Usage: /bookmark add <url> [title], /bookmark list or /bookmark remove <id>";
+
+// Longest join message /joinmsg accepts
+const MAX_JOIN_MESSAGE_LEN: usize = 500;
+
+const INVITE_USAGE: &str = "Usage: /invite <name> <room>";
+
+const JOINMSG_USAGE: &str = "Usage: /joinmsg set <text>, /joinmsg clear or /joinmsg show";
+
+const ROOMPASSWORD_USAGE: &str = "Usage: /roompassword set <password> or /roompassword clear";
+
+const ROOMCONFIG_USAGE: &str = "Usage: /roomconfig <room> [<setting> <value>], \
+     with max_length <n|default>, ttl <ttl|off> or persist <on|off|default>";
+
+// Longest text /feedback accepts
+const MAX_FEEDBACK_LEN: usize = 2000;
+
+// Aliases one user may define, and the longest command one may stand for
+const MAX_ALIASES: usize = 20;
+const MAX_ALIAS_COMMAND_LEN: usize = 200;
+
+const ALIAS_USAGE: &str = "Usage: /alias set <shortcut> <command>, /alias remove <shortcut> \
+     or /alias list; quote a command with spaces, as in /alias set brb \"/away back in 5\"";
+
+const NOTIFY_USAGE: &str = "Usage: /notify add <word>, /notify list or /notify remove <word>";
+
+// Labels of the /roomstats message size buckets, smallest first
+const SIZE_BUCKETS: [&str; 4] = ["0-100 B", "101-500 B", "501-1000 B", ">1000 B"];
+
+// Width of the longest bar in the /roomstats size chart
+const SIZE_CHART_WIDTH: usize = 20;
+
+// Rooms per /rooms page
+const ROOMS_PAGE_SIZE: usize = 20;
+
+const ROOMS_USAGE: &str = "Usage: /rooms [--page <n>] [--all]";
+
+// Longest lifetime /ephemeral and /roomttl accept
+const MAX_TTL: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+#[derive(Debug, PartialEq)]
+pub enum Command<'a> {
+    Nick(&'a str),
+    Whois(&'a str),
+    Admin(&'a str),
+    RoomStats,
+    Ephemeral { ttl: &'a str, text: &'a str },
+    RoomTtl(&'a str),
+    Pin(&'a str),
+    Unpin(&'a str),
+    Pins,
+    PinList,
+    Topic(&'a str),
+    From { sender: &'a str, limit: &'a str },
+    TailAll(&'a str),
+    Motd(&'a str),
+    Audit(&'a str),
+    Quota,
+    Ping(&'a str),
+    Drain(&'a str),
+    WordCount(&'a str),
+    Rooms(&'a str),
+    Join(&'a str),
+    Bookmark(&'a str),
+    Uptime,
+    Quiet,
+    JoinMsg(&'a str),
+    RoomPassword(&'a str),
+    Cls,
+    Notify(&'a str),
+    Alias(AliasAction<'a>),
+    Feedback(&'a str),
+    RoomConfig(&'a str),
+    RoomSettings(&'a str),
+    Msg { to: &'a str, text: &'a str },
+    Purge(&'a str),
+    Restore(&'a str),
+    PurgeDeleted,
+    Events(&'a str),
+    Kick(&'a str),
+    Invite { to: &'a str, room: &'a str },
+    Accept(&'a str),
+    Decline(&'a str),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AliasAction<'a> {
+    List,
+    Set { shortcut: &'a str, command: &'a str },
+    Remove(&'a str),
+    Invalid,
+}
+
+/// Parses a slash command; anything else is regular chat input. Built-in
+/// aliases, such as `/j` for `/join`, are listed with their command.
+pub fn parse(text: &str) -> Option<Command<'_>> {
+    let text = text.trim();
+    let (command, arg) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let arg = arg.trim();
+
+    match command {
+        "/nick" => Some(Command::Nick(arg)),
+        "/whois" => Some(Command::Whois(arg)),
+        "/admin" => Some(Command::Admin(arg)),
+        "/roomstats" => Some(Command::RoomStats),
+        "/ephemeral" => {
+            let (ttl, text) = arg.split_once(char::is_whitespace).unwrap_or((arg, ""));
+            Some(Command::Ephemeral {
+                ttl,
+                text: text.trim(),
+            })
+        }
+        "/roomttl" => Some(Command::RoomTtl(arg)),
+        "/pin" if arg == "list" => Some(Command::PinList),
+        "/pin" => Some(Command::Pin(arg)),
+        "/unpin" => Some(Command::Unpin(arg)),
+        "/pins" => Some(Command::Pins),
+        "/topic" => Some(Command::Topic(arg)),
+        "/tailall" => Some(Command::TailAll(arg)),
+        "/motd" => Some(Command::Motd(arg)),
+        "/audit" => Some(Command::Audit(arg)),
+        "/quota" => Some(Command::Quota),
+        "/ping" => Some(Command::Ping(arg)),
+        "/drain" => Some(Command::Drain(arg)),
+        "/wordcount" => Some(Command::WordCount(arg)),
+        "/rooms" => Some(Command::Rooms(arg)),
+        "/join" | "/j" => Some(Command::Join(arg)),
+        "/bookmark" => Some(Command::Bookmark(arg)),
+        "/uptime" => Some(Command::Uptime),
+        "/quiet" => Some(Command::Quiet),
+        "/joinmsg" => Some(Command::JoinMsg(arg)),
+        "/roompassword" => Some(Command::RoomPassword(arg)),
+        "/cls" => Some(Command::Cls),
+        "/notify" => Some(Command::Notify(arg)),
+        "/alias" => Some(Command::Alias(parse_alias(arg))),
+        "/feedback" => Some(Command::Feedback(arg)),
+        "/roomconfig" => Some(Command::RoomConfig(arg)),
+        "/roomsettings" => Some(Command::RoomSettings(arg)),
+        "/purge" => Some(Command::Purge(arg)),
+        "/restore" => Some(Command::Restore(arg)),
+        "/purge-deleted" => Some(Command::PurgeDeleted),
+        "/events" => Some(Command::Events(arg)),
+        "/kick" => Some(Command::Kick(arg)),
+        "/accept" => Some(Command::Accept(arg)),
+        "/decline" => Some(Command::Decline(arg)),
+        "/invite" => {
+            let (to, room) = arg.split_once(char::is_whitespace).unwrap_or((arg, ""));
+            Some(Command::Invite {
+                to,
+                room: room.trim(),
+            })
+        }
+        "/msg" | "/m" => {
+            let (to, text) = arg.split_once(char::is_whitespace).unwrap_or((arg, ""));
+            Some(Command::Msg {
+                to,
+                text: text.trim(),
+            })
+        }
+        "/from" => {
+            let (sender, limit) = arg.split_once(char::is_whitespace).unwrap_or((arg, ""));
+            Some(Command::From {
+                sender,
+                limit: limit.trim(),
+            })
+        }
+        _ => None,
+    }
+}
+
+// `/alias list`, `/alias remove <shortcut>` or `/alias set <shortcut>
+// <command>`, where the command may be wrapped in double quotes. Shortcuts
+// may be given with or without their slash
+fn parse_alias(arg: &str) -> AliasAction<'_> {
+    let (action, rest) = arg.split_once(char::is_whitespace).unwrap_or((arg, ""));
+    let rest = rest.trim();
+    let (shortcut, command) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let shortcut = shortcut.strip_prefix('/').unwrap_or(shortcut);
+    let command = command.trim();
+
+    match action {
+        "" | "list" if rest.is_empty() => AliasAction::List,
+        "remove" if !shortcut.is_empty() && command.is_empty() => AliasAction::Remove(shortcut),
+        "set" if !shortcut.is_empty() => {
+            let command = match command.strip_prefix('"') {
+                Some(quoted) => quoted.strip_suffix('"'),
+                None => Some(command),
+            };
+            match command.filter(|command| !command.is_empty()) {
+                Some(command) => AliasAction::Set { shortcut, command },
+                None => AliasAction::Invalid,
+            }
+        }
+        _ => AliasAction::Invalid,
+    }
+}
+
+pub async fn run(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    command: Command<'_>,
+) {
+    match command {
+        Command::Nick(new_name) => nick(state, handle, name, new_name).await,
+        Command::Whois(target) => whois(state, handle, target).await,
+        Command::Admin(password) => admin(state, handle, password).await,
+        Command::RoomStats => room_stats(state, handle).await,
+        Command::Ephemeral { ttl, text } => ephemeral(state, handle, name, ttl, text).await,
+        Command::RoomTtl(ttl) => room_ttl(state, handle, name, ttl).await,
+        Command::Pin(id) => pin(state, handle, name, id, true).await,
+        Command::Unpin(id) => pin(state, handle, name, id, false).await,
+        Command::Pins => finish(state, handle, "/pins", pins(state, handle).await).await,
+        Command::PinList => finish(state, handle, "/pin list", pin_list(state, handle).await).await,
+        Command::Topic(arg) => topic(state, handle, name, arg).await,
+        Command::From { sender, limit } => {
+            finish(
+                state,
+                handle,
+                "/from",
+                from(state, handle, sender, limit).await,
+            )
+            .await
+        }
+        Command::TailAll(switch) => tail_all(state, handle, switch).await,
+        Command::Motd(arg) => motd(state, handle, name, arg).await,
+        Command::Audit(limit) => {
+            finish(
+                state,
+                handle,
+                "/audit",
+                audit_log(state, handle, limit).await,
+            )
+            .await
+        }
+        Command::Quota => quota(state, handle, name).await,
+        Command::Ping(token) => {
+            let message_type = MessageType::Pong {
+                token: token.to_string(),
+                server_time: chrono::Utc::now().trunc_subsecs(3),
+                client_sent_at: None,
+            };
+            reply(state, handle, message_type, "").await;
+        }
+        Command::Drain(new_url) => drain(state, handle, name, new_url).await,
+        Command::WordCount(limit) => {
+            let result = word_count(state, handle, limit).await;
+            finish(state, handle, "/wordcount", result).await
+        }
+        Command::Rooms(args) => rooms(state, handle, args).await,
+        Command::Join(room) => join_room(state, handle, room).await,
+        Command::Bookmark(arg) => bookmark(state, handle, name, arg).await,
+        Command::Uptime => {
+            let uptime = util::format_duration(state.started.elapsed());
+            let text = format!("Server uptime: {}", uptime);
+            reply(state, handle, MessageType::System, &text).await;
+        }
+        Command::Quiet => quiet(state, handle).await,
+        Command::JoinMsg(arg) => join_message(state, handle, name, arg).await,
+        Command::RoomPassword(arg) => {
+            let result = room_password(state, handle, name, arg).await;
+            finish(state, handle, "/roompassword", result).await
+        }
+        Command::Cls => reply(state, handle, MessageType::ClearScreen, "").await,
+        Command::Notify(arg) => {
+            let result = notify(state, handle, name, arg).await;
+            finish(state, handle, "/notify", result).await
+        }
+        Command::Alias(action) => {
+            let result = alias(state, handle, name, action).await;
+            finish(state, handle, "/alias", result).await
+        }
+        Command::RoomConfig(arg) => room_config(state, handle, name, arg).await,
+        Command::RoomSettings(arg) => room_settings(state, handle, name, arg).await,
+        Command::Feedback(text) => {
+            let result = feedback(state, handle, name, text).await;
+            finish(state, handle, "/feedback", result).await
+        }
+        Command::Purge(arg) => {
+            let result = purge(state, handle, name, arg).await;
+            finish(state, handle, "/purge", result).await
+        }
+        Command::Restore(id) => {
+            let result = restore(state, handle, name, id).await;
+            finish(state, handle, "/restore", result).await
+        }
+        Command::PurgeDeleted => {
+            let result = purge_deleted(state, handle, name).await;
+            finish(state, handle, "/purge-deleted", result).await
+        }
+        Command::Kick(target) => kick(state, handle, name, target).await,
+        Command::Invite { to, room } => invite(state, handle, name, to, room).await,
+        Command::Accept(room) => accept(state, handle, room).await,
+        Command::Decline(room) => decline(state, handle, room).await,
+        Command::Events(limit) => {
+            let result = events(state, handle, limit).await;
+            finish(state, handle, "/events", result).await
+        }
+        Command::Msg { to, text } => {
+            let result = direct_message(state, handle, name, to, text).await;
+            finish(state, handle, "/msg", result).await
+        }
+    }
+}
+
+async fn nick(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    old_name: &str,
+    new_name: &str,
+) {
+    if new_name.is_empty() {
+        reply(state, handle, MessageType::System, "Usage: /nick <name>").await;
+        return;
+    }
+    if new_name == old_name {
+        let text = format!("You are already known as {}", new_name);
+        reply(state, handle, MessageType::System, &text).await;
+        return;
+    }
+
+    let user_id = handle.id().to_string();
+    let now = chrono::Utc::now().timestamp();
+
+    let (is_admin, name_changed_at) = {
+        let user_states = state.user_states.read().await;
+        user_states
+            .get(&user_id)
+            .map(|user| (user.is_admin, user.name_changed_at))
+            .unwrap_or_default()
+    };
+
+    let elapsed = now - name_changed_at;
+    let cooldown = state.config.nick_cooldown as i64;
+    if !is_admin && elapsed < cooldown {
+        let retry_after = (cooldown - elapsed) as u64;
+        let message_type = MessageType::Error {
+            code: ErrorCode::NameChangeCooldown,
+            retry_after: Some(retry_after),
+        };
+        let text = format!("You can change your name again in {} seconds", retry_after);
+        reply(state, handle, message_type, &text).await;
+        return;
+    }
+
+    if !reserve_name(&state.user_names, &user_id, new_name).await {
+        let message_type = MessageType::Error {
+            code: ErrorCode::NameTaken,
+            retry_after: None,
+        };
+        let text = format!("The name {} is already taken", new_name);
+        reply(state, handle, message_type, &text).await;
+        return;
+    }
+
+    let session_token = {
+        let mut user_states = state.user_states.write().await;
+        let user = user_states.entry(user_id.clone()).or_default();
+        user.name_changed_at = now;
+        user.is_guest = false;
+        user.session_token.clone()
+    };
+    // Resuming the session brings back the new name
+    if let Some(token) = session_token {
+        state
+            .sessions
+            .write()
+            .await
+            .insert(token, new_name.to_string());
+    }
+
+    if let Err(e) = state.store.save_name_change(old_name, new_name, now).await {
+        error!("Failed to save name change: {}", e);
+    }
+
+    let message = Message::system(format!("{} is now known as {}", old_name, new_name));
+    broadcast(state, None, &message).await;
+}
+
+async fn whois(state: &NamespaceState, handle: &Arc<ConnectionHandle<TcpStream>>, target: &str) {
+    if target.is_empty() {
+        reply(state, handle, MessageType::System, "Usage: /whois <name>").await;
+        return;
+    }
+
+    let online = {
+        let names = state.user_names.read().await;
+        names.values().any(|name| name == target)
+    };
+
+    let previous = match state.store.previous_names(target, WHOIS_HISTORY).await {
+        Ok(previous) => previous,
+        Err(e) => {
+            error!("Failed to load name history: {}", e);
+            Vec::new()
+        }
+    };
+
+    let status = if online { "online" } else { "offline" };
+    let text = if previous.is_empty() {
+        format!("{} is {}. No previous names.", target, status)
+    } else {
+        format!(
+            "{} is {}. Previously known as: {}",
+            target,
+            status,
+            previous.join(", ")
+        )
+    };
+    reply(state, handle, MessageType::System, &text).await;
+}
+
+async fn admin(state: &NamespaceState, handle: &Arc<ConnectionHandle<TcpStream>>, password: &str) {
+    let Some(expected) = &state.config.admin_password else {
+        reply(state, handle, unauthorized(), "Admin login is disabled").await;
+        return;
+    };
+
+    // Wrong guesses here and at room passwords count against the same
+    // lockout, and a locked out connection isn't checked at all
+    let id = handle.id();
+    if let Some(retry_after) = state.password_failures.retry_after(&id).await {
+        // Rounded up, so trying again when told is never too early
+        let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        let message_type = MessageType::Error {
+            code: ErrorCode::TooManyAttempts,
+            retry_after: Some(secs),
+        };
+        let text = format!(
+            "Too many wrong passwords, try again in {}",
+            util::format_duration(Duration::from_secs(secs))
+        );
+        reply(state, handle, message_type, &text).await;
+        return;
+    }
+    if !util::constant_time_eq(expected.as_bytes(), password.as_bytes()) {
+        let _ = state.password_failures.attempt(id).await;
+        reply(state, handle, unauthorized(), "Incorrect admin password").await;
+        return;
+    }
+
+    {
+        let mut user_states = state.user_states.write().await;
+        user_states.entry(id.to_string()).or_default().is_admin = true;
+    }
+    reply(state, handle, MessageType::System, "You are now an admin").await;
+}
+
+async fn room_stats(state: &NamespaceState, handle: &Arc<ConnectionHandle<TcpStream>>) {
+    let room = DEFAULT_ROOM;
+    let per_minute = {
+        let activity = state.room_activity.read().await;
+        activity.get(room).map_or(0, |sent| {
+            sent.iter()
+                .filter(|at| at.elapsed() <= ACTIVITY_WINDOW)
+                .count()
+        })
+    };
+    let per_second = per_minute as f64 / ACTIVITY_WINDOW.as_secs_f64();
+
+    // Observers never count as members, so they are reported on their own
+    let observers = {
+        let user_states = state.user_states.read().await;
+        user_states.values().filter(|user| user.is_observer).count()
+    };
+
+    let mut text = format!(
+        "Room {} in {}: {:.2} messages/sec, {} messages/min, {} observers",
+        room, state.name, per_second, per_minute, observers
+    );
+    match state.store.message_sizes(room).await {
+        Ok(sizes) => {
+            text.push_str("\nMessage sizes:\n");
+            text.push_str(&size_chart(sizes));
+        }
+        Err(e) => error!("Failed to count message sizes: {}", e),
+    }
+    reply(state, handle, MessageType::System, &text).await;
+}
+
+// Draws message counts per size bucket as bars of `#`, scaled so the
+// biggest bucket fills SIZE_CHART_WIDTH
+fn size_chart(sizes: [i64; 4]) -> String {
+    let most = sizes.iter().copied().max().unwrap_or(0).max(1);
+    let lines: Vec<String> = SIZE_BUCKETS
+        .iter()
+        .zip(sizes)
+        .map(|(label, count)| {
+            let width = (count as u64 * SIZE_CHART_WIDTH as u64).div_ceil(most as u64) as usize;
+            let bar = "#".repeat(width);
+            format!(
+                "{:>10} | {:<width$} {}",
+                label,
+                bar,
+                count,
+                width = SIZE_CHART_WIDTH
+            )
+        })
+        .collect();
+    lines.join("\n")
+}
+
+/// Sends one page of the namespace's rooms as a `RoomList`, busiest first.
+/// `args` is what followed `/rooms`: `--page <n>` and, for admins, `--all`.
+pub async fn rooms(state: &NamespaceState, handle: &Arc<ConnectionHandle<TcpStream>>, args: &str) {
+    let mut all = false;
+    let mut page = 1;
+    let mut args = args.split_whitespace();
+    while let Some(arg) = args.next() {
+        match arg {
+            "--all" => all = true,
+            "--page" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(n) if n >= 1 => page = n,
+                _ => {
+                    reply(state, handle, MessageType::System, ROOMS_USAGE).await;
+                    return;
+                }
+            },
+            _ => {
+                reply(state, handle, MessageType::System, ROOMS_USAGE).await;
+                return;
+            }
+        }
+    }
+    if all && !require_admin(state, handle, "list every room").await {
+        return;
+    }
+
+    // Rooms with a password are still listed and none is archived, so
+    // `--all` lists the same rooms as everyone else sees
+    let names = [DEFAULT_ROOM];
+    let (members, own_room) = {
+        let clients = state.clients.read().await;
+        let members: Vec<(String, String)> = clients
+            .values()
+            .filter(|client| client.namespace.as_deref() == Some(state.name.as_str()))
+            .filter_map(|client| Some((client.room.clone()?, client.handle.id().to_string())))
+            .collect();
+        let own_room = clients
+            .get(&handle.id())
+            .and_then(|client| client.room.clone());
+        (members, own_room)
+    };
+    let mut rooms = Vec::new();
+    {
+        let user_states = state.user_states.read().await;
+        let hourly_messages = state.hourly_messages.read().await;
+        let room_settings = state.room_settings.read().await;
+        for name in names {
+            // Observers never count as members
+            let members = members
+                .iter()
+                .filter(|(room, _)| room == name)
+                .filter(|(_, id)| !user_states.get(id).is_some_and(|user| user.is_observer))
+                .count();
+            rooms.push(RoomInfo {
+                name: name.to_string(),
+                topic: room_settings
+                    .get(name)
+                    .and_then(|settings| settings.topic.clone()),
+                members,
+                messages_last_hour: hourly_messages.get(name).copied().unwrap_or(0),
+                is_member: own_room.as_deref() == Some(name),
+                has_password: room_settings
+                    .get(name)
+                    .is_some_and(|settings| settings.password_hash.is_some()),
+            });
+        }
+    }
+    rooms.sort_by(|a, b| {
+        b.messages_last_hour
+            .cmp(&a.messages_last_hour)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+
+    let pages = rooms.len().div_ceil(ROOMS_PAGE_SIZE).max(1);
+    if page > pages {
+        let text = format!("No such page: {} (the last is {})", page, pages);
+        reply(state, handle, MessageType::System, &text).await;
+        return;
+    }
+    let rooms = rooms
+        .into_iter()
+        .skip((page - 1) * ROOMS_PAGE_SIZE)
+        .take(ROOMS_PAGE_SIZE)
+        .collect();
+    reply(
+        state,
+        handle,
+        MessageType::RoomList { rooms, page, pages },
+        "",
+    )
+    .await;
+}
+
+async fn ephemeral(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    ttl: &str,
+    text: &str,
+) {
+    match parse_ttl(ttl) {
+        Some(ttl) if !text.is_empty() => {
+            post_chat(state, handle, name, text, Some(ttl), None).await
+        }
+        _ => {
+            let usage =
+                "Usage: /ephemeral <ttl> <message>, e.g. /ephemeral 1h hello (at most 365d)";
+            reply(state, handle, MessageType::System, usage).await;
+        }
+    }
+}
+
+// Sets how long new messages in the room last unless their sender says otherwise
+async fn room_ttl(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    ttl: &str,
+) {
+    if !require_admin(state, handle, "change the room's message lifetime").await {
+        return;
+    }
+
+    let duration = match ttl {
+        "off" => None,
+        ttl => match parse_ttl(ttl) {
+            Some(duration) => Some(duration),
+            None => {
+                let usage = "Usage: /roomttl <ttl|off>, with a ttl of at most 365d";
+                reply(state, handle, MessageType::System, usage).await;
+                return;
+            }
+        },
+    };
+
+    let room = DEFAULT_ROOM;
+    {
+        let mut room_settings = state.room_settings.write().await;
+        room_settings.entry(room.to_string()).or_default().ttl = duration;
+    }
+    audit(state, name, &format!("roomttl {}", ttl), room).await;
+
+    let text = match duration {
+        Some(_) => format!("Messages in {} now disappear after {}", room, ttl),
+        None => format!("Messages in {} no longer disappear", room),
+    };
+    let message = Message::system(text);
+    broadcast(state, None, &message).await;
+}
+
+// A /roomconfig change; None puts back the server's rule
+enum RoomChange {
+    MaxLength(Option<u64>),
+    Ttl(Option<Duration>),
+    Persist(Option<bool>),
+}
+
+// Shows a room's settings, or lets an admin override one of the server's
+// rules for it
+async fn room_config(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    arg: &str,
+) {
+    let mut args = arg.split_whitespace();
+    let (Some(room), setting, value, None) = (args.next(), args.next(), args.next(), args.next())
+    else {
+        reply(state, handle, MessageType::System, ROOMCONFIG_USAGE).await;
+        return;
+    };
+    if room != DEFAULT_ROOM {
+        let text = format!("No such room: {}", room);
+        reply(state, handle, MessageType::System, &text).await;
+        return;
+    }
+
+    let (setting, value) = match (setting, value) {
+        (None, _) => {
+            let text = {
+                let room_settings = state.room_settings.read().await;
+                let settings = room_settings.get(room);
+                let max_length = settings
+                    .and_then(|settings| settings.max_length)
+                    .or(state.config.max_message_length)
+                    .map_or("unlimited".to_string(), |max| max.to_string());
+                let ttl = match settings.and_then(|settings| settings.ttl) {
+                    Some(ttl) => util::format_duration(ttl),
+                    None => "off".to_string(),
+                };
+                let persist = settings.and_then(|settings| settings.persist) != Some(false);
+                format!(
+                    "Settings of {}: max_length {}, ttl {}, persist {}",
+                    room,
+                    max_length,
+                    ttl,
+                    if persist { "on" } else { "off" }
+                )
+            };
+            reply(state, handle, MessageType::System, &text).await;
+            return;
+        }
+        (Some(setting), Some(value)) => (setting, value),
+        (Some(_), None) => {
+            reply(state, handle, MessageType::System, ROOMCONFIG_USAGE).await;
+            return;
+        }
+    };
+
+    if !require_admin(state, handle, "change room settings").await {
+        return;
+    }
+
+    let change = match setting {
+        "max_length" => match value {
+            "default" => Some(RoomChange::MaxLength(None)),
+            value => value
+                .parse::<u64>()
+                .ok()
+                .filter(|max| *max > 0)
+                .map(|max| RoomChange::MaxLength(Some(max))),
+        },
+        "ttl" => match value {
+            "off" => Some(RoomChange::Ttl(None)),
+            value => parse_ttl(value).map(|ttl| RoomChange::Ttl(Some(ttl))),
+        },
+        "persist" => match value {
+            "on" => Some(RoomChange::Persist(Some(true))),
+            "off" => Some(RoomChange::Persist(Some(false))),
+            "default" => Some(RoomChange::Persist(None)),
+            _ => None,
+        },
+        _ => {
+            let message_type = MessageType::Error {
+                code: ErrorCode::UnknownSetting,
+                retry_after: None,
+            };
+            let text = format!(
+                "Unknown room setting {}; rooms have max_length, ttl and persist",
+                setting
+            );
+            reply(state, handle, message_type, &text).await;
+            return;
+        }
+    };
+    let Some(change) = change else {
+        reply(state, handle, MessageType::System, ROOMCONFIG_USAGE).await;
+        return;
+    };
+
+    {
+        let mut room_settings = state.room_settings.write().await;
+        let settings = room_settings.entry(room.to_string()).or_default();
+        match change {
+            RoomChange::MaxLength(max) => settings.max_length = max,
+            RoomChange::Ttl(ttl) => settings.ttl = ttl,
+            RoomChange::Persist(persist) => settings.persist = persist,
+        }
+    }
+
+    let action = format!("roomconfig {} {}", setting, value);
+    audit(state, name, &action, room).await;
+    let text = format!("{} of {} is now {}", setting, room, value);
+    reply(state, handle, MessageType::System, &text).await;
+}
+
+// Content a room can turn off with /roomsettings
+#[derive(Debug, PartialEq)]
+enum Content {
+    Links,
+    Uploads,
+}
+
+// Shows what the caller's room allows, or lets an admin turn links and
+// uploads on or off there with pairs such as `links=off uploads=on`
+async fn room_settings(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    arg: &str,
+) {
+    let room = DEFAULT_ROOM;
+    if arg.is_empty() {
+        let text = {
+            let room_settings = state.room_settings.read().await;
+            let settings = room_settings.get(room);
+            let no_links = settings.is_some_and(|settings| settings.no_links);
+            let no_uploads = settings.is_some_and(|settings| settings.no_uploads);
+            format!(
+                "Content settings of {}: links={} uploads={}",
+                room,
+                if no_links { "off" } else { "on" },
+                if no_uploads { "off" } else { "on" }
+            )
+        };
+        reply(state, handle, MessageType::System, &text).await;
+        return;
+    }
+    if !require_admin(state, handle, "change room settings").await {
+        return;
+    }
+
+    let changes = match parse_content_settings(arg) {
+        Ok(changes) => changes,
+        Err(problems) => {
+            let message_type = MessageType::Error {
+                code: ErrorCode::UnknownSetting,
+                retry_after: None,
+            };
+            let text = format!(
+                "Nothing changed: {}. Rooms have links and uploads, each on or off",
+                problems.join(", ")
+            );
+            reply(state, handle, message_type, &text).await;
+            return;
+        }
+    };
+    {
+        let mut room_settings = state.room_settings.write().await;
+        let settings = room_settings.entry(room.to_string()).or_default();
+        for (content, on) in &changes {
+            match content {
+                Content::Links => settings.no_links = !on,
+                Content::Uploads => settings.no_uploads = !on,
+            }
+        }
+    }
+
+    let pairs = arg.split_whitespace().collect::<Vec<_>>().join(" ");
+    audit(state, name, &format!("roomsettings {}", pairs), room).await;
+    let message = Message::system(format!(
+        "{} changed the settings of {}: {}",
+        name, room, pairs
+    ));
+    broadcast(state, None, &message).await;
+}
+
+// Reads `setting=value` pairs, describing every one that is malformed,
+// names no setting or has a value other than on or off
+fn parse_content_settings(arg: &str) -> Result<Vec<(Content, bool)>, Vec<String>> {
+    let mut changes = Vec::new();
+    let mut problems = Vec::new();
+    for pair in arg.split_whitespace() {
+        let Some((setting, value)) = pair.split_once('=') else {
+            problems.push(format!("{} is not setting=value", pair));
+            continue;
+        };
+        let content = match setting {
+            "links" => Content::Links,
+            "uploads" => Content::Uploads,
+            _ => {
+                problems.push(format!("{} is not a setting", setting));
+                continue;
+            }
+        };
+        match value {
+            "on" => changes.push((content, true)),
+            "off" => changes.push((content, false)),
+            _ => problems.push(format!("{} is not on or off", pair)),
+        }
+    }
+    if problems.is_empty() {
+        Ok(changes)
+    } else {
+        Err(problems)
+    }
+}
+
+async fn pin(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    id: &str,
+    pinned: bool,
+) {
+    let command = if pinned { "/pin" } else { "/unpin" };
+    if !require_admin(state, handle, &format!("use {}", command)).await {
+        return;
+    }
+    let Ok(id) = id.parse::<i64>() else {
+        let text = format!("Usage: {} <message_id>", command);
+        reply(state, handle, MessageType::System, &text).await;
+        return;
+    };
+
+    let message = match state.store.set_pinned(DEFAULT_ROOM, id, pinned).await {
+        Ok(Some(message)) => message,
+        Ok(None) => {
+            let text = format!("No message with id {}", id);
+            reply(state, handle, MessageType::System, &text).await;
+            return;
+        }
+        Err(e) => {
+            error!("Failed to update pin: {}", e);
+            return;
+        }
+    };
+    let action = if pinned { "pin" } else { "unpin" };
+    audit(state, name, action, &format!("message {}", id)).await;
+
+    let message_type = if pinned {
+        MessageType::Pinned
+    } else {
+        MessageType::Unpinned
+    };
+    broadcast(state, None, &stored_message(message_type, &message)).await;
+}
+
+async fn pins(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+) -> Result<(), ChatError> {
+    let pinned = state.store.get_pinned(DEFAULT_ROOM).await?;
+
+    if pinned.is_empty() {
+        reply(state, handle, MessageType::System, "No pinned messages").await;
+        return Ok(());
+    }
+    for message in &pinned {
+        let message = stored_message(MessageType::Pinned, message);
+        if let Err(e) = send(state, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+    }
+    Ok(())
+}
+
+// Unlike /pins, which sends each pinned message whole, sums them up in one
+// numbered list
+async fn pin_list(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+) -> Result<(), ChatError> {
+    let pinned = state.store.get_pinned(DEFAULT_ROOM).await?;
+
+    if pinned.is_empty() {
+        let text = "No pinned messages in this room.";
+        reply(state, handle, MessageType::System, text).await;
+        return Ok(());
+    }
+    let lines: Vec<String> = pinned
+        .iter()
+        .enumerate()
+        .map(|(n, message)| {
+            format!(
+                "{}. {} {}: {}",
+                n + 1,
+                message.sent_at.format("%Y-%m-%d %H:%M"),
+                message.sender,
+                preview(&message.text)
+            )
+        })
+        .collect();
+    let text = format!("Pinned in {}:\n{}", DEFAULT_ROOM, lines.join("\n"));
+    reply(state, handle, MessageType::System, &text).await;
+    Ok(())
+}
+
+// The start of a message, marked with an ellipsis when there is more
+fn preview(text: &str) -> String {
+    match text.char_indices().nth(PIN_LIST_PREVIEW) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+// Invites the user named `to` into `room`. They are sent an Invite they can
+// answer from wherever they are, the lobby included, and accepting lets them
+// in without the room's password
+async fn invite(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    to: &str,
+    room: &str,
+) {
+    if to.is_empty() || room.is_empty() {
+        reply(state, handle, MessageType::System, INVITE_USAGE).await;
+        return;
+    }
+    if to == name {
+        let text = "You can't invite yourself";
+        reply(state, handle, MessageType::System, text).await;
+        return;
+    }
+    if room != DEFAULT_ROOM {
+        let text = format!("No such room: {}", room);
+        reply(state, handle, MessageType::System, &text).await;
+        return;
+    }
+    let Some(id) = connection_named(state, to).await else {
+        let text = format!("{} is not online", to);
+        reply(state, handle, MessageType::System, &text).await;
+        return;
+    };
+
+    let message = Message::new(
+        MessageType::Invite {
+            room: room.to_string(),
+            from: name.to_string(),
+        },
+        format!(
+            "{} invited you to {}. /accept {} or /decline {}",
+            name, room, room, room
+        ),
+    );
+    let text = {
+        let mut clients = state.clients.write().await;
+        match clients.get_mut(&id) {
+            None => format!("{} is not online", to),
+            Some(client) if client.room.as_deref() == Some(room) => {
+                format!("{} is already in {}", to, room)
+            }
+            Some(client) => {
+                client.invites.insert(room.to_string(), name.to_string());
+                let _ = client.outbox.send(Outbound::Message(message));
+                format!("Invited {} to {}", to, room)
+            }
+        }
+    };
+    reply(state, handle, MessageType::System, &text).await;
+}
+
+// Takes up an invitation from /invite and joins its room
+pub async fn accept(state: &NamespaceState, handle: &Arc<ConnectionHandle<TcpStream>>, room: &str) {
+    if room.is_empty() {
+        reply(state, handle, MessageType::System, "Usage: /accept <room>").await;
+        return;
+    }
+    let text = {
+        let mut clients = state.clients.write().await;
+        let Some(client) = clients.get_mut(&handle.id()) else {
+            return;
+        };
+        match &client.room {
+            Some(current) => Some(format!("Already in room {}", current)),
+            None if client.invites.remove(room).is_none() => {
+                Some(format!("You have no invitation to {}", room))
+            }
+            None => None,
+        }
+    };
+    if let Some(text) = text {
+        reply(state, handle, MessageType::System, &text).await;
+        return;
+    }
+
+    // Invites are only ever sent for the main room
+    if enter_room(&state.clients, handle, DEFAULT_ROOM).await {
+        greet_room(state, handle).await;
+    }
+}
+
+// Turns down an invitation from /invite, telling whoever sent it
+pub async fn decline(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    room: &str,
+) {
+    if room.is_empty() {
+        reply(state, handle, MessageType::System, "Usage: /decline <room>").await;
+        return;
+    }
+    let inviter = {
+        let mut clients = state.clients.write().await;
+        let Some(client) = clients.get_mut(&handle.id()) else {
+            return;
+        };
+        client.invites.remove(room)
+    };
+    let Some(inviter) = inviter else {
+        let text = format!("You have no invitation to {}", room);
+        reply(state, handle, MessageType::System, &text).await;
+        return;
+    };
+
+    let text = format!("You declined the invitation to {}", room);
+    reply(state, handle, MessageType::System, &text).await;
+
+    let name = {
+        let names = state.user_names.read().await;
+        names.get(&handle.id().to_string()).cloned()
+    };
+    let Some(id) = connection_named(state, &inviter).await else {
+        return;
+    };
+    let message = Message::system(format!(
+        "{} declined your invitation to {}",
+        name.as_deref().unwrap_or("Someone"),
+        room
+    ));
+    if let Err(e) = enqueue(&state.clients, id, Outbound::Message(message)).await {
+        error!("Failed to send message: {}", e);
+    }
+}
+
+// Disconnects the user named `target`. Nothing stops them coming back; the
+// close code tells their client not to do so on its own
+async fn kick(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    target: &str,
+) {
+    if !require_admin(state, handle, "use /kick").await {
+        return;
+    }
+    if target.is_empty() {
+        reply(state, handle, MessageType::System, "Usage: /kick <name>").await;
+        return;
+    }
+    if target == name {
+        reply(
+            state,
+            handle,
+            MessageType::System,
+            "You can't kick yourself",
+        )
+        .await;
+        return;
+    }
+    let Some(id) = connection_named(state, target).await else {
+        let text = format!("{} is not online", target);
+        reply(state, handle, MessageType::System, &text).await;
+        return;
+    };
+
+    audit(state, name, "kick", target).await;
+    room_event(state, DEFAULT_ROOM, "kick", name, target).await;
+    let message = Message::system(format!("{} kicked {}", name, target));
+    broadcast(state, None, &message).await;
+    close_with(&state.clients, id, CloseReason::Kicked).await;
+}
+
+// Deletes the newest messages a user sent to the room in one go, for
+// cleaning up after spam, or with --preview only lists them. Clients get one
+// BulkDeleted frame and the audit log one entry, however many there were
+async fn purge(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    arg: &str,
+) -> Result<(), ChatError> {
+    if !require_admin(state, handle, "use /purge").await {
+        return Ok(());
+    }
+    let mut args = arg.split_whitespace();
+    let (sender, count, dry_run) = (args.next(), args.next(), args.next());
+    let count = count
+        .and_then(|count| count.parse::<usize>().ok())
+        .filter(|count| *count > 0);
+    let (Some(sender), Some(count), None | Some("--preview"), None) =
+        (sender, count, dry_run, args.next())
+    else {
+        reply(state, handle, MessageType::System, PURGE_USAGE).await;
+        return Ok(());
+    };
+    if count > PURGE_MAX_LIMIT {
+        let text = format!(
+            "/purge deletes at most {} messages at a time",
+            PURGE_MAX_LIMIT
+        );
+        reply(state, handle, MessageType::System, &text).await;
+        return Ok(());
+    }
+
+    let messages = if dry_run.is_some() {
+        state
+            .store
+            .messages_from(DEFAULT_ROOM, sender, count)
+            .await?
+    } else {
+        state
+            .store
+            .purge_messages(DEFAULT_ROOM, sender, count)
+            .await?
+    };
+    if messages.is_empty() {
+        let text = format!("{} has no messages in {}", sender, DEFAULT_ROOM);
+        reply(state, handle, MessageType::System, &text).await;
+        return Ok(());
+    }
+
+    if dry_run.is_some() {
+        let lines: Vec<String> = messages
+            .iter()
+            .map(|message| format!("{}: {}", message.id, preview(&message.text)))
+            .collect();
+        let text = format!(
+            "/purge would delete {} of {}'s messages in {}:\n{}",
+            messages.len(),
+            sender,
+            DEFAULT_ROOM,
+            lines.join("\n")
+        );
+        reply(state, handle, MessageType::System, &text).await;
+        return Ok(());
+    }
+
+    let action = format!("purge {} messages", messages.len());
+    audit(state, name, &action, sender).await;
+    room_event(state, DEFAULT_ROOM, "purge", name, sender).await;
+    let message = Message::new(
+        MessageType::BulkDeleted {
+            message_ids: messages.iter().map(|message| message.id).collect(),
+        },
+        format!(
+            "{} purged {} of {}'s messages",
+            name,
+            messages.len(),
+            sender
+        ),
+    );
+    broadcast(state, None, &message).await;
+    Ok(())
+}
+
+// Undoes /purge for one message, which goes back into history and to every
+// client in its original place
+async fn restore(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    id: &str,
+) -> Result<(), ChatError> {
+    if !require_admin(state, handle, "use /restore").await {
+        return Ok(());
+    }
+    let Ok(id) = id.parse::<i64>() else {
+        reply(
+            state,
+            handle,
+            MessageType::System,
+            "Usage: /restore <message_id>",
+        )
+        .await;
+        return Ok(());
+    };
+
+    let Some(message) = state.store.restore_message(DEFAULT_ROOM, id).await? else {
+        let text = format!("No deleted message with id {}", id);
+        reply(state, handle, MessageType::System, &text).await;
+        return Ok(());
+    };
+    audit(state, name, "restore", &format!("message {}", id)).await;
+    broadcast(state, None, &stored_message(MessageType::Restore, &message)).await;
+    Ok(())
+}
+
+// Removes the room's purged messages for good, after which /restore can't
+// bring them back
+async fn purge_deleted(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+) -> Result<(), ChatError> {
+    if !require_admin(state, handle, "use /purge-deleted").await {
+        return Ok(());
+    }
+
+    let removed = state.store.purge_deleted(DEFAULT_ROOM).await?;
+    if removed > 0 {
+        let action = format!("remove {} deleted messages", removed);
+        audit(state, name, &action, DEFAULT_ROOM).await;
+    }
+    let text = format!(
+        "Removed {} deleted messages from {} for good",
+        removed, DEFAULT_ROOM
+    );
+    reply(state, handle, MessageType::System, &text).await;
+    Ok(())
+}
+
+// Lists, adds or removes the room's bookmarks. Rooms have no moderators of
+// their own, so the list is open to everyone
+async fn bookmark(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    arg: &str,
+) {
+    let room = DEFAULT_ROOM;
+    let (action, arg) = arg.split_once(char::is_whitespace).unwrap_or((arg, ""));
+    let arg = arg.trim();
+
+    match action {
+        "list" => {
+            if let Some(message) = bookmark_list(state).await
+                && let Err(e) = send(state, handle, &message).await
+            {
+                error!("Failed to send message: {}", e);
+            }
+        }
+        "add" => {
+            let (url, title) = arg.split_once(char::is_whitespace).unwrap_or((arg, ""));
+            let title = match title.trim() {
+                "" => url,
+                title => title,
+            };
+            if !is_bookmark_url(url) {
+                let text = format!(
+                    "Bookmarks must be http or https URLs of at most {} characters",
+                    MAX_BOOKMARK_URL_LEN
+                );
+                reply(state, handle, MessageType::System, &text).await;
+                return;
+            }
+            if title.chars().count() > MAX_BOOKMARK_TITLE_LEN {
+                let text = format!(
+                    "Bookmark titles are at most {} characters",
+                    MAX_BOOKMARK_TITLE_LEN
+                );
+                reply(state, handle, MessageType::System, &text).await;
+                return;
+            }
+
+            let count = match state.store.bookmarks(room).await {
+                Ok(bookmarks) => bookmarks.len() as u64,
+                Err(e) => {
+                    error!("Failed to load bookmarks: {}", e);
+                    return;
+                }
+            };
+            if count >= state.config.max_bookmarks {
+                let message_type = MessageType::Error {
+                    code: ErrorCode::CapacityReached,
+                    retry_after: None,
+                };
+                let text = format!("{} already has {} bookmarks", room, count);
+                reply(state, handle, message_type, &text).await;
+                return;
+            }
+
+            let added_at = chrono::Utc::now();
+            if let Err(e) = state
+                .store
+                .add_bookmark(room, url, title, name, added_at)
+                .await
+            {
+                error!("Failed to save bookmark: {}", e);
+                return;
+            }
+            if let Some(message) = bookmark_list(state).await {
+                broadcast(state, None, &message).await;
+            }
+        }
+        "remove" => {
+            let Ok(id) = arg.parse::<i64>() else {
+                reply(state, handle, MessageType::System, BOOKMARK_USAGE).await;
+                return;
+            };
+            match state.store.remove_bookmark(room, id).await {
+                Ok(Some(_)) => {
+                    if let Some(message) = bookmark_list(state).await {
+                        broadcast(state, None, &message).await;
+                    }
+                }
+                Ok(None) => {
+                    let text = format!("No bookmark {} in {}", id, room);
+                    reply(state, handle, MessageType::System, &text).await;
+                }
+                Err(e) => error!("Failed to remove bookmark: {}", e),
+            }
+        }
+        _ => reply(state, handle, MessageType::System, BOOKMARK_USAGE).await,
+    }
+}
+
+// An http or https URL with something after the scheme, short enough to store
+fn is_bookmark_url(url: &str) -> bool {
+    url.len() <= MAX_BOOKMARK_URL_LEN
+        && url
+            .split_once("://")
+            .is_some_and(|(scheme, rest)| matches!(scheme, "http" | "https") && !rest.is_empty())
+}
+
+// Shows the topic with no argument, locks or unlocks it, lists its recent
+// changes, or sets it, with or without `set` in front
+async fn topic(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    arg: &str,
+) {
+    let room = DEFAULT_ROOM;
+    let is_admin = is_admin(state, handle).await;
+
+    if arg.is_empty() {
+        let text = {
+            let room_settings = state.room_settings.read().await;
+            match room_settings
+                .get(room)
+                .and_then(|settings| settings.topic.as_ref())
+            {
+                Some(topic) => format!("The topic of {} is: {}", room, topic),
+                None => format!("{} has no topic", room),
+            }
+        };
+        reply(state, handle, MessageType::System, &text).await;
+        return;
+    }
+
+    // `set` makes even a topic of "lock" or "history" a topic
+    let (arg, set) = match arg.split_once(char::is_whitespace) {
+        Some(("set", topic)) => (topic.trim(), true),
+        _ if arg == "set" => {
+            let text = "Usage: /topic [set] <text>, /topic lock, /topic unlock or /topic history";
+            reply(state, handle, MessageType::System, text).await;
+            return;
+        }
+        _ => (arg, false),
+    };
+    let text = match arg {
+        "history" if !set => {
+            topic_history(state, handle, room).await;
+            return;
+        }
+        "lock" | "unlock" if !set => {
+            if !require_admin(state, handle, &format!("{} the topic", arg)).await {
+                return;
+            }
+            let locked = arg == "lock";
+            {
+                let mut room_settings = state.room_settings.write().await;
+                room_settings
+                    .entry(room.to_string())
+                    .or_default()
+                    .topic_locked = locked;
+            }
+            audit(state, name, &format!("{} topic", arg), room).await;
+            room_event(state, room, &format!("topic_{}", arg), name, "").await;
+            if locked {
+                format!("The topic of {} is now locked", room)
+            } else {
+                format!("The topic of {} is now unlocked", room)
+            }
+        }
+        topic => {
+            {
+                let mut room_settings = state.room_settings.write().await;
+                let settings = room_settings.entry(room.to_string()).or_default();
+                if settings.topic_locked && !is_admin {
+                    drop(room_settings);
+                    let text = format!("The topic of {} is locked", room);
+                    reply(state, handle, unauthorized(), &text).await;
+                    return;
+                }
+                settings.topic = Some(topic.to_string());
+            }
+            room_event(state, room, "topic", name, "").await;
+            if let Err(e) = state.store.record_topic_change(room, topic, name).await {
+                error!("Failed to record the topic change: {}", e);
+            }
+            format!("{} set the topic of {} to: {}", name, room, topic)
+        }
+    };
+
+    let message = Message::system(text);
+    broadcast(state, None, &message).await;
+}
+
+// Lists the room's latest topics, newest first, so an overwritten one can be
+// set again
+async fn topic_history(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    room: &str,
+) {
+    let changes = match state.store.topic_changes(room, TOPIC_HISTORY_LIMIT).await {
+        Ok(changes) => changes,
+        Err(e) => {
+            error!("Failed to load topic changes: {}", e);
+            return;
+        }
+    };
+
+    let text = if changes.is_empty() {
+        format!("The topic of {} has never been set", room)
+    } else {
+        let lines: Vec<String> = changes
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(n, change)| {
+                format!(
+                    "{}. {} {}: {}",
+                    n + 1,
+                    change.at.format("%Y-%m-%d %H:%M"),
+                    change.changed_by,
+                    change.topic
+                )
+            })
+            .collect();
+        format!("Topic history of {}:\n{}", room, lines.join("\n"))
+    };
+    reply(state, handle, MessageType::System, &text).await;
+}
+
+// Shows or changes what everyone joining the room is sent first
+async fn join_message(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    arg: &str,
+) {
+    let room = DEFAULT_ROOM;
+    let (action, text) = arg.split_once(char::is_whitespace).unwrap_or((arg, ""));
+    let text = text.trim();
+
+    let join_message = match (action, text) {
+        ("show", "") => {
+            // Exactly the frame newcomers get
+            match room_greeting(state).await {
+                Some(message) => {
+                    if let Err(e) = send(state, handle, &message).await {
+                        error!("Failed to send message: {}", e);
+                    }
+                }
+                None => {
+                    let text = format!("{} has no join message", room);
+                    reply(state, handle, MessageType::System, &text).await;
+                }
+            }
+            return;
+        }
+        ("set", text) if !text.is_empty() => Some(text),
+        ("clear", "") => None,
+        _ => {
+            reply(state, handle, MessageType::System, JOINMSG_USAGE).await;
+            return;
+        }
+    };
+
+    if !require_admin(state, handle, "change the join message").await {
+        return;
+    }
+    if let Some(text) = join_message
+        && text.chars().count() > MAX_JOIN_MESSAGE_LEN
+    {
+        let text = format!(
+            "Join messages can be at most {} characters",
+            MAX_JOIN_MESSAGE_LEN
+        );
+        reply(state, handle, MessageType::System, &text).await;
+        return;
+    }
+
+    {
+        let mut room_settings = state.room_settings.write().await;
+        room_settings
+            .entry(room.to_string())
+            .or_default()
+            .join_message = join_message.map(str::to_string);
+    }
+    audit(state, name, &format!("joinmsg {}", action), room).await;
+
+    let text = match join_message {
+        Some(_) => format!("Newcomers to {} will now see the new join message", room),
+        None => format!("{} no longer has a join message", room),
+    };
+    reply(state, handle, MessageType::System, &text).await;
+}
+
+// Sets or clears the password /join needs for the room. Only its hash is
+// kept. Members already in the room stay
+async fn room_password(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    arg: &str,
+) -> Result<(), ChatError> {
+    let room = DEFAULT_ROOM;
+    let (action, password) = arg.split_once(char::is_whitespace).unwrap_or((arg, ""));
+    let password = password.trim();
+
+    let password = match (action, password) {
+        ("set", password) if !password.is_empty() => Some(password),
+        ("clear", "") => None,
+        _ => {
+            reply(state, handle, MessageType::System, ROOMPASSWORD_USAGE).await;
+            return Ok(());
+        }
+    };
+    if !require_admin(state, handle, "change the room password").await {
+        return Ok(());
+    }
+
+    let password_hash = match password {
+        Some(password) => Some(util::hash_password(password.to_string()).await?),
+        None => None,
+    };
+    {
+        let mut room_settings = state.room_settings.write().await;
+        room_settings
+            .entry(room.to_string())
+            .or_default()
+            .password_hash = password_hash;
+    }
+    audit(state, name, &format!("roompassword {}", action), room).await;
+
+    let text = match password {
+        Some(_) => format!("Joining {} now takes a password", room),
+        None => format!("{} no longer needs a password", room),
+    };
+    reply(state, handle, MessageType::System, &text).await;
+    Ok(())
+}
+
+// Toggles whether the connection gets the room's System notices. Chat,
+// errors and replies to its own commands still come through
+async fn quiet(state: &NamespaceState, handle: &Arc<ConnectionHandle<TcpStream>>) {
+    let quiet = {
+        let mut clients = state.clients.write().await;
+        let Some(client) = clients.get_mut(&handle.id()) else {
+            return;
+        };
+        client.quiet = !client.quiet;
+        client.quiet
+    };
+    let text = if quiet {
+        "Quiet mode on: join, leave and other notices are hidden. /quiet shows them again"
+    } else {
+        "Quiet mode off"
+    };
+    reply(state, handle, MessageType::System, text).await;
+}
+
+// One line of the feedback file
+#[derive(Serialize)]
+struct Feedback<'a> {
+    user: &'a str,
+    text: &'a str,
+    timestamp: String,
+}
+
+// Appends the user's feedback to the feedback file. Only the sender is told
+// about it
+async fn feedback(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    text: &str,
+) -> Result<(), ChatError> {
+    if text.is_empty() {
+        reply(
+            state,
+            handle,
+            MessageType::System,
+            "Usage: /feedback <text>",
+        )
+        .await;
+        return Ok(());
+    }
+    if text.chars().count() > MAX_FEEDBACK_LEN {
+        let text = format!("Feedback is at most {} characters", MAX_FEEDBACK_LEN);
+        reply(state, handle, MessageType::System, &text).await;
+        return Ok(());
+    }
+
+    let feedback = Feedback {
+        user: name,
+        text,
+        timestamp: chrono::Utc::now().to_rfc3339(),
+    };
+    let mut line = serde_json::to_string(&feedback)?;
+    line.push('\n');
+    // One write per line, so appends from several connections don't interleave
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&state.config.feedback_file)
+        .await?;
+    file.write_all(line.as_bytes()).await?;
+    file.flush().await?;
+
+    let text = "Thank you for your feedback!";
+    reply(state, handle, MessageType::System, text).await;
+    Ok(())
+}
+
+// Sends `text` to the user named `to` alone. A user who is offline but whose
+// session can still be resumed gets it when they come back
+async fn direct_message(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    to: &str,
+    text: &str,
+) -> Result<(), ChatError> {
+    if to.is_empty() || text.is_empty() {
+        reply(
+            state,
+            handle,
+            MessageType::System,
+            "Usage: /msg <name> <text>",
+        )
+        .await;
+        return Ok(());
+    }
+    if to == name {
+        let text = "You can't send a direct message to yourself";
+        reply(state, handle, MessageType::System, text).await;
+        return Ok(());
+    }
+    if let Some(max) = state.config.max_message_length
+        && text.chars().count() as u64 > max
+    {
+        let message_type = MessageType::Error {
+            code: ErrorCode::MessageTooLong,
+            retry_after: None,
+        };
+        let text = format!("Direct messages can be at most {} characters", max);
+        reply(state, handle, message_type, &text).await;
+        return Ok(());
+    }
+
+    let message = Message::new(
+        MessageType::Direct {
+            sender: name.to_string(),
+        },
+        text,
+    );
+    if deliver_direct(state, to, &message).await {
+        let text = format!("Sent to {}", to);
+        reply(state, handle, MessageType::System, &text).await;
+        return Ok(());
+    }
+
+    let resumable = state.sessions.read().await.values().any(|name| name == to);
+    if !resumable {
+        let text = format!("{} is not online", to);
+        reply(state, handle, MessageType::System, &text).await;
+        return Ok(());
+    }
+    state
+        .store
+        .add_notification(to, name, text, chrono::Utc::now())
+        .await?;
+    let text = format!(
+        "{} is offline and will get your message when they return",
+        to
+    );
+    reply(state, handle, MessageType::System, &text).await;
+    Ok(())
+}
+
+// Lists, sets or removes the user's aliases. Named users' aliases are saved
+// under their name and come back when they claim it again; guests' last as
+// long as the connection. An alias never stands for another alias, and a
+// built-in command of the same name runs instead of it
+async fn alias(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    action: AliasAction<'_>,
+) -> Result<(), ChatError> {
+    let user_id = handle.id().to_string();
+    let is_guest = {
+        let user_states = state.user_states.read().await;
+        user_states.get(&user_id).is_some_and(|user| user.is_guest)
+    };
+
+    match action {
+        AliasAction::List => {
+            let text = {
+                let user_states = state.user_states.read().await;
+                let mut aliases: Vec<String> = user_states
+                    .get(&user_id)
+                    .map(|user| {
+                        user.aliases
+                            .iter()
+                            .map(|(shortcut, command)| {
+                                let shadowed = if is_builtin(shortcut) {
+                                    " (the built-in runs instead)"
+                                } else {
+                                    ""
+                                };
+                                format!("/{} = {}{}", shortcut, command, shadowed)
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                aliases.sort();
+                if aliases.is_empty() {
+                    "You have no aliases".to_string()
+                } else {
+                    format!("Your aliases: {}", aliases.join(", "))
+                }
+            };
+            reply(state, handle, MessageType::System, &text).await;
+        }
+        AliasAction::Remove(shortcut) => {
+            let removed = {
+                let mut user_states = state.user_states.write().await;
+                user_states
+                    .get_mut(&user_id)
+                    .and_then(|user| user.aliases.remove(shortcut))
+                    .is_some()
+            };
+            if removed && !is_guest {
+                state.store.remove_alias(name, shortcut).await?;
+            }
+            let text = if removed {
+                format!("Removed the alias /{}", shortcut)
+            } else {
+                format!("You have no alias /{}", shortcut)
+            };
+            reply(state, handle, MessageType::System, &text).await;
+        }
+        AliasAction::Set { shortcut, command } => {
+            set_alias(state, handle, name, is_guest, shortcut, command).await?;
+        }
+        AliasAction::Invalid => reply(state, handle, MessageType::System, ALIAS_USAGE).await,
+    }
+    Ok(())
+}
+
+async fn set_alias(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    is_guest: bool,
+    shortcut: &str,
+    command: &str,
+) -> Result<(), ChatError> {
+    if !command.starts_with('/') || command.len() > MAX_ALIAS_COMMAND_LEN {
+        let text = format!(
+            "An alias stands for a command starting with /, of at most {} characters. {}",
+            MAX_ALIAS_COMMAND_LEN, ALIAS_USAGE
+        );
+        reply(state, handle, MessageType::System, &text).await;
+        return Ok(());
+    }
+
+    let user_id = handle.id().to_string();
+    let refusal = {
+        let user_states = state.user_states.read().await;
+        let none = HashMap::new();
+        let aliases = user_states
+            .get(&user_id)
+            .map_or(&none, |user| &user.aliases);
+        let target = command_name(command);
+        // Another alias that runs /shortcut would now run an alias
+        let runs_shortcut = aliases
+            .iter()
+            .find(|(other, command)| *other != shortcut && command_name(command) == shortcut);
+        if !aliases.contains_key(shortcut) && aliases.len() >= MAX_ALIASES {
+            Some((
+                ErrorCode::CapacityReached,
+                format!("You can have at most {} aliases", MAX_ALIASES),
+            ))
+        } else if !is_builtin(target) && (target == shortcut || aliases.contains_key(target)) {
+            Some((
+                ErrorCode::CircularAlias,
+                format!(
+                    "/{} is an alias, and aliases can't run other aliases",
+                    target
+                ),
+            ))
+        } else if let Some((other, _)) = runs_shortcut.filter(|_| !is_builtin(shortcut)) {
+            Some((
+                ErrorCode::CircularAlias,
+                format!("/{} runs /{}, so it can't be an alias", other, shortcut),
+            ))
+        } else {
+            None
+        }
+    };
+    if let Some((code, text)) = refusal {
+        let message_type = MessageType::Error {
+            code,
+            retry_after: None,
+        };
+        reply(state, handle, message_type, &text).await;
+        return Ok(());
+    }
+
+    if !is_guest {
+        state.store.set_alias(name, shortcut, command).await?;
+    }
+    {
+        let mut user_states = state.user_states.write().await;
+        let user = user_states.entry(user_id).or_default();
+        user.aliases
+            .insert(shortcut.to_string(), command.to_string());
+    }
+    let text = format!("/{} now runs {}", shortcut, command);
+    reply(state, handle, MessageType::System, &text).await;
+    if is_builtin(shortcut) {
+        let text = format!(
+            "/{} is also a built-in command, which runs instead of your alias",
+            shortcut
+        );
+        reply(state, handle, MessageType::System, &text).await;
+    }
+    Ok(())
+}
+
+/// Expands the alias `text` starts with and keeps whatever followed it. None
+/// when it doesn't start with one, or when a built-in command of the same
+/// name takes precedence. Aliases never stand for other aliases, so one
+/// expansion is all there is.
+pub fn expand_alias(aliases: &HashMap<String, String>, text: &str) -> Option<String> {
+    let text = text.trim();
+    let (token, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let shortcut = token.strip_prefix('/')?;
+    if is_builtin(shortcut) {
+        return None;
+    }
+    let command = aliases.get(shortcut)?;
+    Some(match rest.trim() {
+        "" => command.clone(),
+        rest => format!("{} {}", command, rest),
+    })
+}
+
+// Whether `/name` is a built-in command or one of their built-in aliases
+fn is_builtin(name: &str) -> bool {
+    parse(&format!("/{}", name)).is_some()
+}
+
+// The name of the command `command` runs, without its slash
+fn command_name(command: &str) -> &str {
+    let token = command.split(char::is_whitespace).next().unwrap_or("");
+    token.strip_prefix('/').unwrap_or(token)
+}
+
+// Lists, adds or removes the words the user gets a KeywordAlert for. Named
+// users' keywords are saved under their name and come back when they claim
+// it again; guests' last as long as the connection
+async fn notify(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    arg: &str,
+) -> Result<(), ChatError> {
+    let id = handle.id();
+    let (action, word) = arg.split_once(char::is_whitespace).unwrap_or((arg, ""));
+    let keyword = keywords::normalize(word);
+    let is_guest = {
+        let user_states = state.user_states.read().await;
+        user_states
+            .get(&id.to_string())
+            .is_some_and(|user| user.is_guest)
+    };
+
+    match (action, keyword) {
+        ("list", _) => {
+            let text = {
+                let keywords = state.keywords.read().await;
+                match keywords.keywords(id) {
+                    [] => "You have no keywords. Add one with /notify add <word>".to_string(),
+                    keywords => format!("Your keywords: {}", keywords.join(", ")),
+                }
+            };
+            reply(state, handle, MessageType::System, &text).await;
+        }
+        ("add", Some(keyword)) => {
+            let count = {
+                let keywords = state.keywords.read().await;
+                let keywords = keywords.keywords(id);
+                if keywords.contains(&keyword) {
+                    let text = format!("You already have the keyword {}", keyword);
+                    reply(state, handle, MessageType::System, &text).await;
+                    return Ok(());
+                }
+                keywords.len()
+            };
+            if count >= MAX_KEYWORDS {
+                let message_type = MessageType::Error {
+                    code: ErrorCode::CapacityReached,
+                    retry_after: None,
+                };
+                let text = format!("You can have at most {} keywords", MAX_KEYWORDS);
+                reply(state, handle, message_type, &text).await;
+                return Ok(());
+            }
+
+            if !is_guest {
+                state.store.add_keyword(name, &keyword).await?;
+            }
+            state.keywords.write().await.add(id, &keyword);
+            let text = format!("You will be alerted when someone mentions {}", keyword);
+            reply(state, handle, MessageType::System, &text).await;
+        }
+        ("remove", Some(keyword)) => {
+            if !state.keywords.write().await.remove(id, &keyword) {
+                let text = format!("You have no keyword {}", keyword);
+                reply(state, handle, MessageType::System, &text).await;
+                return Ok(());
+            }
+            if !is_guest {
+                state.store.remove_keyword(name, &keyword).await?;
+            }
+            let text = format!("Removed the keyword {}", keyword);
+            reply(state, handle, MessageType::System, &text).await;
+        }
+        ("add" | "remove", None) => {
+            let text = format!(
+                "Keywords are single words of at most {} characters",
+                MAX_KEYWORD_LEN
+            );
+            reply(state, handle, MessageType::System, &text).await;
+        }
+        _ => reply(state, handle, MessageType::System, NOTIFY_USAGE).await,
+    }
+    Ok(())
+}
+
+// Sends the requester the newest messages one sender wrote in the room
+async fn from(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    sender: &str,
+    limit: &str,
+) -> Result<(), ChatError> {
+    let limit = match limit {
+        "" => Some(FROM_DEFAULT_LIMIT),
+        limit => limit.parse::<usize>().ok().filter(|limit| *limit > 0),
+    };
+    let Some(limit) = limit.filter(|_| !sender.is_empty()) else {
+        let usage = "Usage: /from <name> [limit]";
+        reply(state, handle, MessageType::System, usage).await;
+        return Ok(());
+    };
+
+    let limit = limit.min(FROM_MAX_LIMIT);
+    let messages = state
+        .store
+        .messages_from(DEFAULT_ROOM, sender, limit)
+        .await?;
+    send_history(state, handle, &messages).await;
+    Ok(())
+}
+
+// Ranks senders by the words they have stored, across every room
+async fn word_count(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    limit: &str,
+) -> Result<(), ChatError> {
+    let limit = match limit {
+        "" => Some(WORDCOUNT_DEFAULT_LIMIT),
+        limit => limit.parse::<usize>().ok().filter(|limit| *limit > 0),
+    };
+    let Some(limit) = limit else {
+        reply(
+            state,
+            handle,
+            MessageType::System,
+            "Usage: /wordcount [limit]",
+        )
+        .await;
+        return Ok(());
+    };
+
+    let counts = state
+        .store
+        .word_counts(limit.min(WORDCOUNT_MAX_LIMIT))
+        .await?;
+
+    if counts.is_empty() {
+        reply(
+            state,
+            handle,
+            MessageType::System,
+            "Nobody has said anything yet",
+        )
+        .await;
+        return Ok(());
+    }
+    let lines: Vec<String> = counts
+        .iter()
+        .enumerate()
+        .map(|(rank, (sender, words))| format!("{}. {}: {} words", rank + 1, sender, words))
+        .collect();
+    let text = format!("Most words sent:\n{}", lines.join("\n"));
+    reply(state, handle, MessageType::System, &text).await;
+    Ok(())
+}
+
+// Starts or stops copying every chat message in the namespace to an admin
+async fn tail_all(state: &NamespaceState, handle: &Arc<ConnectionHandle<TcpStream>>, switch: &str) {
+    if !require_admin(state, handle, "use /tailall").await {
+        return;
+    }
+
+    let text = match switch {
+        "on" => {
+            let mut tailers = state.tailers.write().await;
+            if !tailers.contains(&handle.id()) && tailers.len() >= MAX_TAILERS {
+                drop(tailers);
+                let message_type = MessageType::Error {
+                    code: ErrorCode::CapacityReached,
+                    retry_after: None,
+                };
+                let text = format!("At most {} admins can tail at once", MAX_TAILERS);
+                reply(state, handle, message_type, &text).await;
+                return;
+            }
+            tailers.insert(handle.id());
+            "Live tail started"
+        }
+        "off" => {
+            state.tailers.write().await.remove(&handle.id());
+            "Live tail stopped"
+        }
+        _ => "Usage: /tailall <on|off>",
+    };
+    reply(state, handle, MessageType::System, text).await;
+}
+
+// Shows the message of the day, or changes it for admins with `set <text>`
+// or `clear`. Changes are saved to the MOTD file, if any, before they apply.
+async fn motd(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    arg: &str,
+) {
+    let (action, text) = arg.split_once(char::is_whitespace).unwrap_or((arg, ""));
+    let (motd, confirmation) = match (action, text.trim()) {
+        ("", _) => {
+            let motd = state.motd.read().await.clone();
+            let text = if motd.is_empty() {
+                "There is no message of the day".to_string()
+            } else {
+                format!("Message of the day: {}", motd)
+            };
+            reply(state, handle, MessageType::System, &text).await;
+            return;
+        }
+        ("set", text) if !text.is_empty() => (text, "Message of the day updated"),
+        ("clear", "") => ("", "Message of the day cleared"),
+        _ => {
+            let usage = "Usage: /motd [set <text>|clear]";
+            reply(state, handle, MessageType::System, usage).await;
+            return;
+        }
+    };
+
+    if !require_admin(state, handle, "change the message of the day").await {
+        return;
+    }
+
+    // Held while saving, so the file always matches the last change applied
+    let mut current = state.motd.write().await;
+    if let Some(path) = &state.config.motd_file
+        && let Err(e) = tokio::fs::write(path, motd).await
+    {
+        drop(current);
+        error!("Failed to save {}: {}", path.display(), e);
+        let text = "The message of the day could not be saved, so it was not changed";
+        reply(state, handle, MessageType::System, text).await;
+        return;
+    }
+    *current = motd.to_string();
+    drop(current);
+    audit(state, name, &format!("{} motd", action), motd).await;
+
+    reply(state, handle, MessageType::System, confirmation).await;
+}
+
+// Stops the server taking connections and asks everyone connected, in every
+// namespace, to reconnect elsewhere, to `new_url` when given
+async fn drain(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    new_url: &str,
+) {
+    if !require_admin(state, handle, "use /drain").await {
+        return;
+    }
+
+    let new_url = (!new_url.is_empty()).then(|| new_url.to_string());
+    *state.drain.write().await = Some(new_url.clone());
+    audit(state, name, "drain", new_url.as_deref().unwrap_or("")).await;
+
+    let closed = send_off(&state.clients, |connections| {
+        drain_notice(reconnect_delay(connections), new_url)
+    })
+    .await;
+    info!("Draining: asked {} connections to move", closed.len());
+}
+
+// Tells the caller how much of their message and upload quotas they have used
+async fn quota(state: &NamespaceState, handle: &Arc<ConnectionHandle<TcpStream>>, name: &str) {
+    if is_admin(state, handle).await {
+        reply(state, handle, MessageType::System, "Admins have no quotas").await;
+        return;
+    }
+
+    let usage = state.message_quota.usage(name).await;
+    let text = match usage.limit {
+        Some(limit) => format!(
+            "You have stored {} of {} messages allowed per 24 hours",
+            usage.used, limit
+        ),
+        None => format!(
+            "You have stored {} messages in the last 24 hours, with no quota",
+            usage.used
+        ),
+    };
+    reply(state, handle, MessageType::System, &text).await;
+
+    let usage = state.upload_quota.usage(name).await;
+    let text = match usage.limit {
+        Some(limit) => format!(
+            "You have sent {} of {} bytes of files allowed per 24 hours",
+            usage.used, limit
+        ),
+        None => format!(
+            "You have sent {} bytes of files in the last 24 hours, with no quota",
+            usage.used
+        ),
+    };
+    reply(state, handle, MessageType::System, &text).await;
+}
+
+// Sends an admin the newest moderation actions, oldest first
+async fn audit_log(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    limit: &str,
+) -> Result<(), ChatError> {
+    if !require_admin(state, handle, "use /audit").await {
+        return Ok(());
+    }
+    let limit = match limit {
+        "" => Some(AUDIT_DEFAULT_LIMIT),
+        limit => limit.parse::<usize>().ok().filter(|limit| *limit > 0),
+    };
+    let Some(limit) = limit else {
+        reply(state, handle, MessageType::System, "Usage: /audit [limit]").await;
+        return Ok(());
+    };
+
+    let entries = state.store.recent_audit(limit.min(AUDIT_MAX_LIMIT)).await?;
+
+    if entries.is_empty() {
+        reply(state, handle, MessageType::System, "The audit log is empty").await;
+        return Ok(());
+    }
+    for entry in &entries {
+        let text = format!(
+            "{} {} {} {}",
+            entry.at.format("%Y-%m-%d %H:%M:%S"),
+            entry.actor,
+            entry.action,
+            entry.target
+        );
+        reply(state, handle, MessageType::System, text.trim_end()).await;
+    }
+    Ok(())
+}
+
+// Lists the room's latest joins, leaves and moderation actions in one reply
+async fn events(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    limit: &str,
+) -> Result<(), ChatError> {
+    if !require_admin(state, handle, "use /events").await {
+        return Ok(());
+    }
+    let limit = match limit {
+        "" => Some(EVENTS_DEFAULT_LIMIT),
+        limit => limit.parse::<usize>().ok().filter(|limit| *limit > 0),
+    };
+    let Some(limit) = limit else {
+        reply(state, handle, MessageType::System, "Usage: /events [limit]").await;
+        return Ok(());
+    };
+
+    let events = state
+        .store
+        .room_events(DEFAULT_ROOM, limit.min(EVENTS_MAX_LIMIT))
+        .await?;
+
+    if events.is_empty() {
+        let text = format!("Nothing has happened in {} yet", DEFAULT_ROOM);
+        reply(state, handle, MessageType::System, &text).await;
+        return Ok(());
+    }
+    let lines: Vec<String> = events
+        .iter()
+        .map(|event| {
+            format!(
+                "{} {}",
+                event.at.format("%Y-%m-%d %H:%M:%S"),
+                describe_event(&event.event_type, &event.actor, &event.target)
+            )
+        })
+        .collect();
+    let text = format!("Events in {}:\n{}", DEFAULT_ROOM, lines.join("\n"));
+    reply(state, handle, MessageType::System, &text).await;
+    Ok(())
+}
+
+fn describe_event(event_type: &str, actor: &str, target: &str) -> String {
+    match event_type {
+        "join" => format!("{} joined", actor),
+        "leave" => format!("{} left", actor),
+        "topic" => format!("{} changed the topic", actor),
+        "topic_lock" => format!("{} locked the topic", actor),
+        "topic_unlock" => format!("{} unlocked the topic", actor),
+        "purge" => format!("{} purged messages from {}", actor, target),
+        "kick" => format!("{} kicked {}", actor, target),
+        other => format!("{} {} {}", actor, other, target)
+            .trim_end()
+            .to_string(),
+    }
+}
+
+// Records a moderation action. Failing to record it doesn't undo the action.
+async fn audit(state: &NamespaceState, actor: &str, action: &str, target: &str) {
+    if let Err(e) = state.store.record_audit(actor, action, target).await {
+        error!("Failed to record {} in the audit log: {}", action, e);
+    }
+}
+
+async fn is_admin(state: &NamespaceState, handle: &Arc<ConnectionHandle<TcpStream>>) -> bool {
+    crate::is_admin(state, &handle.id().to_string()).await
+}
+
+// Rooms have no owners or moderators of their own, so changing a room's
+// settings and moderating it are left to the namespace's admins. Tells anyone
+// else that only admins can `action`, such as "use /kick", and returns
+// whether the connection is an admin
+async fn require_admin(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    action: &str,
+) -> bool {
+    if is_admin(state, handle).await {
+        return true;
+    }
+    let text = format!("Only admins can {}", action);
+    reply(state, handle, unauthorized(), &text).await;
+    false
+}
+
+fn unauthorized() -> MessageType {
+    MessageType::Error {
+        code: ErrorCode::Unauthorized,
+        retry_after: None,
+    }
+}
+
+// Parses a lifetime such as `45s`, `30m`, `1h` or `7d`, up to `MAX_TTL`
+fn parse_ttl(ttl: &str) -> Option<Duration> {
+    let (split, unit) = ttl.char_indices().last()?;
+    let amount: u64 = ttl[..split].parse().ok().filter(|amount| *amount > 0)?;
+    let unit_secs = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    amount
+        .checked_mul(unit_secs)
+        .map(Duration::from_secs)
+        .filter(|ttl| *ttl <= MAX_TTL)
+}
+
+// Reports a command that failed to the log and to the user who ran it
+async fn finish(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    command: &str,
+    result: Result<(), ChatError>,
+) {
+    if let Err(e) = result {
+        let context = format!("Failed to run {}", command);
+        report(&state.clients, handle, &context, e).await;
+    }
+}
+
+async fn reply(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    message_type: MessageType,
+    text: &str,
+) {
+    let message = Message::new(message_type, text);
+    if let Err(e) = send(state, handle, &message).await {
+        error!("Failed to send message: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_each_unit() {
+        assert_eq!(parse_ttl("45s"), Some(Duration::from_secs(45)));
+        assert_eq!(parse_ttl("30m"), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_ttl("1h"), Some(Duration::from_secs(60 * 60)));
+        assert_eq!(parse_ttl("7d"), Some(Duration::from_secs(7 * 24 * 60 * 60)));
+    }
+
+    #[test]
+    fn rejects_malformed_lifetimes() {
+        for ttl in ["", "h", "0s", "-1h", "1w", "1.5h", "1 h"] {
+            assert_eq!(parse_ttl(ttl), None, "{}", ttl);
+        }
+    }
+
+    #[test]
+    fn content_settings_report_every_bad_pair() {
+        assert_eq!(
+            parse_content_settings("links=off uploads=on"),
+            Ok(vec![(Content::Links, false), (Content::Uploads, true)])
+        );
+        assert_eq!(
+            parse_content_settings("filter=strict links=maybe uploads=off images"),
+            Err(vec![
+                "filter is not a setting".to_string(),
+                "links=maybe is not on or off".to_string(),
+                "images is not setting=value".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn previews_stop_at_a_character_limit() {
+        assert_eq!(preview("short"), "short");
+        let exact = "é".repeat(PIN_LIST_PREVIEW);
+        assert_eq!(preview(&exact), exact);
+        let long = format!("{}more", exact);
+        assert_eq!(preview(&long), format!("{}…", exact));
+    }
+
+    #[test]
+    fn alias_commands_may_be_quoted() {
+        assert_eq!(
+            parse(r#"/alias set brb "/away back in 5""#),
+            Some(Command::Alias(AliasAction::Set {
+                shortcut: "brb",
+                command: "/away back in 5",
+            }))
+        );
+        assert_eq!(
+            parse("/alias set /p /ping a/b"),
+            Some(Command::Alias(AliasAction::Set {
+                shortcut: "p",
+                command: "/ping a/b",
+            }))
+        );
+        assert_eq!(
+            parse(r#"/alias set brb "/away"#),
+            Some(Command::Alias(AliasAction::Invalid))
+        );
+        assert_eq!(
+            parse(r#"/alias set brb """#),
+            Some(Command::Alias(AliasAction::Invalid))
+        );
+        assert_eq!(
+            parse("/alias remove /brb"),
+            Some(Command::Alias(AliasAction::Remove("brb")))
+        );
+        assert_eq!(parse("/alias"), Some(Command::Alias(AliasAction::List)));
+        assert_eq!(
+            parse("/alias list"),
+            Some(Command::Alias(AliasAction::List))
+        );
+        assert_eq!(
+            parse("/alias brb /away"),
+            Some(Command::Alias(AliasAction::Invalid))
+        );
+    }
+
+    #[test]
+    fn built_in_aliases_parse_as_their_command() {
+        assert_eq!(parse("/j main"), Some(Command::Join("main")));
+        assert_eq!(
+            parse("/m bob hi"),
+            Some(Command::Msg {
+                to: "bob",
+                text: "hi"
+            })
+        );
+    }
+
+    #[test]
+    fn aliases_expand_once_and_never_over_built_ins() {
+        let aliases = HashMap::from([
+            ("brb".to_string(), "/away back in 5".to_string()),
+            ("p".to_string(), "/ping hello".to_string()),
+            ("j".to_string(), "/ping shadowed".to_string()),
+        ]);
+        assert_eq!(
+            expand_alias(&aliases, "/brb now").as_deref(),
+            Some("/away back in 5 now")
+        );
+        assert_eq!(expand_alias(&aliases, "/p").as_deref(), Some("/ping hello"));
+        assert_eq!(expand_alias(&aliases, "/j main"), None);
+        assert_eq!(expand_alias(&aliases, "/ping"), None);
+        assert_eq!(expand_alias(&aliases, "p"), None);
+    }
+
+    #[test]
+    fn size_charts_scale_to_the_biggest_bucket() {
+        let chart = size_chart([40, 10, 1, 0]);
+        let lines: Vec<&str> = chart.lines().collect();
+        assert_eq!(lines[0], format!("   0-100 B | {} 40", "#".repeat(20)));
+        assert_eq!(lines[1], format!(" 101-500 B | {:<20} 10", "#".repeat(5)));
+        assert_eq!(lines[2], format!("501-1000 B | {:<20} 1", "#"));
+        assert_eq!(lines[3], format!("   >1000 B | {:<20} 0", ""));
+        assert_eq!(size_chart([0; 4]).lines().count(), 4);
+    }
+
+    #[test]
+    fn empty_size_charts_have_no_bars() {
+        for line in size_chart([0; 4]).lines() {
+            assert!(!line.contains('#'), "{:?}", line);
+            assert!(line.ends_with(" 0"), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn size_chart_bars_never_overflow() {
+        // Ties for the biggest bucket both fill the bar exactly
+        let chart = size_chart([7, 7, 0, 0]);
+        let lines: Vec<&str> = chart.lines().collect();
+        assert_eq!(lines[0], format!("   0-100 B | {} 7", "#".repeat(20)));
+        assert_eq!(lines[1], format!(" 101-500 B | {} 7", "#".repeat(20)));
+        // Half the biggest bucket is exactly half the bar
+        let chart = size_chart([0, 0, 4, 8]);
+        let lines: Vec<&str> = chart.lines().collect();
+        assert_eq!(lines[2], format!("501-1000 B | {:<20} 4", "#".repeat(10)));
+        assert_eq!(lines[3], format!("   >1000 B | {} 8", "#".repeat(20)));
+    }
+
+    #[test]
+    fn caps_lifetimes_at_a_year() {
+        assert_eq!(parse_ttl("365d"), Some(MAX_TTL));
+        assert_eq!(parse_ttl("366d"), None);
+        assert_eq!(parse_ttl("106751991167300d"), None);
+    }
+}
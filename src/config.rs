@@ -0,0 +1,477 @@
+use crate::db::{DEFAULT_DATABASE_URL, MEMORY_DATABASE_URL};
+use clap::builder::BoolishValueParser;
+use clap::{Parser, ValueEnum};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+// Database URLs that give every namespace a fresh database of its own
+const UNSHARED_DATABASE_URLS: [&str; 2] = ["sqlite::memory:", MEMORY_DATABASE_URL];
+
+/// Shortest password `/admin` may be configured with.
+pub const MIN_ADMIN_PASSWORD_LEN: usize = 8;
+
+/// Namespace served when none are configured.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// How the server treats users who start chatting before choosing a name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum GuestNames {
+    /// The first message is always taken as the user's name.
+    Require,
+    /// Chatting before naming assigns a unique guest name such as
+    /// `Guest-CalmOtter`; a name is claimed explicitly with `/nick <name>`.
+    Allow,
+    /// Every connection gets a unique guest name as soon as it is greeted,
+    /// with no prompt; a name is claimed with `/nick <name>`.
+    Auto,
+}
+
+/// Where connections land once they enter a namespace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum RoomJoin {
+    /// Every connection joins the main room straight away.
+    Auto,
+    /// Connections start in a lobby with the room list and join a room with
+    /// `/join <room>`.
+    Lobby,
+}
+
+/// What happens to text holding bidi override or isolate characters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum BidiControls {
+    /// The characters are stripped and the rest goes through.
+    Strip,
+    /// The text is refused with an `InvalidText` error.
+    Reject,
+}
+
+/// An isolated chat namespace and the database backing it.
+#[derive(Clone, Debug)]
+pub struct NamespaceConfig {
+    pub name: String,
+    pub database_url: String,
+}
+
+fn parse_namespace(value: &str) -> Result<NamespaceConfig, String> {
+    let (name, database_url) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=DATABASE_URL, got `{}`", value))?;
+    if name.is_empty() || database_url.is_empty() {
+        return Err(format!("expected NAME=DATABASE_URL, got `{}`", value));
+    }
+
+    Ok(NamespaceConfig {
+        name: name.to_string(),
+        database_url: database_url.to_string(),
+    })
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(about = "WebSocket chat server")]
+pub struct ServerConfig {
+    /// Port to listen on
+    #[arg(long, env = "CHAT_PORT", default_value_t = 3000)]
+    pub port: u16,
+
+    /// Validate the configuration, print a summary and exit without serving
+    #[arg(long, env = "CHECK_CONFIG", value_parser = BoolishValueParser::new())]
+    pub check_config: bool,
+
+    /// Write every namespace's database to this file as gzipped JSON lines,
+    /// for a full backup, and exit without serving
+    #[arg(
+        long,
+        env = "CHAT_EXPORT_DB",
+        value_name = "PATH",
+        conflicts_with = "import_db"
+    )]
+    pub export_db: Option<PathBuf>,
+
+    /// Load a backup written by --export-db into the configured namespaces,
+    /// which must be empty, and exit without serving
+    #[arg(long, env = "CHAT_IMPORT_DB", value_name = "PATH")]
+    pub import_db: Option<PathBuf>,
+
+    /// Naming policy for users who have not picked a name yet
+    #[arg(long, env = "CHAT_GUEST_NAMES", value_enum, default_value = "require")]
+    pub guest_names: GuestNames,
+
+    /// Whether text holding bidi overrides or isolates has them stripped or
+    /// is refused. Either way control characters are stripped and text is
+    /// normalized to NFC
+    #[arg(long, env = "CHAT_BIDI_CONTROLS", value_enum, default_value = "strip")]
+    pub bidi_controls: BidiControls,
+
+    /// Whether connections join the main room on their own or pick one from
+    /// a lobby
+    #[arg(long, env = "CHAT_ROOM_JOIN", value_enum, default_value = "auto")]
+    pub room_join: RoomJoin,
+
+    /// Seconds a user must wait between `/nick` renames (admins are exempt)
+    #[arg(long, env = "CHAT_NICK_COOLDOWN", default_value_t = 600)]
+    pub nick_cooldown: u64,
+
+    /// Password for `/admin`, at least 8 characters; admin login is disabled
+    /// when unset
+    #[arg(long, env = "CHAT_ADMIN_PASSWORD")]
+    pub admin_password: Option<String>,
+
+    /// Seconds a connection may go without sending chat input or a command
+    /// before it is disconnected. Observers are exempt
+    #[arg(
+        long,
+        env = "CHAT_IDLE_TIMEOUT",
+        default_value_t = 30 * 60,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub idle_timeout: u64,
+
+    /// Seconds a connection has to choose a name after opening before it is
+    /// disconnected. Observers are exempt
+    #[arg(
+        long,
+        env = "CHAT_NAMING_TIMEOUT",
+        default_value_t = 60,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub naming_timeout: u64,
+
+    /// Connections beyond this many are turned away with a reconnect hint
+    #[arg(long, env = "CHAT_MAX_CONNECTIONS")]
+    pub max_connections: Option<usize>,
+
+    /// Connections one IP address may open per `connect_window`; unlimited
+    /// when unset
+    #[arg(
+        long,
+        env = "CHAT_CONNECT_LIMIT",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub connect_limit: Option<u64>,
+
+    /// Seconds over which `connect_limit` counts connection attempts
+    #[arg(
+        long,
+        env = "CHAT_CONNECT_WINDOW",
+        default_value_t = 60,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub connect_window: u64,
+
+    /// IP addresses exempt from `connect_limit`; loopback always is
+    #[arg(long, env = "CHAT_CONNECT_ALLOWLIST", value_delimiter = ',')]
+    pub connect_allowlist: Vec<IpAddr>,
+
+    /// Messages one user may store per rolling 24 hours; unlimited when unset.
+    /// A positive `message_quota` on the user's row overrides it, and admins
+    /// are exempt
+    #[arg(
+        long,
+        env = "CHAT_MESSAGE_QUOTA",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub message_quota: Option<u64>,
+
+    /// Bytes of files one user may send per rolling 24 hours; unlimited when
+    /// unset. A positive `upload_quota` on the user's row overrides it, and
+    /// admins are exempt
+    #[arg(
+        long,
+        env = "CHAT_UPLOAD_QUOTA",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub upload_quota: Option<u64>,
+
+    /// Longest chat message, in characters; unlimited when unset.
+    /// `/roomconfig` can override it per room
+    #[arg(
+        long,
+        env = "CHAT_MAX_MESSAGE_LENGTH",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub max_message_length: Option<u64>,
+
+    /// Most messages one history replay sends, whatever the client asked
+    /// for; the newest are kept and the client is told the rest were cut
+    #[arg(
+        long,
+        env = "CHAT_MAX_REPLAY",
+        default_value_t = 1000,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub max_replay: u64,
+
+    /// Links one room's bookmark list may hold
+    #[arg(
+        long,
+        env = "CHAT_MAX_BOOKMARKS",
+        default_value_t = 50,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub max_bookmarks: u64,
+
+    /// Largest file, in bytes, one transfer may send
+    #[arg(
+        long,
+        env = "CHAT_MAX_TRANSFER_SIZE",
+        default_value_t = 10 * 1024 * 1024,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub max_transfer_size: u64,
+
+    /// Seconds a file transfer may go without a chunk before it is abandoned
+    #[arg(
+        long,
+        env = "CHAT_TRANSFER_STALL_SECS",
+        default_value_t = 30,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub transfer_stall_secs: u64,
+
+    /// Seconds one outbound frame may take to write before its connection is
+    /// treated as dead and cleaned up
+    #[arg(
+        long,
+        env = "CHAT_SEND_TIMEOUT",
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub send_timeout: u64,
+
+    /// Seconds between WAL checkpoints of each SQLite database, which keep
+    /// the write-ahead log from growing without bound
+    #[arg(
+        long,
+        env = "CHAT_WAL_CHECKPOINT_INTERVAL",
+        default_value_t = 300,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub wal_checkpoint_interval: u64,
+
+    /// Unsaved messages, across a namespace's rooms, at which persistence
+    /// counts as falling behind
+    #[arg(
+        long,
+        env = "CHAT_PERSISTENCE_LAG_DEPTH",
+        default_value_t = 1000,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub persistence_lag_depth: u64,
+
+    /// Seconds the oldest unsaved message may wait before persistence counts
+    /// as falling behind
+    #[arg(
+        long,
+        env = "CHAT_PERSISTENCE_LAG_SECS",
+        default_value_t = 10,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub persistence_lag_secs: u64,
+
+    /// Tell online admins when persistence falls behind and when it catches
+    /// up, on top of the log warning
+    #[arg(long, env = "CHAT_PERSISTENCE_NOTICES", value_parser = BoolishValueParser::new())]
+    pub persistence_notices: bool,
+
+    /// Trace every chat message under its own span, logging how long each
+    /// stage took, from the frame arriving to the last recipient's copy
+    /// being queued, and keeping per-stage percentiles in the metrics
+    #[arg(long, env = "CHAT_TRACE_MESSAGES", value_parser = BoolishValueParser::new())]
+    pub trace_messages: bool,
+
+    /// URL every saved chat message is POSTed to as JSON
+    #[arg(long, env = "CHAT_WEBHOOK_URL")]
+    pub webhook_url: Option<String>,
+
+    /// Expand `:smile:` style shortcodes to emoji before messages are stored
+    #[arg(long, env = "CHAT_EXPAND_EMOJI", value_parser = BoolishValueParser::new())]
+    pub expand_emoji: bool,
+
+    /// Redis server used to share broadcasts between the nodes of a cluster
+    #[arg(long, env = "CHAT_REDIS_URL")]
+    pub redis_url: Option<String>,
+
+    /// Refuse read-only observer connections
+    #[arg(long, env = "CHAT_DISABLE_OBSERVERS", value_parser = BoolishValueParser::new())]
+    pub disable_observers: bool,
+
+    /// File holding the message of the day shown to users as they join;
+    /// `/motd set` rewrites it. No message of the day when unset
+    #[arg(long, env = "CHAT_MOTD_FILE")]
+    pub motd_file: Option<PathBuf>,
+
+    /// File holding the onboarding text, such as rules and commands, that a
+    /// greeting bot sends each user privately once they first get a name;
+    /// `{name}` is replaced with theirs. No greeting bot when unset
+    #[arg(long, env = "CHAT_GREETING_FILE")]
+    pub greeting_file: Option<PathBuf>,
+
+    /// Welcome sent to a user the first time their name is seen; `{name}`
+    /// is replaced with theirs
+    #[arg(
+        long,
+        env = "CHAT_WELCOME_TEMPLATE",
+        default_value = "Welcome, {name}! You can start chatting now."
+    )]
+    pub welcome_template: String,
+
+    /// Welcome sent instead to a user whose name is already in the store
+    #[arg(
+        long,
+        env = "CHAT_WELCOME_BACK_TEMPLATE",
+        default_value = "Welcome back, {name}! You can start chatting now."
+    )]
+    pub welcome_back_template: String,
+
+    /// File `/feedback` appends users' feedback to, one JSON line each
+    #[arg(long, env = "CHAT_FEEDBACK_FILE", default_value = "feedback.log")]
+    pub feedback_file: PathBuf,
+
+    /// File sessions and room settings are saved to on shutdown and restored
+    /// from on startup, so users resume seamlessly across a restart. Nothing
+    /// is handed over when unset
+    #[arg(long, env = "CHAT_HANDOFF_FILE", value_name = "PATH")]
+    pub handoff_file: Option<PathBuf>,
+
+    /// Seconds after shutdown a handoff file is still restored from
+    #[arg(
+        long,
+        env = "CHAT_HANDOFF_MAX_AGE",
+        default_value_t = 300,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub handoff_max_age: u64,
+
+    /// Rooms observers may watch; all rooms when empty
+    #[arg(long, env = "CHAT_OBSERVER_ROOMS", value_delimiter = ',')]
+    pub observer_rooms: Vec<String>,
+
+    /// Isolated namespace as `NAME=DATABASE_URL`; repeat for more. A URL of
+    /// `memory://` keeps that namespace's messages in memory only. Defaults to
+    /// a single `default` namespace on `chat.sqlite`
+    #[arg(long, env = "CHAT_NAMESPACES", value_delimiter = ',', value_parser = parse_namespace)]
+    pub namespace: Vec<NamespaceConfig>,
+}
+
+impl ServerConfig {
+    /// Configured namespaces mapped to their database URLs.
+    pub fn namespaces(&self) -> HashMap<String, String> {
+        if self.namespace.is_empty() {
+            return HashMap::from([(
+                DEFAULT_NAMESPACE.to_string(),
+                DEFAULT_DATABASE_URL.to_string(),
+            )]);
+        }
+
+        self.namespace
+            .iter()
+            .map(|namespace| (namespace.name.clone(), namespace.database_url.clone()))
+            .collect()
+    }
+
+    /// Checks the rules clap can't express on its own.
+    pub fn validate(&self) -> Result<(), String> {
+        self.validate_namespaces()?;
+        self.validate_admin_password()
+            .map_err(|problem| format!("admin password {}", problem))
+    }
+
+    /// Namespace names must be unique and no two namespaces may share a
+    /// database.
+    pub fn validate_namespaces(&self) -> Result<(), String> {
+        let mut names = HashSet::new();
+        let mut database_urls = HashMap::new();
+        for namespace in &self.namespace {
+            if !names.insert(namespace.name.as_str()) {
+                return Err(format!("namespace {} is configured twice", namespace.name));
+            }
+            if UNSHARED_DATABASE_URLS.contains(&namespace.database_url.as_str()) {
+                continue;
+            }
+            if let Some(other) =
+                database_urls.insert(namespace.database_url.as_str(), namespace.name.as_str())
+            {
+                return Err(format!(
+                    "namespaces {} and {} share the database {}",
+                    other, namespace.name, namespace.database_url
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// A configured admin password must be at least
+    /// [`MIN_ADMIN_PASSWORD_LEN`] characters long.
+    pub fn validate_admin_password(&self) -> Result<(), String> {
+        match &self.admin_password {
+            Some(password) if password.chars().count() < MIN_ADMIN_PASSWORD_LEN => Err(format!(
+                "must be at least {} characters",
+                MIN_ADMIN_PASSWORD_LEN
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(namespaces: &[&str]) -> ServerConfig {
+        let mut args = vec!["backend"];
+        for namespace in namespaces {
+            args.extend(["--namespace", namespace]);
+        }
+        ServerConfig::try_parse_from(args).unwrap()
+    }
+
+    fn with_admin_password(password: &str) -> ServerConfig {
+        ServerConfig::try_parse_from(["backend", "--admin-password", password]).unwrap()
+    }
+
+    #[test]
+    fn distinct_namespaces_are_valid() {
+        let config = parse(&["a=sqlite://a.sqlite", "b=sqlite://b.sqlite"]);
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn duplicate_namespace_names_are_rejected() {
+        let config = parse(&["a=sqlite://x.sqlite", "a=sqlite://y.sqlite"]);
+        assert_eq!(
+            config.validate(),
+            Err("namespace a is configured twice".to_string())
+        );
+    }
+
+    #[test]
+    fn shared_databases_are_rejected() {
+        let config = parse(&["a=sqlite://x.sqlite", "b=sqlite://x.sqlite"]);
+        assert_eq!(
+            config.validate(),
+            Err("namespaces a and b share the database sqlite://x.sqlite".to_string())
+        );
+    }
+
+    #[test]
+    fn in_memory_databases_are_never_shared() {
+        let config = parse(&[
+            "a=sqlite::memory:",
+            "b=sqlite::memory:",
+            "c=memory://",
+            "d=memory://",
+        ]);
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn short_admin_passwords_are_rejected() {
+        assert_eq!(
+            with_admin_password("hunter2").validate(),
+            Err("admin password must be at least 8 characters".to_string())
+        );
+        assert_eq!(with_admin_password("hunter22").validate(), Ok(()));
+        assert_eq!(parse(&[]).validate(), Ok(()));
+    }
+}
@@ -0,0 +1,2753 @@
+use crate::DEFAULT_ROOM;
+use crate::memory_store::MemoryStore;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use lume::database::Database;
+use lume::database::error::DatabaseError;
+use lume::define_schema;
+use lume::filter::eq_value;
+use lume::row::Row;
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+
+pub const DEFAULT_DATABASE_URL: &str = "sqlite://chat.sqlite";
+
+/// Selects the in-memory store, which keeps nothing across restarts.
+pub const MEMORY_DATABASE_URL: &str = "memory://";
+
+/// Every table a full backup covers, in the order it is written.
+pub const BACKUP_TABLES: [&str; 11] = [
+    "ChatMessage",
+    "User",
+    "NameHistory",
+    "AuditLog",
+    "Bookmark",
+    "Keyword",
+    "Notification",
+    "RoomEvent",
+    "Alias",
+    "TopicChange",
+    "Upload",
+];
+
+/// Sender of server-generated messages, left out of per-user statistics.
+pub const SYSTEM_SENDER: &str = "__system__";
+
+define_schema! {
+    ChatMessage {
+        room: String,
+        text: String,
+        sender: String,
+        // Server time the message was stored, in milliseconds since the epoch
+        sent_at: i64,
+        // Unix timestamp the message disappears at; 0 keeps it forever
+        expires_at: i64,
+        pinned: bool,
+        // When /purge soft-deleted the message, in milliseconds since the
+        // epoch; 0 while it is live. Deleted rows are only read back by
+        // /restore until /purge-deleted removes them
+        deleted_at: i64,
+    }
+
+    // A ChatMessage row read back together with its rowid, which serves as
+    // the message id; never registered as a table
+    StoredMessage {
+        id: i64,
+        room: String,
+        text: String,
+        sender: String,
+        sent_at: i64,
+        expires_at: i64,
+        pinned: bool,
+    }
+
+    User {
+        name: String [unique()],
+        name_changed_at: i64,
+        // Overrides the server's message quota when above 0
+        message_quota: i64,
+        // Overrides the server's upload quota, in bytes, when above 0
+        upload_quota: i64,
+    }
+
+    // A file a user finished sending, for their upload quota
+    Upload {
+        sender: String,
+        size: i64,
+        // When the file was relayed, in milliseconds since the epoch
+        sent_at: i64,
+    }
+
+    NameHistory {
+        old_name: String,
+        new_name: String,
+        timestamp: String,
+    }
+
+    // One moderation action, such as a pin, and who took it
+    AuditLog {
+        actor: String,
+        action: String,
+        target: String,
+        timestamp: String,
+    }
+
+    // A link on a room's shared bookmark list
+    Bookmark {
+        room: String,
+        url: String,
+        title: String,
+        added_by: String,
+        // When the link was added, in milliseconds since the epoch
+        timestamp: i64,
+    }
+
+    // A word a named user gets a KeywordAlert for, set with /notify
+    Keyword {
+        name: String,
+        keyword: String,
+    }
+
+    // A command shortcut a named user set with /alias
+    Alias {
+        name: String,
+        shortcut: String,
+        command: String,
+    }
+
+    // A join, leave or moderation action in one room, for /events
+    RoomEvent {
+        room: String,
+        event_type: String,
+        actor_name: String,
+        // Who the action was taken against; empty when no one
+        target_name: String,
+        // When it happened, in milliseconds since the epoch
+        timestamp: i64,
+    }
+
+    // A topic set with /topic, for /topic history
+    TopicChange {
+        room: String,
+        new_topic: String,
+        changed_by: String,
+        // When the topic was set, in milliseconds since the epoch
+        changed_at: i64,
+    }
+
+    // A /msg sent to a user who was offline, held until they resume their
+    // session
+    Notification {
+        recipient_name: String,
+        sender_name: String,
+        text: String,
+        // When the message was sent, in milliseconds since the epoch
+        created_at: i64,
+        delivered: bool,
+    }
+
+    // A Notification row read back together with its rowid, which keeps
+    // notifications in the order they were sent; never registered as a table
+    StoredNotification {
+        id: i64,
+        sender_name: String,
+        text: String,
+        created_at: i64,
+    }
+
+    // A Bookmark row read back together with its rowid, which serves as the
+    // bookmark id; never registered as a table
+    StoredBookmark {
+        id: i64,
+        url: String,
+        title: String,
+        added_by: String,
+        timestamp: i64,
+    }
+
+    // Row shape of `PRAGMA table_info`; never registered as a table
+    TableColumn {
+        name: String,
+    }
+
+    // A ChatMessage row as older versions stored it, with chrono's Display
+    // output as its timestamp; never registered as a table
+    LegacyMessage {
+        id: i64,
+        timestamp: String,
+    }
+
+    // A sender's approximate word total; never registered as a table
+    WordCount {
+        sender: String,
+        words: i64,
+    }
+
+    // A room's messages counted by size; never registered as a table
+    MessageSizes {
+        to_100: i64,
+        to_500: i64,
+        to_1000: i64,
+        over_1000: i64,
+    }
+
+    // Any table's row as a JSON object of its columns and rowid, with the
+    // rowid alongside for paging; never registered as a table
+    ExportedRow {
+        id: i64,
+        json: String,
+    }
+
+    // A count of matching rows; never registered as a table
+    MessageCount {
+        count: i64,
+    }
+
+    // Row shape of `PRAGMA wal_checkpoint`; never registered as a table
+    WalCheckpoint {
+        busy: i64,
+        log: i64,
+        checkpointed: i64,
+    }
+}
+
+// Columns selected into a StoredMessage
+const STORED_MESSAGE_COLUMNS: &str = "rowid AS id, room, text, sender, sent_at, expires_at, pinned";
+
+// Columns selected into a StoredBookmark
+const STORED_BOOKMARK_COLUMNS: &str = "rowid AS id, url, title, added_by, timestamp";
+
+// Columns selected into a StoredNotification
+const STORED_NOTIFICATION_COLUMNS: &str = "rowid AS id, sender_name, text, created_at";
+
+// Legacy rows converted per UPDATE by the timestamp migration
+const MIGRATION_BATCH: usize = 500;
+
+/// A chat message as read back from a store.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SavedMessage {
+    pub id: i64,
+    pub sender: String,
+    pub text: String,
+    pub sent_at: DateTime<Utc>,
+    pub expires_at: Option<i64>,
+    pub pinned: bool,
+}
+
+/// Which of a room's messages a history replay picks from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplayFilter<'a> {
+    /// The whole history.
+    All,
+    /// Messages after this one in history order; none when the room
+    /// doesn't have it.
+    After(i64),
+    /// Messages other than these.
+    Excluding(&'a [i64]),
+}
+
+/// A room bookmark as read back from a store.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SavedBookmark {
+    pub id: i64,
+    pub url: String,
+    pub title: String,
+    pub added_by: String,
+    pub added_at: DateTime<Utc>,
+}
+
+/// A direct message held for an offline user, as read back from a store.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SavedNotification {
+    pub sender: String,
+    pub text: String,
+    pub sent_at: DateTime<Utc>,
+}
+
+/// A room event as read back from a store.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SavedRoomEvent {
+    pub event_type: String,
+    pub actor: String,
+    pub target: String,
+    pub at: DateTime<Utc>,
+}
+
+/// A user's row as loaded on connect.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SavedUser {
+    pub name_changed_at: i64,
+    // False when this load created the row
+    pub returning: bool,
+}
+
+/// A topic change as read back from a store.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SavedTopicChange {
+    pub topic: String,
+    pub changed_by: String,
+    pub at: DateTime<Utc>,
+}
+
+/// A moderation action as read back from a store.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    pub actor: String,
+    pub action: String,
+    pub target: String,
+    pub at: DateTime<Utc>,
+}
+
+/// Outcome of a WAL checkpoint.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Checkpoint {
+    /// A reader or writer kept the checkpoint from finishing; the rest of the
+    /// WAL is copied back on a later run.
+    pub busy: bool,
+    /// Pages in the WAL when the checkpoint started.
+    pub wal_pages: i64,
+    /// Pages of those copied back into the database.
+    pub checkpointed_pages: i64,
+}
+
+/// One namespace's storage, picked by the scheme of its database URL.
+#[derive(Clone)]
+pub enum Store {
+    Sqlite(SqliteStore),
+    Memory(MemoryStore),
+}
+
+impl Store {
+    pub fn new(url: String) -> Self {
+        if url == MEMORY_DATABASE_URL {
+            Store::Memory(MemoryStore::new())
+        } else {
+            Store::Sqlite(SqliteStore::new(url))
+        }
+    }
+
+    /// Human readable name of the backend, for startup logs.
+    pub fn backend(&self) -> &'static str {
+        match self {
+            Store::Sqlite(_) => "SQLite",
+            Store::Memory(_) => "memory (nothing persists across restarts)",
+        }
+    }
+
+    // Opens a connection and runs a trivial query, for startup checks
+    pub async fn ping(&self) -> Result<(), DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.ping().await,
+            Store::Memory(_) => Ok(()),
+        }
+    }
+
+    // Saves a chat message sent at `sent_at`, server time, and returns its id
+    pub async fn save_message(
+        &self,
+        room: &str,
+        text: &str,
+        sender: &str,
+        sent_at: DateTime<Utc>,
+        expires_at: Option<i64>,
+    ) -> Result<i64, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => {
+                store
+                    .save_message(room, text, sender, sent_at, expires_at)
+                    .await
+            }
+            Store::Memory(store) => {
+                let delay = store.save_delay();
+                if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+                Ok(store.save_message(room, text, sender, sent_at, expires_at))
+            }
+        }
+    }
+
+    // The room's history, oldest first by server time
+    pub async fn get_messages(&self, room: &str) -> Result<Vec<SavedMessage>, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.live_messages(room, "").await,
+            Store::Memory(store) => Ok(store.live_messages(room, |_| true)),
+        }
+    }
+
+    // The newest `limit` messages of the room's history `filter` lets
+    // through, oldest first, and how many it lets through in all. Only those
+    // `limit` are loaded, however long the history
+    pub async fn replay_messages(
+        &self,
+        room: &str,
+        filter: ReplayFilter<'_>,
+        limit: usize,
+    ) -> Result<(Vec<SavedMessage>, usize), DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.replay_messages(room, filter, limit).await,
+            Store::Memory(store) => Ok(store.replay_messages(room, filter, limit)),
+        }
+    }
+
+    // Whether the room's history holds the message
+    pub async fn has_message(&self, room: &str, id: i64) -> Result<bool, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.has_message(room, id).await,
+            Store::Memory(store) => Ok(!store.live_messages(room, |m| m.id == id).is_empty()),
+        }
+    }
+
+    pub async fn get_pinned(&self, room: &str) -> Result<Vec<SavedMessage>, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.live_messages(room, "AND pinned").await,
+            Store::Memory(store) => Ok(store.live_messages(room, |message| message.pinned)),
+        }
+    }
+
+    // The newest `limit` messages `sender` wrote in the room, oldest first
+    pub async fn messages_from(
+        &self,
+        room: &str,
+        sender: &str,
+        limit: usize,
+    ) -> Result<Vec<SavedMessage>, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.messages_from(room, sender, limit).await,
+            Store::Memory(store) => {
+                let mut messages = store.live_messages(room, |message| message.sender == sender);
+                let skip = messages.len().saturating_sub(limit);
+                Ok(messages.split_off(skip))
+            }
+        }
+    }
+
+    // Deletes the newest `limit` messages `sender` wrote in the room, the
+    // same ones messages_from returns, and returns them oldest first
+    pub async fn purge_messages(
+        &self,
+        room: &str,
+        sender: &str,
+        limit: usize,
+    ) -> Result<Vec<SavedMessage>, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.purge_messages(room, sender, limit).await,
+            Store::Memory(store) => Ok(store.purge_messages(room, sender, limit)),
+        }
+    }
+
+    // Brings back a soft-deleted message, returning it when the room has it
+    // deleted and it hasn't expired since
+    pub async fn restore_message(
+        &self,
+        room: &str,
+        id: i64,
+    ) -> Result<Option<SavedMessage>, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.restore_message(room, id).await,
+            Store::Memory(store) => Ok(store.restore_message(room, id)),
+        }
+    }
+
+    // Removes the room's soft-deleted messages for good, returning how many
+    pub async fn purge_deleted(&self, room: &str) -> Result<usize, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.purge_deleted(room).await,
+            Store::Memory(store) => Ok(store.purge_deleted(room)),
+        }
+    }
+
+    // Pins or unpins a message, returning it when it exists in the room
+    pub async fn set_pinned(
+        &self,
+        room: &str,
+        id: i64,
+        pinned: bool,
+    ) -> Result<Option<SavedMessage>, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.set_pinned(room, id, pinned).await,
+            Store::Memory(store) => Ok(store.set_pinned(room, id, pinned)),
+        }
+    }
+
+    // Adds a link to the room's bookmark list and returns its id
+    pub async fn add_bookmark(
+        &self,
+        room: &str,
+        url: &str,
+        title: &str,
+        added_by: &str,
+        added_at: DateTime<Utc>,
+    ) -> Result<i64, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => {
+                store
+                    .add_bookmark(room, url, title, added_by, added_at)
+                    .await
+            }
+            Store::Memory(store) => Ok(store.add_bookmark(room, url, title, added_by, added_at)),
+        }
+    }
+
+    // The room's bookmarks, oldest first
+    pub async fn bookmarks(&self, room: &str) -> Result<Vec<SavedBookmark>, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.bookmarks(room).await,
+            Store::Memory(store) => Ok(store.bookmarks(room)),
+        }
+    }
+
+    // Removes a bookmark, returning it when it exists in the room
+    pub async fn remove_bookmark(
+        &self,
+        room: &str,
+        id: i64,
+    ) -> Result<Option<SavedBookmark>, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.remove_bookmark(room, id).await,
+            Store::Memory(store) => Ok(store.remove_bookmark(room, id)),
+        }
+    }
+
+    // The keywords saved under the name, oldest first
+    pub async fn keywords(&self, name: &str) -> Result<Vec<String>, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.keywords(name).await,
+            Store::Memory(store) => Ok(store.keywords(name)),
+        }
+    }
+
+    pub async fn add_keyword(&self, name: &str, keyword: &str) -> Result<(), DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.add_keyword(name, keyword).await,
+            Store::Memory(store) => {
+                store.add_keyword(name, keyword);
+                Ok(())
+            }
+        }
+    }
+
+    // Removes a keyword saved under the name, returning whether it was
+    pub async fn remove_keyword(&self, name: &str, keyword: &str) -> Result<bool, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.remove_keyword(name, keyword).await,
+            Store::Memory(store) => Ok(store.remove_keyword(name, keyword)),
+        }
+    }
+
+    // The aliases saved under the name as (shortcut, command), oldest first
+    pub async fn aliases(&self, name: &str) -> Result<Vec<(String, String)>, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.aliases(name).await,
+            Store::Memory(store) => Ok(store.aliases(name)),
+        }
+    }
+
+    // Saves an alias under the name, replacing any with the same shortcut
+    pub async fn set_alias(
+        &self,
+        name: &str,
+        shortcut: &str,
+        command: &str,
+    ) -> Result<(), DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.set_alias(name, shortcut, command).await,
+            Store::Memory(store) => {
+                store.set_alias(name, shortcut, command);
+                Ok(())
+            }
+        }
+    }
+
+    // Removes an alias saved under the name, returning whether there was one
+    pub async fn remove_alias(&self, name: &str, shortcut: &str) -> Result<bool, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.remove_alias(name, shortcut).await,
+            Store::Memory(store) => Ok(store.remove_alias(name, shortcut)),
+        }
+    }
+
+    // Holds a direct message for `recipient` until they next resume their
+    // session
+    pub async fn add_notification(
+        &self,
+        recipient: &str,
+        sender: &str,
+        text: &str,
+        sent_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        match self {
+            Store::Sqlite(store) => {
+                store
+                    .add_notification(recipient, sender, text, sent_at)
+                    .await
+            }
+            Store::Memory(store) => {
+                store.add_notification(recipient, sender, text, sent_at);
+                Ok(())
+            }
+        }
+    }
+
+    // The recipient's undelivered notifications, oldest first, marked
+    // delivered as they are read so no later call returns them again
+    pub async fn take_notifications(
+        &self,
+        recipient: &str,
+    ) -> Result<Vec<SavedNotification>, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.take_notifications(recipient).await,
+            Store::Memory(store) => Ok(store.take_notifications(recipient)),
+        }
+    }
+
+    // Deletes disappearing messages whose time is up
+    pub async fn delete_expired(&self, now: i64) -> Result<(), DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.delete_expired(now).await,
+            Store::Memory(store) => {
+                store.delete_expired(now);
+                Ok(())
+            }
+        }
+    }
+
+    // Loads the user's row, creating it on first sight
+    pub async fn load_user(&self, name: &str) -> Result<SavedUser, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.load_user(name).await,
+            Store::Memory(store) => Ok(store.load_user(name)),
+        }
+    }
+
+    // Records a rename and stamps the cooldown on both names
+    pub async fn save_name_change(
+        &self,
+        old_name: &str,
+        new_name: &str,
+        changed_at: i64,
+    ) -> Result<(), DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.save_name_change(old_name, new_name, changed_at).await,
+            Store::Memory(store) => {
+                store.save_name_change(old_name, new_name, changed_at);
+                Ok(())
+            }
+        }
+    }
+
+    // Walks the rename history backwards from `name`, newest first
+    pub async fn previous_names(
+        &self,
+        name: &str,
+        limit: usize,
+    ) -> Result<Vec<String>, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.previous_names(name, limit).await,
+            Store::Memory(store) => Ok(store.previous_names(name, limit)),
+        }
+    }
+
+    // When `sender` stored each message still held since `since`, in any
+    // room, oldest first
+    pub async fn sent_since(
+        &self,
+        sender: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<DateTime<Utc>>, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.sent_since(sender, since).await,
+            Store::Memory(store) => Ok(store.sent_since(sender, since)),
+        }
+    }
+
+    // The `limit` senders with the most words across every room, most first.
+    // Words are approximated as one more than the number of spaces
+    pub async fn word_counts(&self, limit: usize) -> Result<Vec<(String, i64)>, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.word_counts(limit).await,
+            Store::Memory(store) => Ok(store.word_counts(limit)),
+        }
+    }
+
+    // How many of the room's messages are 0-100 bytes long, 101-500,
+    // 501-1000 and over 1000, in that order. System messages don't count
+    pub async fn message_sizes(&self, room: &str) -> Result<[i64; 4], DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.message_sizes(room).await,
+            Store::Memory(store) => Ok(store.message_sizes(room)),
+        }
+    }
+
+    // The user's own message quota, if their row overrides the server's
+    pub async fn message_quota(&self, name: &str) -> Result<Option<u64>, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.message_quota(name).await,
+            // Nothing can set an override without a database to edit
+            Store::Memory(_) => Ok(None),
+        }
+    }
+
+    // Records a file `sender` finished sending
+    pub async fn record_upload(
+        &self,
+        sender: &str,
+        size: u64,
+        sent_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.record_upload(sender, size, sent_at).await,
+            Store::Memory(store) => {
+                store.record_upload(sender, size, sent_at);
+                Ok(())
+            }
+        }
+    }
+
+    // When `sender` sent each file since `since`, and its size, oldest first
+    pub async fn uploaded_since(
+        &self,
+        sender: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<(DateTime<Utc>, u64)>, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.uploaded_since(sender, since).await,
+            Store::Memory(store) => Ok(store.uploaded_since(sender, since)),
+        }
+    }
+
+    // The user's own upload quota in bytes, if their row overrides the server's
+    pub async fn upload_quota(&self, name: &str) -> Result<Option<u64>, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.upload_quota(name).await,
+            // Nothing can set an override without a database to edit
+            Store::Memory(_) => Ok(None),
+        }
+    }
+
+    // Records a moderation action, such as `alice` pinning `message 12`
+    pub async fn record_audit(
+        &self,
+        actor: &str,
+        action: &str,
+        target: &str,
+    ) -> Result<(), DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.record_audit(actor, action, target).await,
+            Store::Memory(store) => {
+                store.record_audit(actor, action, target);
+                Ok(())
+            }
+        }
+    }
+
+    // Records something that happened in a room, such as `alice` joining it
+    pub async fn record_room_event(
+        &self,
+        room: &str,
+        event_type: &str,
+        actor: &str,
+        target: &str,
+    ) -> Result<(), DatabaseError> {
+        match self {
+            Store::Sqlite(store) => {
+                store
+                    .record_room_event(room, event_type, actor, target)
+                    .await
+            }
+            Store::Memory(store) => {
+                store.record_room_event(room, event_type, actor, target);
+                Ok(())
+            }
+        }
+    }
+
+    // The room's newest `limit` events, oldest first
+    pub async fn room_events(
+        &self,
+        room: &str,
+        limit: usize,
+    ) -> Result<Vec<SavedRoomEvent>, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.room_events(room, limit).await,
+            Store::Memory(store) => Ok(store.room_events(room, limit)),
+        }
+    }
+
+    pub async fn record_topic_change(
+        &self,
+        room: &str,
+        topic: &str,
+        changed_by: &str,
+    ) -> Result<(), DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.record_topic_change(room, topic, changed_by).await,
+            Store::Memory(store) => {
+                store.record_topic_change(room, topic, changed_by);
+                Ok(())
+            }
+        }
+    }
+
+    // The room's newest `limit` topics, oldest first
+    pub async fn topic_changes(
+        &self,
+        room: &str,
+        limit: usize,
+    ) -> Result<Vec<SavedTopicChange>, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.topic_changes(room, limit).await,
+            Store::Memory(store) => Ok(store.topic_changes(room, limit)),
+        }
+    }
+
+    // The newest `limit` moderation actions, oldest first
+    pub async fn recent_audit(&self, limit: usize) -> Result<Vec<AuditEntry>, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.recent_audit(limit).await,
+            Store::Memory(store) => Ok(store.recent_audit(limit)),
+        }
+    }
+
+    // Copies the WAL back into the database and truncates it. None when the
+    // store keeps no WAL
+    pub async fn checkpoint(&self) -> Result<Option<Checkpoint>, DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.checkpoint().await,
+            Store::Memory(_) => Ok(None),
+        }
+    }
+
+    pub async fn create_tables(&self) -> Result<(), DatabaseError> {
+        match self {
+            Store::Sqlite(store) => store.create_tables().await,
+            Store::Memory(_) => Ok(()),
+        }
+    }
+}
+
+/// A namespace's SQLite database. The connection pool opens on first use and
+/// is shared by every clone, which also keeps `sqlite::memory:` databases
+/// alive across calls.
+#[derive(Clone)]
+pub struct SqliteStore {
+    url: String,
+    db: Arc<OnceCell<Database>>,
+}
+
+impl SqliteStore {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            db: Arc::new(OnceCell::new()),
+        }
+    }
+
+    async fn connect(&self) -> Result<&Database, DatabaseError> {
+        self.db
+            .get_or_try_init(|| Database::connect(&self.url))
+            .await
+    }
+
+    // Opens a connection and runs a trivial query, for startup checks
+    pub async fn ping(&self) -> Result<(), DatabaseError> {
+        let db = self.connect().await?;
+        db.sql::<TableColumn>("SELECT 1").await?;
+        Ok(())
+    }
+
+    // Written as raw SQL because lume can't hand back the rowid of an insert
+    pub async fn save_message(
+        &self,
+        room: &str,
+        text: &str,
+        sender: &str,
+        sent_at: DateTime<Utc>,
+        expires_at: Option<i64>,
+    ) -> Result<i64, DatabaseError> {
+        let db = self.connect().await?;
+
+        let saved = db
+            .sql::<StoredMessage>(&format!(
+                "INSERT INTO ChatMessage (room, text, sender, sent_at, expires_at, pinned, deleted_at) \
+                 VALUES ({}, {}, {}, {}, {}, 0, 0) RETURNING rowid AS id",
+                quote(room),
+                quote(text),
+                quote(sender),
+                sent_at.timestamp_millis(),
+                expires_at.unwrap_or(0)
+            ))
+            .await?;
+
+        saved
+            .first()
+            .and_then(|row| row.get(StoredMessage::id()))
+            .ok_or_else(|| DatabaseError::QueryError("insert returned no id".to_string()))
+    }
+
+    // Pins or unpins a message, returning it when it exists in the room
+    pub async fn set_pinned(
+        &self,
+        room: &str,
+        id: i64,
+        pinned: bool,
+    ) -> Result<Option<SavedMessage>, DatabaseError> {
+        let db = self.connect().await?;
+
+        let messages = db
+            .sql::<StoredMessage>(&format!(
+                "UPDATE ChatMessage SET pinned = {} WHERE rowid = {} AND room = {} AND {} \
+                 RETURNING {}",
+                pinned as i64,
+                id,
+                quote(room),
+                live(),
+                STORED_MESSAGE_COLUMNS
+            ))
+            .await?;
+
+        Ok(messages.first().map(saved_message))
+    }
+
+    // Messages in the room that haven't expired, even if the pruner hasn't
+    // deleted them yet, oldest first. Messages stored in the same millisecond
+    // keep the order they were saved in
+    async fn live_messages(
+        &self,
+        room: &str,
+        condition: &str,
+    ) -> Result<Vec<SavedMessage>, DatabaseError> {
+        let db = self.connect().await?;
+
+        let messages = db
+            .sql::<StoredMessage>(&format!(
+                "SELECT {} FROM ChatMessage WHERE room = {} AND {} {} ORDER BY sent_at, rowid",
+                STORED_MESSAGE_COLUMNS,
+                quote(room),
+                live(),
+                condition
+            ))
+            .await?;
+
+        Ok(messages.iter().map(saved_message).collect())
+    }
+
+    pub async fn replay_messages(
+        &self,
+        room: &str,
+        filter: ReplayFilter<'_>,
+        limit: usize,
+    ) -> Result<(Vec<SavedMessage>, usize), DatabaseError> {
+        let db = self.connect().await?;
+
+        let condition = match filter {
+            ReplayFilter::All => String::new(),
+            // In history order, so by send time and then rowid
+            ReplayFilter::After(id) => format!(
+                "AND (sent_at, rowid) > \
+                 (SELECT sent_at, rowid FROM ChatMessage WHERE room = {} AND rowid = {})",
+                quote(room),
+                id
+            ),
+            ReplayFilter::Excluding([]) => String::new(),
+            ReplayFilter::Excluding(ids) => {
+                let ids: Vec<String> = ids.iter().map(i64::to_string).collect();
+                format!("AND rowid NOT IN ({})", ids.join(", "))
+            }
+        };
+        let messages = db
+            .sql::<StoredMessage>(&format!(
+                "SELECT {} FROM ChatMessage WHERE room = {} AND {} {} \
+                 ORDER BY sent_at DESC, rowid DESC LIMIT {}",
+                STORED_MESSAGE_COLUMNS,
+                quote(room),
+                live(),
+                condition,
+                limit.min(i64::MAX as usize)
+            ))
+            .await?;
+        let count = db
+            .sql::<MessageCount>(&format!(
+                "SELECT COUNT(*) AS count FROM ChatMessage WHERE room = {} AND {} {}",
+                quote(room),
+                live(),
+                condition
+            ))
+            .await?;
+
+        let total = count
+            .first()
+            .and_then(|row| row.get(MessageCount::count()))
+            .unwrap_or_default();
+        Ok((
+            messages.iter().rev().map(saved_message).collect(),
+            usize::try_from(total).unwrap_or_default(),
+        ))
+    }
+
+    pub async fn has_message(&self, room: &str, id: i64) -> Result<bool, DatabaseError> {
+        let condition = format!("AND rowid = {}", id);
+        Ok(!self.live_messages(room, &condition).await?.is_empty())
+    }
+
+    pub async fn messages_from(
+        &self,
+        room: &str,
+        sender: &str,
+        limit: usize,
+    ) -> Result<Vec<SavedMessage>, DatabaseError> {
+        let db = self.connect().await?;
+
+        let messages = db
+            .sql::<StoredMessage>(&format!(
+                "SELECT {} FROM ChatMessage WHERE room = {} AND sender = {} AND {} \
+                 ORDER BY sent_at DESC, rowid DESC LIMIT {}",
+                STORED_MESSAGE_COLUMNS,
+                quote(room),
+                quote(sender),
+                live(),
+                limit
+            ))
+            .await?;
+
+        Ok(messages.iter().rev().map(saved_message).collect())
+    }
+
+    // Soft-deletes in one statement, so a purge can't be half done
+    pub async fn purge_messages(
+        &self,
+        room: &str,
+        sender: &str,
+        limit: usize,
+    ) -> Result<Vec<SavedMessage>, DatabaseError> {
+        let db = self.connect().await?;
+
+        let purged = db
+            .sql::<StoredMessage>(&format!(
+                "UPDATE ChatMessage SET deleted_at = {} WHERE rowid IN (\
+                 SELECT rowid FROM ChatMessage WHERE room = {} AND sender = {} AND {} \
+                 ORDER BY sent_at DESC, rowid DESC LIMIT {}) RETURNING {}",
+                Utc::now().timestamp_millis(),
+                quote(room),
+                quote(sender),
+                live(),
+                limit,
+                STORED_MESSAGE_COLUMNS
+            ))
+            .await?;
+
+        // RETURNING comes back in no particular order
+        let mut purged: Vec<SavedMessage> = purged.iter().map(saved_message).collect();
+        purged.sort_by_key(|message| (message.sent_at, message.id));
+        Ok(purged)
+    }
+
+    pub async fn restore_message(
+        &self,
+        room: &str,
+        id: i64,
+    ) -> Result<Option<SavedMessage>, DatabaseError> {
+        let db = self.connect().await?;
+
+        let restored = db
+            .sql::<StoredMessage>(&format!(
+                "UPDATE ChatMessage SET deleted_at = 0 WHERE rowid = {} AND room = {} \
+                 AND deleted_at > 0 AND (expires_at = 0 OR expires_at > {}) RETURNING {}",
+                id,
+                quote(room),
+                Utc::now().timestamp(),
+                STORED_MESSAGE_COLUMNS
+            ))
+            .await?;
+
+        Ok(restored.first().map(saved_message))
+    }
+
+    pub async fn purge_deleted(&self, room: &str) -> Result<usize, DatabaseError> {
+        let db = self.connect().await?;
+
+        let removed = db
+            .sql::<StoredMessage>(&format!(
+                "DELETE FROM ChatMessage WHERE room = {} AND deleted_at > 0 RETURNING {}",
+                quote(room),
+                STORED_MESSAGE_COLUMNS
+            ))
+            .await?;
+
+        Ok(removed.len())
+    }
+
+    // Raw SQL for the same reason as save_message
+    pub async fn add_bookmark(
+        &self,
+        room: &str,
+        url: &str,
+        title: &str,
+        added_by: &str,
+        added_at: DateTime<Utc>,
+    ) -> Result<i64, DatabaseError> {
+        let db = self.connect().await?;
+
+        let saved = db
+            .sql::<StoredBookmark>(&format!(
+                "INSERT INTO Bookmark (room, url, title, added_by, timestamp) \
+                 VALUES ({}, {}, {}, {}, {}) RETURNING {}",
+                quote(room),
+                quote(url),
+                quote(title),
+                quote(added_by),
+                added_at.timestamp_millis(),
+                STORED_BOOKMARK_COLUMNS
+            ))
+            .await?;
+
+        saved
+            .first()
+            .and_then(|row| row.get(StoredBookmark::id()))
+            .ok_or_else(|| DatabaseError::QueryError("insert returned no id".to_string()))
+    }
+
+    pub async fn bookmarks(&self, room: &str) -> Result<Vec<SavedBookmark>, DatabaseError> {
+        let db = self.connect().await?;
+
+        let bookmarks = db
+            .sql::<StoredBookmark>(&format!(
+                "SELECT {} FROM Bookmark WHERE room = {} ORDER BY rowid",
+                STORED_BOOKMARK_COLUMNS,
+                quote(room)
+            ))
+            .await?;
+
+        Ok(bookmarks.iter().map(saved_bookmark).collect())
+    }
+
+    pub async fn remove_bookmark(
+        &self,
+        room: &str,
+        id: i64,
+    ) -> Result<Option<SavedBookmark>, DatabaseError> {
+        let db = self.connect().await?;
+
+        let removed = db
+            .sql::<StoredBookmark>(&format!(
+                "DELETE FROM Bookmark WHERE rowid = {} AND room = {} RETURNING {}",
+                id,
+                quote(room),
+                STORED_BOOKMARK_COLUMNS
+            ))
+            .await?;
+
+        Ok(removed.first().map(saved_bookmark))
+    }
+
+    pub async fn keywords(&self, name: &str) -> Result<Vec<String>, DatabaseError> {
+        let db = self.connect().await?;
+
+        let keywords = db
+            .sql::<Keyword>(&format!(
+                "SELECT name, keyword FROM Keyword WHERE name = {} ORDER BY rowid",
+                quote(name)
+            ))
+            .await?;
+
+        Ok(keywords
+            .iter()
+            .filter_map(|row| row.get(Keyword::keyword()))
+            .collect())
+    }
+
+    pub async fn add_keyword(&self, name: &str, keyword: &str) -> Result<(), DatabaseError> {
+        let db = self.connect().await?;
+
+        let keyword = Keyword {
+            name: name.to_string(),
+            keyword: keyword.to_string(),
+        };
+        db.insert(keyword).execute().await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_keyword(&self, name: &str, keyword: &str) -> Result<bool, DatabaseError> {
+        let db = self.connect().await?;
+
+        let removed = db
+            .sql::<Keyword>(&format!(
+                "DELETE FROM Keyword WHERE name = {} AND keyword = {} RETURNING name, keyword",
+                quote(name),
+                quote(keyword)
+            ))
+            .await?;
+
+        Ok(!removed.is_empty())
+    }
+
+    pub async fn aliases(&self, name: &str) -> Result<Vec<(String, String)>, DatabaseError> {
+        let db = self.connect().await?;
+
+        let aliases = db
+            .sql::<Alias>(&format!(
+                "SELECT name, shortcut, command FROM Alias WHERE name = {} ORDER BY rowid",
+                quote(name)
+            ))
+            .await?;
+
+        Ok(aliases
+            .iter()
+            .filter_map(|row| Some((row.get(Alias::shortcut())?, row.get(Alias::command())?)))
+            .collect())
+    }
+
+    pub async fn set_alias(
+        &self,
+        name: &str,
+        shortcut: &str,
+        command: &str,
+    ) -> Result<(), DatabaseError> {
+        self.remove_alias(name, shortcut).await?;
+        let db = self.connect().await?;
+
+        let alias = Alias {
+            name: name.to_string(),
+            shortcut: shortcut.to_string(),
+            command: command.to_string(),
+        };
+        db.insert(alias).execute().await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_alias(&self, name: &str, shortcut: &str) -> Result<bool, DatabaseError> {
+        let db = self.connect().await?;
+
+        let removed = db
+            .sql::<Alias>(&format!(
+                "DELETE FROM Alias WHERE name = {} AND shortcut = {} \
+                 RETURNING name, shortcut, command",
+                quote(name),
+                quote(shortcut)
+            ))
+            .await?;
+
+        Ok(!removed.is_empty())
+    }
+
+    pub async fn add_notification(
+        &self,
+        recipient: &str,
+        sender: &str,
+        text: &str,
+        sent_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        let db = self.connect().await?;
+
+        let notification = Notification {
+            recipient_name: recipient.to_string(),
+            sender_name: sender.to_string(),
+            text: text.to_string(),
+            created_at: sent_at.timestamp_millis(),
+            delivered: false,
+        };
+        db.insert(notification).execute().await?;
+
+        Ok(())
+    }
+
+    // One UPDATE both reads and marks the notifications, so two connections
+    // resuming at once can't both deliver them
+    pub async fn take_notifications(
+        &self,
+        recipient: &str,
+    ) -> Result<Vec<SavedNotification>, DatabaseError> {
+        let db = self.connect().await?;
+
+        let taken = db
+            .sql::<StoredNotification>(&format!(
+                "UPDATE Notification SET delivered = 1 \
+                 WHERE recipient_name = {} AND NOT delivered RETURNING {}",
+                quote(recipient),
+                STORED_NOTIFICATION_COLUMNS
+            ))
+            .await?;
+
+        // RETURNING comes back in no particular order
+        let mut taken: Vec<(i64, SavedNotification)> = taken
+            .iter()
+            .map(|row| {
+                let id = row.get(StoredNotification::id()).unwrap_or_default();
+                (id, saved_notification(row))
+            })
+            .collect();
+        taken.sort_by_key(|(id, _)| *id);
+        Ok(taken
+            .into_iter()
+            .map(|(_, notification)| notification)
+            .collect())
+    }
+
+    // Deletes disappearing messages whose time is up
+    pub async fn delete_expired(&self, now: i64) -> Result<(), DatabaseError> {
+        let db = self.connect().await?;
+
+        db.sql::<ChatMessage>(&format!(
+            "DELETE FROM ChatMessage WHERE expires_at > 0 AND expires_at <= {}",
+            now
+        ))
+        .await?;
+
+        Ok(())
+    }
+
+    // Loads the user's row, creating it on first sight, and returns when they last renamed
+    pub async fn load_user(&self, name: &str) -> Result<SavedUser, DatabaseError> {
+        let db = self.connect().await?;
+
+        let users = db
+            .query::<User, SelectUser>()
+            .filter(eq_value(User::name(), name.to_string()))
+            .execute()
+            .await?;
+
+        if let Some(user) = users.first() {
+            return Ok(SavedUser {
+                name_changed_at: user.get(User::name_changed_at()).unwrap_or_default(),
+                returning: true,
+            });
+        }
+
+        let user = User {
+            name: name.to_string(),
+            name_changed_at: 0,
+            message_quota: 0,
+            upload_quota: 0,
+        };
+        db.insert(user).execute().await?;
+
+        Ok(SavedUser {
+            name_changed_at: 0,
+            returning: false,
+        })
+    }
+
+    // Records a rename in the history and stamps the cooldown on both names'
+    // rows, so reconnecting under either one keeps it
+    pub async fn save_name_change(
+        &self,
+        old_name: &str,
+        new_name: &str,
+        changed_at: i64,
+    ) -> Result<(), DatabaseError> {
+        let db = self.connect().await?;
+
+        let change = NameHistory {
+            old_name: old_name.to_string(),
+            new_name: new_name.to_string(),
+            timestamp: Utc::now().to_string(),
+        };
+        db.insert(change).execute().await?;
+
+        // lume's update qualifies SET columns with the table name, which SQLite rejects
+        self.load_user(old_name).await?;
+        self.load_user(new_name).await?;
+        db.sql::<User>(&format!(
+            "UPDATE User SET name_changed_at = {} WHERE name IN ({}, {})",
+            changed_at,
+            quote(old_name),
+            quote(new_name)
+        ))
+        .await?;
+
+        Ok(())
+    }
+
+    // Walks the rename history backwards from `name`, newest first
+    pub async fn previous_names(
+        &self,
+        name: &str,
+        limit: usize,
+    ) -> Result<Vec<String>, DatabaseError> {
+        let db = self.connect().await?;
+
+        let mut names = Vec::new();
+        let mut current = name.to_string();
+        while names.len() < limit {
+            let changes = db
+                .query::<NameHistory, SelectNameHistory>()
+                .filter(eq_value(NameHistory::new_name(), current.clone()))
+                .execute()
+                .await?;
+
+            let Some(old_name) = changes
+                .last()
+                .and_then(|change| change.get(NameHistory::old_name()))
+            else {
+                break;
+            };
+
+            // A rename loop (a -> b -> a) would otherwise repeat forever
+            if old_name == name || names.contains(&old_name) {
+                break;
+            }
+            names.push(old_name.clone());
+            current = old_name;
+        }
+
+        Ok(names)
+    }
+
+    pub async fn sent_since(
+        &self,
+        sender: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<DateTime<Utc>>, DatabaseError> {
+        let db = self.connect().await?;
+
+        let messages = db
+            .sql::<StoredMessage>(&format!(
+                "SELECT {} FROM ChatMessage WHERE sender = {} AND sent_at > {} \
+                 ORDER BY sent_at, rowid",
+                STORED_MESSAGE_COLUMNS,
+                quote(sender),
+                since.timestamp_millis()
+            ))
+            .await?;
+
+        Ok(messages
+            .iter()
+            .map(|row| saved_message(row).sent_at)
+            .collect())
+    }
+
+    pub async fn word_counts(&self, limit: usize) -> Result<Vec<(String, i64)>, DatabaseError> {
+        let db = self.connect().await?;
+
+        let counts = db
+            .sql::<WordCount>(&format!(
+                "SELECT sender, \
+                 SUM(length(text) - length(replace(text, ' ', '')) + 1) AS words \
+                 FROM ChatMessage WHERE sender != {} AND deleted_at = 0 \
+                 GROUP BY sender ORDER BY words DESC, sender LIMIT {}",
+                quote(SYSTEM_SENDER),
+                limit
+            ))
+            .await?;
+
+        Ok(counts
+            .iter()
+            .map(|row| {
+                (
+                    row.get(WordCount::sender()).unwrap_or_default(),
+                    row.get(WordCount::words()).unwrap_or_default(),
+                )
+            })
+            .collect())
+    }
+
+    pub async fn message_sizes(&self, room: &str) -> Result<[i64; 4], DatabaseError> {
+        let db = self.connect().await?;
+
+        // Casting to a blob makes length() count bytes rather than characters
+        let rows = db
+            .sql::<MessageSizes>(&format!(
+                "SELECT \
+                 COALESCE(SUM(CASE WHEN length(CAST(text AS BLOB)) <= 100 THEN 1 ELSE 0 END), 0) AS to_100, \
+                 COALESCE(SUM(CASE WHEN length(CAST(text AS BLOB)) BETWEEN 101 AND 500 THEN 1 ELSE 0 END), 0) AS to_500, \
+                 COALESCE(SUM(CASE WHEN length(CAST(text AS BLOB)) BETWEEN 501 AND 1000 THEN 1 ELSE 0 END), 0) AS to_1000, \
+                 COALESCE(SUM(CASE WHEN length(CAST(text AS BLOB)) > 1000 THEN 1 ELSE 0 END), 0) AS over_1000 \
+                 FROM ChatMessage WHERE room = {} AND sender != {} AND deleted_at = 0",
+                quote(room),
+                quote(SYSTEM_SENDER)
+            ))
+            .await?;
+
+        let Some(row) = rows.first() else {
+            return Ok([0; 4]);
+        };
+        Ok([
+            row.get(MessageSizes::to_100()).unwrap_or_default(),
+            row.get(MessageSizes::to_500()).unwrap_or_default(),
+            row.get(MessageSizes::to_1000()).unwrap_or_default(),
+            row.get(MessageSizes::over_1000()).unwrap_or_default(),
+        ])
+    }
+
+    pub async fn message_quota(&self, name: &str) -> Result<Option<u64>, DatabaseError> {
+        let db = self.connect().await?;
+
+        let users = db
+            .query::<User, SelectUser>()
+            .filter(eq_value(User::name(), name.to_string()))
+            .execute()
+            .await?;
+
+        Ok(users
+            .first()
+            .and_then(|user| user.get(User::message_quota()))
+            .and_then(|quota| u64::try_from(quota).ok())
+            .filter(|quota| *quota > 0))
+    }
+
+    pub async fn record_upload(
+        &self,
+        sender: &str,
+        size: u64,
+        sent_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        let db = self.connect().await?;
+
+        let upload = Upload {
+            sender: sender.to_string(),
+            size: i64::try_from(size).unwrap_or(i64::MAX),
+            sent_at: sent_at.timestamp_millis(),
+        };
+        db.insert(upload).execute().await?;
+
+        Ok(())
+    }
+
+    pub async fn uploaded_since(
+        &self,
+        sender: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<(DateTime<Utc>, u64)>, DatabaseError> {
+        let db = self.connect().await?;
+
+        let uploads = db
+            .sql::<Upload>(&format!(
+                "SELECT sender, size, sent_at FROM Upload WHERE sender = {} AND sent_at > {} \
+                 ORDER BY sent_at, rowid",
+                quote(sender),
+                since.timestamp_millis()
+            ))
+            .await?;
+
+        Ok(uploads
+            .iter()
+            .map(|row| {
+                let sent_at = row.get(Upload::sent_at()).unwrap_or_default();
+                let size = row.get(Upload::size()).unwrap_or_default();
+                (
+                    DateTime::from_timestamp_millis(sent_at).unwrap_or_default(),
+                    u64::try_from(size).unwrap_or_default(),
+                )
+            })
+            .collect())
+    }
+
+    pub async fn upload_quota(&self, name: &str) -> Result<Option<u64>, DatabaseError> {
+        let db = self.connect().await?;
+
+        let users = db
+            .query::<User, SelectUser>()
+            .filter(eq_value(User::name(), name.to_string()))
+            .execute()
+            .await?;
+
+        Ok(users
+            .first()
+            .and_then(|user| user.get(User::upload_quota()))
+            .and_then(|quota| u64::try_from(quota).ok())
+            .filter(|quota| *quota > 0))
+    }
+
+    pub async fn record_audit(
+        &self,
+        actor: &str,
+        action: &str,
+        target: &str,
+    ) -> Result<(), DatabaseError> {
+        let db = self.connect().await?;
+
+        let entry = AuditLog {
+            actor: actor.to_string(),
+            action: action.to_string(),
+            target: target.to_string(),
+            timestamp: Utc::now().to_string(),
+        };
+        db.insert(entry).execute().await?;
+
+        Ok(())
+    }
+
+    pub async fn record_room_event(
+        &self,
+        room: &str,
+        event_type: &str,
+        actor: &str,
+        target: &str,
+    ) -> Result<(), DatabaseError> {
+        let db = self.connect().await?;
+
+        let event = RoomEvent {
+            room: room.to_string(),
+            event_type: event_type.to_string(),
+            actor_name: actor.to_string(),
+            target_name: target.to_string(),
+            timestamp: Utc::now().timestamp_millis(),
+        };
+        db.insert(event).execute().await?;
+
+        Ok(())
+    }
+
+    pub async fn room_events(
+        &self,
+        room: &str,
+        limit: usize,
+    ) -> Result<Vec<SavedRoomEvent>, DatabaseError> {
+        let db = self.connect().await?;
+
+        let events = db
+            .sql::<RoomEvent>(&format!(
+                "SELECT room, event_type, actor_name, target_name, timestamp FROM RoomEvent \
+                 WHERE room = {} ORDER BY rowid DESC LIMIT {}",
+                quote(room),
+                limit
+            ))
+            .await?;
+
+        Ok(events
+            .iter()
+            .rev()
+            .map(|row| SavedRoomEvent {
+                event_type: row.get(RoomEvent::event_type()).unwrap_or_default(),
+                actor: row.get(RoomEvent::actor_name()).unwrap_or_default(),
+                target: row.get(RoomEvent::target_name()).unwrap_or_default(),
+                at: row
+                    .get(RoomEvent::timestamp())
+                    .and_then(DateTime::from_timestamp_millis)
+                    .unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    pub async fn record_topic_change(
+        &self,
+        room: &str,
+        topic: &str,
+        changed_by: &str,
+    ) -> Result<(), DatabaseError> {
+        let db = self.connect().await?;
+
+        let change = TopicChange {
+            room: room.to_string(),
+            new_topic: topic.to_string(),
+            changed_by: changed_by.to_string(),
+            changed_at: Utc::now().timestamp_millis(),
+        };
+        db.insert(change).execute().await?;
+
+        Ok(())
+    }
+
+    pub async fn topic_changes(
+        &self,
+        room: &str,
+        limit: usize,
+    ) -> Result<Vec<SavedTopicChange>, DatabaseError> {
+        let db = self.connect().await?;
+
+        let changes = db
+            .sql::<TopicChange>(&format!(
+                "SELECT room, new_topic, changed_by, changed_at FROM TopicChange \
+                 WHERE room = {} ORDER BY rowid DESC LIMIT {}",
+                quote(room),
+                limit
+            ))
+            .await?;
+
+        Ok(changes
+            .iter()
+            .rev()
+            .map(|row| SavedTopicChange {
+                topic: row.get(TopicChange::new_topic()).unwrap_or_default(),
+                changed_by: row.get(TopicChange::changed_by()).unwrap_or_default(),
+                at: row
+                    .get(TopicChange::changed_at())
+                    .and_then(DateTime::from_timestamp_millis)
+                    .unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    pub async fn recent_audit(&self, limit: usize) -> Result<Vec<AuditEntry>, DatabaseError> {
+        let db = self.connect().await?;
+
+        let entries = db
+            .sql::<AuditLog>(&format!(
+                "SELECT actor, action, target, timestamp FROM AuditLog \
+                 ORDER BY rowid DESC LIMIT {}",
+                limit
+            ))
+            .await?;
+
+        Ok(entries
+            .iter()
+            .rev()
+            .map(|row| AuditEntry {
+                actor: row.get(AuditLog::actor()).unwrap_or_default(),
+                action: row.get(AuditLog::action()).unwrap_or_default(),
+                target: row.get(AuditLog::target()).unwrap_or_default(),
+                at: row
+                    .get(AuditLog::timestamp())
+                    .and_then(|at| at.parse().ok())
+                    .unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    // lume has no checkpoint API, so these are the raw PRAGMAs. SQLite answers
+    // with a log of -1 when the database isn't in WAL mode, and with zeros
+    // after a TRUNCATE that emptied the log, so the page counts come from a
+    // PASSIVE checkpoint that copies the pages back first
+    pub async fn checkpoint(&self) -> Result<Option<Checkpoint>, DatabaseError> {
+        let db = self.connect().await?;
+
+        let copied = db
+            .sql::<WalCheckpoint>("PRAGMA wal_checkpoint(PASSIVE)")
+            .await?;
+        let truncated = db
+            .sql::<WalCheckpoint>("PRAGMA wal_checkpoint(TRUNCATE)")
+            .await?;
+
+        let busy = truncated
+            .first()
+            .is_some_and(|row| row.get(WalCheckpoint::busy()).unwrap_or_default() != 0);
+        Ok(copied
+            .first()
+            .map(|row| Checkpoint {
+                busy,
+                wal_pages: row.get(WalCheckpoint::log()).unwrap_or(-1),
+                checkpointed_pages: row.get(WalCheckpoint::checkpointed()).unwrap_or(-1),
+            })
+            .filter(|checkpoint| checkpoint.wal_pages >= 0))
+    }
+
+    // The table's columns in table order; empty when it doesn't exist
+    pub async fn columns(&self, table: &str) -> Result<Vec<String>, DatabaseError> {
+        let db = self.connect().await?;
+
+        let columns = db
+            .sql::<TableColumn>(&format!("PRAGMA table_info({})", table))
+            .await?;
+
+        Ok(columns
+            .iter()
+            .filter_map(|row| row.get(TableColumn::name()))
+            .collect())
+    }
+
+    // Up to `limit` of the table's rows with a rowid above `after`, by rowid.
+    // Each is a JSON object of `columns` plus the rowid, which importing
+    // keeps, so bookmark and message ids survive a round trip
+    pub async fn export_rows(
+        &self,
+        table: &str,
+        columns: &[String],
+        after: i64,
+        limit: usize,
+    ) -> Result<Vec<(i64, String)>, DatabaseError> {
+        let db = self.connect().await?;
+
+        let fields: Vec<String> = columns
+            .iter()
+            .map(|column| format!("{}, \"{}\"", quote(column), column))
+            .collect();
+        let rows = db
+            .sql::<ExportedRow>(&format!(
+                "SELECT rowid AS id, json_object('rowid', rowid, {}) AS json FROM {} \
+                 WHERE rowid > {} ORDER BY rowid LIMIT {}",
+                fields.join(", "),
+                table,
+                after,
+                limit
+            ))
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| {
+                (
+                    row.get(ExportedRow::id()).unwrap_or_default(),
+                    row.get(ExportedRow::json()).unwrap_or_default(),
+                )
+            })
+            .collect())
+    }
+
+    // Inserts rows as export_rows hands them out, with one value per column
+    // in `columns`. Columns the table lacks are refused rather than spliced
+    // into the SQL
+    pub async fn import_rows(
+        &self,
+        table: &str,
+        columns: &[String],
+        rows: &[Vec<serde_json::Value>],
+    ) -> Result<(), DatabaseError> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let known = self.columns(table).await?;
+        if let Some(unknown) = columns
+            .iter()
+            .find(|column| column.as_str() != "rowid" && !known.contains(*column))
+        {
+            return Err(DatabaseError::QueryError(format!(
+                "{} has no column {}",
+                table, unknown
+            )));
+        }
+
+        let mut values = Vec::with_capacity(rows.len());
+        for row in rows {
+            let row = row
+                .iter()
+                .map(sql_value)
+                .collect::<Option<Vec<String>>>()
+                .ok_or_else(|| {
+                    DatabaseError::QueryError(format!("{} rows hold only scalars", table))
+                })?;
+            values.push(format!("({})", row.join(", ")));
+        }
+        let columns: Vec<String> = columns
+            .iter()
+            .map(|column| format!("\"{}\"", column))
+            .collect();
+
+        let db = self.connect().await?;
+        db.sql::<TableColumn>(&format!(
+            "INSERT INTO {} ({}) VALUES {}",
+            table,
+            columns.join(", "),
+            values.join(", ")
+        ))
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn create_tables(&self) -> Result<(), DatabaseError> {
+        let db = self.connect().await?;
+        db.register_table::<ChatMessage>().await?;
+        db.register_table::<User>().await?;
+        db.register_table::<NameHistory>().await?;
+        db.register_table::<AuditLog>().await?;
+        db.register_table::<Bookmark>().await?;
+        db.register_table::<Keyword>().await?;
+        db.register_table::<Notification>().await?;
+        db.register_table::<RoomEvent>().await?;
+        db.register_table::<Alias>().await?;
+        db.register_table::<TopicChange>().await?;
+        db.register_table::<Upload>().await?;
+
+        run_migrations(db).await
+    }
+}
+
+// Brings tables created by older versions up to date. Each step checks the
+// live table first, so running it again is a no-op.
+async fn run_migrations(db: &Database) -> Result<(), DatabaseError> {
+    // Disappearing messages added an expiry column
+    if !has_column(db, "ChatMessage", "expires_at").await? {
+        db.sql::<ChatMessage>(
+            "ALTER TABLE ChatMessage ADD COLUMN expires_at INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
+    }
+
+    // Pinning added a flag to every message
+    if !has_column(db, "ChatMessage", "pinned").await? {
+        db.sql::<ChatMessage>(
+            "ALTER TABLE ChatMessage ADD COLUMN pinned BOOLEAN NOT NULL DEFAULT 0",
+        )
+        .await?;
+    }
+
+    // Purging added a soft-delete time to every message
+    if !has_column(db, "ChatMessage", "deleted_at").await? {
+        db.sql::<ChatMessage>(
+            "ALTER TABLE ChatMessage ADD COLUMN deleted_at INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
+    }
+
+    // Messages from before rooms existed all belong to the default room
+    if !has_column(db, "ChatMessage", "room").await? {
+        db.sql::<ChatMessage>(&format!(
+            "ALTER TABLE ChatMessage ADD COLUMN room TEXT NOT NULL DEFAULT {}",
+            quote(DEFAULT_ROOM)
+        ))
+        .await?;
+        db.sql::<ChatMessage>(&format!(
+            "UPDATE ChatMessage SET room = {} WHERE room IS NULL",
+            quote(DEFAULT_ROOM)
+        ))
+        .await?;
+    }
+
+    // Message timestamps were chrono's Display output, which sorts and
+    // range-filters badly; they became integer milliseconds. The text column
+    // only goes once every row is converted, so an interrupted run starts over
+    if has_column(db, "ChatMessage", "timestamp").await? {
+        migrate_message_timestamps(db).await?;
+    }
+
+    db.sql::<ChatMessage>(
+        "CREATE INDEX IF NOT EXISTS ChatMessage_room_sent_at ON ChatMessage (room, sent_at)",
+    )
+    .await?;
+
+    Ok(())
+}
+
+async fn migrate_message_timestamps(db: &Database) -> Result<(), DatabaseError> {
+    if !has_column(db, "ChatMessage", "sent_at").await? {
+        db.sql::<ChatMessage>(
+            "ALTER TABLE ChatMessage ADD COLUMN sent_at INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
+    }
+
+    let rows = db
+        .sql::<LegacyMessage>("SELECT rowid AS id, timestamp FROM ChatMessage")
+        .await?;
+    let converted: Vec<(i64, i64)> = rows
+        .iter()
+        .filter_map(|row| {
+            let id = row.get(LegacyMessage::id())?;
+            // Unreadable timestamps sort into the epoch rather than vanishing
+            let sent_at = row
+                .get(LegacyMessage::timestamp())
+                .and_then(|at| parse_legacy_timestamp(&at))
+                .map_or(0, |at| at.timestamp_millis());
+            Some((id, sent_at))
+        })
+        .collect();
+
+    for batch in converted.chunks(MIGRATION_BATCH) {
+        let cases: String = batch
+            .iter()
+            .map(|(id, sent_at)| format!(" WHEN {} THEN {}", id, sent_at))
+            .collect();
+        let ids: Vec<String> = batch.iter().map(|(id, _)| id.to_string()).collect();
+        db.sql::<ChatMessage>(&format!(
+            "UPDATE ChatMessage SET sent_at = CASE rowid{} END WHERE rowid IN ({})",
+            cases,
+            ids.join(", ")
+        ))
+        .await?;
+    }
+
+    db.sql::<ChatMessage>("ALTER TABLE ChatMessage DROP COLUMN timestamp")
+        .await?;
+
+    Ok(())
+}
+
+// Reads a timestamp as older versions stored it, chrono's Display output
+// such as `2024-06-02 21:30:00.123456789 UTC`
+fn parse_legacy_timestamp(at: &str) -> Option<DateTime<Utc>> {
+    at.parse().ok().or_else(|| {
+        let naive = at.strip_suffix(" UTC")?;
+        NaiveDateTime::parse_from_str(naive, "%Y-%m-%d %H:%M:%S%.f")
+            .ok()
+            .map(|at| at.and_utc())
+    })
+}
+
+async fn has_column(db: &Database, table: &str, column: &str) -> Result<bool, DatabaseError> {
+    let columns = db
+        .sql::<TableColumn>(&format!("PRAGMA table_info({})", table))
+        .await?;
+
+    Ok(columns
+        .iter()
+        .any(|row| row.get(TableColumn::name()).as_deref() == Some(column)))
+}
+
+// A JSON scalar as an SQL literal; None for arrays and objects
+fn sql_value(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::Null => Some("NULL".to_string()),
+        serde_json::Value::Bool(value) => Some(i64::from(*value).to_string()),
+        serde_json::Value::Number(value) => Some(value.to_string()),
+        serde_json::Value::String(value) => Some(quote(value)),
+        serde_json::Value::Array(_) | serde_json::Value::Object(_) => None,
+    }
+}
+
+fn saved_message(row: &Row<StoredMessage>) -> SavedMessage {
+    SavedMessage {
+        id: row.get(StoredMessage::id()).unwrap_or_default(),
+        sender: row.get(StoredMessage::sender()).unwrap_or_default(),
+        text: row.get(StoredMessage::text()).unwrap_or_default(),
+        sent_at: row
+            .get(StoredMessage::sent_at())
+            .and_then(DateTime::from_timestamp_millis)
+            .unwrap_or_default(),
+        expires_at: row.get(StoredMessage::expires_at()).filter(|at| *at > 0),
+        pinned: row.get(StoredMessage::pinned()).unwrap_or_default(),
+    }
+}
+
+fn saved_bookmark(row: &Row<StoredBookmark>) -> SavedBookmark {
+    SavedBookmark {
+        id: row.get(StoredBookmark::id()).unwrap_or_default(),
+        url: row.get(StoredBookmark::url()).unwrap_or_default(),
+        title: row.get(StoredBookmark::title()).unwrap_or_default(),
+        added_by: row.get(StoredBookmark::added_by()).unwrap_or_default(),
+        added_at: row
+            .get(StoredBookmark::timestamp())
+            .and_then(DateTime::from_timestamp_millis)
+            .unwrap_or_default(),
+    }
+}
+
+fn saved_notification(row: &Row<StoredNotification>) -> SavedNotification {
+    SavedNotification {
+        sender: row
+            .get(StoredNotification::sender_name())
+            .unwrap_or_default(),
+        text: row.get(StoredNotification::text()).unwrap_or_default(),
+        sent_at: row
+            .get(StoredNotification::created_at())
+            .and_then(DateTime::from_timestamp_millis)
+            .unwrap_or_default(),
+    }
+}
+
+// SQL condition matching messages whose expiry hasn't passed and that
+// aren't soft-deleted
+fn live() -> String {
+    format!(
+        "(expires_at = 0 OR expires_at > {}) AND deleted_at = 0",
+        Utc::now().timestamp()
+    )
+}
+
+// Quotes a string literal for the raw statements lume can't build itself
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every backend, each with a fresh, empty database
+    async fn stores() -> Vec<Store> {
+        let stores = vec![
+            Store::new("sqlite::memory:".to_string()),
+            Store::new(MEMORY_DATABASE_URL.to_string()),
+        ];
+        for store in &stores {
+            store.create_tables().await.unwrap();
+        }
+        stores
+    }
+
+    fn texts(messages: &[SavedMessage]) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|message| message.text.as_str())
+            .collect()
+    }
+
+    fn titles(bookmarks: &[SavedBookmark]) -> Vec<&str> {
+        bookmarks
+            .iter()
+            .map(|bookmark| bookmark.title.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn urls_pick_the_backend() {
+        assert!(matches!(
+            Store::new(MEMORY_DATABASE_URL.to_string()),
+            Store::Memory(_)
+        ));
+        assert!(matches!(
+            Store::new(DEFAULT_DATABASE_URL.to_string()),
+            Store::Sqlite(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn history_is_per_room_and_oldest_first() {
+        for store in stores().await {
+            let first = store
+                .save_message("main", "one", "alice", Utc::now(), None)
+                .await
+                .unwrap();
+            let second = store
+                .save_message("main", "two", "bob", Utc::now(), None)
+                .await
+                .unwrap();
+            store
+                .save_message("other", "elsewhere", "carol", Utc::now(), None)
+                .await
+                .unwrap();
+
+            let history = store.get_messages("main").await.unwrap();
+            assert_eq!(texts(&history), ["one", "two"], "{}", store.backend());
+            assert_eq!(history[0].id, first);
+            assert_eq!(history[1].id, second);
+            assert_eq!(history[1].sender, "bob");
+            assert!(store.get_messages("empty").await.unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_load_only_the_newest_messages() {
+        for store in stores().await {
+            let mut ids = Vec::new();
+            for text in ["1", "2", "3", "4", "5"] {
+                let id = store
+                    .save_message("main", text, "alice", Utc::now(), None)
+                    .await
+                    .unwrap();
+                ids.push(id);
+            }
+            store
+                .save_message("other", "elsewhere", "bob", Utc::now(), None)
+                .await
+                .unwrap();
+            let backend = store.backend();
+            let replay = async |filter, limit| {
+                let (messages, total) = store.replay_messages("main", filter, limit).await.unwrap();
+                let texts: Vec<String> = messages.into_iter().map(|m| m.text).collect();
+                (texts, total)
+            };
+
+            assert_eq!(
+                replay(ReplayFilter::All, 2).await,
+                (vec!["4".to_string(), "5".to_string()], 5),
+                "{}",
+                backend
+            );
+            assert_eq!(
+                replay(ReplayFilter::After(ids[2]), 10).await,
+                (vec!["4".to_string(), "5".to_string()], 2),
+                "{}",
+                backend
+            );
+            assert_eq!(
+                replay(ReplayFilter::After(ids[1]), 1).await,
+                (vec!["5".to_string()], 3),
+                "{}",
+                backend
+            );
+            let seen = [ids[0], ids[2], ids[4]];
+            assert_eq!(
+                replay(ReplayFilter::Excluding(&seen), 10).await,
+                (vec!["2".to_string(), "4".to_string()], 2),
+                "{}",
+                backend
+            );
+            assert_eq!(
+                replay(ReplayFilter::Excluding(&seen), 1).await,
+                (vec!["4".to_string()], 2),
+                "{}",
+                backend
+            );
+            assert_eq!(
+                replay(ReplayFilter::Excluding(&[]), 1).await,
+                (vec!["5".to_string()], 5),
+                "{}",
+                backend
+            );
+
+            // Messages other rooms have aren't anchors here
+            assert!(store.has_message("main", ids[0]).await.unwrap());
+            assert!(!store.has_message("main", ids[4] + 1).await.unwrap());
+            assert_eq!(replay(ReplayFilter::After(ids[4] + 1), 10).await.1, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_from_filters_by_sender() {
+        for store in stores().await {
+            for text in ["a1", "a2", "a3"] {
+                store
+                    .save_message("main", text, "alice", Utc::now(), None)
+                    .await
+                    .unwrap();
+                store
+                    .save_message("main", "b", "bob", Utc::now(), None)
+                    .await
+                    .unwrap();
+            }
+            store
+                .save_message("other", "a elsewhere", "alice", Utc::now(), None)
+                .await
+                .unwrap();
+
+            let from_alice = store.messages_from("main", "alice", 10).await.unwrap();
+            assert_eq!(
+                texts(&from_alice),
+                ["a1", "a2", "a3"],
+                "{}",
+                store.backend()
+            );
+            assert!(from_alice.iter().all(|message| message.sender == "alice"));
+
+            let newest = store.messages_from("main", "alice", 2).await.unwrap();
+            assert_eq!(texts(&newest), ["a2", "a3"], "{}", store.backend());
+
+            let nobody = store.messages_from("main", "carol", 10).await.unwrap();
+            assert!(nobody.is_empty(), "{}", store.backend());
+        }
+    }
+
+    #[tokio::test]
+    async fn purges_hide_the_senders_newest_messages() {
+        for store in stores().await {
+            for text in ["a1", "a2", "a3"] {
+                store
+                    .save_message("main", text, "alice", Utc::now(), None)
+                    .await
+                    .unwrap();
+                store
+                    .save_message("main", "b", "bob", Utc::now(), None)
+                    .await
+                    .unwrap();
+            }
+            store
+                .save_message("other", "a elsewhere", "alice", Utc::now(), None)
+                .await
+                .unwrap();
+
+            let purged = store.purge_messages("main", "alice", 2).await.unwrap();
+            assert_eq!(texts(&purged), ["a2", "a3"], "{}", store.backend());
+            let history = store.get_messages("main").await.unwrap();
+            assert_eq!(
+                texts(&history),
+                ["a1", "b", "b", "b"],
+                "{}",
+                store.backend()
+            );
+
+            // Asking for more than there are takes what is left
+            let purged = store.purge_messages("main", "alice", 50).await.unwrap();
+            assert_eq!(texts(&purged), ["a1"], "{}", store.backend());
+            assert!(
+                store
+                    .purge_messages("main", "alice", 50)
+                    .await
+                    .unwrap()
+                    .is_empty()
+            );
+            assert_eq!(store.get_messages("other").await.unwrap().len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn deleted_messages_can_be_restored_until_purged() {
+        for store in stores().await {
+            let mut ids = Vec::new();
+            for text in ["a1", "a2", "a3"] {
+                let id = store
+                    .save_message("main", text, "alice", Utc::now(), None)
+                    .await
+                    .unwrap();
+                ids.push(id);
+            }
+            store.purge_messages("main", "alice", 2).await.unwrap();
+
+            let restored = store.restore_message("main", ids[1]).await.unwrap();
+            assert_eq!(restored.map(|message| message.text), Some("a2".to_string()));
+            // Live messages, and other rooms' messages, can't be restored
+            assert!(
+                store
+                    .restore_message("main", ids[0])
+                    .await
+                    .unwrap()
+                    .is_none()
+            );
+            assert!(
+                store
+                    .restore_message("other", ids[2])
+                    .await
+                    .unwrap()
+                    .is_none()
+            );
+            let history = store.get_messages("main").await.unwrap();
+            assert_eq!(texts(&history), ["a1", "a2"], "{}", store.backend());
+
+            assert_eq!(store.purge_deleted("main").await.unwrap(), 1);
+            assert!(
+                store
+                    .restore_message("main", ids[2])
+                    .await
+                    .unwrap()
+                    .is_none()
+            );
+            assert_eq!(store.purge_deleted("main").await.unwrap(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn expired_messages_are_hidden_then_deleted() {
+        let now = Utc::now().timestamp();
+        for store in stores().await {
+            store
+                .save_message("main", "gone", "alice", Utc::now(), Some(now - 1))
+                .await
+                .unwrap();
+            store
+                .save_message("main", "later", "alice", Utc::now(), Some(now + 60))
+                .await
+                .unwrap();
+            store
+                .save_message("main", "kept", "alice", Utc::now(), None)
+                .await
+                .unwrap();
+
+            let history = store.get_messages("main").await.unwrap();
+            assert_eq!(texts(&history), ["later", "kept"], "{}", store.backend());
+            assert_eq!(history[0].expires_at, Some(now + 60));
+            assert_eq!(history[1].expires_at, None);
+
+            store.delete_expired(now + 60).await.unwrap();
+            let history = store.get_messages("main").await.unwrap();
+            assert_eq!(texts(&history), ["kept"], "{}", store.backend());
+        }
+    }
+
+    #[tokio::test]
+    async fn pins_only_apply_within_the_room() {
+        for store in stores().await {
+            let id = store
+                .save_message("main", "pin me", "alice", Utc::now(), None)
+                .await
+                .unwrap();
+            store
+                .save_message("main", "not me", "alice", Utc::now(), None)
+                .await
+                .unwrap();
+
+            assert_eq!(store.set_pinned("other", id, true).await.unwrap(), None);
+            assert_eq!(
+                store.set_pinned("main", id + 100, true).await.unwrap(),
+                None
+            );
+
+            let pinned = store.set_pinned("main", id, true).await.unwrap().unwrap();
+            assert!(pinned.pinned, "{}", store.backend());
+            let pins = store.get_pinned("main").await.unwrap();
+            assert_eq!(texts(&pins), ["pin me"], "{}", store.backend());
+
+            store.set_pinned("main", id, false).await.unwrap();
+            assert!(store.get_pinned("main").await.unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn bookmarks_are_kept_per_room_in_order() {
+        for store in stores().await {
+            let docs = store
+                .add_bookmark(
+                    "main",
+                    "https://docs.example.com",
+                    "Docs",
+                    "alice",
+                    Utc::now(),
+                )
+                .await
+                .unwrap();
+            store
+                .add_bookmark("main", "https://example.com", "Home", "bob", Utc::now())
+                .await
+                .unwrap();
+            store
+                .add_bookmark(
+                    "other",
+                    "https://elsewhere.example",
+                    "Else",
+                    "bob",
+                    Utc::now(),
+                )
+                .await
+                .unwrap();
+
+            let bookmarks = store.bookmarks("main").await.unwrap();
+            assert_eq!(titles(&bookmarks), ["Docs", "Home"], "{}", store.backend());
+
+            assert_eq!(store.remove_bookmark("other", docs).await.unwrap(), None);
+            let removed = store.remove_bookmark("main", docs).await.unwrap().unwrap();
+            assert_eq!(removed.url, "https://docs.example.com");
+            assert_eq!(removed.added_by, "alice");
+            let bookmarks = store.bookmarks("main").await.unwrap();
+            assert_eq!(titles(&bookmarks), ["Home"], "{}", store.backend());
+        }
+    }
+
+    #[tokio::test]
+    async fn users_are_returning_once_their_row_exists() {
+        for store in stores().await {
+            let first = store.load_user("alice").await.unwrap();
+            assert!(!first.returning, "{}", store.backend());
+            let second = store.load_user("alice").await.unwrap();
+            assert!(second.returning, "{}", store.backend());
+        }
+    }
+
+    #[tokio::test]
+    async fn renames_stamp_both_names_and_chain_backwards() {
+        for store in stores().await {
+            assert_eq!(store.load_user("alice").await.unwrap().name_changed_at, 0);
+
+            store.save_name_change("alice", "bob", 100).await.unwrap();
+            store.save_name_change("bob", "carol", 200).await.unwrap();
+            store.save_name_change("carol", "alice", 300).await.unwrap();
+
+            assert_eq!(store.load_user("bob").await.unwrap().name_changed_at, 200);
+            assert_eq!(store.load_user("carol").await.unwrap().name_changed_at, 300);
+            assert_eq!(
+                store.previous_names("carol", 5).await.unwrap(),
+                ["bob", "alice"],
+                "{}",
+                store.backend()
+            );
+            // The loop back to alice stops instead of repeating
+            assert_eq!(
+                store.previous_names("alice", 5).await.unwrap(),
+                ["carol", "bob"],
+                "{}",
+                store.backend()
+            );
+            assert_eq!(store.previous_names("carol", 1).await.unwrap(), ["bob"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn audit_returns_the_newest_entries_oldest_first() {
+        for store in stores().await {
+            for n in 1..=3 {
+                store
+                    .record_audit("alice", "pin", &format!("message {}", n))
+                    .await
+                    .unwrap();
+            }
+
+            let entries = store.recent_audit(2).await.unwrap();
+            let targets: Vec<_> = entries.iter().map(|entry| entry.target.as_str()).collect();
+            assert_eq!(targets, ["message 2", "message 3"], "{}", store.backend());
+            assert_eq!(entries[0].actor, "alice");
+            assert_eq!(entries[0].action, "pin");
+        }
+    }
+
+    #[tokio::test]
+    async fn room_events_are_kept_per_room() {
+        for store in stores().await {
+            for (room, event_type, actor, target) in [
+                ("main", "join", "alice", ""),
+                ("other", "join", "bob", ""),
+                ("main", "purge", "alice", "carol"),
+                ("main", "leave", "alice", ""),
+            ] {
+                store
+                    .record_room_event(room, event_type, actor, target)
+                    .await
+                    .unwrap();
+            }
+
+            let events = store.room_events("main", 2).await.unwrap();
+            let types: Vec<_> = events
+                .iter()
+                .map(|event| event.event_type.as_str())
+                .collect();
+            assert_eq!(types, ["purge", "leave"], "{}", store.backend());
+            assert_eq!(events[0].actor, "alice");
+            assert_eq!(events[0].target, "carol");
+            assert_eq!(store.room_events("other", 10).await.unwrap().len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn topic_changes_are_kept_per_room() {
+        for store in stores().await {
+            for (room, topic, changed_by) in [
+                ("main", "Welcome", "alice"),
+                ("other", "Elsewhere", "bob"),
+                ("main", "Release day", "bob"),
+                ("main", "buy now", "carol"),
+            ] {
+                store
+                    .record_topic_change(room, topic, changed_by)
+                    .await
+                    .unwrap();
+            }
+
+            let changes = store.topic_changes("main", 2).await.unwrap();
+            let topics: Vec<_> = changes.iter().map(|change| change.topic.as_str()).collect();
+            assert_eq!(topics, ["Release day", "buy now"], "{}", store.backend());
+            assert_eq!(changes[0].changed_by, "bob");
+            assert_eq!(store.topic_changes("other", 10).await.unwrap().len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn word_counts_rank_senders_and_skip_system_messages() {
+        for store in stores().await {
+            for (room, text, sender) in [
+                ("main", "one two three", "alice"),
+                ("other", "four", "alice"),
+                ("main", "a b c d e f", "bob"),
+                ("main", "hi", "carol"),
+                ("main", "server restarting in five minutes", SYSTEM_SENDER),
+            ] {
+                store
+                    .save_message(room, text, sender, Utc::now(), None)
+                    .await
+                    .unwrap();
+            }
+
+            assert_eq!(
+                store.word_counts(2).await.unwrap(),
+                [("bob".to_string(), 6), ("alice".to_string(), 4)],
+                "{}",
+                store.backend()
+            );
+            assert_eq!(store.word_counts(10).await.unwrap().len(), 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn aliases_are_saved_per_name() {
+        for store in stores().await {
+            store
+                .set_alias("alice", "brb", "/away back in 5")
+                .await
+                .unwrap();
+            store.set_alias("alice", "p", "/ping").await.unwrap();
+            store.set_alias("bob", "p", "/pins").await.unwrap();
+            // Setting a shortcut again replaces its command
+            store.set_alias("alice", "p", "/ping hello").await.unwrap();
+
+            let pair = |shortcut: &str, command: &str| (shortcut.to_string(), command.to_string());
+            assert_eq!(
+                store.aliases("alice").await.unwrap(),
+                [pair("brb", "/away back in 5"), pair("p", "/ping hello")],
+                "{}",
+                store.backend()
+            );
+            assert!(store.remove_alias("alice", "brb").await.unwrap());
+            assert!(!store.remove_alias("alice", "brb").await.unwrap());
+            assert_eq!(store.aliases("bob").await.unwrap(), [pair("p", "/pins")]);
+            assert!(store.aliases("carol").await.unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn keywords_are_saved_per_name() {
+        for store in stores().await {
+            store.add_keyword("alice", "deploy").await.unwrap();
+            store.add_keyword("alice", "release").await.unwrap();
+            store.add_keyword("bob", "deploy").await.unwrap();
+
+            assert_eq!(
+                store.keywords("alice").await.unwrap(),
+                ["deploy", "release"],
+                "{}",
+                store.backend()
+            );
+            assert!(store.remove_keyword("alice", "deploy").await.unwrap());
+            assert!(!store.remove_keyword("alice", "deploy").await.unwrap());
+            assert_eq!(store.keywords("alice").await.unwrap(), ["release"]);
+            assert_eq!(store.keywords("bob").await.unwrap(), ["deploy"]);
+            assert!(store.keywords("carol").await.unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn notifications_are_taken_once_in_order() {
+        for store in stores().await {
+            let at = |ms: i64| DateTime::from_timestamp_millis(ms).unwrap();
+            store
+                .add_notification("bob", "alice", "first", at(1_717_363_800_000))
+                .await
+                .unwrap();
+            store
+                .add_notification("carol", "alice", "not for bob", at(1_717_363_801_000))
+                .await
+                .unwrap();
+            store
+                .add_notification("bob", "dave", "second", at(1_717_363_802_000))
+                .await
+                .unwrap();
+
+            let taken = store.take_notifications("bob").await.unwrap();
+            assert_eq!(
+                taken,
+                [
+                    SavedNotification {
+                        sender: "alice".to_string(),
+                        text: "first".to_string(),
+                        sent_at: at(1_717_363_800_000),
+                    },
+                    SavedNotification {
+                        sender: "dave".to_string(),
+                        text: "second".to_string(),
+                        sent_at: at(1_717_363_802_000),
+                    },
+                ],
+                "{}",
+                store.backend()
+            );
+            assert!(store.take_notifications("bob").await.unwrap().is_empty());
+            assert_eq!(store.take_notifications("carol").await.unwrap().len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn message_sizes_are_bucketed_by_bytes() {
+        for store in stores().await {
+            for (room, text, sender) in [
+                ("main", "a".repeat(100), "alice"),
+                ("main", "é".repeat(60), "alice"),
+                ("main", "a".repeat(500), "bob"),
+                ("main", "a".repeat(1000), "bob"),
+                ("main", "a".repeat(1001), "carol"),
+                ("other", "a".repeat(10), "alice"),
+                ("main", "restarting".to_string(), SYSTEM_SENDER),
+            ] {
+                store
+                    .save_message(room, &text, sender, Utc::now(), None)
+                    .await
+                    .unwrap();
+            }
+
+            // 60 two-byte characters are 120 bytes
+            assert_eq!(
+                store.message_sizes("main").await.unwrap(),
+                [1, 2, 1, 1],
+                "{}",
+                store.backend()
+            );
+            assert_eq!(store.message_sizes("empty").await.unwrap(), [0; 4]);
+        }
+    }
+
+    #[test]
+    fn legacy_timestamps_parse_with_sub_second_precision() {
+        let at = |ms: i64| DateTime::from_timestamp_millis(ms);
+        assert_eq!(
+            parse_legacy_timestamp("2024-06-02 21:30:00.123456789 UTC"),
+            at(1_717_363_800_123).map(|at| at + chrono::TimeDelta::nanoseconds(456_789))
+        );
+        assert_eq!(
+            parse_legacy_timestamp("2024-06-02 21:30:00.5 UTC"),
+            at(1_717_363_800_500)
+        );
+        assert_eq!(
+            parse_legacy_timestamp("2024-06-02 21:30:00 UTC"),
+            at(1_717_363_800_000)
+        );
+        assert_eq!(
+            parse_legacy_timestamp("2024-06-02T21:30:00.250Z"),
+            at(1_717_363_800_250)
+        );
+        assert_eq!(parse_legacy_timestamp("yesterday"), None);
+    }
+
+    #[tokio::test]
+    async fn legacy_timestamps_migrate_to_milliseconds() {
+        let sqlite = SqliteStore::new("sqlite::memory:".to_string());
+        let db = sqlite.connect().await.unwrap();
+        // The table as the first version created it
+        db.sql::<ChatMessage>(
+            "CREATE TABLE ChatMessage (text TEXT NOT NULL, sender TEXT NOT NULL, \
+             timestamp TEXT NOT NULL)",
+        )
+        .await
+        .unwrap();
+        for (text, timestamp) in [
+            ("second", "2024-06-02 21:30:00.987654321 UTC"),
+            ("first", "2024-06-02 21:30:00.123456789 UTC"),
+            ("whole", "2024-06-02 21:31:00 UTC"),
+            ("garbled", "not a time"),
+        ] {
+            db.sql::<ChatMessage>(&format!(
+                "INSERT INTO ChatMessage (text, sender, timestamp) VALUES ({}, 'alice', {})",
+                quote(text),
+                quote(timestamp)
+            ))
+            .await
+            .unwrap();
+        }
+
+        let store = Store::Sqlite(sqlite.clone());
+        store.create_tables().await.unwrap();
+        // A second run finds nothing left to do
+        store.create_tables().await.unwrap();
+        assert!(!has_column(db, "ChatMessage", "timestamp").await.unwrap());
+
+        let messages = store.get_messages(DEFAULT_ROOM).await.unwrap();
+        assert_eq!(texts(&messages), ["garbled", "first", "second", "whole"]);
+        let millis: Vec<_> = messages
+            .iter()
+            .map(|message| message.sent_at.timestamp_millis())
+            .collect();
+        assert_eq!(
+            millis,
+            [0, 1_717_363_800_123, 1_717_363_800_987, 1_717_363_860_000]
+        );
+
+        let since = DateTime::from_timestamp_millis(1_717_363_800_500).unwrap();
+        let sent = store.sent_since("alice", since).await.unwrap();
+        assert_eq!(sent.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn checkpoints_truncate_the_wal() {
+        let path = std::env::temp_dir().join(format!("chat-wal-{}.sqlite", std::process::id()));
+        let wal = path.with_extension("sqlite-wal");
+        // Nothing creates the file, and an empty one is an empty database
+        std::fs::File::create(&path).unwrap();
+        let sqlite = SqliteStore::new(format!("sqlite://{}", path.display()));
+        let store = Store::Sqlite(sqlite.clone());
+        store.create_tables().await.unwrap();
+        let db = sqlite.connect().await.unwrap();
+        db.sql::<TableColumn>("PRAGMA journal_mode=WAL")
+            .await
+            .unwrap();
+
+        for n in 0..500 {
+            store
+                .save_message(
+                    DEFAULT_ROOM,
+                    &format!("message {}", n),
+                    "alice",
+                    Utc::now(),
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+        assert!(std::fs::metadata(&wal).unwrap().len() > 0);
+
+        let checkpoint = store.checkpoint().await.unwrap().unwrap();
+        assert!(!checkpoint.busy);
+        assert!(checkpoint.wal_pages > 0, "{:?}", checkpoint);
+        assert_eq!(checkpoint.checkpointed_pages, checkpoint.wal_pages);
+        assert_eq!(std::fs::metadata(&wal).unwrap().len(), 0);
+        assert_eq!(store.get_messages(DEFAULT_ROOM).await.unwrap().len(), 500);
+
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[tokio::test]
+    async fn stores_without_a_wal_skip_checkpoints() {
+        for store in stores().await {
+            assert_eq!(
+                store.checkpoint().await.unwrap(),
+                None,
+                "{}",
+                store.backend()
+            );
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use std::borrow::Cow;
+
+// Shortcodes understood by `expand_shortcodes`, without the surrounding colons
+const SHORTCODES: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("-1", "👎"),
+    ("100", "💯"),
+    ("angry", "😠"),
+    ("blush", "😊"),
+    ("broken_heart", "💔"),
+    ("clap", "👏"),
+    ("cold_sweat", "😰"),
+    ("confused", "😕"),
+    ("cry", "😢"),
+    ("eyes", "👀"),
+    ("facepalm", "🤦"),
+    ("fire", "🔥"),
+    ("grin", "😁"),
+    ("heart", "❤️"),
+    ("heart_eyes", "😍"),
+    ("joy", "😂"),
+    ("kiss", "😘"),
+    ("laughing", "😆"),
+    ("muscle", "💪"),
+    ("neutral_face", "😐"),
+    ("ok_hand", "👌"),
+    ("party", "🥳"),
+    ("pray", "🙏"),
+    ("rocket", "🚀"),
+    ("rofl", "🤣"),
+    ("scream", "😱"),
+    ("shrug", "🤷"),
+    ("sleeping", "😴"),
+    ("slightly_smiling_face", "🙂"),
+    ("smile", "😄"),
+    ("smiley", "😃"),
+    ("smirk", "😏"),
+    ("sob", "😭"),
+    ("sparkles", "✨"),
+    ("star", "⭐"),
+    ("sunglasses", "😎"),
+    ("tada", "🎉"),
+    ("thinking", "🤔"),
+    ("thumbsdown", "👎"),
+    ("thumbsup", "👍"),
+    ("unamused", "😒"),
+    ("wave", "👋"),
+    ("wink", "😉"),
+    ("x", "❌"),
+    ("white_check_mark", "✅"),
+    ("yum", "😋"),
+    ("zzz", "💤"),
+];
+
+fn lookup(shortcode: &str) -> Option<&'static str> {
+    SHORTCODES
+        .iter()
+        .find(|(code, _)| *code == shortcode)
+        .map(|(_, emoji)| *emoji)
+}
+
+fn is_shortcode_char(c: char) -> bool {
+    c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '_' | '+' | '-')
+}
+
+/// Replaces known `:shortcode:` sequences with their emoji.
+///
+/// Unknown shortcodes and colons that don't delimit a shortcode (`http://`,
+/// `12:30:45`) are left untouched.
+pub fn expand_shortcodes(text: &str) -> Cow<'_, str> {
+    if !text.contains(':') {
+        return Cow::Borrowed(text);
+    }
+
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(open) = rest.find(':') {
+        expanded.push_str(&rest[..=open]);
+        let after = &rest[open + 1..];
+
+        let candidate = after
+            .find(':')
+            .map(|close| &after[..close])
+            .filter(|code| !code.is_empty() && code.chars().all(is_shortcode_char));
+
+        match candidate.and_then(|code| lookup(code).map(|emoji| (code, emoji))) {
+            Some((code, emoji)) => {
+                expanded.pop();
+                expanded.push_str(emoji);
+                rest = &after[code.len() + 1..];
+            }
+            // The closing colon may still open the next shortcode
+            None => rest = after,
+        }
+    }
+    expanded.push_str(rest);
+
+    Cow::Owned(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_known_shortcodes() {
+        assert_eq!(expand_shortcodes("ship it :rocket:"), "ship it 🚀");
+        assert_eq!(expand_shortcodes(":+1::tada:"), "👍🎉");
+    }
+
+    #[test]
+    fn leaves_unknown_shortcodes_alone() {
+        assert_eq!(
+            expand_shortcodes("a :not_an_emoji: b"),
+            "a :not_an_emoji: b"
+        );
+    }
+
+    #[test]
+    fn leaves_urls_and_times_alone() {
+        assert_eq!(
+            expand_shortcodes("see http://example.com"),
+            "see http://example.com"
+        );
+        assert_eq!(expand_shortcodes("at 12:30:45"), "at 12:30:45");
+    }
+
+    #[test]
+    fn borrows_text_without_shortcodes() {
+        assert!(matches!(expand_shortcodes("plain text"), Cow::Borrowed(_)));
+    }
+}
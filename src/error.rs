@@ -0,0 +1,131 @@
+use crate::protocol::{ErrorCode, Message, MessageType};
+use lume::database::error::DatabaseError;
+use std::fmt;
+
+/// Anything that can go wrong while handling a client's input.
+#[derive(Debug)]
+pub enum ChatError {
+    Database(DatabaseError),
+    Serialization(serde_json::Error),
+    Io(std::io::Error),
+    /// A password couldn't be hashed.
+    PasswordHash(argon2::password_hash::Error),
+    /// A frame couldn't be queued for a connection, with why.
+    Send(&'static str),
+    /// The client sent something the server can't make sense of; the text
+    /// is shown to it as is.
+    Protocol(String),
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatError::Database(e) => write!(f, "database error: {}", e),
+            ChatError::Serialization(e) => write!(f, "serialization error: {}", e),
+            ChatError::Io(e) => write!(f, "I/O error: {}", e),
+            ChatError::PasswordHash(e) => write!(f, "password hashing error: {}", e),
+            ChatError::Send(reason) => f.write_str(reason),
+            ChatError::Protocol(problem) => write!(f, "protocol error: {}", problem),
+        }
+    }
+}
+
+impl std::error::Error for ChatError {}
+
+impl From<DatabaseError> for ChatError {
+    fn from(e: DatabaseError) -> Self {
+        ChatError::Database(e)
+    }
+}
+
+impl From<serde_json::Error> for ChatError {
+    fn from(e: serde_json::Error) -> Self {
+        ChatError::Serialization(e)
+    }
+}
+
+impl From<std::io::Error> for ChatError {
+    fn from(e: std::io::Error) -> Self {
+        ChatError::Io(e)
+    }
+}
+
+impl From<argon2::password_hash::Error> for ChatError {
+    fn from(e: argon2::password_hash::Error) -> Self {
+        ChatError::PasswordHash(e)
+    }
+}
+
+impl ChatError {
+    /// The Error frame telling the client its request failed. Server-side
+    /// details stay in the log.
+    pub fn to_message(&self) -> Message {
+        let (code, text) = match self {
+            ChatError::Protocol(problem) => (ErrorCode::InvalidFrame, problem.clone()),
+            ChatError::Database(_)
+            | ChatError::Serialization(_)
+            | ChatError::Io(_)
+            | ChatError::PasswordHash(_)
+            | ChatError::Send(_) => (
+                ErrorCode::Internal,
+                "Something went wrong on the server, please try again".to_string(),
+            ),
+        };
+        Message::new(
+            MessageType::Error {
+                code,
+                retry_after: None,
+            },
+            text,
+        )
+    }
+
+    /// Whether the client can still be told about it; it can't when
+    /// sending is what failed.
+    pub fn reaches_client(&self) -> bool {
+        !matches!(self, ChatError::Send(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(message: &Message) -> ErrorCode {
+        match message.message_type {
+            MessageType::Error { code, .. } => code,
+            _ => panic!("not an Error frame"),
+        }
+    }
+
+    #[test]
+    fn server_failures_hide_their_details_from_clients() {
+        let error = ChatError::from(DatabaseError::QueryError("disk I/O error".to_string()));
+        let message = error.to_message();
+        assert_eq!(code(&message), ErrorCode::Internal);
+        assert_eq!(
+            message.data,
+            "Something went wrong on the server, please try again"
+        );
+        assert!(error.to_string().contains("disk I/O error"));
+        assert!(error.reaches_client());
+
+        let error = ChatError::from(serde_json::from_str::<u32>("nope").unwrap_err());
+        assert_eq!(code(&error.to_message()), ErrorCode::Internal);
+    }
+
+    #[test]
+    fn protocol_errors_tell_the_client_what_was_wrong() {
+        let error = ChatError::Protocol("Could not decode MessagePack frame.".to_string());
+        let message = error.to_message();
+        assert_eq!(code(&message), ErrorCode::InvalidFrame);
+        assert_eq!(message.data, "Could not decode MessagePack frame.");
+    }
+
+    #[test]
+    fn send_failures_cannot_reach_the_client() {
+        let error = ChatError::Send("connection sender has stopped");
+        assert!(!error.reaches_client());
+        assert_eq!(error.to_string(), "connection sender has stopped");
+    }
+}
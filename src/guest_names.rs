@@ -0,0 +1,121 @@
+use rand::Rng;
+use rand::seq::IndexedRandom;
+
+/// Generated names a connection tries before falling back to one built from
+/// its connection id.
+pub const ATTEMPTS: usize = 20;
+
+/// Every guest name starts with this, generated or not.
+pub const PREFIX: &str = "Guest-";
+
+// Curated so that no adjective and noun pair spells out a blocked word, which
+// the tests check for every combination
+const ADJECTIVES: [&str; 40] = [
+    "Amber", "Bold", "Brave", "Breezy", "Bright", "Calm", "Clever", "Cozy", "Crisp", "Curious",
+    "Eager", "Fancy", "Gentle", "Glad", "Golden", "Happy", "Jolly", "Kind", "Lively", "Lucky",
+    "Merry", "Mighty", "Misty", "Nimble", "Noble", "Plucky", "Proud", "Quick", "Quiet", "Rapid",
+    "Rosy", "Silver", "Sleek", "Snowy", "Sunny", "Swift", "Tidy", "Velvet", "Witty", "Zesty",
+];
+
+const NOUNS: [&str; 40] = [
+    "Badger", "Beaver", "Cedar", "Comet", "Dolphin", "Ember", "Falcon", "Finch", "Fox", "Gecko",
+    "Heron", "Koala", "Lark", "Lemur", "Lynx", "Maple", "Meadow", "Moose", "Newt", "Otter", "Owl",
+    "Panda", "Pebble", "Penguin", "Puffin", "Quokka", "Raven", "River", "Robin", "Salmon",
+    "Sparrow", "Squid", "Summit", "Tiger", "Toucan", "Walrus", "Willow", "Wombat", "Yak", "Zebra",
+];
+
+// Names no guest is given, whatever case they are written in
+const RESERVED: [&str; 5] = ["admin", "moderator", "server", "system", "me"];
+
+/// A random `Guest-AdjectiveNoun` name, such as `Guest-CalmOtter`.
+pub fn generate(rng: &mut impl Rng) -> String {
+    let adjective = ADJECTIVES.choose(rng).copied().unwrap_or("Quiet");
+    let noun = NOUNS.choose(rng).copied().unwrap_or("Otter");
+    format!("{}{}{}", PREFIX, adjective, noun)
+}
+
+/// Whether `name` is neither reserved nor held by anyone in `taken`.
+pub fn is_free<'a>(name: &str, mut taken: impl Iterator<Item = &'a String>) -> bool {
+    !is_reserved(name) && !taken.any(|taken| taken == name)
+}
+
+/// The name a connection gets once every generated one collided, built from
+/// its connection id. Anyone can pick a name like `Guest-42` for themselves,
+/// so while the name is not free a counter is added: `Guest-42-2` and so on.
+pub fn fallback<'a>(user_id: &str, taken: impl Iterator<Item = &'a String> + Clone) -> String {
+    let base = format!("{}{}", PREFIX, user_id);
+    let mut name = base.clone();
+    let mut count = 1;
+    while !is_free(&name, taken.clone()) {
+        count += 1;
+        name = format!("{}-{}", base, count);
+    }
+    name
+}
+
+fn is_reserved(name: &str) -> bool {
+    RESERVED
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    // Substrings no generated name may contain, in any case
+    const BLOCKED: [&str; 26] = [
+        "anal", "anus", "ass", "butt", "cock", "crap", "cum", "damn", "dead", "dick", "die",
+        "fart", "fuck", "hate", "hell", "kill", "nazi", "piss", "poop", "porn", "sex", "shit",
+        "slut", "tit", "twat", "wank",
+    ];
+
+    // Longest name the wordlists can produce
+    const MAX_LEN: usize = 20;
+
+    #[test]
+    fn no_combination_contains_a_blocked_word() {
+        for adjective in ADJECTIVES {
+            for noun in NOUNS {
+                let name = format!("{}{}{}", PREFIX, adjective, noun).to_lowercase();
+                for blocked in BLOCKED {
+                    assert!(!name.contains(blocked), "{} contains {}", name, blocked);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn generated_names_stay_in_charset_and_length() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..10_000 {
+            let name = generate(&mut rng);
+            let words = name.strip_prefix(PREFIX).unwrap();
+            assert!(!words.is_empty(), "{}", name);
+            assert!(words.chars().all(|c| c.is_ascii_alphabetic()), "{}", name);
+            assert!(name.len() <= MAX_LEN, "{}", name);
+            assert!(is_free(&name, std::iter::empty()), "{}", name);
+        }
+    }
+
+    #[test]
+    fn taken_and_reserved_names_are_not_free() {
+        let taken = ["Guest-CalmOtter".to_string()];
+        assert!(!is_free("Guest-CalmOtter", taken.iter()));
+        assert!(is_free("Guest-BoldOtter", taken.iter()));
+        assert!(!is_free("System", std::iter::empty()));
+        assert!(!is_free("ADMIN", std::iter::empty()));
+    }
+
+    #[test]
+    fn fallback_names_are_built_from_the_connection_id() {
+        assert_eq!(fallback("42", std::iter::empty()), "Guest-42");
+
+        // Someone already took it with /nick
+        let taken = ["Guest-42".to_string(), "Guest-42-2".to_string()];
+        assert_eq!(fallback("42", taken[..1].iter()), "Guest-42-2");
+        assert_eq!(fallback("42", taken.iter()), "Guest-42-3");
+    }
+}
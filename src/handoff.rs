@@ -0,0 +1,116 @@
+use crate::RoomSettings;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+// Layout of the handoff file; files of any other version are ignored
+const VERSION: u32 = 1;
+
+/// What one namespace hands over to the next run of the server: the state
+/// a reconnecting user would otherwise lose, and nothing tied to a live
+/// connection.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct NamespaceHandoff {
+    /// Session token -> the name it restores.
+    pub sessions: HashMap<String, String>,
+    pub room_settings: HashMap<String, RoomSettings>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HandoffFile {
+    version: u32,
+    saved_at: DateTime<Utc>,
+    namespaces: HashMap<String, NamespaceHandoff>,
+}
+
+/// Writes every namespace's handoff to `path`. The previous file is only
+/// replaced once the new one is complete.
+pub async fn save(path: &Path, namespaces: HashMap<String, NamespaceHandoff>) -> io::Result<()> {
+    let file = HandoffFile {
+        version: VERSION,
+        saved_at: Utc::now(),
+        namespaces,
+    };
+    let partial = partial_path(path);
+    tokio::fs::write(&partial, serde_json::to_vec(&file)?).await?;
+    tokio::fs::rename(&partial, path).await
+}
+
+/// Reads the handoffs a previous run saved to `path`, keyed by namespace.
+/// Nothing when there is no file; a file that can't be read, is corrupt, has
+/// another version or is older than `max_age` is ignored with a warning.
+pub async fn load(path: &Path, max_age: TimeDelta) -> HashMap<String, NamespaceHandoff> {
+    let contents = match tokio::fs::read(path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return HashMap::new(),
+        Err(e) => {
+            warn!("Ignoring handoff file {}: {}", path.display(), e);
+            return HashMap::new();
+        }
+    };
+    let file: HandoffFile = match serde_json::from_slice(&contents) {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("Ignoring corrupt handoff file {}: {}", path.display(), e);
+            return HashMap::new();
+        }
+    };
+    if file.version != VERSION {
+        warn!(
+            "Ignoring handoff file {} of version {}",
+            path.display(),
+            file.version
+        );
+        return HashMap::new();
+    }
+    let age = Utc::now() - file.saved_at;
+    if age > max_age {
+        warn!(
+            "Ignoring stale handoff file {} saved {}s ago",
+            path.display(),
+            age.num_seconds()
+        );
+        return HashMap::new();
+    }
+    file.namespaces
+}
+
+// Where a new handoff is written before it takes the place of the old one
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    PathBuf::from(partial)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("handoff-{}-{}.json", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn stale_corrupt_and_unknown_files_are_ignored() {
+        assert!(
+            load(&temp_path("missing"), TimeDelta::minutes(5))
+                .await
+                .is_empty()
+        );
+
+        let path = temp_path("ignored");
+        let stale = r#"{"version":1,"saved_at":"2024-06-02T21:30:00Z","namespaces":{"default":{"sessions":{"token":"alice"},"room_settings":{}}}}"#;
+        let newer = format!(
+            r#"{{"version":2,"saved_at":"{}","namespaces":{{}}}}"#,
+            Utc::now().to_rfc3339()
+        );
+        for contents in [stale, "{not json", &newer] {
+            std::fs::write(&path, contents).unwrap();
+            assert!(load(&path, TimeDelta::minutes(5)).await.is_empty());
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,237 @@
+use crate::db::ReplayFilter;
+use crate::protocol::{HistoryDay, HistoryMode, HistoryRequest, Message};
+use chrono::{DateTime, FixedOffset, TimeDelta, Utc};
+
+// Furthest any real timezone sits from UTC
+const MAX_UTC_OFFSET_SECS: i32 = 14 * 60 * 60;
+
+/// Messages replayed to a client that names no limit. Clients may ask for
+/// more, up to the server's configured `max_replay`.
+pub const DEFAULT_REPLAY: usize = 1000;
+
+/// Most ids an `unseen` request may list.
+pub const MAX_SEEN_IDS: usize = 5000;
+
+// Longest gap after which a sender's next message still continues their last
+const CONTINUATION_WINDOW: TimeDelta = TimeDelta::seconds(30);
+
+/// Whether a chat message from `sender` at `sent_at` continues the
+/// `previous` one, given as its sender and send time: same sender, at most
+/// 30 seconds later.
+pub fn continues(
+    previous: Option<(&str, DateTime<Utc>)>,
+    sender: &str,
+    sent_at: DateTime<Utc>,
+) -> bool {
+    previous.is_some_and(|(previous_sender, previous_at)| {
+        previous_sender == sender && sent_at - previous_at <= CONTINUATION_WINDOW
+    })
+}
+
+/// The fixed offset for `minutes` east of UTC, clamped to ±14 hours.
+pub fn utc_offset(minutes: i32) -> FixedOffset {
+    let secs = minutes
+        .saturating_mul(60)
+        .clamp(-MAX_UTC_OFFSET_SECS, MAX_UTC_OFFSET_SECS);
+    FixedOffset::east_opt(secs).unwrap()
+}
+
+/// What `request` wants replayed out of a room's history: the messages to
+/// pick from and the most to replay, newest kept. None when it wants no
+/// replay at all. `since_known` says whether the room has the request's
+/// `since_id`; when it doesn't, the newest messages are replayed instead.
+pub fn plan(request: &HistoryRequest, since_known: bool) -> Option<(ReplayFilter<'_>, usize)> {
+    let filter = match request.mode {
+        HistoryMode::None => return None,
+        HistoryMode::Since => match request.since_id {
+            Some(since_id) if since_known => ReplayFilter::After(since_id),
+            _ => ReplayFilter::All,
+        },
+        HistoryMode::Unseen if request.seen_ids.len() <= MAX_SEEN_IDS => {
+            ReplayFilter::Excluding(&request.seen_ids)
+        }
+        HistoryMode::Unseen | HistoryMode::Recent => ReplayFilter::All,
+    };
+    Some((filter, request.limit.unwrap_or(DEFAULT_REPLAY)))
+}
+
+/// Buckets messages, oldest first, into the calendar days they were sent on
+/// as seen from `offset`. The first message of a day never continues the
+/// one before it.
+pub fn group_by_day(
+    messages: impl IntoIterator<Item = (DateTime<Utc>, Message)>,
+    offset: FixedOffset,
+) -> Vec<HistoryDay> {
+    let mut days: Vec<HistoryDay> = Vec::new();
+    for (sent_at, mut message) in messages {
+        let day = sent_at
+            .with_timezone(&offset)
+            .format("%Y-%m-%d")
+            .to_string();
+        match days.last_mut() {
+            Some(last) if last.day == day => last.messages.push(message),
+            _ => {
+                message.continuation = false;
+                days.push(HistoryDay {
+                    day,
+                    messages: vec![message],
+                })
+            }
+        }
+    }
+    days
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::MessageType;
+
+    fn chat(at: &str, data: &str) -> (DateTime<Utc>, Message) {
+        let message = Message::new(MessageType::Chat, data);
+        (at.parse().unwrap(), message)
+    }
+
+    fn days(grouped: &[HistoryDay]) -> Vec<(&str, Vec<&str>)> {
+        grouped
+            .iter()
+            .map(|day| {
+                let messages = day.messages.iter().map(|m| m.data.as_str()).collect();
+                (day.day.as_str(), messages)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn messages_straddling_midnight_split_at_local_midnight() {
+        let messages = [
+            chat("2024-06-02T21:30:00Z", "late"),
+            chat("2024-06-02T22:30:00Z", "after local midnight"),
+            chat("2024-06-03T09:00:00Z", "morning"),
+        ];
+
+        let in_utc = group_by_day(messages.clone(), utc_offset(0));
+        assert_eq!(
+            days(&in_utc),
+            [
+                ("2024-06-02", vec!["late", "after local midnight"]),
+                ("2024-06-03", vec!["morning"]),
+            ]
+        );
+
+        let two_hours_east = group_by_day(messages.clone(), utc_offset(120));
+        assert_eq!(
+            days(&two_hours_east),
+            [
+                ("2024-06-02", vec!["late"]),
+                ("2024-06-03", vec!["after local midnight", "morning"]),
+            ]
+        );
+
+        let ten_hours_west = group_by_day(messages, utc_offset(-600));
+        assert_eq!(
+            days(&ten_hours_west),
+            [(
+                "2024-06-02",
+                vec!["late", "after local midnight", "morning"]
+            )]
+        );
+    }
+
+    #[test]
+    fn offsets_clamp_to_fourteen_hours() {
+        assert_eq!(utc_offset(14 * 60 + 1), utc_offset(14 * 60));
+        assert_eq!(utc_offset(i32::MAX).local_minus_utc(), 14 * 60 * 60);
+        assert_eq!(utc_offset(i32::MIN).local_minus_utc(), -14 * 60 * 60);
+        assert_eq!(utc_offset(-90).local_minus_utc(), -90 * 60);
+    }
+
+    fn request(mode: HistoryMode, limit: Option<usize>, since_id: Option<i64>) -> HistoryRequest {
+        HistoryRequest {
+            mode,
+            limit,
+            since_id,
+            seen_ids: Vec::new(),
+        }
+    }
+
+    fn unseen(limit: Option<usize>, seen_ids: Vec<i64>) -> HistoryRequest {
+        HistoryRequest {
+            mode: HistoryMode::Unseen,
+            limit,
+            since_id: None,
+            seen_ids,
+        }
+    }
+
+    #[test]
+    fn history_requests_plan_the_replay() {
+        let recent = HistoryRequest::default();
+        assert_eq!(
+            plan(&recent, false),
+            Some((ReplayFilter::All, DEFAULT_REPLAY))
+        );
+
+        let none = request(HistoryMode::None, Some(3), None);
+        assert_eq!(plan(&none, false), None);
+
+        let newest = request(HistoryMode::Recent, Some(2), None);
+        assert_eq!(plan(&newest, false), Some((ReplayFilter::All, 2)));
+
+        let since = request(HistoryMode::Since, Some(1), Some(3));
+        assert_eq!(plan(&since, true), Some((ReplayFilter::After(3), 1)));
+    }
+
+    #[test]
+    fn unknown_since_ids_fall_back_to_recent() {
+        let unknown = request(HistoryMode::Since, Some(2), Some(99));
+        assert_eq!(plan(&unknown, false), Some((ReplayFilter::All, 2)));
+    }
+
+    #[test]
+    fn unseen_requests_fill_scattered_gaps() {
+        let scattered = unseen(Some(1), vec![1, 3, 5]);
+        assert_eq!(
+            plan(&scattered, false),
+            Some((ReplayFilter::Excluding(&[1, 3, 5]), 1))
+        );
+
+        // Too many ids are ignored rather than checked one by one
+        let oversized = unseen(Some(2), (1..=MAX_SEEN_IDS as i64 + 1).collect());
+        assert_eq!(plan(&oversized, false), Some((ReplayFilter::All, 2)));
+    }
+
+    #[test]
+    fn greedy_limits_are_left_for_the_server_to_cap() {
+        let greedy = request(HistoryMode::Recent, Some(usize::MAX), None);
+        assert_eq!(plan(&greedy, false), Some((ReplayFilter::All, usize::MAX)));
+    }
+
+    #[test]
+    fn runs_from_one_sender_continue_within_thirty_seconds() {
+        let at = |time: &str| -> DateTime<Utc> { time.parse().unwrap() };
+        let previous = Some(("alice", at("2024-06-02T21:30:00Z")));
+
+        assert!(continues(previous, "alice", at("2024-06-02T21:30:30Z")));
+        assert!(!continues(previous, "alice", at("2024-06-02T21:30:31Z")));
+        assert!(!continues(previous, "bob", at("2024-06-02T21:30:05Z")));
+        assert!(!continues(None, "alice", at("2024-06-02T21:30:05Z")));
+    }
+
+    #[test]
+    fn days_never_start_with_a_continuation() {
+        let mut late = chat("2024-06-02T23:59:50Z", "late");
+        late.1.continuation = true;
+        let mut early = chat("2024-06-03T00:00:05Z", "early");
+        early.1.continuation = true;
+
+        let grouped = group_by_day([late, early], utc_offset(0));
+        assert!(!grouped[0].messages[0].continuation);
+        assert!(!grouped[1].messages[0].continuation);
+    }
+
+    #[test]
+    fn no_messages_means_no_days() {
+        assert!(group_by_day(Vec::new(), utc_offset(0)).is_empty());
+    }
+}
@@ -0,0 +1,209 @@
+use aho_corasick::AhoCorasick;
+use std::collections::HashMap;
+use tracing::error;
+
+/// Keywords one user may be subscribed to at once.
+pub const MAX_KEYWORDS: usize = 20;
+
+/// Longest keyword, in characters.
+pub const MAX_KEYWORD_LEN: usize = 50;
+
+/// The form a keyword is stored and matched in: trimmed and lowercased.
+/// None when it is empty, too long or more than one word.
+pub fn normalize(keyword: &str) -> Option<String> {
+    let keyword = keyword.trim();
+    if keyword.is_empty()
+        || keyword.chars().count() > MAX_KEYWORD_LEN
+        || keyword.contains(char::is_whitespace)
+    {
+        return None;
+    }
+    Some(keyword.to_lowercase())
+}
+
+/// Every connection's keyword subscriptions, with one automaton over all of
+/// them so a message is scanned once however many users subscribe.
+#[derive(Default)]
+pub struct Subscriptions {
+    by_connection: HashMap<u64, Vec<String>>,
+    matcher: Option<Matcher>,
+}
+
+struct Matcher {
+    automaton: AhoCorasick,
+    // Pattern index to keyword, and to the connections subscribed to it
+    keywords: Vec<String>,
+    subscribers: Vec<Vec<u64>>,
+}
+
+impl Subscriptions {
+    /// The connection's keywords, in the order they were added.
+    pub fn keywords(&self, id: u64) -> &[String] {
+        self.by_connection.get(&id).map_or(&[], Vec::as_slice)
+    }
+
+    /// Replaces the connection's keywords, e.g. with the ones saved under
+    /// the name it just claimed.
+    pub fn set(&mut self, id: u64, keywords: Vec<String>) {
+        if keywords.is_empty() {
+            self.by_connection.remove(&id);
+        } else {
+            self.by_connection.insert(id, keywords);
+        }
+        self.rebuild();
+    }
+
+    /// Subscribes the connection to a normalized keyword. False when it
+    /// already was.
+    pub fn add(&mut self, id: u64, keyword: &str) -> bool {
+        let keywords = self.by_connection.entry(id).or_default();
+        if keywords.iter().any(|k| k == keyword) {
+            return false;
+        }
+        keywords.push(keyword.to_string());
+        self.rebuild();
+        true
+    }
+
+    /// Unsubscribes the connection from a normalized keyword. False when it
+    /// wasn't subscribed.
+    pub fn remove(&mut self, id: u64, keyword: &str) -> bool {
+        let Some(keywords) = self.by_connection.get_mut(&id) else {
+            return false;
+        };
+        let Some(index) = keywords.iter().position(|k| k == keyword) else {
+            return false;
+        };
+        keywords.remove(index);
+        if keywords.is_empty() {
+            self.by_connection.remove(&id);
+        }
+        self.rebuild();
+        true
+    }
+
+    /// Drops a closed connection's keywords.
+    pub fn forget(&mut self, id: u64) {
+        if self.by_connection.remove(&id).is_some() {
+            self.rebuild();
+        }
+    }
+
+    /// The connections `text` alerts, each with the first of its keywords
+    /// the text mentions as a whole word, whatever its case.
+    pub fn alerts(&self, text: &str) -> Vec<(u64, &str)> {
+        let Some(matcher) = &self.matcher else {
+            return Vec::new();
+        };
+        let text = text.to_lowercase();
+        let mut alerts: Vec<(u64, &str)> = Vec::new();
+        for found in matcher.automaton.find_overlapping_iter(&text) {
+            if !is_whole_word(&text, found.start(), found.end()) {
+                continue;
+            }
+            let pattern = found.pattern().as_usize();
+            for &id in &matcher.subscribers[pattern] {
+                if !alerts.iter().any(|(alerted, _)| *alerted == id) {
+                    alerts.push((id, &matcher.keywords[pattern]));
+                }
+            }
+        }
+        alerts
+    }
+
+    // Subscriptions change far less often than messages arrive, so the
+    // automaton is built afresh on every change
+    fn rebuild(&mut self) {
+        let mut by_keyword: HashMap<&str, Vec<u64>> = HashMap::new();
+        for (id, keywords) in &self.by_connection {
+            for keyword in keywords {
+                by_keyword.entry(keyword).or_default().push(*id);
+            }
+        }
+        if by_keyword.is_empty() {
+            self.matcher = None;
+            return;
+        }
+
+        let (keywords, subscribers): (Vec<String>, Vec<Vec<u64>>) = by_keyword
+            .into_iter()
+            .map(|(keyword, ids)| (keyword.to_string(), ids))
+            .unzip();
+        self.matcher = match AhoCorasick::new(&keywords) {
+            Ok(automaton) => Some(Matcher {
+                automaton,
+                keywords,
+                subscribers,
+            }),
+            Err(e) => {
+                error!("Failed to build keyword matcher: {}", e);
+                None
+            }
+        };
+    }
+}
+
+// Whether text[start..end] has no letter, digit or underscore right before
+// or after it
+fn is_whole_word(text: &str, start: usize, end: usize) -> bool {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    !text[..start].chars().next_back().is_some_and(is_word)
+        && !text[end..].chars().next().is_some_and(is_word)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keywords_must_be_one_short_word() {
+        assert_eq!(normalize("  Deploy "), Some("deploy".to_string()));
+        assert_eq!(normalize(""), None);
+        assert_eq!(normalize("two words"), None);
+        assert_eq!(normalize(&"a".repeat(MAX_KEYWORD_LEN + 1)), None);
+    }
+
+    #[test]
+    fn only_whole_words_match_in_any_case() {
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.add(1, "deploy");
+        assert_eq!(subscriptions.alerts("DEPLOY at five"), [(1, "deploy")]);
+        assert_eq!(subscriptions.alerts("the deploy."), [(1, "deploy")]);
+        assert!(
+            subscriptions
+                .alerts("redeploy deployed deploy_2")
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn each_subscriber_is_alerted_once() {
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.add(1, "deploy");
+        subscriptions.add(1, "prod");
+        subscriptions.add(2, "prod");
+        // Overlapping keywords are all found
+        subscriptions.add(3, "prod-db");
+
+        let mut alerts = subscriptions.alerts("deploy to prod-db, prod too");
+        alerts.sort();
+        assert_eq!(alerts, [(1, "deploy"), (2, "prod"), (3, "prod-db")]);
+    }
+
+    #[test]
+    fn changes_rebuild_the_matcher() {
+        let mut subscriptions = Subscriptions::default();
+        assert!(subscriptions.add(1, "deploy"));
+        assert!(!subscriptions.add(1, "deploy"));
+        assert_eq!(subscriptions.keywords(1), ["deploy"]);
+
+        assert!(subscriptions.remove(1, "deploy"));
+        assert!(!subscriptions.remove(1, "deploy"));
+        assert!(subscriptions.alerts("deploy").is_empty());
+
+        subscriptions.set(2, vec!["release".to_string()]);
+        assert_eq!(subscriptions.alerts("release"), [(2, "release")]);
+        subscriptions.forget(2);
+        assert!(subscriptions.alerts("release").is_empty());
+    }
+}
@@ -0,0 +1,120 @@
+//! Per-message tracing of chat messages, from their frame arriving to the
+//! last recipient's copy being queued.
+
+use crate::metrics::{self, Stage};
+use std::time::{Duration, Instant};
+use tracing::{Span, field, info, info_span};
+
+/// When a frame arrived and when it had been decoded.
+#[derive(Clone, Copy, Debug)]
+pub struct Arrival {
+    pub received: Instant,
+    pub parsed: Instant,
+}
+
+impl Arrival {
+    /// An arrival decoded just now.
+    pub fn parsed_now(received: Instant) -> Self {
+        Self {
+            received,
+            parsed: Instant::now(),
+        }
+    }
+}
+
+/// When one chat message got through each stage, under a span carrying its
+/// id, namespace, room, sender and size. Only a handful of timestamps, so tracing every
+/// message costs no more than a span.
+pub struct MessageTrace {
+    span: Span,
+    // Whose metrics the stages are added to
+    namespace: String,
+    arrival: Arrival,
+    filtered: Option<Instant>,
+    enqueued: Option<Instant>,
+    acked: Option<Instant>,
+}
+
+impl MessageTrace {
+    pub fn start(
+        arrival: Arrival,
+        namespace: &str,
+        room: &str,
+        sender: &str,
+        bytes: usize,
+    ) -> Self {
+        Self {
+            span: info_span!(
+                "chat_message",
+                id = field::Empty,
+                namespace,
+                room,
+                sender,
+                bytes
+            ),
+            namespace: namespace.to_string(),
+            arrival,
+            filtered: None,
+            enqueued: None,
+            acked: None,
+        }
+    }
+
+    pub fn filtered(&mut self) {
+        self.filtered = Some(Instant::now());
+    }
+
+    pub fn enqueued(&mut self) {
+        self.enqueued = Some(Instant::now());
+    }
+
+    pub fn acked(&mut self, id: Option<i64>) {
+        self.acked = Some(Instant::now());
+        if let Some(id) = id {
+            self.span.record("id", id);
+        }
+    }
+
+    /// Ends the trace once the last recipient's copy is queued, logging how
+    /// long each stage took and adding them to the namespace's metrics. Messages that
+    /// aren't saved skip both persistence stages.
+    pub fn delivered(self, recipients: usize) {
+        let delivered = Instant::now();
+        let Arrival { received, parsed } = self.arrival;
+        let filtered = self.filtered.unwrap_or(parsed);
+        let persist_enqueue = self.enqueued.map(|enqueued| enqueued - filtered);
+        let persist_ack = self
+            .enqueued
+            .zip(self.acked)
+            .map(|(enqueued, acked)| acked - enqueued);
+        let delivery = delivered - self.acked.unwrap_or(filtered);
+        let total = delivered - received;
+
+        metrics::record_stage(&self.namespace, Stage::Parse, parsed - received);
+        metrics::record_stage(&self.namespace, Stage::Filters, filtered - parsed);
+        if let Some(took) = persist_enqueue {
+            metrics::record_stage(&self.namespace, Stage::PersistEnqueue, took);
+        }
+        if let Some(took) = persist_ack {
+            metrics::record_stage(&self.namespace, Stage::PersistAck, took);
+        }
+        metrics::record_stage(&self.namespace, Stage::Delivery, delivery);
+        metrics::record_stage(&self.namespace, Stage::Total, total);
+
+        info!(
+            parent: &self.span,
+            recipients,
+            parse_us = micros(parsed - received),
+            filters_us = micros(filtered - parsed),
+            persist_enqueue_us = persist_enqueue.map(micros),
+            persist_ack_us = persist_ack.map(micros),
+            delivery_us = micros(delivery),
+            total_us = micros(total),
+            "Chat message delivered"
+        );
+    }
+}
+
+fn micros(took: Duration) -> u64 {
+    u64::try_from(took.as_micros()).unwrap_or(u64::MAX)
+}
@@ -0,0 +1,3098 @@
+pub mod backup;
+#[doc(hidden)]
+pub mod bench;
+pub mod check;
+mod cluster;
+mod commands;
+pub mod config;
+mod db;
+mod emoji;
+mod error;
+mod guest_names;
+mod handoff;
+mod history;
+mod keywords;
+mod latency;
+mod markdown;
+mod memory_store;
+pub mod metrics;
+mod outbox;
+mod protocol;
+mod quota;
+mod sanitize;
+mod save_queue;
+mod shorthand;
+mod throttle;
+mod transfer;
+mod turns;
+mod util;
+mod webhook;
+
+use chrono::{DateTime, SubsecRound, TimeDelta, Utc};
+use cluster::Cluster;
+use commands::Command;
+use config::{GuestNames, RoomJoin, ServerConfig};
+use db::{SavedMessage, Store};
+use error::ChatError;
+use handoff::NamespaceHandoff;
+use latency::{Arrival, MessageTrace};
+use outbox::{Outbound, Outbox};
+use protocol::{
+    BookmarkInfo, ClientControl, CloseReason, ErrorCode, Handshake, HistoryMode, HistoryRequest,
+    Input, JSON_SUBPROTOCOL, MSGPACK_SUBPROTOCOL, Message, MessageType, Protocol,
+};
+use quota::{Quota, QuotaKind};
+use save_queue::{LagChange, LagMonitor, SaveQueue, Saved};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use throttle::{ConnectThrottle, Throttle};
+use tokio::net::TcpStream;
+use tokio::sync::{Mutex, RwLock, oneshot};
+use tracing::{Instrument, error, info, warn};
+use transfer::Transfer;
+use turns::Turns;
+use webhook::{ChatEvent, Webhook};
+use wynd::handle::ConnectionHandle;
+use wynd::wynd::Wynd;
+
+// Shared state to store user names
+type UserNames = Arc<RwLock<HashMap<String, String>>>;
+
+// File transfers in progress, keyed by connection id
+type Transfers = Arc<Mutex<HashMap<u64, Transfer>>>;
+
+// Every connection's /notify keywords and the matcher over them
+type Keywords = Arc<RwLock<keywords::Subscriptions>>;
+
+// Per-user state beyond the name, keyed like `UserNames`
+type UserStates = Arc<RwLock<HashMap<String, UserState>>>;
+
+// Last chat message per user, for duplicate suppression
+type LastMessages = Arc<RwLock<HashMap<String, LastMessage>>>;
+
+// Send times of recent chat messages per room, for throughput stats
+type RoomActivity = Arc<RwLock<HashMap<String, VecDeque<Instant>>>>;
+
+// Chat messages per room since the last hourly reset, for /rooms
+type HourlyMessages = Arc<RwLock<HashMap<String, u64>>>;
+
+// Sender and send time of each room's latest chat message, cleared when
+// anything else is broadcast, for continuation flags
+type LastSenders = Arc<RwLock<HashMap<String, (String, DateTime<Utc>)>>>;
+
+// Per-room settings changed with commands such as /roomttl and /topic
+type RoomSettingsMap = Arc<RwLock<HashMap<String, RoomSettings>>>;
+
+// Admins receiving a copy of every chat message, keyed by handle id
+type Tailers = Arc<RwLock<HashSet<u64>>>;
+
+// Set once an admin drains the server, to the URL clients should move to
+type Drain = Arc<RwLock<Option<Option<String>>>>;
+
+// Message of the day, shared by every namespace; empty when there is none
+type Motd = Arc<RwLock<String>>;
+
+// Session token -> the name it restores, kept across disconnects
+type Sessions = Arc<RwLock<HashMap<String, String>>>;
+
+// User ID -> hashes of their most recent `Send` frames
+type RecentSends = Arc<RwLock<HashMap<String, SendHashes>>>;
+
+// Connected clients keyed by handle id
+type Clients = Arc<RwLock<HashMap<u64, Client>>>;
+
+struct Client {
+    handle: Arc<ConnectionHandle<TcpStream>>,
+    protocol: Protocol,
+    outbox: outbox::Sender,
+    // Set as soon as the connection goes away; frames for it are dropped
+    closed: Arc<AtomicBool>,
+    // Set by the first close_with, so the connection is only closed once
+    closing: AtomicBool,
+    // Set once the connection has entered a namespace
+    namespace: Option<String>,
+    // The room the connection is in; None while it waits in the lobby
+    room: Option<String>,
+    // Timezone replayed history is grouped by, set with Hello
+    utc_offset: chrono::FixedOffset,
+    // What joining a room replays, set with Hello or the handshake
+    history: HistoryRequest,
+    // Set with /quiet: broadcast System notices, such as joins and leaves,
+    // are not delivered
+    quiet: bool,
+    // When the last Ping frame was answered, for rate limiting them
+    last_ping: Option<Instant>,
+    // When the connection last sent chat input or a command, for the idle
+    // timeout
+    last_activity: Instant,
+    // When the connection opened, for the naming timeout
+    connected_at: Instant,
+    // Rooms the connection was invited to with /invite, and who by, until
+    // it accepts or declines
+    invites: HashMap<String, String>,
+    // Whether the namespace greeting went out; held while it is being sent
+    greeted: Arc<Mutex<bool>>,
+}
+
+impl Client {
+    // What the connection's metrics count under
+    fn metrics_namespace(&self) -> &str {
+        self.namespace.as_deref().unwrap_or(metrics::NO_NAMESPACE)
+    }
+}
+
+#[derive(Default)]
+struct UserState {
+    is_admin: bool,
+    // Read-only connection that never gets a name
+    is_observer: bool,
+    // Named by the server rather than by themselves, until their first
+    // /nick. Guests' keywords are never saved
+    is_guest: bool,
+    // Shortcuts set with /alias, without their slash, and the command each
+    // stands for; saved under the user's name unless they are a guest
+    aliases: HashMap<String, String>,
+    // Unix seconds of the last /nick, persisted on the User row
+    name_changed_at: i64,
+    // Whether the store already knew their name when they claimed it
+    returning: bool,
+    // Issued to connections that opened with a handshake; bound to their
+    // name in `Sessions` once they are welcomed
+    session_token: Option<String>,
+}
+
+// Handed over to the next run on restart, see `handoff`. Settings missing
+// from an older handoff keep their defaults
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct RoomSettings {
+    // Default lifetime of new messages, set with /roomttl
+    ttl: Option<Duration>,
+    topic: Option<String>,
+    // Only admins may change a locked topic
+    topic_locked: bool,
+    // Sent to everyone who joins, set with /joinmsg
+    join_message: Option<String>,
+    // Argon2 hash of the password /join needs, set with /roompassword
+    password_hash: Option<String>,
+    // Overrides of the server's rules, set with /roomconfig. None keeps the
+    // server's: --max-message-length, and saving every message
+    max_length: Option<u64>,
+    persist: Option<bool>,
+    // Content turned off with /roomsettings
+    no_links: bool,
+    no_uploads: bool,
+}
+
+struct LastMessage {
+    text: String,
+    repeats: usize,
+    first_sent: Instant,
+}
+
+// The last SEND_HASHES sends of one user that carried a `client_msg_id`,
+// oldest first
+#[derive(Default)]
+struct SendHashes {
+    order: VecDeque<u64>,
+    seen: HashSet<u64>,
+}
+
+impl SendHashes {
+    // Remembers the hash and reports whether it was new
+    fn insert(&mut self, hash: u64) -> bool {
+        if !self.seen.insert(hash) {
+            return false;
+        }
+        if self.order.len() == SEND_HASHES
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.seen.remove(&oldest);
+        }
+        self.order.push_back(hash);
+        true
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    config: Arc<ServerConfig>,
+    clients: Clients,
+    namespaces: Arc<HashMap<String, NamespaceState>>,
+    // Per-IP connect limit, when configured
+    connect_throttle: Option<ConnectThrottle>,
+    drain: Drain,
+}
+
+// Everything one namespace owns. Chat handlers only ever see a single
+// namespace, so nothing they do can reach another one.
+#[derive(Clone)]
+struct NamespaceState {
+    name: String,
+    config: Arc<ServerConfig>,
+    store: Store,
+    // Serializes each room's message saves
+    saves: SaveQueue,
+    // Shared with every namespace; only members of this one are addressed
+    clients: Clients,
+    user_names: UserNames,
+    user_states: UserStates,
+    last_messages: LastMessages,
+    recent_sends: RecentSends,
+    sessions: Sessions,
+    room_activity: RoomActivity,
+    hourly_messages: HourlyMessages,
+    last_senders: LastSenders,
+    room_settings: RoomSettingsMap,
+    tailers: Tailers,
+    transfers: Transfers,
+    keywords: Keywords,
+    message_quota: Quota,
+    upload_quota: Quota,
+    // Wrong room passwords given by each connection, by connection id
+    password_failures: Throttle<u64>,
+    motd: Motd,
+    drain: Drain,
+    // When the server started serving, for /uptime
+    started: Instant,
+    // Onboarding text the greeting bot sends new users; no bot when None
+    greeting: Option<Arc<str>>,
+    webhook: Option<Webhook>,
+    cluster: Option<Cluster>,
+}
+
+// What every namespace shares with the rest of the server
+#[derive(Clone)]
+struct Shared {
+    config: Arc<ServerConfig>,
+    clients: Clients,
+    motd: Motd,
+    drain: Drain,
+    // When the server started serving, for /uptime
+    started: Instant,
+    // Onboarding text the greeting bot sends new users; no bot when None
+    greeting: Option<Arc<str>>,
+    webhook: Option<Webhook>,
+    cluster: Option<Cluster>,
+}
+
+impl NamespaceState {
+    fn new(name: String, store: Store, shared: Shared) -> Self {
+        let Shared {
+            config,
+            clients,
+            motd,
+            drain,
+            started,
+            greeting,
+            webhook,
+            cluster,
+        } = shared;
+        let message_quota = Quota::new(store.clone(), QuotaKind::Messages, config.message_quota);
+        Self {
+            name,
+            upload_quota: Quota::new(store.clone(), QuotaKind::UploadBytes, config.upload_quota),
+            message_quota: message_quota.clone(),
+            saves: SaveQueue::new(store.clone(), message_quota),
+            config,
+            store,
+            clients,
+            user_names: Arc::new(RwLock::new(HashMap::new())),
+            user_states: Arc::new(RwLock::new(HashMap::new())),
+            last_messages: Arc::new(RwLock::new(HashMap::new())),
+            recent_sends: Arc::new(RwLock::new(HashMap::new())),
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            room_activity: Arc::new(RwLock::new(HashMap::new())),
+            hourly_messages: Arc::new(RwLock::new(HashMap::new())),
+            last_senders: Arc::new(RwLock::new(HashMap::new())),
+            room_settings: Arc::new(RwLock::new(HashMap::new())),
+            tailers: Arc::new(RwLock::new(HashSet::new())),
+            transfers: Arc::new(Mutex::new(HashMap::new())),
+            keywords: Arc::new(RwLock::new(keywords::Subscriptions::default())),
+            password_failures: Throttle::new(MAX_PASSWORD_FAILURES, PASSWORD_FAILURE_WINDOW),
+            motd,
+            drain,
+            started,
+            greeting,
+            webhook,
+            cluster,
+        }
+    }
+
+    // Removes the user from every per-user map and returns the name they
+    // chatted under, if they had one. Room-wide state stays behind.
+    async fn forget_user(&self, id: u64) -> Option<String> {
+        let user_id = &id.to_string();
+        self.tailers.write().await.remove(&id);
+        self.transfers.lock().await.remove(&id);
+        self.keywords.write().await.forget(id);
+        let name = self.user_names.write().await.remove(user_id);
+        self.user_states.write().await.remove(user_id);
+        self.last_messages.write().await.remove(user_id);
+        self.recent_sends.write().await.remove(user_id);
+        name
+    }
+
+    // What a restart would otherwise lose: sessions, so users resume under
+    // their names, and the rooms' settings
+    async fn handoff(&self) -> NamespaceHandoff {
+        NamespaceHandoff {
+            sessions: self.sessions.read().await.clone(),
+            room_settings: self.room_settings.read().await.clone(),
+        }
+    }
+
+    // Takes over what the previous run handed off
+    async fn restore(&self, handoff: NamespaceHandoff) {
+        self.sessions.write().await.extend(handoff.sessions);
+        self.room_settings
+            .write()
+            .await
+            .extend(handoff.room_settings);
+    }
+}
+
+// Sender shown on the greeting bot's private messages
+const GREETING_BOT: &str = "bot";
+
+// Room every connection joins on open
+const DEFAULT_ROOM: &str = "main";
+
+// How far back room throughput is measured
+const ACTIVITY_WINDOW: Duration = Duration::from_secs(60);
+
+// How often the per-room message counts /rooms sorts by start over
+const HOURLY_RESET: Duration = Duration::from_secs(60 * 60);
+
+// How often expired disappearing messages are deleted
+const EXPIRY_SWEEP: Duration = Duration::from_secs(60);
+
+// How often connections are checked against the idle and naming timeouts,
+// at most
+const IDLE_SWEEP: Duration = Duration::from_secs(60);
+
+// How often the message save backlog is checked
+const LAG_CHECK: Duration = Duration::from_secs(1);
+
+// How long a connection that entered a namespace has to send control frames
+// such as Observe before it is greeted
+const GREETING_GRACE: Duration = Duration::from_millis(250);
+
+// How long shutdown waits for goodbye frames to flush
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+// Admins that may tail every room at once
+const MAX_TAILERS: usize = 4;
+// Frames an admin's connection may have queued before their tail is stopped
+const TAIL_MAX_BACKLOG: usize = 256;
+
+// Shortest gap between two Ping frames that are both answered
+const PING_INTERVAL: Duration = Duration::from_secs(2);
+
+// Furthest a client's clock may claim to be from the server's
+const MAX_CLOCK_SKEW: TimeDelta = TimeDelta::minutes(5);
+
+// Sends per user remembered to recognise resends
+const SEND_HASHES: usize = 20;
+
+// Wrong room passwords a connection may give before it has to wait for the
+// oldest to fall out of the window
+const MAX_PASSWORD_FAILURES: usize = 5;
+const PASSWORD_FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+// Identical messages allowed in a row before further repeats are suppressed
+const DUPLICATE_LIMIT: usize = 2;
+// Repeats only count as a run when they fall within this window
+const DUPLICATE_WINDOW: Duration = Duration::from_secs(30);
+
+/// Runs the chat server until it is interrupted with Ctrl-C.
+pub async fn serve(config: ServerConfig) {
+    let started = Instant::now();
+    let config = Arc::new(config);
+    let mut wynd: Wynd<TcpStream> = Wynd::new();
+    let motd = match &config.motd_file {
+        Some(path) => match tokio::fs::read_to_string(path).await {
+            Ok(motd) => motd.trim().to_string(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => panic!("Failed to read {}: {}", path.display(), e),
+        },
+        None => String::new(),
+    };
+    let greeting = match &config.greeting_file {
+        Some(path) => match tokio::fs::read_to_string(path).await {
+            Ok(greeting) => Some(Arc::from(greeting.trim())),
+            Err(e) => panic!("Failed to read {}: {}", path.display(), e),
+        },
+        None => None,
+    };
+    let shared = Shared {
+        config: Arc::clone(&config),
+        clients: Arc::new(RwLock::new(HashMap::new())),
+        motd: Arc::new(RwLock::new(motd)),
+        drain: Arc::new(RwLock::new(None)),
+        started,
+        greeting,
+        webhook: config.webhook_url.clone().map(Webhook::new),
+        cluster: match &config.redis_url {
+            Some(url) => Some(Cluster::connect(url).await.unwrap()),
+            None => None,
+        },
+    };
+
+    let mut namespaces = HashMap::new();
+    for (name, database_url) in config.namespaces() {
+        let store = Store::new(database_url);
+        store.create_tables().await.unwrap();
+        info!("Namespace {} stores messages in {}", name, store.backend());
+
+        let namespace = NamespaceState::new(name.clone(), store, shared.clone());
+        spawn_activity_pruner(namespace.room_activity.clone());
+        spawn_hourly_reset(namespace.hourly_messages.clone());
+        spawn_expiry_pruner(namespace.store.clone());
+        spawn_persistence_watch(namespace.clone());
+        spawn_wal_checkpointer(
+            namespace.store.clone(),
+            Duration::from_secs(config.wal_checkpoint_interval),
+        );
+        namespace.message_quota.spawn_pruner();
+        namespace.upload_quota.spawn_pruner();
+        namespace.password_failures.spawn_pruner();
+        if let Some(cluster) = &shared.cluster {
+            let local = namespace.clone();
+            cluster.subscribe(&name, DEFAULT_ROOM, move |message| {
+                let local = local.clone();
+                async move {
+                    deliver(&local, None, &message).await;
+                }
+            });
+        }
+        namespaces.insert(name, namespace);
+    }
+
+    if let Some(path) = &config.handoff_file {
+        let max_age = TimeDelta::seconds(config.handoff_max_age as i64);
+        for (name, handoff) in handoff::load(path, max_age).await {
+            match namespaces.get(&name) {
+                Some(namespace) => namespace.restore(handoff).await,
+                None => warn!("Ignoring handed off state of unknown namespace {}", name),
+            }
+        }
+    }
+
+    let connect_throttle = config.connect_limit.map(|limit| {
+        let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+        let window = Duration::from_secs(config.connect_window);
+        ConnectThrottle::new(limit, window, config.connect_allowlist.clone())
+    });
+    if let Some(throttle) = &connect_throttle {
+        throttle.spawn_pruner();
+    }
+
+    let state = AppState {
+        config,
+        clients: shared.clients,
+        namespaces: Arc::new(namespaces),
+        connect_throttle,
+        drain: shared.drain,
+    };
+    spawn_idle_sweep(state.clone());
+    spawn_naming_sweep(state.clone());
+
+    let port = state.config.port;
+    let shutdown_state = state.clone();
+
+    wynd.on_connection(move |conn| {
+        let state = state.clone();
+        async move {
+            // Counted here rather than on open, which wynd may run twice
+            let throttled = match &state.connect_throttle {
+                Some(throttle) => throttle.attempt(conn.addr().ip()).await.err(),
+                None => None,
+            };
+            let closed = Arc::new(AtomicBool::new(false));
+            let turns = Turns::new();
+            // Every log line from this connection's handlers carries its fields
+            let span = tracing::info_span!("connection", user_id = %conn.id(), room = DEFAULT_ROOM);
+
+            let open_state = state.clone();
+            let open_closed = Arc::clone(&closed);
+            let open_span = span.clone();
+            conn.on_open(move |handle| {
+                let state = open_state.clone();
+                let closed = Arc::clone(&open_closed);
+                async move {
+                    if let Some(retry_after) = throttled {
+                        let message = Message::new(
+                            MessageType::Closing {
+                                retry_after_ms: retry_after.as_millis() as u64,
+                            },
+                            "Too many connection attempts, please reconnect later",
+                        );
+                        reject(&state, &handle, CloseReason::Throttled, &message).await;
+                        return;
+                    }
+                    if let Some(new_url) = state.drain.read().await.clone() {
+                        let notice = drain_notice(reconnect_delay(0), new_url);
+                        reject(&state, &handle, CloseReason::Restart, &notice).await;
+                        return;
+                    }
+                    {
+                        // wynd can run the open handler twice for one connection;
+                        // only the first run sets it up
+                        let mut clients = state.clients.write().await;
+                        if clients.contains_key(&handle.id()) {
+                            return;
+                        }
+                        if state
+                            .config
+                            .max_connections
+                            .is_some_and(|max| clients.len() >= max)
+                        {
+                            let retry_after_ms = reconnect_delay(clients.len());
+                            drop(clients);
+                            let message = Message::new(
+                                MessageType::Closing { retry_after_ms },
+                                "Server is full, please reconnect later",
+                            );
+                            reject(&state, &handle, CloseReason::ServerFull, &message).await;
+                            return;
+                        }
+                        clients.insert(
+                            handle.id(),
+                            Client {
+                                handle: Arc::clone(&handle),
+                                protocol: Protocol::default(),
+                                outbox: outbox::spawn(
+                                    Arc::clone(&handle),
+                                    Arc::clone(&closed),
+                                    Duration::from_secs(state.config.send_timeout),
+                                    {
+                                        // wynd only reports a Close frame, so a socket
+                                        // that dies without one is cleaned up from here
+                                        let state = state.clone();
+                                        let id = handle.id();
+                                        move || {
+                                            tokio::spawn(
+                                                async move {
+                                                    cleanup_connection(&state, id).await;
+                                                }
+                                                .in_current_span(),
+                                            );
+                                        }
+                                    },
+                                ),
+                                closed,
+                                closing: AtomicBool::new(false),
+                                namespace: None,
+                                room: None,
+                                utc_offset: history::utc_offset(0),
+                                history: HistoryRequest::default(),
+                                quiet: false,
+                                last_ping: None,
+                                last_activity: Instant::now(),
+                                connected_at: Instant::now(),
+                                invites: HashMap::new(),
+                                greeted: Arc::new(Mutex::new(false)),
+                            },
+                        );
+                    }
+
+                    // With a single namespace there is nothing to choose
+                    if state.namespaces.len() == 1 {
+                        let name = state.namespaces.keys().next().unwrap().clone();
+                        enter_namespace(&state, &handle, &name).await;
+                        return;
+                    }
+
+                    let message = Message::new(
+                        MessageType::Welcome,
+                        "Welcome! Please join a namespace to continue.",
+                    );
+                    if let Err(e) = notify(&state.clients, &handle, &message).await {
+                        error!("Failed to send namespace prompt: {}", e);
+                    }
+                }
+                .instrument(open_span.clone())
+            })
+            .await;
+
+            // Handle incoming messages
+            let text_state = state.clone();
+            let text_span = span.clone();
+            let text_turns = turns.clone();
+            conn.on_text(move |event, handle| {
+                let state = text_state.clone();
+                let turn = text_turns.take();
+                async move {
+                    let received = Instant::now();
+                    turn.wait().await;
+                    let input = Input::from_text(&event.data);
+                    handle_input(&state, &handle, input, Arrival::parsed_now(received)).await;
+                }
+                .instrument(text_span.clone())
+            });
+
+            // Binary frames carry MessagePack input for negotiated clients; for everyone
+            // else they are relayed as a notice to the room
+            let binary_state = state.clone();
+            let binary_span = span.clone();
+            conn.on_binary(move |event, handle| {
+                let state = binary_state.clone();
+                let turn = turns.take();
+                async move {
+                    let received = Instant::now();
+                    turn.wait().await;
+                    let user_id = handle.id().to_string();
+
+                    // While a transfer is open every binary frame is a chunk of it
+                    if let Some(namespace) = namespace_of(&state, handle.id()).await
+                        && receive_chunk(namespace, &handle, &event.data).await
+                    {
+                        return;
+                    }
+
+                    let protocol = protocol_of(&state, handle.id()).await;
+                    if protocol == Protocol::MessagePack {
+                        match protocol.decode(&event.data) {
+                            Some(input) => {
+                                let arrival = Arrival::parsed_now(received);
+                                handle_input(&state, &handle, input, arrival).await
+                            }
+                            None => {
+                                let problem = "Could not decode MessagePack frame.".to_string();
+                                let error = ChatError::Protocol(problem);
+                                report(&state.clients, &handle, "Bad frame", error).await;
+                            }
+                        }
+                        return;
+                    }
+
+                    let Some(state) = namespace_of(&state, handle.id()).await else {
+                        return;
+                    };
+                    if is_observer(state, &user_id).await {
+                        return;
+                    }
+
+                    let name = {
+                        let names = state.user_names.read().await;
+                        names
+                            .get(&user_id)
+                            .cloned()
+                            .unwrap_or_else(|| user_id.clone())
+                    };
+
+                    // Broadcast binary data with user identification
+                    let message = Message::system(format!(
+                        "{} sent binary data ({} bytes)",
+                        name,
+                        event.data.len()
+                    ));
+                    broadcast(state, None, &message).await;
+                }
+                .instrument(binary_span.clone())
+            });
+
+            // Clean up when user disconnects
+            let id = conn.id();
+            conn.on_close(move |_| {
+                // Racing broadcasts check the flag without locking, so it goes
+                // up before the cleanup below waits on any lock
+                closed.store(true, Ordering::Relaxed);
+                let state = state.clone();
+                async move { cleanup_connection(&state, id).await }.instrument(span.clone())
+            });
+        }
+    });
+
+    tokio::select! {
+        result = wynd.listen(port, move || {
+            info!("Chat server listening on port {}", port);
+        }) => result.unwrap(),
+        _ = tokio::signal::ctrl_c() => shutdown(&shutdown_state).await,
+    }
+}
+
+// Suggested reconnect delay, growing with the number of connections that
+// will be reconnecting at the same time
+fn reconnect_delay(connections: usize) -> u64 {
+    const BASE_MS: u64 = 1_000;
+    const PER_CONNECTION_MS: u64 = 20;
+    const MAX_MS: u64 = 60_000;
+
+    (BASE_MS + PER_CONNECTION_MS * connections as u64).min(MAX_MS)
+}
+
+// Turns away a connection that was never registered, telling it when to
+// retry. It has no outbox for close_with to go through, so the frames are
+// written here
+async fn reject(
+    state: &AppState,
+    handle: &ConnectionHandle<TcpStream>,
+    reason: CloseReason,
+    message: &Message,
+) {
+    // Errors are ignored: wynd can fire on_open twice for one connection, and
+    // the second rejection finds the socket already closed
+    let timeout = Duration::from_secs(state.config.send_timeout);
+    let mut outbox = Outbox::new();
+    let notice = closing_notice(metrics::NO_NAMESPACE, handle.id(), reason);
+    for message in [message, &notice] {
+        let frame = outbox.stamp(message);
+        let _ = outbox::send_frame(handle, frame, timeout, metrics::NO_NAMESPACE).await;
+    }
+    let _ = handle.close().await;
+}
+
+// Logs and counts a server-initiated close, and builds the last frame the
+// connection gets, which carries the close code wynd can't put in the close
+// frame itself
+fn closing_notice(namespace: &str, id: u64, reason: CloseReason) -> Message {
+    info!(
+        "Closing connection {} with {}: {}",
+        id,
+        reason.code(),
+        reason.reason()
+    );
+    metrics::record_close(namespace, reason);
+    Message::new(
+        MessageType::Disconnected {
+            code: reason.code(),
+            reconnect: reason.reconnect(),
+        },
+        reason.reason().to_string(),
+    )
+}
+
+// Closes a registered connection for `reason`: queues the closing notice
+// behind whatever is already queued, then the Close. Only the first call for
+// a connection does anything. The outbox cleans the connection up once the
+// socket is closed, and the returned receiver fires then
+async fn close_with(
+    clients: &Clients,
+    id: u64,
+    reason: CloseReason,
+) -> Option<oneshot::Receiver<()>> {
+    let clients = clients.read().await;
+    close_client(clients.get(&id)?, reason)
+}
+
+// close_with for callers already holding the clients lock
+fn close_client(client: &Client, reason: CloseReason) -> Option<oneshot::Receiver<()>> {
+    if client.closing.swap(true, Ordering::Relaxed) {
+        return None;
+    }
+    let (done, closed) = oneshot::channel();
+    let notice = closing_notice(client.metrics_namespace(), client.handle.id(), reason);
+    client
+        .outbox
+        .send(Outbound::Message(notice))
+        .and_then(|_| client.outbox.send(Outbound::Close(done)))
+        .ok()
+        .map(|_| closed)
+}
+
+// Forgets everything held for a connection and tells its namespace it left.
+// Runs for a Close frame and for sockets that die without one; whichever
+// comes second finds nothing left to do.
+async fn cleanup_connection(state: &AppState, id: u64) {
+    let client = state.clients.write().await.remove(&id);
+    let Some(client) = client else {
+        return;
+    };
+    client.closed.store(true, Ordering::Relaxed);
+
+    let Some(namespace) = client
+        .namespace
+        .and_then(|name| state.namespaces.get(&name))
+    else {
+        return;
+    };
+    if let Some(name) = namespace.forget_user(id).await {
+        if let Some(room) = &client.room {
+            room_event(namespace, room, "leave", &name, "").await;
+        }
+        let message = Message::system(format!("{} left the chat!", name));
+        broadcast(namespace, None, &message).await;
+    }
+}
+
+// Says goodbye to every client with a reconnect hint and closes their sockets
+async fn shutdown(state: &AppState) {
+    info!("Shutting down");
+
+    let new_url = state.drain.read().await.clone().flatten();
+    let closed = send_off(&state.clients, |connections| {
+        let reason = "Server is restarting".to_string();
+        restart_notice(reason, reconnect_delay(connections), new_url)
+    })
+    .await;
+
+    if tokio::time::timeout(SHUTDOWN_GRACE, futures_util::future::join_all(closed))
+        .await
+        .is_err()
+    {
+        error!("Timed out waiting for connections to close");
+    }
+
+    if let Some(path) = &state.config.handoff_file {
+        let mut namespaces = HashMap::new();
+        for (name, namespace) in state.namespaces.iter() {
+            namespaces.insert(name.clone(), namespace.handoff().await);
+        }
+        match handoff::save(path, namespaces).await {
+            Ok(()) => info!("Handed off state to {}", path.display()),
+            Err(e) => error!("Failed to write {}: {}", path.display(), e),
+        }
+    }
+}
+
+// Sends every client the notice `notice` builds for the number of clients,
+// then closes their sockets for a restart once it is out. Returns receivers
+// that fire as each socket closes.
+async fn send_off(
+    clients: &Clients,
+    notice: impl FnOnce(usize) -> Message,
+) -> Vec<oneshot::Receiver<()>> {
+    let clients = clients.read().await;
+    let message = notice(clients.len());
+    clients
+        .values()
+        .filter_map(|client| {
+            client
+                .outbox
+                .send(Outbound::Message(message.clone()))
+                .ok()?;
+            close_client(client, CloseReason::Restart)
+        })
+        .collect()
+}
+
+fn restart_notice(reason: String, reconnect_after_ms: u64, new_url: Option<String>) -> Message {
+    Message::new(
+        MessageType::ServerRestart {
+            reason: reason.clone(),
+            reconnect_after_ms,
+            new_url,
+        },
+        reason,
+    )
+}
+
+// What a drained server tells clients on their way out
+fn drain_notice(reconnect_after_ms: u64, new_url: Option<String>) -> Message {
+    let reason = "Server is draining for maintenance".to_string();
+    restart_notice(reason, reconnect_after_ms, new_url)
+}
+
+async fn handle_input(
+    state: &AppState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    input: Input,
+    arrival: Arrival,
+) {
+    {
+        // Connections turned away on open are never registered
+        let mut clients = state.clients.write().await;
+        let Some(client) = clients.get_mut(&handle.id()) else {
+            return;
+        };
+        // Only what the user typed keeps them from idling out, not control
+        // frames such as Ping
+        if matches!(
+            input,
+            Input::Text(_) | Input::Control(ClientControl::Send { .. })
+        ) {
+            client.last_activity = Instant::now();
+        }
+    }
+
+    match input {
+        Input::Text(text) => match greet(state, handle).await {
+            Some(namespace) => handle_text(namespace, handle, &text, arrival).await,
+            None => refuse_outside_namespace(state, handle).await,
+        },
+        Input::Control(ClientControl::Send {
+            text,
+            client_msg_id,
+            sent_at,
+        }) => match greet(state, handle).await {
+            Some(namespace) => {
+                handle_send(namespace, handle, &text, client_msg_id, sent_at, arrival).await
+            }
+            None => refuse_outside_namespace(state, handle).await,
+        },
+        Input::Handshake(handshake) => match namespace_of(state, handle.id()).await {
+            Some(namespace) => {
+                if let Some(request) = handshake.history()
+                    && let Some(client) = state.clients.write().await.get_mut(&handle.id())
+                {
+                    client.history = request.clone();
+                }
+                let resumed = handle_handshake(namespace, handle, handshake).await;
+                greet(state, handle).await;
+                if let Some(name) = resumed {
+                    welcome(namespace, handle, &name, false).await;
+                    deliver_notifications(namespace, handle, &name).await;
+                }
+            }
+            None => refuse_outside_namespace(state, handle).await,
+        },
+        Input::Control(ClientControl::Negotiate { subprotocol }) => {
+            if negotiate_protocol(state, handle, &subprotocol).await {
+                greet(state, handle).await;
+            }
+        }
+        Input::Control(ClientControl::RequestResend { from_seq }) => {
+            let resend = Outbound::Resend { from_seq };
+            if let Err(e) = enqueue(&state.clients, handle.id(), resend).await {
+                error!("Failed to request resend: {}", e);
+            }
+        }
+        Input::Control(ClientControl::SendFile {
+            filename,
+            size,
+            chunks,
+            to,
+        }) => match greet(state, handle).await {
+            Some(namespace) => begin_transfer(namespace, handle, &filename, size, chunks, to).await,
+            None => refuse_outside_namespace(state, handle).await,
+        },
+        Input::Control(ClientControl::Ping { token, sent_at }) => {
+            let message = Message::new(
+                MessageType::Pong {
+                    token,
+                    server_time: Utc::now().trunc_subsecs(3),
+                    client_sent_at: sent_at,
+                },
+                "",
+            );
+            let mut clients = state.clients.write().await;
+            if let Some(client) = clients.get_mut(&handle.id())
+                && client
+                    .last_ping
+                    .is_none_or(|last| last.elapsed() >= PING_INTERVAL)
+            {
+                client.last_ping = Some(Instant::now());
+                if client.outbox.send(Outbound::Unsequenced(message)).is_err() {
+                    error!("Failed to send message: connection sender has stopped");
+                }
+            }
+        }
+        Input::Control(ClientControl::JoinNamespace { namespace }) => {
+            enter_namespace(state, handle, &namespace).await
+        }
+        Input::Control(ClientControl::Hello {
+            utc_offset_minutes,
+            markdown,
+            history,
+        }) => {
+            if let Some(client) = state.clients.write().await.get_mut(&handle.id()) {
+                client.utc_offset = history::utc_offset(utc_offset_minutes);
+                if let Some(request) = history {
+                    client.history = request;
+                }
+                let _ = client.outbox.send(Outbound::EscapeMarkdown(markdown));
+            }
+            greet(state, handle).await;
+        }
+        Input::Control(ClientControl::Observe { room }) => {
+            match namespace_of(state, handle.id()).await {
+                Some(namespace) => {
+                    let room = room.as_deref().unwrap_or(DEFAULT_ROOM);
+                    observe(namespace, handle, room).await;
+                    greet(state, handle).await;
+                }
+                None => {
+                    let message = Message::system("Join a namespace before observing.");
+                    if let Err(e) = notify(&state.clients, handle, &message).await {
+                        error!("Failed to send message: {}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn refuse_outside_namespace(state: &AppState, handle: &Arc<ConnectionHandle<TcpStream>>) {
+    let message = Message::system("Join a namespace before chatting.");
+    if let Err(e) = notify(&state.clients, handle, &message).await {
+        error!("Failed to send message: {}", e);
+    }
+}
+
+// Moves a connection into a namespace and schedules its greeting. Unknown
+// namespaces get an error and a close.
+async fn enter_namespace(state: &AppState, handle: &Arc<ConnectionHandle<TcpStream>>, name: &str) {
+    if !state.namespaces.contains_key(name) {
+        let message = Message::new(
+            MessageType::Error {
+                code: ErrorCode::UnknownNamespace,
+                retry_after: None,
+            },
+            format!("Unknown namespace: {}", name),
+        );
+        if let Err(e) = notify(&state.clients, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+        close_with(&state.clients, handle.id(), CloseReason::UnknownNamespace).await;
+        return;
+    }
+
+    // A connection stays in the namespace it entered first
+    let current = {
+        let mut clients = state.clients.write().await;
+        let Some(client) = clients.get_mut(&handle.id()) else {
+            return;
+        };
+        match &client.namespace {
+            Some(current) => Some(current.clone()),
+            None => {
+                client.namespace = Some(name.to_string());
+                let _ = client.outbox.send(Outbound::Namespace(name.to_string()));
+                None
+            }
+        }
+    };
+    if let Some(current) = current {
+        let message = Message::system(format!("Already in namespace {}", current));
+        if let Err(e) = notify(&state.clients, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+        return;
+    }
+
+    // A room with a password can only be joined with /join, from the lobby
+    let locked = match state.namespaces.get(name) {
+        Some(namespace) => namespace
+            .room_settings
+            .read()
+            .await
+            .get(DEFAULT_ROOM)
+            .is_some_and(|settings| settings.password_hash.is_some()),
+        None => false,
+    };
+    if state.config.room_join == RoomJoin::Auto
+        && !locked
+        && !enter_room(&state.clients, handle, DEFAULT_ROOM).await
+    {
+        return;
+    }
+
+    // Control frames sent right after entering still shape the greeting
+    let (state, handle) = (state.clone(), Arc::clone(handle));
+    tokio::spawn(
+        async move {
+            tokio::time::sleep(GREETING_GRACE).await;
+            greet(&state, &handle).await;
+        }
+        .in_current_span(),
+    );
+}
+
+// Puts a connection in `room`, which callers have already checked exists.
+// Returns whether it got there. Broadcasts to the room are held back from
+// then until its history replay has gone out.
+async fn enter_room(
+    clients: &Clients,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    room: &'static str,
+) -> bool {
+    if let Err(e) = handle.join(room).await {
+        error!("Failed to join room: {}", e);
+        return false;
+    }
+    match clients.write().await.get_mut(&handle.id()) {
+        Some(client) => {
+            // Under the same lock as the room, so no broadcast gets in first
+            let _ = client.outbox.send(Outbound::HoldLive);
+            client.room = Some(room.to_string());
+            true
+        }
+        None => false,
+    }
+}
+
+// The room a connection is in, or None while it waits in the lobby
+async fn room_of(clients: &Clients, id: u64) -> Option<String> {
+    clients.read().await.get(&id)?.room.clone()
+}
+
+// Moves a connection out of the lobby into `room` and greets it there. A room
+// with a password only lets in those who give it after the room's name.
+async fn join_room(state: &NamespaceState, handle: &Arc<ConnectionHandle<TcpStream>>, arg: &str) {
+    let (room, password) = match arg.split_once(char::is_whitespace) {
+        Some((room, password)) => (room, Some(password.trim())),
+        None => (arg, None),
+    };
+    let refusal = if room.is_empty() {
+        Some("Usage: /join <room> [password]".to_string())
+    } else if let Some(current) = room_of(&state.clients, handle.id()).await {
+        Some(format!("Already in room {}", current))
+    } else if room != DEFAULT_ROOM {
+        Some(format!("No such room: {}", room))
+    } else {
+        None
+    };
+    if let Some(refusal) = refusal {
+        let message = Message::system(refusal);
+        if let Err(e) = send(state, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+        return;
+    }
+
+    let password_hash = {
+        let room_settings = state.room_settings.read().await;
+        room_settings
+            .get(room)
+            .and_then(|settings| settings.password_hash.clone())
+    };
+    let refusal = match (password_hash, password) {
+        (None, _) => None,
+        (Some(_), None) => Some((
+            ErrorCode::Unauthorized,
+            None,
+            format!("{} needs a password: /join {} <password>", room, room),
+        )),
+        (Some(hash), Some(password)) => {
+            let id = handle.id();
+            // Locked out connections aren't checked at all, so guessing costs
+            // the server nothing until the window moves on
+            if let Some(retry_after) = state.password_failures.retry_after(&id).await {
+                // Rounded up, so trying again when told is never too early
+                let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                Some((
+                    ErrorCode::TooManyAttempts,
+                    Some(secs),
+                    format!(
+                        "Too many wrong passwords for {}, try again in {}",
+                        room,
+                        util::format_duration(Duration::from_secs(secs))
+                    ),
+                ))
+            } else if util::verify_password(password.to_string(), hash).await {
+                None
+            } else {
+                let _ = state.password_failures.attempt(id).await;
+                Some((
+                    ErrorCode::Unauthorized,
+                    None,
+                    format!("Incorrect password for {}", room),
+                ))
+            }
+        }
+    };
+    if let Some((code, retry_after, refusal)) = refusal {
+        let message = Message::new(MessageType::Error { code, retry_after }, refusal);
+        if let Err(e) = send(state, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+        return;
+    }
+
+    if enter_room(&state.clients, handle, DEFAULT_ROOM).await {
+        greet_room(state, handle).await;
+    }
+}
+
+// Greets a connection once it has entered a namespace: with the room list in
+// the lobby, or with the room greeting when it has already joined one. Called
+// on the first frame after entering a namespace and once the grace period is
+// up, whichever is first; frames handled after this see the greeting already
+// sent. Returns the connection's namespace, if it has entered one.
+async fn greet<'a>(
+    app: &'a AppState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+) -> Option<&'a NamespaceState> {
+    let state = namespace_of(app, handle.id()).await?;
+    let greeted = {
+        let clients = app.clients.read().await;
+        Arc::clone(&clients.get(&handle.id())?.greeted)
+    };
+    let mut greeted = greeted.lock().await;
+    if *greeted {
+        return Some(state);
+    }
+    *greeted = true;
+
+    if room_of(&app.clients, handle.id()).await.is_none() {
+        commands::rooms(state, handle, "").await;
+        let message = Message::new(
+            MessageType::Welcome,
+            "Welcome! Pick a room with /join <room>",
+        );
+        if let Err(e) = send(state, handle, &message).await {
+            error!("Failed to send room prompt: {}", e);
+        }
+        return Some(state);
+    }
+    greet_room(state, handle).await;
+    Some(state)
+}
+
+// Sends the room's pinned messages, history and, unless the connection is
+// observing or already named, the name prompt
+async fn greet_room(state: &NamespaceState, handle: &Arc<ConnectionHandle<TcpStream>>) {
+    if let Some(message) = room_greeting(state).await
+        && let Err(e) = send(state, handle, &message).await
+    {
+        error!("Failed to send message: {}", e);
+    }
+    // Pinned messages go first so clients can keep them at the top
+    let pinned = match state.store.get_pinned(DEFAULT_ROOM).await {
+        Ok(pinned) => pinned,
+        Err(e) => {
+            error!("Failed to load pinned messages: {}", e);
+            Vec::new()
+        }
+    };
+    for message in &pinned {
+        let message = stored_message(MessageType::Pinned, message);
+        if let Err(e) = send(state, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+    }
+    // Bookmarks only go out when there are some, so clients without a
+    // sidebar see the same join as before
+    if let Some(message) = bookmark_list(state).await
+        && let MessageType::Bookmarks { bookmarks } = &message.message_type
+        && !bookmarks.is_empty()
+        && let Err(e) = send(state, handle, &message).await
+    {
+        error!("Failed to send message: {}", e);
+    }
+    let request = {
+        let clients = state.clients.read().await;
+        clients
+            .get(&handle.id())
+            .map(|client| client.history.clone())
+            .unwrap_or_default()
+    };
+    replay_history(state, handle, &request).await;
+
+    // Observers never get a name, and resumed sessions already have one
+    let user_id = handle.id().to_string();
+    if is_observer(state, &user_id).await || state.user_names.read().await.contains_key(&user_id) {
+        return;
+    }
+
+    // Ask for the user's name
+    let prompt = match state.config.guest_names {
+        GuestNames::Require => "Welcome! Please enter your name:",
+        GuestNames::Allow => {
+            "Welcome! Start chatting as a guest, or pick a name with /nick <name>:"
+        }
+        GuestNames::Auto => {
+            let name = assign_guest_name(state, &handle.id().to_string()).await;
+            welcome(state, handle, &name, true).await;
+            onboard(state, handle, &name).await;
+            return;
+        }
+    };
+    let message = Message::new(MessageType::Welcome, prompt);
+    if let Err(e) = send(state, handle, &message).await {
+        error!("Failed to send name prompt: {}", e);
+    }
+}
+
+// The room's join message as a RoomGreeting frame, if it has one
+async fn room_greeting(state: &NamespaceState) -> Option<Message> {
+    let room_settings = state.room_settings.read().await;
+    let text = room_settings.get(DEFAULT_ROOM)?.join_message.clone()?;
+    Some(Message::new(
+        MessageType::RoomGreeting {
+            room: DEFAULT_ROOM.to_string(),
+        },
+        text,
+    ))
+}
+
+// The room's bookmark list as a Bookmarks frame, or None when it can't be
+// loaded
+async fn bookmark_list(state: &NamespaceState) -> Option<Message> {
+    let bookmarks = match state.store.bookmarks(DEFAULT_ROOM).await {
+        Ok(bookmarks) => bookmarks,
+        Err(e) => {
+            error!("Failed to load bookmarks: {}", e);
+            return None;
+        }
+    };
+    let bookmarks = bookmarks
+        .into_iter()
+        .map(|bookmark| BookmarkInfo {
+            id: bookmark.id,
+            url: bookmark.url,
+            title: bookmark.title,
+            added_by: bookmark.added_by,
+            added_at: bookmark.added_at,
+        })
+        .collect();
+    Some(Message::new(MessageType::Bookmarks { bookmarks }, ""))
+}
+
+// Sends the part of the room's history `request` asks for, then lets
+// through the broadcasts held back since the connection entered the room.
+// Sent even when there is no history, so they are never held for good
+async fn replay_history(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    request: &HistoryRequest,
+) {
+    let mut replay = Vec::new();
+    match load_replay(state, request).await {
+        Ok(Some((messages, older))) => {
+            replay = history_frames(state, handle, &messages, older).await;
+        }
+        Ok(None) => {}
+        Err(e) => error!("Failed to load history: {}", e),
+    }
+    if let Err(e) = enqueue(&state.clients, handle.id(), Outbound::Replay(replay)).await {
+        error!("Failed to send message: {}", e);
+    }
+}
+
+// The messages `request` wants replayed, loading no more than the server's
+// maximum, and how many older ones it wanted past that. None when it wants
+// no replay at all
+async fn load_replay(
+    state: &NamespaceState,
+    request: &HistoryRequest,
+) -> Result<Option<(Vec<SavedMessage>, usize)>, ChatError> {
+    let since_known = match (request.mode, request.since_id) {
+        (HistoryMode::Since, Some(since_id)) => {
+            state.store.has_message(DEFAULT_ROOM, since_id).await?
+        }
+        _ => false,
+    };
+    let Some((filter, limit)) = history::plan(request, since_known) else {
+        return Ok(None);
+    };
+    let max_replay = usize::try_from(state.config.max_replay).unwrap_or(usize::MAX);
+    let (messages, total) = state
+        .store
+        .replay_messages(DEFAULT_ROOM, filter, limit.min(max_replay))
+        .await?;
+    let older = total.min(limit).saturating_sub(messages.len());
+    Ok(Some((messages, older)))
+}
+
+// Sends stored messages in one PastMessages frame, as history_frames does
+async fn send_history(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    messages: &[SavedMessage],
+) {
+    for message in history_frames(state, handle, messages, 0).await {
+        if let Err(e) = send(state, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+    }
+}
+
+// Stored messages as one PastMessages frame, grouped by day in the
+// connection's timezone. When `older` messages were left out before them,
+// or there are more than the configured maximum, a notice saying so follows
+async fn history_frames(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    messages: &[SavedMessage],
+    older: usize,
+) -> Vec<Message> {
+    let utc_offset = {
+        let clients = state.clients.read().await;
+        match clients.get(&handle.id()) {
+            Some(client) => client.utc_offset,
+            None => return Vec::new(),
+        }
+    };
+    let max_replay = usize::try_from(state.config.max_replay).unwrap_or(usize::MAX);
+    replay_messages(messages, utc_offset, max_replay, older)
+}
+
+// The frames history_frames sends for `messages`, for a client `utc_offset`
+// from UTC. Loads are capped already; the cap here catches callers that
+// hand over more than `max_replay`
+fn replay_messages(
+    messages: &[SavedMessage],
+    utc_offset: chrono::FixedOffset,
+    max_replay: usize,
+    older: usize,
+) -> Vec<Message> {
+    let capped = messages.len().saturating_sub(max_replay);
+    let messages = &messages[capped..];
+    let skipped = older + capped;
+
+    let mut previous: Option<&SavedMessage> = None;
+    let history = messages.iter().map(|message| {
+        let mut replayed = stored_message(MessageType::Chat, message);
+        replayed.continuation = history::continues(
+            previous.map(|previous| (previous.sender.as_str(), previous.sent_at)),
+            &message.sender,
+            message.sent_at,
+        );
+        previous = Some(message);
+        (message.sent_at, replayed)
+    });
+    let message = Message::new(
+        MessageType::PastMessages {
+            days: history::group_by_day(history, utc_offset),
+        },
+        "",
+    );
+    let mut frames = vec![message];
+
+    if skipped > 0 {
+        frames.push(Message::system(format!(
+            "History truncated to the newest {} messages; {} older ones were not sent",
+            max_replay, skipped
+        )));
+    }
+    frames
+}
+
+// Renders a stored chat message as `sender: text`
+fn stored_message(message_type: MessageType, message: &SavedMessage) -> Message {
+    Message {
+        message_type,
+        data: format!("{}: {}", message.sender, message.text),
+        id: Some(message.id),
+        expires_at: message.expires_at,
+        sent_at: Some(message.sent_at),
+        continuation: false,
+    }
+}
+
+// A client's claimed time, pulled back to within MAX_CLOCK_SKEW of `now`
+fn clamp_client_time(at: DateTime<Utc>, now: DateTime<Utc>) -> DateTime<Utc> {
+    at.clamp(now - MAX_CLOCK_SKEW, now + MAX_CLOCK_SKEW)
+}
+
+// The namespace the connection has entered, if any
+async fn namespace_of(state: &AppState, id: u64) -> Option<&NamespaceState> {
+    let clients = state.clients.read().await;
+    let name = clients.get(&id)?.namespace.as_ref()?;
+    state.namespaces.get(name)
+}
+
+async fn handle_text(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    text: &str,
+    arrival: Arrival,
+) {
+    // Names, topics and room names all arrive here too, so they are cleaned
+    // up alike
+    let Some(text) = sanitize::sanitize(text, state.config.bidi_controls) else {
+        let message = Message::new(
+            MessageType::Error {
+                code: ErrorCode::InvalidText,
+                retry_after: None,
+            },
+            "Text can't contain bidi override or isolate characters",
+        );
+        if let Err(e) = send(state, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+        return;
+    };
+    let text = text.as_ref();
+
+    // The lobby only offers looking around and picking a room, or answering an
+    // invitation to one
+    if room_of(&state.clients, handle.id()).await.is_none() {
+        match commands::parse(text) {
+            Some(Command::Rooms(args)) => commands::rooms(state, handle, args).await,
+            Some(Command::Join(room)) => join_room(state, handle, room).await,
+            Some(Command::Accept(room)) => commands::accept(state, handle, room).await,
+            Some(Command::Decline(room)) => commands::decline(state, handle, room).await,
+            _ => {
+                let message = Message::system("Join a room first with /join <room>");
+                if let Err(e) = send(state, handle, &message).await {
+                    error!("Failed to send message: {}", e);
+                }
+            }
+        }
+        return;
+    }
+
+    let user_id = handle.id().to_string();
+
+    if is_observer(state, &user_id).await {
+        let message = Message::new(
+            MessageType::Error {
+                code: ErrorCode::PermissionDenied,
+                retry_after: None,
+            },
+            "Observers cannot send messages",
+        );
+        if let Err(e) = send(state, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+        return;
+    }
+
+    // Check if user has set their name
+    let name = {
+        let names = state.user_names.read().await;
+        names.get(&user_id).cloned()
+    };
+
+    let name = match name {
+        Some(name) => name,
+        None => match state.config.guest_names {
+            GuestNames::Require => {
+                // First message is their name
+                set_name(state, handle, text).await;
+                return;
+            }
+            // Auto mode names everyone on greeting, so this is a safety net
+            GuestNames::Allow | GuestNames::Auto => {
+                if let Some(Command::Nick(name)) = commands::parse(text) {
+                    set_name(state, handle, name).await;
+                    return;
+                }
+
+                // Chatting before naming makes them a guest
+                let name = assign_guest_name(state, &user_id).await;
+                welcome(state, handle, &name, true).await;
+                onboard(state, handle, &name).await;
+                name
+            }
+        },
+    };
+
+    let expanded = {
+        let user_states = state.user_states.read().await;
+        user_states
+            .get(&user_id)
+            .and_then(|user| commands::expand_alias(&user.aliases, text))
+    };
+    let text = expanded.as_deref().unwrap_or(text);
+
+    if let Some(command) = commands::parse(text) {
+        commands::run(state, handle, &name, command).await;
+        return;
+    }
+
+    // Regular chat message - broadcast with their name
+    post_chat(state, handle, &name, text, None, Some(arrival)).await;
+}
+
+// Handles chat input sent as a `Send` frame, unless it resends one of the
+// user's recent sends, and acknowledges it either way. Only a `client_msg_id`
+// marks a resend; without one, the same text sent again is a new message.
+// The client's own clock is only echoed back, never used for ordering.
+async fn handle_send(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    text: &str,
+    client_msg_id: Option<String>,
+    client_sent_at: Option<DateTime<Utc>>,
+    arrival: Arrival,
+) {
+    // Recorded before handling, so a resend racing the original is caught too
+    let is_new = match &client_msg_id {
+        Some(id) => {
+            let mut hasher = DefaultHasher::new();
+            (text, id).hash(&mut hasher);
+            let mut recent_sends = state.recent_sends.write().await;
+            recent_sends
+                .entry(handle.id().to_string())
+                .or_default()
+                .insert(hasher.finish())
+        }
+        None => true,
+    };
+    if is_new {
+        handle_text(state, handle, text, arrival).await;
+    }
+
+    let now = Utc::now().trunc_subsecs(3);
+    let message = Message {
+        sent_at: Some(now),
+        ..Message::new(
+            MessageType::Ack {
+                client_msg_id,
+                client_sent_at: client_sent_at.map(|at| clamp_client_time(at, now)),
+            },
+            "",
+        )
+    };
+    if let Err(e) = send(state, handle, &message).await {
+        error!("Failed to send message: {}", e);
+    }
+}
+
+// Saves and delivers a chat message. Without an explicit `ttl` the room's
+// default lifetime, if any, applies. With --trace-messages, a message typed
+// straight into the chat is traced from its `arrival` to its last recipient.
+async fn post_chat(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    text: &str,
+    ttl: Option<Duration>,
+    arrival: Option<Arrival>,
+) {
+    let user_id = handle.id().to_string();
+    let mut trace = arrival
+        .filter(|_| state.config.trace_messages)
+        .map(|arrival| MessageTrace::start(arrival, &state.name, DEFAULT_ROOM, name, text.len()));
+
+    let (max_length, persist, no_links) = {
+        let room_settings = state.room_settings.read().await;
+        let settings = room_settings.get(DEFAULT_ROOM);
+        (
+            settings
+                .and_then(|settings| settings.max_length)
+                .or(state.config.max_message_length),
+            settings
+                .and_then(|settings| settings.persist)
+                .unwrap_or(true),
+            settings.is_some_and(|settings| settings.no_links),
+        )
+    };
+    // Expanded first, so the room's rules hold for what is stored and sent
+    let text = if state.config.expand_emoji {
+        emoji::expand_shortcodes(text)
+    } else {
+        Cow::Borrowed(text)
+    };
+    let text = shorthand::expand(&text);
+    let text = text.as_ref();
+
+    if let Some(max) = max_length
+        && text.chars().count() as u64 > max
+    {
+        let message = Message::new(
+            MessageType::Error {
+                code: ErrorCode::MessageTooLong,
+                retry_after: None,
+            },
+            format!(
+                "Messages in {} can be at most {} characters",
+                DEFAULT_ROOM, max
+            ),
+        );
+        if let Err(e) = send(state, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+        return;
+    }
+    if no_links && contains_link(text) {
+        let text = format!("Links are off in {}", DEFAULT_ROOM);
+        refuse_by_policy(state, handle, &text).await;
+        return;
+    }
+
+    if is_duplicate(state, &user_id, text).await {
+        let message = Message::system("Duplicate message suppressed");
+        if let Err(e) = send(state, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+        return;
+    }
+
+    // Only stored messages count, so only they can go over the quota
+    if persist
+        && !is_admin(state, &user_id).await
+        && let Err(exceeded) = state.message_quota.check(name, 1).await
+    {
+        let message = Message::new(
+            MessageType::Error {
+                code: ErrorCode::MessageQuotaExceeded,
+                retry_after: Some(exceeded.reset.as_secs()),
+            },
+            format!(
+                "You have used your quota of {} messages per 24 hours",
+                exceeded.limit
+            ),
+        );
+        if let Err(e) = send(state, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+        return;
+    }
+
+    let ttl = match ttl {
+        Some(ttl) => Some(ttl),
+        None => {
+            let room_settings = state.room_settings.read().await;
+            room_settings
+                .get(DEFAULT_ROOM)
+                .and_then(|settings| settings.ttl)
+        }
+    };
+    let expires_at = ttl.map(|ttl| {
+        let secs = i64::try_from(ttl.as_secs()).unwrap_or(i64::MAX);
+        chrono::Utc::now().timestamp().saturating_add(secs)
+    });
+
+    if let Some(trace) = &mut trace {
+        trace.filtered();
+    }
+
+    let Saved { id, sent_at } = if persist {
+        state
+            .saves
+            .save(DEFAULT_ROOM, name, text, expires_at, trace.as_mut())
+            .await
+    } else {
+        Saved {
+            id: None,
+            sent_at: Utc::now().trunc_subsecs(3),
+        }
+    };
+    record_activity(state, DEFAULT_ROOM).await;
+    let continuation = {
+        let mut last_senders = state.last_senders.write().await;
+        let previous = last_senders
+            .get(DEFAULT_ROOM)
+            .map(|(sender, sent_at)| (sender.as_str(), *sent_at));
+        let continuation = history::continues(previous, name, sent_at);
+        last_senders.insert(DEFAULT_ROOM.to_string(), (name.to_string(), sent_at));
+        continuation
+    };
+
+    if let Some(webhook) = &state.webhook {
+        webhook.deliver(ChatEvent {
+            namespace: state.name.clone(),
+            room: DEFAULT_ROOM.to_string(),
+            sender: name.to_string(),
+            text: text.to_string(),
+            timestamp: sent_at.to_rfc3339(),
+        });
+    }
+
+    let message = Message {
+        message_type: MessageType::Chat,
+        data: format!("{}: {}", name, text),
+        id,
+        expires_at,
+        sent_at: Some(sent_at),
+        continuation,
+    };
+
+    // Send to others with their name
+    let recipients = broadcast_chat(state, Some(handle.id()), name, text, &message).await;
+
+    // Echo back to sender with "Me:"
+    let message = Message {
+        message_type: MessageType::Chat,
+        data: format!("Me: {}", text),
+        id,
+        expires_at,
+        sent_at: Some(sent_at),
+        continuation,
+    };
+    if let Err(e) = send(state, handle, &message).await {
+        error!("Failed to echo message: {}", e);
+    }
+    if let Some(trace) = trace {
+        trace.delivered(recipients + 1);
+    }
+
+    if id.is_none() && persist {
+        let message = Message::system("Message may not be saved");
+        if let Err(e) = send(state, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+    }
+}
+
+async fn set_name(state: &NamespaceState, handle: &Arc<ConnectionHandle<TcpStream>>, name: &str) {
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        let message = Message::system("Name cannot be empty. Please enter your name:");
+        if let Err(e) = send(state, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+        return;
+    }
+
+    if !claim_name(state, handle, &name).await {
+        let message = Message::new(
+            MessageType::Error {
+                code: ErrorCode::NameTaken,
+                retry_after: None,
+            },
+            format!(
+                "The name {} is already taken. Please enter another name:",
+                name
+            ),
+        );
+        if let Err(e) = send(state, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+        return;
+    }
+
+    welcome(state, handle, &name, false).await;
+    onboard(state, handle, &name).await;
+}
+
+// Gives the connection `name` unless another user holds it, guests included,
+// carrying the rename cooldown over from earlier sessions
+async fn claim_name(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+) -> bool {
+    let user_id = handle.id().to_string();
+    if !reserve_name(&state.user_names, &user_id, name).await {
+        return false;
+    }
+
+    let (name_changed_at, returning) = match state.store.load_user(name).await {
+        Ok(user) => (user.name_changed_at, user.returning),
+        Err(e) => {
+            error!("Failed to load user: {}", e);
+            (0, false)
+        }
+    };
+    {
+        let mut user_states = state.user_states.write().await;
+        let user = user_states.entry(user_id).or_default();
+        user.name_changed_at = name_changed_at;
+        user.returning = returning;
+    }
+
+    match state.store.keywords(name).await {
+        Ok(keywords) => state.keywords.write().await.set(handle.id(), keywords),
+        Err(e) => error!("Failed to load keywords: {}", e),
+    }
+    match state.store.aliases(name).await {
+        Ok(aliases) => {
+            let mut user_states = state.user_states.write().await;
+            let user = user_states.entry(handle.id().to_string()).or_default();
+            user.aliases = aliases.into_iter().collect();
+        }
+        Err(e) => error!("Failed to load aliases: {}", e),
+    }
+    true
+}
+
+// Gives `user_id` the name unless another user holds it. The check and the
+// insert happen under one write lock, so two connections racing for a name
+// can't both get it.
+async fn reserve_name(names: &UserNames, user_id: &str, name: &str) -> bool {
+    let mut names = names.write().await;
+    if names
+        .iter()
+        .any(|(id, taken)| taken == name && id != user_id)
+    {
+        return false;
+    }
+    names.insert(user_id.to_string(), name.to_string());
+    true
+}
+
+// Sets up a connection's identity from its opening handshake. Returns the
+// name a resumed session restored, for the caller to welcome once the
+// greeting is out. A resume that fails carries on as a new session.
+async fn handle_handshake(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    handshake: Handshake,
+) -> Option<String> {
+    let user_id = handle.id().to_string();
+    if is_observer(state, &user_id).await || state.user_names.read().await.contains_key(&user_id) {
+        let message = Message::system("The handshake must come before picking a name");
+        if let Err(e) = send(state, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+        return None;
+    }
+
+    if let Handshake::Resume { token, .. } = handshake {
+        let name = state.sessions.read().await.get(&token).cloned();
+        let (code, problem) = match name {
+            None => (ErrorCode::UnknownSession, "Unknown session".to_string()),
+            Some(name) if claim_name(state, handle, &name).await => {
+                let mut user_states = state.user_states.write().await;
+                user_states.entry(user_id).or_default().session_token = Some(token);
+                return Some(name);
+            }
+            Some(name) => (
+                ErrorCode::NameTaken,
+                format!("{} is in use by another connection", name),
+            ),
+        };
+        let message = Message::new(
+            MessageType::Error {
+                code,
+                retry_after: None,
+            },
+            format!("{}, starting a new session", problem),
+        );
+        if let Err(e) = send(state, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+    }
+
+    // Everyone else starts a new session, bound to a name on welcome
+    let token = format!("{:032x}", rand::random::<u128>());
+    let mut user_states = state.user_states.write().await;
+    user_states.entry(user_id).or_default().session_token = Some(token);
+    None
+}
+
+// Switches a connection that hasn't picked a name into read-only observer mode
+async fn observe(state: &NamespaceState, handle: &Arc<ConnectionHandle<TcpStream>>, room: &str) {
+    let user_id = handle.id().to_string();
+    let config = &state.config;
+
+    let refusal = if config.disable_observers {
+        Some("Observer mode is disabled".to_string())
+    } else if room != DEFAULT_ROOM {
+        Some(format!("No such room: {}", room))
+    } else if !config.observer_rooms.is_empty() && !config.observer_rooms.iter().any(|r| r == room)
+    {
+        Some(format!("Room {} cannot be observed", room))
+    } else if state.user_names.read().await.contains_key(&user_id) {
+        Some("Observer mode must be chosen before picking a name".to_string())
+    } else {
+        None
+    };
+
+    if let Some(refusal) = refusal {
+        let message = Message::new(
+            MessageType::Error {
+                code: ErrorCode::PermissionDenied,
+                retry_after: None,
+            },
+            refusal,
+        );
+        if let Err(e) = send(state, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+        return;
+    }
+
+    {
+        let mut user_states = state.user_states.write().await;
+        user_states.entry(user_id).or_default().is_observer = true;
+    }
+
+    let message = Message::system(format!("Observing {} (read-only)", room));
+    if let Err(e) = send(state, handle, &message).await {
+        error!("Failed to send message: {}", e);
+    }
+}
+
+async fn is_admin(state: &NamespaceState, user_id: &str) -> bool {
+    let user_states = state.user_states.read().await;
+    user_states.get(user_id).is_some_and(|user| user.is_admin)
+}
+
+async fn is_observer(state: &NamespaceState, user_id: &str) -> bool {
+    let user_states = state.user_states.read().await;
+    user_states
+        .get(user_id)
+        .is_some_and(|user| user.is_observer)
+}
+
+// Picks a guest name no connected user has and stores it for the user. Each
+// attempt checks and claims its name under one lock, so two connections
+// can't be handed the same name
+async fn assign_guest_name(state: &NamespaceState, user_id: &str) -> String {
+    {
+        let mut user_states = state.user_states.write().await;
+        user_states.entry(user_id.to_string()).or_default().is_guest = true;
+    }
+
+    for _ in 0..guest_names::ATTEMPTS {
+        let name = guest_names::generate(&mut rand::rng());
+        let mut names = state.user_names.write().await;
+        if guest_names::is_free(&name, names.values()) {
+            names.insert(user_id.to_string(), name.clone());
+            return name;
+        }
+    }
+
+    let mut names = state.user_names.write().await;
+    let name = guest_names::fallback(user_id, names.values());
+    names.insert(user_id.to_string(), name.clone());
+    name
+}
+
+// Sends the greeting bot's onboarding text privately to a user who just got
+// their first name. Resumed sessions were onboarded when they started
+async fn onboard(state: &NamespaceState, handle: &Arc<ConnectionHandle<TcpStream>>, name: &str) {
+    let Some(greeting) = &state.greeting else {
+        return;
+    };
+    let message = Message::new(
+        MessageType::Direct {
+            sender: GREETING_BOT.to_string(),
+        },
+        greeting.replace("{name}", name),
+    );
+    if let Err(e) = send(state, handle, &message).await {
+        error!("Failed to send message: {}", e);
+    }
+}
+
+// Welcomes the user under `name` and announces them, with the welcome-back
+// template when the store already knew the name. Guests were named by the
+// server and are told how to pick a name of their own. Connections that opened
+// with a handshake get their session token, now bound to `name`.
+async fn welcome(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+    guest: bool,
+) {
+    let message = if guest {
+        Message::new(
+            MessageType::GuestWelcome {
+                name: name.to_string(),
+            },
+            format!(
+                "Welcome, {}! You can start chatting now, or pick a name with /nick <name>.",
+                name
+            ),
+        )
+    } else {
+        let returning = {
+            let user_states = state.user_states.read().await;
+            user_states
+                .get(&handle.id().to_string())
+                .is_some_and(|user| user.returning)
+        };
+        let template = if returning {
+            &state.config.welcome_back_template
+        } else {
+            &state.config.welcome_template
+        };
+        Message::new(MessageType::Welcome, template.replace("{name}", name))
+    };
+    if let Err(e) = send(state, handle, &message).await {
+        error!("Failed to send message: {}", e);
+    }
+
+    let token = {
+        let user_states = state.user_states.read().await;
+        user_states
+            .get(&handle.id().to_string())
+            .and_then(|user| user.session_token.clone())
+    };
+    if let Some(token) = token {
+        state
+            .sessions
+            .write()
+            .await
+            .insert(token.clone(), name.to_string());
+        let message = Message::new(MessageType::Session { token }, "");
+        if let Err(e) = send(state, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+    }
+
+    let motd = state.motd.read().await.clone();
+    if !motd.is_empty() {
+        let message = Message::system(motd);
+        if let Err(e) = send(state, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+    }
+
+    room_event(state, DEFAULT_ROOM, "join", name, "").await;
+
+    // Announce to others
+    let message = Message::system(format!("{} joined the chat!", name));
+    broadcast(state, Some(handle.id()), &message).await;
+}
+
+// Opens a file transfer for a named user, once its declaration checks out
+async fn begin_transfer(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    filename: &str,
+    size: u64,
+    chunks: u32,
+    to: Option<String>,
+) {
+    let user_id = handle.id().to_string();
+    if !state.user_names.read().await.contains_key(&user_id) {
+        transfer_failed(state, handle, "Pick a name before sending files").await;
+        return;
+    }
+    if room_of(&state.clients, handle.id()).await.is_none() {
+        transfer_failed(state, handle, "Join a room before sending files").await;
+        return;
+    }
+    // Files sent to one user never show up in the room
+    if to.is_none()
+        && state
+            .room_settings
+            .read()
+            .await
+            .get(DEFAULT_ROOM)
+            .is_some_and(|settings| settings.no_uploads)
+    {
+        let text = format!("Uploads are off in {}", DEFAULT_ROOM);
+        refuse_by_policy(state, handle, &text).await;
+        return;
+    }
+    if let Some(to) = &to
+        && recipients(state, handle.id(), Some(to)).await.is_empty()
+    {
+        let text = format!("{} is not online", to);
+        transfer_failed(state, handle, &text).await;
+        return;
+    }
+
+    let transfer = match Transfer::begin(filename, size, chunks, to, state.config.max_transfer_size)
+    {
+        Ok(transfer) => transfer,
+        Err(e) => {
+            let text = format!("Cannot send {}: {}", filename, e);
+            transfer_failed(state, handle, &text).await;
+            return;
+        }
+    };
+    if !is_admin(state, &user_id).await
+        && let Some(name) = state.user_names.read().await.get(&user_id).cloned()
+        && let Err(exceeded) = state.upload_quota.check(&name, size).await
+    {
+        let message = Message::new(
+            MessageType::Error {
+                code: ErrorCode::UploadQuotaExceeded,
+                retry_after: Some(exceeded.reset.as_secs()),
+            },
+            format!(
+                "Cannot send {}: you may send {} bytes of files per 24 hours",
+                filename, exceeded.limit
+            ),
+        );
+        if let Err(e) = send(state, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+        return;
+    }
+    {
+        let mut transfers = state.transfers.lock().await;
+        if let Some(open) = transfers.get(&handle.id()) {
+            let text = format!("Finish sending {} first", open.filename);
+            drop(transfers);
+            transfer_failed(state, handle, &text).await;
+            return;
+        }
+        transfers.insert(handle.id(), transfer);
+    }
+    spawn_transfer_watch(state.clone(), handle.clone());
+
+    let message = Message::system(format!("Ready for the {} chunks of {}", chunks, filename));
+    if let Err(e) = send(state, handle, &message).await {
+        error!("Failed to send message: {}", e);
+    }
+}
+
+// Feeds a binary frame to the connection's open transfer, relaying the file
+// once it is complete. False when there is no transfer to take the frame.
+async fn receive_chunk(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    frame: &[u8],
+) -> bool {
+    let mut transfers = state.transfers.lock().await;
+    let Some(transfer) = transfers.get_mut(&handle.id()) else {
+        return false;
+    };
+    match transfer.push(frame) {
+        Ok(None) => {}
+        Ok(Some(contents)) => {
+            let transfer = transfers.remove(&handle.id()).unwrap();
+            drop(transfers);
+            relay_file(state, handle, transfer, contents).await;
+        }
+        Err(e) => {
+            let transfer = transfers.remove(&handle.id()).unwrap();
+            drop(transfers);
+            let text = format!("Sending {} failed: {}", transfer.filename, e);
+            transfer_failed(state, handle, &text).await;
+        }
+    }
+    true
+}
+
+// Sends a finished file to everyone it was for, then tells the sender
+async fn relay_file(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    transfer: Transfer,
+    contents: Vec<u8>,
+) {
+    let sender = {
+        let names = state.user_names.read().await;
+        names.get(&handle.id().to_string()).cloned()
+    };
+    let Some(sender) = sender else {
+        return;
+    };
+    let message = Message::new(
+        MessageType::File {
+            sender: sender.clone(),
+            filename: transfer.filename.clone(),
+            size: transfer.size,
+            to: transfer.to.clone(),
+        },
+        "",
+    );
+    for id in recipients(state, handle.id(), transfer.to.as_deref()).await {
+        let file = Outbound::File(message.clone(), contents.clone());
+        if let Err(e) = enqueue(&state.clients, id, file).await {
+            error!("Failed to relay file: {}", e);
+        }
+    }
+
+    // Counted once relayed, like a message once stored
+    let sent_at = Utc::now().trunc_subsecs(3);
+    match state
+        .store
+        .record_upload(&sender, transfer.size, sent_at)
+        .await
+    {
+        Ok(()) => {
+            state
+                .upload_quota
+                .record(&sender, sent_at, transfer.size)
+                .await
+        }
+        Err(e) => error!("Failed to record the upload of {}: {}", sender, e),
+    }
+
+    let target = transfer.to.as_deref().unwrap_or(DEFAULT_ROOM);
+    let message = Message::system(format!(
+        "Sent {} ({} bytes) to {}",
+        transfer.filename, transfer.size, target
+    ));
+    if let Err(e) = send(state, handle, &message).await {
+        error!("Failed to send message: {}", e);
+    }
+}
+
+// Connections in the room other than `sender`, or only the ones named `to`
+async fn recipients(state: &NamespaceState, sender: u64, to: Option<&str>) -> Vec<u64> {
+    let in_room: Vec<u64> = state
+        .clients
+        .read()
+        .await
+        .values()
+        .filter(|client| client.namespace.as_deref() == Some(state.name.as_str()))
+        .filter(|client| client.room.as_deref() == Some(DEFAULT_ROOM))
+        .map(|client| client.handle.id())
+        .filter(|id| *id != sender)
+        .collect();
+    let Some(to) = to else {
+        return in_room;
+    };
+    let names = state.user_names.read().await;
+    in_room
+        .into_iter()
+        .filter(|id| names.get(&id.to_string()).is_some_and(|name| name == to))
+        .collect()
+}
+
+// Abandons the connection's transfer once it goes too long without a chunk
+fn spawn_transfer_watch(state: NamespaceState, handle: Arc<ConnectionHandle<TcpStream>>) {
+    let stall = Duration::from_secs(state.config.transfer_stall_secs);
+    tokio::spawn(async move {
+        loop {
+            let deadline = match state.transfers.lock().await.get(&handle.id()) {
+                Some(transfer) => transfer.last_chunk + stall,
+                None => return,
+            };
+            tokio::time::sleep_until(deadline).await;
+
+            let mut transfers = state.transfers.lock().await;
+            if transfers
+                .get(&handle.id())
+                .is_some_and(|transfer| transfer.last_chunk.elapsed() >= stall)
+            {
+                let transfer = transfers.remove(&handle.id()).unwrap();
+                drop(transfers);
+                let text = format!(
+                    "Sending {} failed: no chunk for {}s",
+                    transfer.filename,
+                    stall.as_secs()
+                );
+                transfer_failed(&state, &handle, &text).await;
+                return;
+            }
+        }
+    });
+}
+
+// Tells the sender their room's settings don't allow what they sent
+async fn refuse_by_policy(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    text: &str,
+) {
+    let message = Message::new(
+        MessageType::Error {
+            code: ErrorCode::RoomPolicy,
+            retry_after: None,
+        },
+        text,
+    );
+    if let Err(e) = send(state, handle, &message).await {
+        error!("Failed to send message: {}", e);
+    }
+}
+
+// Whether any word of the text looks like a web link, leading punctuation
+// such as an opening parenthesis aside
+fn contains_link(text: &str) -> bool {
+    text.split_whitespace().any(|word| {
+        let word = word
+            .trim_start_matches(|c: char| !c.is_alphanumeric())
+            .to_ascii_lowercase();
+        ["http://", "https://", "www."]
+            .iter()
+            .any(|prefix| word.starts_with(prefix))
+    })
+}
+
+async fn transfer_failed(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    text: &str,
+) {
+    let message = Message::new(
+        MessageType::Error {
+            code: ErrorCode::TransferFailed,
+            retry_after: None,
+        },
+        text,
+    );
+    if let Err(e) = send(state, handle, &message).await {
+        error!("Failed to send message: {}", e);
+    }
+}
+
+// Records the message and reports whether it repeats the user's last one too often
+async fn is_duplicate(state: &NamespaceState, user_id: &str, text: &str) -> bool {
+    let mut last_messages = state.last_messages.write().await;
+
+    match last_messages.get_mut(user_id) {
+        Some(last) if last.text == text && last.first_sent.elapsed() < DUPLICATE_WINDOW => {
+            last.repeats += 1;
+            last.repeats > DUPLICATE_LIMIT
+        }
+        _ => {
+            last_messages.insert(
+                user_id.to_string(),
+                LastMessage {
+                    text: text.to_string(),
+                    repeats: 1,
+                    first_sent: Instant::now(),
+                },
+            );
+            false
+        }
+    }
+}
+
+async fn record_activity(state: &NamespaceState, room: &str) {
+    let mut activity = state.room_activity.write().await;
+    activity
+        .entry(room.to_string())
+        .or_default()
+        .push_back(Instant::now());
+}
+
+// Drops activity entries that have aged out of the window, once a second
+fn spawn_activity_pruner(room_activity: RoomActivity) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let mut activity = room_activity.write().await;
+            for sent in activity.values_mut() {
+                while sent
+                    .front()
+                    .is_some_and(|at| at.elapsed() > ACTIVITY_WINDOW)
+                {
+                    sent.pop_front();
+                }
+            }
+            activity.retain(|_, sent| !sent.is_empty());
+        }
+    });
+}
+
+// Clears the per-room message counts every hour
+fn spawn_hourly_reset(hourly_messages: HourlyMessages) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(HOURLY_RESET);
+        // The first tick is immediate, and there is nothing to clear yet
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            hourly_messages.write().await.clear();
+        }
+    });
+}
+
+// Deletes disappearing messages once their time is up
+fn spawn_expiry_pruner(store: Store) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EXPIRY_SWEEP);
+        loop {
+            interval.tick().await;
+            if let Err(e) = store.delete_expired(chrono::Utc::now().timestamp()).await {
+                error!("Failed to delete expired messages: {}", e);
+            }
+        }
+    });
+}
+
+// Warns when message saves fall behind the configured thresholds, and again
+// once they catch up
+fn spawn_persistence_watch(state: NamespaceState) {
+    tokio::spawn(async move {
+        let mut monitor = LagMonitor::new(
+            state.config.persistence_lag_depth as usize,
+            Duration::from_secs(state.config.persistence_lag_secs),
+        );
+        let mut interval = tokio::time::interval(LAG_CHECK);
+        loop {
+            interval.tick().await;
+            let lag = state.saves.lag();
+            let text = match monitor.observe(lag) {
+                Some(LagChange::Degraded) => {
+                    warn!(
+                        "Namespace {} has {} unsaved messages, the oldest waiting {}s",
+                        state.name,
+                        lag.depth,
+                        lag.oldest.as_secs()
+                    );
+                    format!("Message persistence delayed by {}s", lag.oldest.as_secs())
+                }
+                Some(LagChange::Recovered) => {
+                    info!("Namespace {} is saving messages on time again", state.name);
+                    "Message persistence has caught up".to_string()
+                }
+                None => continue,
+            };
+            if state.config.persistence_notices {
+                notify_admins(&state, &text).await;
+            }
+        }
+    });
+}
+
+// Sends a System notice to every admin in the namespace
+async fn notify_admins(state: &NamespaceState, text: &str) {
+    let admins: Vec<u64> = state
+        .user_states
+        .read()
+        .await
+        .iter()
+        .filter(|(_, user)| user.is_admin)
+        .filter_map(|(user_id, _)| user_id.parse().ok())
+        .collect();
+    let message = Message::system(text);
+    for id in admins {
+        if let Err(e) = enqueue(&state.clients, id, Outbound::Message(message.clone())).await {
+            error!("Failed to notify admin: {}", e);
+        }
+    }
+}
+
+// Disconnects connections that have sent no chat input or commands for
+// --idle-timeout, checking once a minute or, for shorter timeouts, as often
+// as the timeout. Observers never send anything, so they are left alone
+fn spawn_idle_sweep(state: AppState) {
+    tokio::spawn(async move {
+        let timeout = Duration::from_secs(state.config.idle_timeout);
+        let mut interval = tokio::time::interval(IDLE_SWEEP.min(timeout));
+        loop {
+            interval.tick().await;
+            let idle: Vec<(u64, Option<String>)> = state
+                .clients
+                .read()
+                .await
+                .iter()
+                .filter(|(_, client)| client.last_activity.elapsed() > timeout)
+                .map(|(id, client)| (*id, client.namespace.clone()))
+                .collect();
+            for (id, namespace) in idle {
+                if let Some(namespace) = namespace.and_then(|name| state.namespaces.get(&name))
+                    && is_observer(namespace, &id.to_string()).await
+                {
+                    continue;
+                }
+                close_with(&state.clients, id, CloseReason::Idle).await;
+            }
+        }
+    });
+}
+
+// Disconnects connections that still have no name --naming-timeout after
+// opening, whether they never entered a namespace or never answered the
+// name prompt. Once named, a connection is never checked again. Observers
+// never get a name, so they are left alone
+fn spawn_naming_sweep(state: AppState) {
+    tokio::spawn(async move {
+        let timeout = Duration::from_secs(state.config.naming_timeout);
+        let mut interval = tokio::time::interval(IDLE_SWEEP.min(timeout));
+        loop {
+            interval.tick().await;
+            let overdue: Vec<(u64, Option<String>)> = state
+                .clients
+                .read()
+                .await
+                .iter()
+                .filter(|(_, client)| client.connected_at.elapsed() > timeout)
+                .map(|(id, client)| (*id, client.namespace.clone()))
+                .collect();
+            for (id, namespace) in overdue {
+                if let Some(namespace) = namespace.and_then(|name| state.namespaces.get(&name)) {
+                    let user_id = id.to_string();
+                    if namespace.user_names.read().await.contains_key(&user_id)
+                        || is_observer(namespace, &user_id).await
+                    {
+                        continue;
+                    }
+                }
+                close_with(&state.clients, id, CloseReason::Unnamed).await;
+            }
+        }
+    });
+}
+
+// Checkpoints the store's WAL so steady writes can't grow it without bound
+fn spawn_wal_checkpointer(store: Store, every: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        // The first tick completes immediately; nothing has been written yet
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match store.checkpoint().await {
+                Ok(Some(checkpoint)) if checkpoint.busy => warn!(
+                    "WAL checkpoint was blocked, copied {} of {} pages",
+                    checkpoint.checkpointed_pages, checkpoint.wal_pages
+                ),
+                Ok(Some(checkpoint)) => info!(
+                    "WAL checkpoint copied {} pages",
+                    checkpoint.checkpointed_pages
+                ),
+                Ok(None) => {}
+                Err(e) => error!("Failed to checkpoint the WAL: {}", e),
+            }
+        }
+    });
+}
+
+// Switches the connection to the subprotocol it asked for. A connection
+// offering none the server speaks is refused and closed, like one asking for
+// an unknown namespace. Returns whether it was accepted
+async fn negotiate_protocol(
+    state: &AppState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    offered: &str,
+) -> bool {
+    let Some(protocol) = Protocol::negotiate(offered) else {
+        let message = Message::new(
+            MessageType::Error {
+                code: ErrorCode::UnsupportedProtocol,
+                retry_after: None,
+            },
+            format!(
+                "Unsupported subprotocol: {}. This server speaks {} and {}",
+                offered, JSON_SUBPROTOCOL, MSGPACK_SUBPROTOCOL
+            ),
+        );
+        if let Err(e) = notify(&state.clients, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+        close_with(
+            &state.clients,
+            handle.id(),
+            CloseReason::UnsupportedProtocol,
+        )
+        .await;
+        return false;
+    };
+
+    {
+        let mut clients = state.clients.write().await;
+        if let Some(client) = clients.get_mut(&handle.id()) {
+            client.protocol = protocol;
+            // Queued ahead of the confirmation so it goes out in the new encoding
+            let _ = client.outbox.send(Outbound::SetProtocol(protocol));
+        }
+    }
+    let message = Message::system(format!("Using subprotocol {}", protocol.subprotocol()));
+    if let Err(e) = notify(&state.clients, handle, &message).await {
+        error!("Failed to send message: {}", e);
+    }
+    true
+}
+
+async fn protocol_of(state: &AppState, id: u64) -> Protocol {
+    let clients = state.clients.read().await;
+    clients
+        .get(&id)
+        .map(|client| client.protocol)
+        .unwrap_or_default()
+}
+
+// Queues a message on the connection's sender task
+async fn send(
+    state: &NamespaceState,
+    handle: &ConnectionHandle<TcpStream>,
+    message: &Message,
+) -> Result<(), ChatError> {
+    notify(&state.clients, handle, message).await
+}
+
+// Like `send`, for connections that may not have entered a namespace yet
+async fn notify(
+    clients: &Clients,
+    handle: &ConnectionHandle<TcpStream>,
+    message: &Message,
+) -> Result<(), ChatError> {
+    enqueue(clients, handle.id(), Outbound::Message(message.clone())).await
+}
+
+// The one place a failed request is handled: logged with `context` and, when
+// the connection can still be reached, answered with an Error frame
+async fn report(
+    clients: &Clients,
+    handle: &ConnectionHandle<TcpStream>,
+    context: &str,
+    error: ChatError,
+) {
+    error!("{}: {}", context, error);
+    if error.reaches_client()
+        && let Err(e) = notify(clients, handle, &error.to_message()).await
+    {
+        error!("Failed to send message: {}", e);
+    }
+}
+
+async fn enqueue(clients: &Clients, id: u64, outbound: Outbound) -> Result<(), ChatError> {
+    let clients = clients.read().await;
+    let client = clients
+        .get(&id)
+        .ok_or(ChatError::Send("connection is not registered"))?;
+    if client.closed.load(Ordering::Relaxed) {
+        metrics::record_frame_dropped_after_close(client.metrics_namespace());
+        return Ok(());
+    }
+    client
+        .outbox
+        .send(outbound)
+        .map_err(|_| ChatError::Send("connection sender has stopped"))?;
+    Ok(())
+}
+
+// Sends a message to every client in the namespace except `skip`, on this
+// node and, when clustered, on every other node. Returns how many clients on
+// this node it was queued for
+async fn broadcast(state: &NamespaceState, skip: Option<u64>, message: &Message) -> usize {
+    // Anything else shown in the room breaks up a run of chat messages
+    if !matches!(message.message_type, MessageType::Chat) {
+        state.last_senders.write().await.remove(DEFAULT_ROOM);
+    }
+    let recipients = deliver(state, skip, message).await;
+    if let Some(cluster) = &state.cluster {
+        cluster.publish(&state.name, DEFAULT_ROOM, message);
+    }
+    recipients
+}
+
+// Broadcasts a chat message and copies it to the admins tailing the
+// namespace. Returns how many clients on this node it was queued for
+async fn broadcast_chat(
+    state: &NamespaceState,
+    skip: Option<u64>,
+    sender: &str,
+    text: &str,
+    message: &Message,
+) -> usize {
+    let recipients = broadcast(state, skip, message).await;
+    alert_keywords(state, skip, sender, text, message).await;
+    *state
+        .hourly_messages
+        .write()
+        .await
+        .entry(DEFAULT_ROOM.to_string())
+        .or_default() += 1;
+
+    let tail = Message::new(
+        MessageType::AdminTail {
+            room: DEFAULT_ROOM.to_string(),
+            sender: sender.to_string(),
+            text: text.to_string(),
+            id: message.id,
+        },
+        "",
+    );
+
+    let mut fallen_behind = Vec::new();
+    {
+        let tailers = state.tailers.read().await;
+        if tailers.is_empty() {
+            return recipients;
+        }
+        let clients = state.clients.read().await;
+        for id in tailers.iter() {
+            let Some(client) = clients.get(id) else {
+                continue;
+            };
+            if client.outbox.queued() > TAIL_MAX_BACKLOG {
+                fallen_behind.push(*id);
+                continue;
+            }
+            if !client.closed.load(Ordering::Relaxed) {
+                let _ = client.outbox.send(Outbound::Message(tail.clone()));
+            }
+        }
+    }
+
+    // A tail must never pile up frames for an admin who can't keep up
+    if !fallen_behind.is_empty() {
+        let mut tailers = state.tailers.write().await;
+        for id in &fallen_behind {
+            tailers.remove(id);
+        }
+        drop(tailers);
+
+        let message = Message::system("Live tail stopped because you are falling behind");
+        for id in fallen_behind {
+            let _ = enqueue(&state.clients, id, Outbound::Message(message.clone())).await;
+        }
+    }
+    recipients
+}
+
+// Sends a KeywordAlert to everyone in the room whose /notify keywords the
+// chat message mentions, except its sender
+async fn alert_keywords(
+    state: &NamespaceState,
+    skip: Option<u64>,
+    sender: &str,
+    text: &str,
+    message: &Message,
+) {
+    let mut alerts: Vec<(u64, String)> = {
+        let keywords = state.keywords.read().await;
+        keywords
+            .alerts(text)
+            .into_iter()
+            .filter(|(id, _)| Some(*id) != skip)
+            .map(|(id, keyword)| (id, keyword.to_string()))
+            .collect()
+    };
+    if alerts.is_empty() {
+        return;
+    }
+    // Nor is anyone else chatting under the sender's name
+    {
+        let names = state.user_names.read().await;
+        alerts.retain(|(id, _)| names.get(&id.to_string()).map(String::as_str) != Some(sender));
+    }
+
+    let clients = state.clients.read().await;
+    for (id, keyword) in alerts {
+        let Some(client) = clients.get(&id) else {
+            continue;
+        };
+        if client.room.as_deref() != Some(DEFAULT_ROOM) || client.closed.load(Ordering::Relaxed) {
+            continue;
+        }
+        let data = format!("{} mentioned {}", sender, keyword);
+        let alert = Message::new(
+            MessageType::KeywordAlert {
+                keyword,
+                message: Box::new(message.clone()),
+            },
+            data,
+        );
+        let _ = client.outbox.send(Outbound::Message(alert));
+    }
+}
+
+// Records a join, leave or moderation action in the room's event log, for
+// /events. Failing to record it doesn't undo what happened
+async fn room_event(
+    state: &NamespaceState,
+    room: &str,
+    event_type: &str,
+    actor: &str,
+    target: &str,
+) {
+    if let Err(e) = state
+        .store
+        .record_room_event(room, event_type, actor, target)
+        .await
+    {
+        error!(
+            "Failed to record {} in the events of {}: {}",
+            event_type, room, e
+        );
+    }
+}
+
+// The namespace's connection on this node going by `name`
+async fn connection_named(state: &NamespaceState, name: &str) -> Option<u64> {
+    let names = state.user_names.read().await;
+    names
+        .iter()
+        .find(|(_, named)| named.as_str() == name)
+        .and_then(|(id, _)| id.parse().ok())
+}
+
+// Sends a direct message to the namespace's connection named `to` on this
+// node. Returns whether it was there to get it
+async fn deliver_direct(state: &NamespaceState, to: &str, message: &Message) -> bool {
+    let Some(id) = connection_named(state, to).await else {
+        return false;
+    };
+    enqueue(&state.clients, id, Outbound::Message(message.clone()))
+        .await
+        .is_ok()
+}
+
+// Sends a resumed session the direct messages held for it while it was
+// offline, oldest first. Only a resume delivers them: anyone can pick a free
+// name, but only the session's owner holds its token
+async fn deliver_notifications(
+    state: &NamespaceState,
+    handle: &Arc<ConnectionHandle<TcpStream>>,
+    name: &str,
+) {
+    let notifications = match state.store.take_notifications(name).await {
+        Ok(notifications) => notifications,
+        Err(e) => {
+            error!("Failed to load notifications: {}", e);
+            return;
+        }
+    };
+    for notification in notifications {
+        let message = Message {
+            sent_at: Some(notification.sent_at),
+            ..Message::new(
+                MessageType::Direct {
+                    sender: notification.sender,
+                },
+                notification.text,
+            )
+        };
+        if let Err(e) = send(state, handle, &message).await {
+            error!("Failed to send message: {}", e);
+        }
+    }
+}
+
+// Sends a message to this node's clients in the namespace except `skip`,
+// returning how many it was queued for
+async fn deliver(state: &NamespaceState, skip: Option<u64>, message: &Message) -> usize {
+    let clients = state.clients.read().await;
+    let mut recipients = 0;
+    for client in clients.values() {
+        if client.namespace.as_deref() != Some(state.name.as_str()) {
+            continue;
+        }
+        // Connections still in the lobby aren't in the room yet
+        if client.room.as_deref() != Some(DEFAULT_ROOM) {
+            continue;
+        }
+        if Some(client.handle.id()) == skip {
+            continue;
+        }
+        if client.quiet && matches!(message.message_type, MessageType::System) {
+            continue;
+        }
+        if client.closed.load(Ordering::Relaxed) {
+            metrics::record_frame_dropped_after_close(&state.name);
+            continue;
+        }
+        if client.outbox.send(Outbound::Live(message.clone())).is_err() {
+            error!("Failed to broadcast message: connection sender has stopped");
+            continue;
+        }
+        recipients += 1;
+    }
+    recipients
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn namespace() -> NamespaceState {
+        let config = ServerConfig::try_parse_from(["backend"]).unwrap();
+        let shared = Shared {
+            config: Arc::new(config),
+            clients: Arc::new(RwLock::new(HashMap::new())),
+            motd: Arc::new(RwLock::new(String::new())),
+            drain: Arc::new(RwLock::new(None)),
+            started: Instant::now(),
+            greeting: None,
+            webhook: None,
+            cluster: None,
+        };
+        NamespaceState::new(
+            config::DEFAULT_NAMESPACE.to_string(),
+            Store::new(db::MEMORY_DATABASE_URL.to_string()),
+            shared,
+        )
+    }
+
+    #[tokio::test]
+    async fn forgetting_a_user_empties_every_per_user_map() {
+        let state = namespace();
+        let user_id = "7";
+        state
+            .user_names
+            .write()
+            .await
+            .insert(user_id.to_string(), "alice".to_string());
+        state
+            .user_states
+            .write()
+            .await
+            .insert(user_id.to_string(), UserState::default());
+        assert!(!is_duplicate(&state, user_id, "hello").await);
+
+        state.tailers.write().await.insert(7);
+        state
+            .recent_sends
+            .write()
+            .await
+            .entry(user_id.to_string())
+            .or_default()
+            .insert(1);
+
+        assert_eq!(state.forget_user(7).await.as_deref(), Some("alice"));
+        assert!(state.user_names.read().await.is_empty());
+        assert!(state.user_states.read().await.is_empty());
+        assert!(state.last_messages.read().await.is_empty());
+        assert!(state.tailers.read().await.is_empty());
+        assert!(state.recent_sends.read().await.is_empty());
+
+        // Forgetting twice is harmless
+        assert_eq!(state.forget_user(7).await, None);
+    }
+
+    #[tokio::test]
+    async fn handed_off_state_survives_a_restart() {
+        let before = namespace();
+        before
+            .sessions
+            .write()
+            .await
+            .insert("token".to_string(), "alice".to_string());
+        let settings = RoomSettings {
+            ttl: Some(Duration::from_secs(3600)),
+            topic: Some("Release day".to_string()),
+            topic_locked: true,
+            join_message: Some("Be nice".to_string()),
+            max_length: Some(500),
+            persist: Some(false),
+            no_links: true,
+            no_uploads: false,
+            password_hash: Some(util::hash_password("hunter2".to_string()).await.unwrap()),
+        };
+        before
+            .room_settings
+            .write()
+            .await
+            .insert(DEFAULT_ROOM.to_string(), settings.clone());
+        // Connection state isn't handed off
+        before
+            .user_names
+            .write()
+            .await
+            .insert("7".to_string(), "alice".to_string());
+
+        let path = std::env::temp_dir().join(format!("handoff-{}.json", std::process::id()));
+        let namespaces = HashMap::from([(before.name.clone(), before.handoff().await)]);
+        handoff::save(&path, namespaces).await.unwrap();
+        let mut loaded = handoff::load(&path, TimeDelta::minutes(5)).await;
+        let _ = std::fs::remove_file(&path);
+
+        let after = namespace();
+        after.restore(loaded.remove(&after.name).unwrap()).await;
+        assert_eq!(after.sessions.read().await["token"], "alice");
+        assert_eq!(after.room_settings.read().await[DEFAULT_ROOM], settings);
+        // The room stays locked with the same password
+        let hash = after.room_settings.read().await[DEFAULT_ROOM]
+            .password_hash
+            .clone()
+            .unwrap();
+        assert!(util::verify_password("hunter2".to_string(), hash).await);
+        assert!(after.user_names.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn racing_connections_cannot_both_reserve_a_name() {
+        for _ in 0..200 {
+            let state = namespace();
+            let racers: Vec<_> = ["1", "2"]
+                .into_iter()
+                .map(|user_id| {
+                    let names = state.user_names.clone();
+                    tokio::spawn(async move { reserve_name(&names, user_id, "alice").await })
+                })
+                .collect();
+            let mut won = 0;
+            for racer in racers {
+                if racer.await.unwrap() {
+                    won += 1;
+                }
+            }
+            assert_eq!(won, 1);
+            assert_eq!(state.user_names.read().await.len(), 1);
+        }
+    }
+
+    #[test]
+    fn links_are_found_wherever_they_start() {
+        assert!(contains_link("see https://example.com"));
+        assert!(contains_link("(HTTP://example.com)"));
+        assert!(contains_link("at www.example.com."));
+        assert!(!contains_link("the www is down, check example.com"));
+    }
+
+    #[test]
+    fn client_times_clamp_to_five_minutes_of_server_time() {
+        let now: DateTime<Utc> = "2024-06-02T21:30:00Z".parse().unwrap();
+        let ahead = now + TimeDelta::hours(3);
+        let behind = now - TimeDelta::days(2);
+        let close = now - TimeDelta::seconds(90);
+
+        assert_eq!(clamp_client_time(ahead, now), now + TimeDelta::minutes(5));
+        assert_eq!(clamp_client_time(behind, now), now - TimeDelta::minutes(5));
+        assert_eq!(clamp_client_time(close, now), close);
+    }
+
+    #[test]
+    fn send_hashes_forget_the_oldest_past_capacity() {
+        let mut hashes = SendHashes::default();
+        for hash in 0..SEND_HASHES as u64 {
+            assert!(hashes.insert(hash));
+        }
+        assert!(!hashes.insert(0));
+
+        // One more pushes out the oldest, which then counts as new again
+        assert!(hashes.insert(SEND_HASHES as u64));
+        assert!(hashes.insert(0));
+        assert!(!hashes.insert(SEND_HASHES as u64));
+    }
+}
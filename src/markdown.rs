@@ -0,0 +1,55 @@
+use std::borrow::Cow;
+
+// Punctuation markdown renderers may give meaning to, escaped with a backslash
+const MARKDOWN_SPECIAL: &[char] = &[
+    '\\', '`', '*', '_', '~', '[', ']', '(', ')', '#', '+', '-', '!', '|',
+];
+
+/// Escapes `text` so a markdown renderer shows it as typed: markdown
+/// punctuation gets a backslash and HTML's special characters become entities.
+pub fn escape(text: &str) -> Cow<'_, str> {
+    let special = |c: char| MARKDOWN_SPECIAL.contains(&c) || matches!(c, '&' | '<' | '>');
+    if !text.contains(special) {
+        return Cow::Borrowed(text);
+    }
+
+    let mut escaped = String::with_capacity(text.len() + 8);
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            c if MARKDOWN_SPECIAL.contains(&c) => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markdown_punctuation_is_backslashed() {
+        assert_eq!(escape("*bold* and _em_"), r"\*bold\* and \_em\_");
+        assert_eq!(escape("[link](url)"), r"\[link\]\(url\)");
+        assert_eq!(escape(r"a\b"), r"a\\b");
+    }
+
+    #[test]
+    fn html_becomes_entities() {
+        assert_eq!(
+            escape("<b>fish & chips</b>"),
+            r"&lt;b&gt;fish &amp; chips&lt;/b&gt;"
+        );
+    }
+
+    #[test]
+    fn plain_text_is_borrowed() {
+        assert!(matches!(escape("hello there"), Cow::Borrowed(_)));
+    }
+}
@@ -0,0 +1,523 @@
+use crate::db::{
+    AuditEntry, ReplayFilter, SYSTEM_SENDER, SavedBookmark, SavedMessage, SavedNotification,
+    SavedRoomEvent, SavedTopicChange, SavedUser,
+};
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Messages each room keeps before the oldest are dropped.
+pub const ROOM_CAPACITY: usize = 1000;
+
+/// A store that keeps everything in process memory, for deployments that
+/// want no persistence. Each room is a bounded ring buffer and everything is
+/// lost on restart. Clones share the same data.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    data: Arc<Mutex<Data>>,
+    // Waited out before each message save, to stand in for a slow database
+    save_delay: Arc<Mutex<Duration>>,
+}
+
+#[derive(Default)]
+struct Data {
+    next_id: i64,
+    rooms: HashMap<String, VecDeque<SavedMessage>>,
+    // Each room's purged messages, kept for /restore until /purge-deleted
+    deleted: HashMap<String, Vec<SavedMessage>>,
+    // When each known user last renamed
+    users: HashMap<String, i64>,
+    // Renames as (old name, new name), oldest first
+    renames: Vec<(String, String)>,
+    // Moderation actions, oldest first
+    audit: Vec<AuditEntry>,
+    // Each room's events, oldest first
+    room_events: HashMap<String, Vec<SavedRoomEvent>>,
+    // Each room's topic changes, oldest first
+    topic_changes: HashMap<String, Vec<SavedTopicChange>>,
+    // Each room's bookmarks, oldest first
+    bookmarks: HashMap<String, Vec<SavedBookmark>>,
+    // Each named user's /notify keywords, oldest first
+    keywords: HashMap<String, Vec<String>>,
+    // Each named user's aliases as (shortcut, command), oldest first
+    aliases: HashMap<String, Vec<(String, String)>>,
+    // Each recipient's undelivered notifications, oldest first. Delivered
+    // ones are dropped, as nothing reads them again
+    notifications: HashMap<String, Vec<SavedNotification>>,
+    // Each sender's finished uploads as (sent at, size), oldest first
+    uploads: HashMap<String, Vec<(DateTime<Utc>, u64)>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes every later message save take `delay`, for exercising a write
+    /// backlog.
+    #[cfg(test)]
+    pub fn delay_saves(&self, delay: Duration) {
+        *self.save_delay.lock().unwrap() = delay;
+    }
+
+    pub fn save_delay(&self) -> Duration {
+        *self.save_delay.lock().unwrap()
+    }
+
+    pub fn save_message(
+        &self,
+        room: &str,
+        text: &str,
+        sender: &str,
+        sent_at: DateTime<Utc>,
+        expires_at: Option<i64>,
+    ) -> i64 {
+        let mut data = self.data.lock().unwrap();
+        data.next_id += 1;
+        let id = data.next_id;
+
+        let messages = data.rooms.entry(room.to_string()).or_default();
+        if messages.len() == ROOM_CAPACITY {
+            messages.pop_front();
+        }
+        messages.push_back(SavedMessage {
+            id,
+            sender: sender.to_string(),
+            text: text.to_string(),
+            sent_at,
+            expires_at,
+            pinned: false,
+        });
+        id
+    }
+
+    // Messages in the room that haven't expired and match `filter`, oldest first
+    pub fn live_messages(
+        &self,
+        room: &str,
+        filter: impl Fn(&SavedMessage) -> bool,
+    ) -> Vec<SavedMessage> {
+        let now = chrono::Utc::now().timestamp();
+        let data = self.data.lock().unwrap();
+        data.rooms
+            .get(room)
+            .into_iter()
+            .flatten()
+            .filter(|message| is_live(message, now) && filter(message))
+            .cloned()
+            .collect()
+    }
+
+    // The newest `limit` live messages in the room that `filter` lets
+    // through, oldest first, and how many it lets through in all. Only those
+    // `limit` are cloned
+    pub fn replay_messages(
+        &self,
+        room: &str,
+        filter: ReplayFilter<'_>,
+        limit: usize,
+    ) -> (Vec<SavedMessage>, usize) {
+        let now = chrono::Utc::now().timestamp();
+        let data = self.data.lock().unwrap();
+        let Some(messages) = data.rooms.get(room) else {
+            return (Vec::new(), 0);
+        };
+        let (start, excluded) = match filter {
+            ReplayFilter::All => (0, HashSet::new()),
+            ReplayFilter::After(id) => match messages.iter().position(|m| m.id == id) {
+                Some(position) => (position + 1, HashSet::new()),
+                None => return (Vec::new(), 0),
+            },
+            ReplayFilter::Excluding(ids) => (0, ids.iter().copied().collect()),
+        };
+
+        let mut picked = Vec::new();
+        let mut total = 0;
+        for message in messages.range(start..).rev() {
+            if !is_live(message, now) || excluded.contains(&message.id) {
+                continue;
+            }
+            total += 1;
+            if picked.len() < limit {
+                picked.push(message.clone());
+            }
+        }
+        picked.reverse();
+        (picked, total)
+    }
+
+    pub fn set_pinned(&self, room: &str, id: i64, pinned: bool) -> Option<SavedMessage> {
+        let now = chrono::Utc::now().timestamp();
+        let mut data = self.data.lock().unwrap();
+        let message = data
+            .rooms
+            .get_mut(room)?
+            .iter_mut()
+            .find(|message| message.id == id && is_live(message, now))?;
+        message.pinned = pinned;
+        Some(message.clone())
+    }
+
+    // Purged messages move out of the room to where only /restore finds them
+    pub fn purge_messages(&self, room: &str, sender: &str, limit: usize) -> Vec<SavedMessage> {
+        let now = chrono::Utc::now().timestamp();
+        let mut data = self.data.lock().unwrap();
+        let Some(messages) = data.rooms.get_mut(room) else {
+            return Vec::new();
+        };
+        let ids: Vec<i64> = messages
+            .iter()
+            .rev()
+            .filter(|message| message.sender == sender && is_live(message, now))
+            .take(limit)
+            .map(|message| message.id)
+            .collect();
+        let mut purged = Vec::with_capacity(ids.len());
+        messages.retain(|message| {
+            if ids.contains(&message.id) {
+                purged.push(message.clone());
+                false
+            } else {
+                true
+            }
+        });
+        data.deleted
+            .entry(room.to_string())
+            .or_default()
+            .extend(purged.iter().cloned());
+        purged
+    }
+
+    pub fn restore_message(&self, room: &str, id: i64) -> Option<SavedMessage> {
+        let now = chrono::Utc::now().timestamp();
+        let mut data = self.data.lock().unwrap();
+        let deleted = data.deleted.get_mut(room)?;
+        let index = deleted
+            .iter()
+            .position(|message| message.id == id && is_live(message, now))?;
+        let message = deleted.remove(index);
+
+        let messages = data.rooms.entry(room.to_string()).or_default();
+        let at = messages.partition_point(|other| other.id < id);
+        messages.insert(at, message.clone());
+        if messages.len() > ROOM_CAPACITY {
+            messages.pop_front();
+        }
+        Some(message)
+    }
+
+    pub fn purge_deleted(&self, room: &str) -> usize {
+        let mut data = self.data.lock().unwrap();
+        data.deleted.remove(room).map_or(0, |deleted| deleted.len())
+    }
+
+    pub fn add_bookmark(
+        &self,
+        room: &str,
+        url: &str,
+        title: &str,
+        added_by: &str,
+        added_at: DateTime<Utc>,
+    ) -> i64 {
+        let mut data = self.data.lock().unwrap();
+        data.next_id += 1;
+        let id = data.next_id;
+
+        data.bookmarks
+            .entry(room.to_string())
+            .or_default()
+            .push(SavedBookmark {
+                id,
+                url: url.to_string(),
+                title: title.to_string(),
+                added_by: added_by.to_string(),
+                added_at,
+            });
+        id
+    }
+
+    pub fn bookmarks(&self, room: &str) -> Vec<SavedBookmark> {
+        let data = self.data.lock().unwrap();
+        data.bookmarks.get(room).cloned().unwrap_or_default()
+    }
+
+    pub fn remove_bookmark(&self, room: &str, id: i64) -> Option<SavedBookmark> {
+        let mut data = self.data.lock().unwrap();
+        let bookmarks = data.bookmarks.get_mut(room)?;
+        let index = bookmarks.iter().position(|bookmark| bookmark.id == id)?;
+        Some(bookmarks.remove(index))
+    }
+
+    pub fn delete_expired(&self, now: i64) {
+        let mut data = self.data.lock().unwrap();
+        for messages in data.rooms.values_mut() {
+            messages.retain(|message| is_live(message, now));
+        }
+        for messages in data.deleted.values_mut() {
+            messages.retain(|message| is_live(message, now));
+        }
+    }
+
+    pub fn load_user(&self, name: &str) -> SavedUser {
+        let mut data = self.data.lock().unwrap();
+        let returning = data.users.contains_key(name);
+        SavedUser {
+            name_changed_at: *data.users.entry(name.to_string()).or_default(),
+            returning,
+        }
+    }
+
+    pub fn save_name_change(&self, old_name: &str, new_name: &str, changed_at: i64) {
+        let mut data = self.data.lock().unwrap();
+        data.renames
+            .push((old_name.to_string(), new_name.to_string()));
+        data.users.insert(old_name.to_string(), changed_at);
+        data.users.insert(new_name.to_string(), changed_at);
+    }
+
+    pub fn previous_names(&self, name: &str, limit: usize) -> Vec<String> {
+        let data = self.data.lock().unwrap();
+
+        let mut names = Vec::new();
+        let mut current = name;
+        while names.len() < limit {
+            let Some((old_name, _)) = data
+                .renames
+                .iter()
+                .rev()
+                .find(|(_, new_name)| new_name == current)
+            else {
+                break;
+            };
+
+            // A rename loop (a -> b -> a) would otherwise repeat forever
+            if old_name == name || names.contains(old_name) {
+                break;
+            }
+            names.push(old_name.clone());
+            current = old_name;
+        }
+        names
+    }
+
+    pub fn sent_since(&self, sender: &str, since: DateTime<Utc>) -> Vec<DateTime<Utc>> {
+        let data = self.data.lock().unwrap();
+        let mut sent: Vec<_> = data
+            .rooms
+            .values()
+            .flatten()
+            .filter(|message| message.sender == sender && message.sent_at > since)
+            .map(|message| message.sent_at)
+            .collect();
+        sent.sort();
+        sent
+    }
+
+    pub fn word_counts(&self, limit: usize) -> Vec<(String, i64)> {
+        let data = self.data.lock().unwrap();
+        let mut counts: HashMap<&str, i64> = HashMap::new();
+        for message in data.rooms.values().flatten() {
+            if message.sender != SYSTEM_SENDER {
+                // Same approximation as the SQL: one more word than spaces
+                let words = message.text.matches(' ').count() as i64 + 1;
+                *counts.entry(&message.sender).or_default() += words;
+            }
+        }
+
+        let mut counts: Vec<_> = counts
+            .into_iter()
+            .map(|(sender, words)| (sender.to_string(), words))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts.truncate(limit);
+        counts
+    }
+
+    pub fn keywords(&self, name: &str) -> Vec<String> {
+        let data = self.data.lock().unwrap();
+        data.keywords.get(name).cloned().unwrap_or_default()
+    }
+
+    pub fn add_keyword(&self, name: &str, keyword: &str) {
+        let mut data = self.data.lock().unwrap();
+        data.keywords
+            .entry(name.to_string())
+            .or_default()
+            .push(keyword.to_string());
+    }
+
+    pub fn aliases(&self, name: &str) -> Vec<(String, String)> {
+        let data = self.data.lock().unwrap();
+        data.aliases.get(name).cloned().unwrap_or_default()
+    }
+
+    pub fn set_alias(&self, name: &str, shortcut: &str, command: &str) {
+        self.remove_alias(name, shortcut);
+        let mut data = self.data.lock().unwrap();
+        data.aliases
+            .entry(name.to_string())
+            .or_default()
+            .push((shortcut.to_string(), command.to_string()));
+    }
+
+    pub fn remove_alias(&self, name: &str, shortcut: &str) -> bool {
+        let mut data = self.data.lock().unwrap();
+        let Some(aliases) = data.aliases.get_mut(name) else {
+            return false;
+        };
+        let Some(index) = aliases.iter().position(|(s, _)| s == shortcut) else {
+            return false;
+        };
+        aliases.remove(index);
+        true
+    }
+
+    pub fn add_notification(
+        &self,
+        recipient: &str,
+        sender: &str,
+        text: &str,
+        sent_at: DateTime<Utc>,
+    ) {
+        let mut data = self.data.lock().unwrap();
+        data.notifications
+            .entry(recipient.to_string())
+            .or_default()
+            .push(SavedNotification {
+                sender: sender.to_string(),
+                text: text.to_string(),
+                sent_at,
+            });
+    }
+
+    pub fn take_notifications(&self, recipient: &str) -> Vec<SavedNotification> {
+        let mut data = self.data.lock().unwrap();
+        data.notifications.remove(recipient).unwrap_or_default()
+    }
+
+    pub fn remove_keyword(&self, name: &str, keyword: &str) -> bool {
+        let mut data = self.data.lock().unwrap();
+        let Some(keywords) = data.keywords.get_mut(name) else {
+            return false;
+        };
+        let Some(index) = keywords.iter().position(|k| k == keyword) else {
+            return false;
+        };
+        keywords.remove(index);
+        true
+    }
+
+    pub fn record_upload(&self, sender: &str, size: u64, sent_at: DateTime<Utc>) {
+        let mut data = self.data.lock().unwrap();
+        data.uploads
+            .entry(sender.to_string())
+            .or_default()
+            .push((sent_at, size));
+    }
+
+    pub fn uploaded_since(&self, sender: &str, since: DateTime<Utc>) -> Vec<(DateTime<Utc>, u64)> {
+        let data = self.data.lock().unwrap();
+        data.uploads
+            .get(sender)
+            .into_iter()
+            .flatten()
+            .filter(|(sent_at, _)| *sent_at > since)
+            .copied()
+            .collect()
+    }
+
+    pub fn message_sizes(&self, room: &str) -> [i64; 4] {
+        let data = self.data.lock().unwrap();
+        let mut sizes = [0; 4];
+        let messages = data.rooms.get(room).into_iter().flatten();
+        for message in messages.filter(|message| message.sender != SYSTEM_SENDER) {
+            let bucket = match message.text.len() {
+                0..=100 => 0,
+                101..=500 => 1,
+                501..=1000 => 2,
+                _ => 3,
+            };
+            sizes[bucket] += 1;
+        }
+        sizes
+    }
+
+    pub fn record_audit(&self, actor: &str, action: &str, target: &str) {
+        let mut data = self.data.lock().unwrap();
+        data.audit.push(AuditEntry {
+            actor: actor.to_string(),
+            action: action.to_string(),
+            target: target.to_string(),
+            at: chrono::Utc::now(),
+        });
+    }
+
+    pub fn record_room_event(&self, room: &str, event_type: &str, actor: &str, target: &str) {
+        let mut data = self.data.lock().unwrap();
+        data.room_events
+            .entry(room.to_string())
+            .or_default()
+            .push(SavedRoomEvent {
+                event_type: event_type.to_string(),
+                actor: actor.to_string(),
+                target: target.to_string(),
+                at: chrono::Utc::now(),
+            });
+    }
+
+    pub fn room_events(&self, room: &str, limit: usize) -> Vec<SavedRoomEvent> {
+        let data = self.data.lock().unwrap();
+        let events = data.room_events.get(room).map_or(&[][..], Vec::as_slice);
+        let skip = events.len().saturating_sub(limit);
+        events[skip..].to_vec()
+    }
+
+    pub fn record_topic_change(&self, room: &str, topic: &str, changed_by: &str) {
+        let mut data = self.data.lock().unwrap();
+        data.topic_changes
+            .entry(room.to_string())
+            .or_default()
+            .push(SavedTopicChange {
+                topic: topic.to_string(),
+                changed_by: changed_by.to_string(),
+                at: chrono::Utc::now(),
+            });
+    }
+
+    pub fn topic_changes(&self, room: &str, limit: usize) -> Vec<SavedTopicChange> {
+        let data = self.data.lock().unwrap();
+        let changes = data.topic_changes.get(room).map_or(&[][..], Vec::as_slice);
+        let skip = changes.len().saturating_sub(limit);
+        changes[skip..].to_vec()
+    }
+
+    pub fn recent_audit(&self, limit: usize) -> Vec<AuditEntry> {
+        let data = self.data.lock().unwrap();
+        let skip = data.audit.len().saturating_sub(limit);
+        data.audit[skip..].to_vec()
+    }
+}
+
+fn is_live(message: &SavedMessage, now: i64) -> bool {
+    message.expires_at.is_none_or(|at| at > now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rooms_keep_only_the_newest_messages() {
+        let store = MemoryStore::new();
+        for n in 0..ROOM_CAPACITY + 5 {
+            store.save_message("main", &n.to_string(), "alice", Utc::now(), None);
+        }
+        store.save_message("other", "elsewhere", "alice", Utc::now(), None);
+
+        let history = store.live_messages("main", |_| true);
+        assert_eq!(history.len(), ROOM_CAPACITY);
+        assert_eq!(history[0].text, "5");
+        assert_eq!(store.live_messages("other", |_| true).len(), 1);
+    }
+}
@@ -0,0 +1,182 @@
+//! Counters, kept per namespace. Connections that haven't entered one, such
+//! as those turned away on open, count under [`NO_NAMESPACE`].
+
+use crate::protocol::CloseReason;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, RwLock};
+use std::time::Duration;
+
+/// What connections outside any namespace count under.
+pub const NO_NAMESPACE: &str = "";
+
+// Latency histogram buckets; bucket `n` counts durations under 2^n
+// microseconds, the last one everything longer
+const LATENCY_BUCKETS: usize = 40;
+
+static NAMESPACES: LazyLock<RwLock<HashMap<String, Arc<Counters>>>> =
+    LazyLock::new(Default::default);
+
+struct Counters {
+    frames_dropped_after_close: AtomicU64,
+    send_timeouts: AtomicU64,
+    send_errors: AtomicU64,
+    closes: [AtomicU64; CloseReason::ALL.len()],
+    stage_latencies: [[AtomicU64; LATENCY_BUCKETS]; Stage::ALL.len()],
+}
+
+impl Counters {
+    fn new() -> Self {
+        Self {
+            frames_dropped_after_close: AtomicU64::new(0),
+            send_timeouts: AtomicU64::new(0),
+            send_errors: AtomicU64::new(0),
+            closes: [const { AtomicU64::new(0) }; CloseReason::ALL.len()],
+            stage_latencies: [const { [const { AtomicU64::new(0) }; LATENCY_BUCKETS] };
+                Stage::ALL.len()],
+        }
+    }
+}
+
+// The namespace's counters, made on its first count
+fn counters(namespace: &str) -> Arc<Counters> {
+    if let Some(counters) = NAMESPACES.read().unwrap().get(namespace) {
+        return Arc::clone(counters);
+    }
+    let mut namespaces = NAMESPACES.write().unwrap();
+    let counters = namespaces
+        .entry(namespace.to_string())
+        .or_insert_with(|| Arc::new(Counters::new()));
+    Arc::clone(counters)
+}
+
+// Reads one of the namespace's counters, zero if it has counted nothing yet
+fn read(namespace: &str, counter: impl FnOnce(&Counters) -> u64) -> u64 {
+    NAMESPACES
+        .read()
+        .unwrap()
+        .get(namespace)
+        .map_or(0, |counters| counter(counters))
+}
+
+/// A step a traced chat message goes through. Each is timed from the step
+/// before it, except `Total`, which runs from the frame arriving to the last
+/// recipient's copy being queued.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// Decoding the frame.
+    Parse,
+    /// Room rules, duplicate and quota checks, and text expansion.
+    Filters,
+    /// Handing the message to its room's writer.
+    PersistEnqueue,
+    /// Waiting for the writer to store it.
+    PersistAck,
+    /// Queueing a copy for every recipient.
+    Delivery,
+    Total,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 6] = [
+        Stage::Parse,
+        Stage::Filters,
+        Stage::PersistEnqueue,
+        Stage::PersistAck,
+        Stage::Delivery,
+        Stage::Total,
+    ];
+}
+
+/// Frames discarded because their connection had already closed.
+pub fn frames_dropped_after_close(namespace: &str) -> u64 {
+    read(namespace, |counters| {
+        counters.frames_dropped_after_close.load(Ordering::Relaxed)
+    })
+}
+
+pub(crate) fn record_frame_dropped_after_close(namespace: &str) {
+    counters(namespace)
+        .frames_dropped_after_close
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// Writes abandoned because they outlasted the send timeout.
+pub fn send_timeouts(namespace: &str) -> u64 {
+    read(namespace, |counters| {
+        counters.send_timeouts.load(Ordering::Relaxed)
+    })
+}
+
+pub(crate) fn record_send_timeout(namespace: &str) {
+    counters(namespace)
+        .send_timeouts
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// Writes that failed for any reason other than a timeout.
+pub fn send_errors(namespace: &str) -> u64 {
+    read(namespace, |counters| {
+        counters.send_errors.load(Ordering::Relaxed)
+    })
+}
+
+pub(crate) fn record_send_error(namespace: &str) {
+    counters(namespace)
+        .send_errors
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// Connections the server closed with the given close code, one per
+/// `CloseReason`. Zero for codes the server never uses.
+pub fn closes(namespace: &str, code: u16) -> u64 {
+    let Some(index) = CloseReason::ALL
+        .iter()
+        .position(|reason| reason.code() == code)
+    else {
+        return 0;
+    };
+    read(namespace, |counters| {
+        counters.closes[index].load(Ordering::Relaxed)
+    })
+}
+
+pub(crate) fn record_close(namespace: &str, reason: CloseReason) {
+    counters(namespace).closes[reason as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// The 95th percentile of a stage's latency over the namespace's traced
+/// messages, rounded up to a power of two microseconds. None until a message
+/// has been traced.
+pub fn stage_p95(namespace: &str, stage: Stage) -> Option<Duration> {
+    let counters = NAMESPACES.read().unwrap().get(namespace).cloned()?;
+    let buckets = &counters.stage_latencies[stage as usize];
+    let counts: [u64; LATENCY_BUCKETS] =
+        std::array::from_fn(|bucket| buckets[bucket].load(Ordering::Relaxed));
+    let total: u64 = counts.iter().sum();
+    if total == 0 {
+        return None;
+    }
+
+    let rank = (total * 19).div_ceil(20);
+    let mut seen = 0;
+    let bucket = counts
+        .iter()
+        .position(|count| {
+            seen += count;
+            seen >= rank
+        })
+        .unwrap_or(LATENCY_BUCKETS - 1);
+    Some(Duration::from_micros(1 << bucket))
+}
+
+pub(crate) fn record_stage(namespace: &str, stage: Stage, took: Duration) {
+    let micros = took.as_micros();
+    let bucket = if micros == 0 {
+        0
+    } else {
+        (u128::BITS - micros.leading_zeros()) as usize
+    };
+    counters(namespace).stage_latencies[stage as usize][bucket.min(LATENCY_BUCKETS - 1)]
+        .fetch_add(1, Ordering::Relaxed);
+}
@@ -0,0 +1,496 @@
+use crate::markdown;
+use crate::metrics;
+use crate::protocol::{CloseReason, Frame, Message, MessageType, Protocol};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{Instrument, error};
+use wynd::handle::ConnectionHandle;
+
+/// Number of sent frames each connection keeps around for resends.
+pub const RESEND_BUFFER: usize = 256;
+
+/// Work items for a connection's sender task.
+pub enum Outbound {
+    Message(Message),
+    /// A message broadcast to the connection's room, held back while a
+    /// history replay is pending.
+    Live(Message),
+    /// Holds back live messages until the next `Replay`, so none can
+    /// overtake the history or repeat what it holds.
+    HoldLive,
+    /// The history replay, which may be empty, followed by the live messages
+    /// held back since `HoldLive`. Chat messages the replay already covers
+    /// are dropped.
+    Replay(Vec<Message>),
+    /// A message sent without a sequence number and left out of resends,
+    /// such as a `Pong`, which is only meaningful right away.
+    Unsequenced(Message),
+    SetProtocol(Protocol),
+    /// Whether chat text is escaped for a markdown-rendering client.
+    EscapeMarkdown(bool),
+    /// The namespace the connection entered, which its metrics count under
+    /// from then on.
+    Namespace(String),
+    Resend {
+        from_seq: u64,
+    },
+    /// A `File` message followed by the file itself in a binary frame. Only
+    /// the message is kept for resends.
+    File(Message, Vec<u8>),
+    /// Closes the socket once everything queued before it has been sent,
+    /// then stops the task.
+    Close(oneshot::Sender<()>),
+}
+
+/// Queues work for a connection's sender task and keeps count of what it
+/// hasn't got to yet.
+#[derive(Clone)]
+pub struct Sender {
+    tx: mpsc::UnboundedSender<Outbound>,
+    queued: Arc<AtomicUsize>,
+}
+
+/// The sender task has stopped, so nothing more can be queued.
+#[derive(Debug)]
+pub struct Stopped;
+
+impl Sender {
+    pub fn send(&self, outbound: Outbound) -> Result<(), Stopped> {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        self.tx.send(outbound).map_err(|_| {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            Stopped
+        })
+    }
+
+    /// Work items the sender task has yet to handle.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+/// State owned by a connection's sender task: the negotiated protocol and
+/// markdown capability, the outbound sequence counter and the ring buffer of
+/// recently sent frames.
+pub struct Outbox {
+    protocol: Protocol,
+    escape_markdown: bool,
+    next_seq: u64,
+    sent: VecDeque<(u64, Frame)>,
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Self {
+            protocol: Protocol::default(),
+            escape_markdown: false,
+            next_seq: 1,
+            sent: VecDeque::with_capacity(RESEND_BUFFER),
+        }
+    }
+
+    /// Stamps the message with the next sequence number, encodes it and
+    /// remembers the frame for later resends.
+    pub fn stamp(&mut self, message: &Message) -> Frame {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        let frame = if self.escape_markdown {
+            self.protocol.encode(seq, &escape_chat(message))
+        } else {
+            self.protocol.encode(seq, message)
+        };
+        if self.sent.len() == RESEND_BUFFER {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((seq, frame.clone()));
+        frame
+    }
+
+    /// Frames to replay for a resend request, or `None` when the requested
+    /// range has already fallen out of the buffer and the client must resync.
+    pub fn replay(&self, from_seq: u64) -> Option<Vec<Frame>> {
+        let oldest = self.sent.front().map_or(self.next_seq, |(seq, _)| *seq);
+        if from_seq < oldest {
+            return None;
+        }
+
+        Some(
+            self.sent
+                .iter()
+                .filter(|(seq, _)| *seq >= from_seq)
+                .map(|(_, frame)| frame.clone())
+                .collect(),
+        )
+    }
+}
+
+/// Spawns the task that owns all writes to `handle`.
+///
+/// Once `closed` is set, by the close handler, by a failed write or by an
+/// `Outbound::Close`, queued frames are counted and dropped instead of
+/// written. A write that takes longer than `send_timeout` counts as failed,
+/// so a half-open socket can't stall the task. `on_dead` runs once, when a
+/// write first finds the socket gone or after the task closed it. The task
+/// exits once every sender for it has been dropped. The task runs in the caller's span, so its logs carry
+/// the connection's fields.
+pub fn spawn(
+    handle: Arc<ConnectionHandle<TcpStream>>,
+    closed: Arc<AtomicBool>,
+    send_timeout: Duration,
+    on_dead: impl FnOnce() + Send + 'static,
+) -> Sender {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let queued = Arc::new(AtomicUsize::new(0));
+    let sender = Sender {
+        tx,
+        queued: Arc::clone(&queued),
+    };
+
+    tokio::spawn(
+        async move {
+            let mut outbox = Outbox::new();
+            let on_dead = Mutex::new(Some(on_dead));
+            let namespace = Mutex::new(Arc::<str>::from(metrics::NO_NAMESPACE));
+            let dead = || {
+                if let Some(on_dead) = on_dead.lock().unwrap().take() {
+                    on_dead();
+                }
+            };
+            let write = async |frame| {
+                let namespace = Arc::clone(&namespace.lock().unwrap());
+                if closed.load(Ordering::Relaxed) {
+                    metrics::record_frame_dropped_after_close(&namespace);
+                    return;
+                }
+                // A failed write means the socket is gone; say so once
+                if let Err(e) = send_frame(&handle, frame, send_timeout, &namespace).await
+                    && !closed.swap(true, Ordering::Relaxed)
+                {
+                    error!("Failed to send message, dropping the rest: {}", e);
+                    if matches!(e, SendError::TimedOut(_)) {
+                        metrics::record_close(&namespace, CloseReason::SlowConsumer);
+                    }
+                    dead();
+                }
+            };
+
+            // Live messages waiting for the replay, while one is pending
+            let mut held: Option<Vec<Message>> = None;
+
+            while let Some(outbound) = rx.recv().await {
+                queued.fetch_sub(1, Ordering::Relaxed);
+                match outbound {
+                    Outbound::Message(message) => write(outbox.stamp(&message)).await,
+                    Outbound::Live(message) => match &mut held {
+                        Some(held) => held.push(message),
+                        None => write(outbox.stamp(&message)).await,
+                    },
+                    Outbound::HoldLive => {
+                        held.get_or_insert_default();
+                    }
+                    Outbound::Replay(replay) => {
+                        let through = replayed_through(&replay);
+                        for message in &replay {
+                            write(outbox.stamp(message)).await;
+                        }
+                        for message in held.take().unwrap_or_default() {
+                            if !is_replayed(&message, through) {
+                                write(outbox.stamp(&message)).await;
+                            }
+                        }
+                    }
+                    Outbound::Unsequenced(message) => {
+                        write(outbox.protocol.encode_unsequenced(&message)).await
+                    }
+                    Outbound::File(message, contents) => {
+                        write(outbox.stamp(&message)).await;
+                        write(Frame::Binary(contents)).await;
+                    }
+                    Outbound::SetProtocol(protocol) => outbox.protocol = protocol,
+                    Outbound::EscapeMarkdown(escape) => outbox.escape_markdown = escape,
+                    Outbound::Namespace(name) => *namespace.lock().unwrap() = name.into(),
+                    Outbound::Resend { from_seq } => match outbox.replay(from_seq) {
+                        Some(frames) => {
+                            for frame in frames {
+                                // Nothing more can get through once one write failed
+                                if closed.load(Ordering::Relaxed) {
+                                    break;
+                                }
+                                write(frame).await;
+                            }
+                        }
+                        None => {
+                            let message = Message::new(
+                                MessageType::Resync,
+                                format!(
+                                    "Frames from {} are no longer available, please resync",
+                                    from_seq
+                                ),
+                            );
+                            write(outbox.stamp(&message)).await;
+                        }
+                    },
+                    Outbound::Close(done) => {
+                        // Whatever was held back still goes before the close
+                        for message in held.take().unwrap_or_default() {
+                            write(outbox.stamp(&message)).await;
+                        }
+                        if let Err(e) = handle.close().await {
+                            error!("Failed to close connection: {}", e);
+                        }
+                        closed.store(true, Ordering::Relaxed);
+                        dead();
+                        let _ = done.send(());
+                        break;
+                    }
+                }
+            }
+        }
+        .in_current_span(),
+    );
+
+    sender
+}
+
+// Newest id among the chat messages a history replay holds
+fn replayed_through(replay: &[Message]) -> Option<i64> {
+    replay
+        .iter()
+        .filter_map(|message| match &message.message_type {
+            MessageType::PastMessages { days } => Some(days),
+            _ => None,
+        })
+        .flatten()
+        .flat_map(|day| &day.messages)
+        .filter_map(|message| message.id)
+        .max()
+}
+
+// Whether a held live message is a chat message stored before the replay
+// was loaded, and so already in it or left out of it on purpose
+fn is_replayed(message: &Message, through: Option<i64>) -> bool {
+    matches!(message.message_type, MessageType::Chat)
+        && message
+            .id
+            .zip(through)
+            .is_some_and(|(id, through)| id <= through)
+}
+
+// The message with the text of chat messages, replayed ones included,
+// escaped for markdown
+fn escape_chat(message: &Message) -> Message {
+    let mut message = message.clone();
+    match &mut message.message_type {
+        MessageType::Chat | MessageType::Pinned | MessageType::Unpinned | MessageType::Restore => {
+            message.data = markdown::escape(&message.data).into_owned();
+        }
+        MessageType::PastMessages { days } => {
+            for replayed in days.iter_mut().flat_map(|day| &mut day.messages) {
+                replayed.data = markdown::escape(&replayed.data).into_owned();
+            }
+        }
+        _ => {}
+    }
+    message
+}
+
+/// Why a frame couldn't be written.
+#[derive(Debug)]
+pub enum SendError {
+    /// The write was still pending when the send timeout ran out.
+    TimedOut(Duration),
+    Failed(Box<dyn std::error::Error>),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::TimedOut(timeout) => write!(f, "timed out after {:?}", timeout),
+            SendError::Failed(e) => e.fmt(f),
+        }
+    }
+}
+
+/// Writes one frame, giving up after `timeout`. Failures are counted in
+/// the namespace's [`metrics`], timeouts apart from other errors.
+pub async fn send_frame(
+    handle: &ConnectionHandle<TcpStream>,
+    frame: Frame,
+    timeout: Duration,
+    namespace: &str,
+) -> Result<(), SendError> {
+    let write = async {
+        match frame {
+            Frame::Text(text) => handle.send_text(text).await,
+            Frame::Binary(bytes) => handle.send_binary(bytes).await,
+        }
+    };
+    match tokio::time::timeout(timeout, write).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => {
+            metrics::record_send_error(namespace);
+            Err(SendError::Failed(e))
+        }
+        Err(_) => {
+            metrics::record_send_timeout(namespace);
+            Err(SendError::TimedOut(timeout))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::HistoryDay;
+
+    fn chat(data: &str) -> Message {
+        Message::new(MessageType::Chat, data)
+    }
+
+    #[test]
+    #[cfg(not(feature = "snake_case_types"))]
+    fn stamps_consecutive_sequence_numbers() {
+        let mut outbox = Outbox::new();
+        assert_eq!(
+            outbox.stamp(&chat("a")),
+            Frame::Text(r#"{"seq":1,"message_type":"Chat","data":"a"}"#.to_string())
+        );
+        assert_eq!(
+            outbox.stamp(&chat("b")),
+            Frame::Text(r#"{"seq":2,"message_type":"Chat","data":"b"}"#.to_string())
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "snake_case_types"))]
+    fn structured_payloads_are_not_nested_in_strings() {
+        let mut outbox = Outbox::new();
+        let bookmarks = Message {
+            message_type: MessageType::Bookmarks {
+                bookmarks: vec![crate::protocol::BookmarkInfo {
+                    id: 1,
+                    url: "https://example.com".to_string(),
+                    title: "Docs".to_string(),
+                    added_by: "alice".to_string(),
+                    added_at: "2024-06-02T21:30:00Z".parse().unwrap(),
+                }],
+            },
+            ..chat("")
+        };
+        assert_eq!(
+            outbox.stamp(&chat("alice: hi")),
+            Frame::Text(r#"{"seq":1,"message_type":"Chat","data":"alice: hi"}"#.to_string())
+        );
+        assert_eq!(
+            outbox.stamp(&bookmarks),
+            Frame::Text(
+                r#"{"seq":2,"message_type":{"Bookmarks":{"bookmarks":[{"id":1,"url":"https://example.com","title":"Docs","added_by":"alice","added_at":"2024-06-02T21:30:00Z"}]}},"data":""}"#
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "snake_case_types")]
+    fn snake_case_types_respells_message_types() {
+        let mut outbox = Outbox::new();
+        let mut pinned = chat("a");
+        pinned.message_type = MessageType::Pinned;
+        let alert = Message {
+            message_type: MessageType::KeywordAlert {
+                keyword: "deploy".to_string(),
+                message: Box::new(chat("deploy")),
+            },
+            ..chat("alice mentioned deploy")
+        };
+        assert_eq!(
+            outbox.stamp(&pinned),
+            Frame::Text(r#"{"seq":1,"message_type":"pinned","data":"a"}"#.to_string())
+        );
+        assert_eq!(
+            outbox.stamp(&alert),
+            Frame::Text(
+                r#"{"seq":2,"message_type":{"keyword_alert":{"keyword":"deploy","message":{"message_type":"chat","data":"deploy"}}},"data":"alice mentioned deploy"}"#
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn replays_missed_frames_byte_for_byte() {
+        let mut outbox = Outbox::new();
+        let sent: Vec<Frame> = (1..=5)
+            .map(|n| outbox.stamp(&chat(&n.to_string())))
+            .collect();
+
+        // The client saw 1 and 2, then lost the rest
+        assert_eq!(outbox.replay(3), Some(sent[2..].to_vec()));
+        assert_eq!(outbox.replay(1), Some(sent));
+    }
+
+    #[test]
+    fn replays_binary_frames_unchanged() {
+        let mut outbox = Outbox::new();
+        outbox.protocol = Protocol::MessagePack;
+        let sent = outbox.stamp(&chat("packed"));
+
+        assert!(matches!(sent, Frame::Binary(_)));
+        assert_eq!(outbox.replay(1), Some(vec![sent]));
+    }
+
+    #[test]
+    fn nothing_to_replay_past_the_newest_frame() {
+        let mut outbox = Outbox::new();
+        outbox.stamp(&chat("a"));
+        assert_eq!(outbox.replay(2), Some(Vec::new()));
+    }
+
+    #[test]
+    fn evicted_frames_require_a_resync() {
+        let mut outbox = Outbox::new();
+        for n in 0..RESEND_BUFFER + 10 {
+            outbox.stamp(&chat(&n.to_string()));
+        }
+
+        // Frames 1 through 10 were pushed out of the buffer
+        assert_eq!(outbox.replay(10), None);
+        let replayed = outbox.replay(11).unwrap();
+        assert_eq!(replayed.len(), RESEND_BUFFER);
+    }
+
+    #[test]
+    fn held_chat_the_replay_covers_is_dropped() {
+        let stored = |id| Message {
+            id: Some(id),
+            ..chat("alice: hi")
+        };
+        let replay = [Message {
+            message_type: MessageType::PastMessages {
+                days: vec![HistoryDay {
+                    day: "2024-06-02".to_string(),
+                    messages: vec![stored(3), stored(4)],
+                }],
+            },
+            ..chat("")
+        }];
+        let through = replayed_through(&replay);
+        assert_eq!(through, Some(4));
+
+        assert!(is_replayed(&stored(4), through));
+        assert!(!is_replayed(&stored(5), through));
+        // Unsaved chat and other broadcasts always go through
+        assert!(!is_replayed(&chat("bob: unsaved"), through));
+        let notice = Message {
+            message_type: MessageType::System,
+            ..stored(2)
+        };
+        assert!(!is_replayed(&notice, through));
+        assert!(!is_replayed(&stored(1), replayed_through(&[])));
+    }
+}
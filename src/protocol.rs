@@ -0,0 +1,620 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Subprotocol name for the default JSON text framing.
+pub const JSON_SUBPROTOCOL: &str = "chat.json";
+
+/// Subprotocol name for the `binary_protocol` feature: MessagePack in binary frames.
+pub const MSGPACK_SUBPROTOCOL: &str = "chat.msgpack";
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Message {
+    pub message_type: MessageType,
+    /// Text to show for the message. Structured payloads, such as replayed
+    /// history or the bookmark list, are fields of `message_type` and are
+    /// never JSON-encoded into this string.
+    pub data: String,
+    /// Stored chat message this refers to, for commands like `/pin`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
+    /// Unix timestamp after which a disappearing message is gone.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    /// Server time a chat message was stored, or an `Ack` was sent, as
+    /// RFC 3339 with millisecond precision.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sent_at: Option<DateTime<Utc>>,
+    /// Set on a chat message that follows one from the same sender within
+    /// half a minute with nothing in between, so clients can group them
+    /// under one name.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub continuation: bool,
+}
+
+impl Message {
+    /// A frame that refers to no stored message; set the other fields with
+    /// struct update syntax when it does.
+    pub fn new(message_type: MessageType, data: impl Into<String>) -> Self {
+        Message {
+            message_type,
+            data: data.into(),
+            id: None,
+            expires_at: None,
+            sent_at: None,
+            continuation: false,
+        }
+    }
+
+    /// A plain notice from the server.
+    pub fn system(data: impl Into<String>) -> Self {
+        Message::new(MessageType::System, data)
+    }
+}
+
+/// Variants are spelled `PastMessages` on the wire, as clients have always
+/// received them, or `past_messages` with the `snake_case_types` feature.
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "snake_case_types", serde(rename_all = "snake_case"))]
+pub enum MessageType {
+    System,
+    Welcome,
+    /// Welcome for a user the server named itself, so clients can suggest
+    /// picking a name of their own with `/nick`.
+    GuestWelcome {
+        name: String,
+    },
+    /// Token that restores the user's name on a later connection opened
+    /// with a `resume` handshake. Follows the welcome of connections that
+    /// opened with a handshake; legacy clients never get one.
+    Session {
+        token: String,
+    },
+    /// The room's history replayed on join, bucketed by calendar day in the
+    /// connection's timezone, oldest first.
+    PastMessages {
+        days: Vec<HistoryDay>,
+    },
+    Chat,
+    /// A message was pinned, or is being listed as pinned; `id` names it.
+    Pinned,
+    /// A message was unpinned; `id` names it.
+    Unpinned,
+    /// An admin purged these stored messages with `/purge`; clients should
+    /// remove them. They are gone from history too, unless restored.
+    BulkDeleted {
+        message_ids: Vec<i64>,
+    },
+    /// An admin brought back a purged message with `/restore`; `id` names
+    /// it and clients should put it back in place.
+    Restore,
+    Resync,
+    /// A `Send` frame was handled, or recognised as a resend of one that was.
+    Ack {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        client_msg_id: Option<String>,
+        /// The frame's `sent_at`, clamped to within five minutes of the
+        /// server's clock, for measuring latency against the Ack's own
+        /// `sent_at`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        client_sent_at: Option<DateTime<Utc>>,
+    },
+    Error {
+        code: ErrorCode,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        retry_after: Option<u64>,
+    },
+    /// A copy of a chat message for admins tailing every room with
+    /// `/tailall on`. Never stored and never sent to anyone else.
+    AdminTail {
+        room: String,
+        sender: String,
+        text: String,
+        /// Absent when the message couldn't be saved.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<i64>,
+    },
+    /// A private message to this connection alone: the greeting bot's
+    /// onboarding text or another user's `/msg`. Never broadcast. A `/msg`
+    /// held while its recipient was offline arrives when they resume their
+    /// session, with the `sent_at` it was sent at.
+    Direct {
+        sender: String,
+    },
+    /// Answer to a `Ping` control frame or `/ping`, echoing the client's
+    /// token so it can measure the round trip. Never stored or broadcast.
+    /// Answers to `Ping` frames carry no `seq` and are never resent.
+    Pong {
+        token: String,
+        /// The server's clock as it answered, for estimating clock offset.
+        server_time: DateTime<Utc>,
+        /// The `Ping` frame's `sent_at`, when it had one.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        client_sent_at: Option<DateTime<Utc>>,
+    },
+    /// Answer to `/rooms`: one page of rooms, busiest first.
+    RoomList {
+        rooms: Vec<RoomInfo>,
+        /// 1-based page number, out of `pages`.
+        page: usize,
+        pages: usize,
+    },
+    /// The room's shared bookmark list, oldest first. Sent on joining the
+    /// room, in answer to `/bookmark list`, and to the whole room whenever
+    /// the list changes.
+    Bookmarks {
+        bookmarks: Vec<BookmarkInfo>,
+    },
+    /// A file someone sent with `SendFile`. The very next frame is a binary
+    /// one holding its contents; resends replay this frame but not the file.
+    File {
+        sender: String,
+        filename: String,
+        size: u64,
+        /// Set when the file was sent to this user alone rather than the
+        /// room.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        to: Option<String>,
+    },
+    /// `from` invited this connection to `room` with `/invite`. The client
+    /// answers with `/accept <room>`, which joins it, or `/decline <room>`.
+    Invite {
+        room: String,
+        from: String,
+    },
+    /// A chat message mentioned one of the user's `/notify` keywords as a
+    /// whole word. Sent alongside the message itself, at most once per
+    /// message; `data` reads e.g. "alice mentioned deploy".
+    KeywordAlert {
+        keyword: String,
+        message: Box<Message>,
+    },
+    /// Answer to `/cls`: the client should clear the messages it shows.
+    /// Only ever sent to the connection that asked; nothing is deleted.
+    ClearScreen,
+    /// The room's join message, set with `/joinmsg`, in `data`. Sent right
+    /// after joining the room, before anything else about it.
+    RoomGreeting {
+        room: String,
+    },
+    /// Last frame before the server turns away a connection it can't take
+    /// right now. wynd can't attach a reason to the close frame itself, so
+    /// the reconnect hint travels here.
+    Closing {
+        retry_after_ms: u64,
+    },
+    /// Very last frame before the server closes the socket, whatever the
+    /// reason. wynd can't put a close code in the close frame itself, so
+    /// `code` is the [`CloseReason`] code it would have carried; `data` says
+    /// why in words. Clients should only reconnect on their own when
+    /// `reconnect` is set, after any delay an earlier frame asked for.
+    Disconnected {
+        code: u16,
+        reconnect: bool,
+    },
+    /// Last frame before the server closes the socket for a restart or for
+    /// maintenance. Clients should reconnect after `reconnect_after_ms` plus
+    /// some jitter, to `new_url` when there is one.
+    ServerRestart {
+        reason: String,
+        reconnect_after_ms: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        new_url: Option<String>,
+    },
+}
+
+/// One calendar day of replayed history.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HistoryDay {
+    /// The day as `YYYY-MM-DD`.
+    pub day: String,
+    pub messages: Vec<Message>,
+}
+
+/// A room as listed by `/rooms`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    /// Connections in the room, not counting observers.
+    pub members: usize,
+    /// Chat messages since the server last reset its hourly counts.
+    pub messages_last_hour: u64,
+    /// Whether the caller is in the room.
+    pub is_member: bool,
+    /// Whether `/join` needs the room's password.
+    pub has_password: bool,
+}
+
+/// A link on a room's bookmark list.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BookmarkInfo {
+    /// What `/bookmark remove` takes.
+    pub id: i64,
+    pub url: String,
+    pub title: String,
+    pub added_by: String,
+    pub added_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// The user renamed too recently; `retry_after` says when they may again.
+    NameChangeCooldown,
+    /// The requested name belongs to another connected user.
+    NameTaken,
+    /// The caller lacks the privileges the command needs.
+    Unauthorized,
+    /// The requested namespace is not configured on this server.
+    UnknownNamespace,
+    /// The connection's mode doesn't allow the action, e.g. an observer chatting.
+    PermissionDenied,
+    /// A fixed limit is used up, e.g. the number of admins tailing at once.
+    CapacityReached,
+    /// The sender stored as many messages as their quota allows for the last
+    /// 24 hours; `retry_after` says when the oldest of them stops counting.
+    MessageQuotaExceeded,
+    /// The file would take the sender past the bytes their quota allows them
+    /// to upload per 24 hours; `retry_after` says when enough of their earlier
+    /// uploads stop counting for it to fit.
+    UploadQuotaExceeded,
+    /// A `resume` handshake presented a token the server doesn't know.
+    UnknownSession,
+    /// A file transfer was refused, went wrong or stalled, and is abandoned.
+    TransferFailed,
+    /// The chat message is longer than its room allows.
+    MessageTooLong,
+    /// `/roomconfig` was given a setting rooms don't have, or `/roomsettings`
+    /// pairs it can't apply; the text lists every one.
+    UnknownSetting,
+    /// The message or file is something its room's settings turn off, such
+    /// as a link where `links=off`.
+    RoomPolicy,
+    /// A `Negotiate` frame offered no subprotocol the server speaks; the
+    /// connection is closed.
+    UnsupportedProtocol,
+    /// An `/alias` would run another alias, or be run by one.
+    CircularAlias,
+    /// A frame from the client couldn't be decoded.
+    InvalidFrame,
+    /// The text holds bidi override or isolate characters, which the server
+    /// is set to refuse rather than strip.
+    InvalidText,
+    /// The connection gave too many wrong room passwords in a short while;
+    /// `retry_after` says when it may try again.
+    TooManyAttempts,
+    /// The server failed to handle the request, e.g. its database did;
+    /// trying again later may work.
+    Internal,
+}
+
+/// Why the server closed a connection, each with its own code in the
+/// 4000-4999 range the WebSocket spec leaves to applications.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// 4000: the server is restarting or draining for maintenance.
+    Restart,
+    /// 4001: the server is at `--max-connections`.
+    ServerFull,
+    /// 4002: too many connection attempts from the client's address.
+    Throttled,
+    /// 4003: nothing was sent for longer than `--idle-timeout`.
+    Idle,
+    /// 4004: writes to the client timed out. Never reaches the client,
+    /// whose socket isn't being read; it is only logged and counted.
+    SlowConsumer,
+    /// 4005: an admin used `/kick`.
+    Kicked,
+    /// 4006: the client asked for a namespace this server doesn't have.
+    UnknownNamespace,
+    /// 4007: the client offered no subprotocol this server speaks.
+    UnsupportedProtocol,
+    /// 4008: no name was chosen within `--naming-timeout` of connecting.
+    Unnamed,
+}
+
+impl CloseReason {
+    pub const ALL: [CloseReason; 9] = [
+        CloseReason::Restart,
+        CloseReason::ServerFull,
+        CloseReason::Throttled,
+        CloseReason::Idle,
+        CloseReason::SlowConsumer,
+        CloseReason::Kicked,
+        CloseReason::UnknownNamespace,
+        CloseReason::UnsupportedProtocol,
+        CloseReason::Unnamed,
+    ];
+
+    pub fn code(&self) -> u16 {
+        4000 + *self as u16
+    }
+
+    /// Whether trying again later can work. Restarts and full or throttled
+    /// servers pass; kicks and idle timeouts were meant to end the session.
+    pub fn reconnect(&self) -> bool {
+        matches!(
+            self,
+            CloseReason::Restart
+                | CloseReason::ServerFull
+                | CloseReason::Throttled
+                | CloseReason::SlowConsumer
+        )
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            CloseReason::Restart => "Server is restarting",
+            CloseReason::ServerFull => "Server is full",
+            CloseReason::Throttled => "Too many connection attempts",
+            CloseReason::Idle => "Disconnected due to inactivity",
+            CloseReason::SlowConsumer => "Too slow to keep up with the chat",
+            CloseReason::Kicked => "You were kicked by an admin",
+            CloseReason::UnknownNamespace => "Unknown namespace",
+            CloseReason::UnsupportedProtocol => "Unsupported subprotocol",
+            CloseReason::Unnamed => "No name was chosen in time",
+        }
+    }
+}
+
+/// A message as it goes out on the wire, stamped with the connection's
+/// outbound sequence number.
+#[derive(Serialize)]
+struct Envelope<'a> {
+    seq: u64,
+    #[serde(flatten)]
+    message: &'a Message,
+}
+
+/// Control frames a client can send instead of chat input.
+#[derive(Deserialize)]
+pub enum ClientControl {
+    /// Picks the wire protocol for the rest of the connection: the first
+    /// of `subprotocol`'s comma-separated names the server speaks, in the
+    /// client's order of preference. Offering none it speaks closes the
+    /// connection.
+    ///
+    /// wynd accepts the upgrade without exposing the `Sec-WebSocket-Protocol`
+    /// header, so the subprotocol is negotiated with this frame right after
+    /// the socket opens instead of during the HTTP handshake. The field takes
+    /// the header's value as is.
+    Negotiate { subprotocol: String },
+    /// Asks the server to replay every frame from `from_seq` onwards.
+    RequestResend { from_seq: u64 },
+    /// Enters one of the server's isolated namespaces (`prod`, `staging`).
+    ///
+    /// Stands in for the `/ws/<namespace>` upgrade path, which wynd doesn't
+    /// expose. Only needed when the server runs more than one namespace.
+    JoinNamespace { namespace: String },
+    /// Watches a room read-only: no name, no chatting, not listed as a member.
+    ///
+    /// Stands in for `?mode=observe` on the upgrade URL and must be sent
+    /// before picking a name. `room` defaults to the main room.
+    Observe { room: Option<String> },
+    /// Sets the timezone history is grouped by, as minutes east of UTC.
+    ///
+    /// Offsets are fixed, so clients resend it when their offset changes.
+    /// Values beyond ±14 hours are clamped. The history replay waits briefly
+    /// for this frame, so send it right after the socket opens; arriving
+    /// later it only affects later replays.
+    ///
+    /// `markdown` declares that the client renders markdown, so chat text is
+    /// sent to it escaped; what is stored stays as typed.
+    ///
+    /// `history` picks what the replay on joining the room holds.
+    Hello {
+        utc_offset_minutes: i32,
+        #[serde(default)]
+        markdown: bool,
+        #[serde(default)]
+        history: Option<HistoryRequest>,
+    },
+    /// Starts sending a file of `size` bytes in `chunks` binary frames, to
+    /// the room or, with `to`, to one user.
+    ///
+    /// Every chunk frame starts with its 0-based chunk number as a 4-byte
+    /// big-endian integer, and chunks go in order. While a transfer is open
+    /// the connection's binary frames are all taken as its chunks. The
+    /// server relays the file once the last chunk is in, and abandons the
+    /// transfer when chunks stop coming.
+    SendFile {
+        filename: String,
+        size: u64,
+        chunks: u32,
+        #[serde(default)]
+        to: Option<String>,
+    },
+    /// Asks for an immediate `Pong` echoing `token`, which may also be
+    /// called `nonce`, and `sent_at`. Unlike `/ping`, works before the user
+    /// has a name and for observers. Pings closer together than two seconds
+    /// go unanswered.
+    Ping {
+        #[serde(alias = "nonce")]
+        token: String,
+        #[serde(default)]
+        sent_at: Option<DateTime<Utc>>,
+    },
+    /// Chat input the server acknowledges with an `Ack`.
+    ///
+    /// Clients on flaky links may resend it until acknowledged: a copy of
+    /// one of the sender's last few `Send` frames, same text and same
+    /// `client_msg_id`, is acknowledged again but not handled twice. Frames
+    /// without a `client_msg_id` are never taken for resends.
+    ///
+    /// `sent_at` is the client's clock as RFC 3339 and is only echoed back
+    /// in the `Ack`; messages are stamped and ordered by server time.
+    Send {
+        text: String,
+        client_msg_id: Option<String>,
+        #[serde(default)]
+        sent_at: Option<DateTime<Utc>>,
+    },
+}
+
+/// Opening frame saying whether the connection is a new user or a returning
+/// one, such as `{"kind":"resume","token":"..."}`.
+///
+/// Clients that open with their name as plain text instead keep the legacy
+/// first-message-is-your-name flow.
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Handshake {
+    /// A new user: the server prompts for a name as usual and sends a
+    /// `Session` token once the user is welcomed.
+    New {
+        #[serde(default)]
+        history: Option<HistoryRequest>,
+    },
+    /// A returning user: the name the token was issued for is restored
+    /// without a prompt. Admin rights are not; log in again with `/admin`.
+    Resume {
+        token: String,
+        #[serde(default)]
+        history: Option<HistoryRequest>,
+    },
+}
+
+impl Handshake {
+    /// What the connection wants replayed on joining the room, if it said.
+    pub fn history(&self) -> Option<&HistoryRequest> {
+        match self {
+            Handshake::New { history } | Handshake::Resume { history, .. } => history.as_ref(),
+        }
+    }
+}
+
+/// What history a connection wants replayed when it joins a room, such as
+/// `{"mode":"recent","limit":20}`, sent as the `history` field of `Hello` or
+/// of either handshake. Connections that never send one get the newest
+/// `DEFAULT_REPLAY` messages, as before. `history::plan` turns it into what
+/// to load. There is no `/history` command yet to send one mid-session.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct HistoryRequest {
+    #[serde(default)]
+    pub mode: HistoryMode,
+    /// Most messages to replay, keeping the newest; clamped to the server's
+    /// maximum.
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// Replays only messages after this one in `since` mode. An id the room
+    /// doesn't have falls back to `recent`.
+    #[serde(default)]
+    pub since_id: Option<i64>,
+    /// Ids of messages the client already has, in `unseen` mode. More than
+    /// the server accepts falls back to `recent`.
+    #[serde(default)]
+    pub seen_ids: Vec<i64>,
+}
+
+/// Which messages a `HistoryRequest` replays, spelled `none`, `recent`,
+/// `since` or `unseen` on the wire. Left out, it is `recent`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryMode {
+    /// No replay at all, e.g. for bots.
+    None,
+    /// The newest messages, up to `limit`.
+    #[default]
+    Recent,
+    /// Messages after `since_id`, up to `limit`.
+    Since,
+    /// Messages whose ids aren't in `seen_ids`, up to `limit`, for clients
+    /// with scattered gaps.
+    Unseen,
+}
+
+/// Wire encoding negotiated for a connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+/// An encoded outbound frame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Protocol {
+    /// The first of a `Sec-WebSocket-Protocol` style list, most preferred
+    /// first, that the server speaks.
+    pub fn negotiate(offered: &str) -> Option<Self> {
+        offered
+            .split(',')
+            .find_map(|name| Self::from_subprotocol(name.trim()))
+    }
+
+    pub fn from_subprotocol(name: &str) -> Option<Self> {
+        match name {
+            JSON_SUBPROTOCOL => Some(Protocol::Json),
+            MSGPACK_SUBPROTOCOL => Some(Protocol::MessagePack),
+            _ => None,
+        }
+    }
+
+    pub fn subprotocol(&self) -> &'static str {
+        match self {
+            Protocol::Json => JSON_SUBPROTOCOL,
+            Protocol::MessagePack => MSGPACK_SUBPROTOCOL,
+        }
+    }
+
+    /// Encodes a message without a sequence number, for frames that are
+    /// never resent.
+    pub fn encode_unsequenced(&self, message: &Message) -> Frame {
+        self.serialize(message)
+    }
+
+    pub fn encode(&self, seq: u64, message: &Message) -> Frame {
+        self.serialize(&Envelope { seq, message })
+    }
+
+    fn serialize(&self, value: &impl Serialize) -> Frame {
+        match self {
+            Protocol::Json => Frame::Text(serde_json::to_string(value).unwrap()),
+            Protocol::MessagePack => Frame::Binary(rmp_serde::to_vec_named(value).unwrap()),
+        }
+    }
+
+    /// Decodes the input a client sent inside a binary frame.
+    ///
+    /// Only meaningful for [`Protocol::MessagePack`], where clients send their
+    /// chat input as a MessagePack string, or a control frame as a MessagePack
+    /// map, instead of a text frame.
+    pub fn decode(&self, bytes: &[u8]) -> Option<Input> {
+        match self {
+            Protocol::Json => None,
+            Protocol::MessagePack => {
+                if let Ok(control) = rmp_serde::from_slice(bytes) {
+                    return Some(Input::Control(control));
+                }
+                if let Ok(handshake) = rmp_serde::from_slice(bytes) {
+                    return Some(Input::Handshake(handshake));
+                }
+                rmp_serde::from_slice(bytes).ok().map(Input::Text)
+            }
+        }
+    }
+}
+
+/// A decoded inbound frame.
+pub enum Input {
+    Text(String),
+    Control(ClientControl),
+    Handshake(Handshake),
+}
+
+impl Input {
+    pub fn from_text(text: &str) -> Self {
+        if let Ok(control) = serde_json::from_str(text) {
+            return Input::Control(control);
+        }
+        match serde_json::from_str(text) {
+            Ok(handshake) => Input::Handshake(handshake),
+            Err(_) => Input::Text(text.to_string()),
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use crate::db::Store;
+use chrono::{DateTime, TimeDelta, Utc};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tracing::error;
+
+/// Rolling window stored messages and uploads count against a quota for.
+pub const QUOTA_WINDOW: TimeDelta = TimeDelta::hours(24);
+
+// How often senders with nothing left in the window are forgotten
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// What a quota counts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QuotaKind {
+    /// Stored chat messages, one each.
+    Messages,
+    /// Bytes of the files a user finished sending.
+    UploadBytes,
+}
+
+/// A sender's standing against their quota.
+pub struct Usage {
+    pub used: u64,
+    pub limit: Option<u64>,
+}
+
+/// A refused message or upload: the quota it hit and how long until enough
+/// of the sender's earlier usage stops counting for it to fit.
+pub struct Exceeded {
+    pub limit: u64,
+    pub reset: Duration,
+}
+
+/// Limits how much one sender may store or upload per `QUOTA_WINDOW`.
+///
+/// Usage comes from the store the first time a sender is seen, so it
+/// survives restarts, and is kept in memory from then on. Nothing counts
+/// until it is stored; see [`Quota::record`].
+#[derive(Clone)]
+pub struct Quota {
+    store: Store,
+    kind: QuotaKind,
+    // Applies to senders without an override on their User row
+    default_limit: Option<u64>,
+    senders: Arc<RwLock<HashMap<String, Sender>>>,
+}
+
+struct Sender {
+    limit: Option<u64>,
+    // When each counted message or upload was stored and how much it
+    // counted for, oldest first
+    used: VecDeque<(DateTime<Utc>, u64)>,
+    total: u64,
+}
+
+impl Sender {
+    // Drops usage that has aged out of the window
+    fn prune(&mut self, now: DateTime<Utc>) -> &mut Self {
+        let since = now - QUOTA_WINDOW;
+        while let Some((at, amount)) = self.used.front().copied()
+            && at <= since
+        {
+            self.used.pop_front();
+            self.total -= amount;
+        }
+        self
+    }
+
+    // When enough of the current usage will have aged out for `amount`
+    // more to fit under `limit`
+    fn fits_at(&self, amount: u64, limit: u64, now: DateTime<Utc>) -> DateTime<Utc> {
+        let mut total = self.total;
+        for (at, used) in &self.used {
+            total -= used;
+            if total + amount <= limit {
+                return *at + QUOTA_WINDOW;
+            }
+        }
+        now + QUOTA_WINDOW
+    }
+}
+
+impl Quota {
+    pub fn new(store: Store, kind: QuotaKind, default_limit: Option<u64>) -> Self {
+        Self {
+            store,
+            kind,
+            default_limit,
+            senders: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Refuses `amount` more from `sender` when it would take them past their
+    /// quota. Nothing is counted until it is stored.
+    pub async fn check(&self, sender: &str, amount: u64) -> Result<(), Exceeded> {
+        let now = Utc::now();
+        self.with_sender(sender, now, |sender| match sender.limit {
+            Some(limit) if sender.total + amount > limit => {
+                let fits_at = sender.fits_at(amount, limit, now);
+                let reset = (fits_at - now).to_std().unwrap_or_default();
+                Err(Exceeded { limit, reset })
+            }
+            _ => Ok(()),
+        })
+        .await
+    }
+
+    /// Counts `amount` the store has just saved for `sender`. Senders not
+    /// loaded yet are skipped: the store already holds it for when they are.
+    pub async fn record(&self, sender: &str, sent_at: DateTime<Utc>, amount: u64) {
+        if let Some(sender) = self.senders.write().await.get_mut(sender) {
+            sender.used.push_back((sent_at, amount));
+            sender.total += amount;
+        }
+    }
+
+    pub async fn usage(&self, sender: &str) -> Usage {
+        self.with_sender(sender, Utc::now(), |sender| Usage {
+            used: sender.total,
+            limit: sender.limit,
+        })
+        .await
+    }
+
+    /// Spawns a task that forgets senders with nothing left in the window.
+    pub fn spawn_pruner(&self) {
+        let quota = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+            loop {
+                interval.tick().await;
+                let since = Utc::now() - QUOTA_WINDOW;
+                let mut senders = quota.senders.write().await;
+                senders.retain(|_, sender| sender.used.back().is_some_and(|(at, _)| *at > since));
+            }
+        });
+    }
+
+    // Runs `f` on the sender's cached usage. A sender seen for the first
+    // time is loaded from the store without holding the lock, so one slow
+    // load doesn't hold up everyone else's messages
+    async fn with_sender<T>(
+        &self,
+        name: &str,
+        now: DateTime<Utc>,
+        f: impl FnOnce(&mut Sender) -> T,
+    ) -> T {
+        if let Some(sender) = self.senders.write().await.get_mut(name) {
+            return f(sender.prune(now));
+        }
+
+        let loaded = self.load(name, now).await;
+        let mut senders = self.senders.write().await;
+        // Whichever load got here first wins; they read the same rows
+        let sender = senders.entry(name.to_string()).or_insert(loaded);
+        f(sender.prune(now))
+    }
+
+    async fn load(&self, name: &str, now: DateTime<Utc>) -> Sender {
+        let since = now - QUOTA_WINDOW;
+        let used = match self.kind {
+            QuotaKind::Messages => self
+                .store
+                .sent_since(name, since)
+                .await
+                .map(|sent| sent.into_iter().map(|at| (at, 1)).collect()),
+            QuotaKind::UploadBytes => self.store.uploaded_since(name, since).await,
+        };
+        let used: VecDeque<_> = match used {
+            Ok(used) => used.into(),
+            Err(e) => {
+                error!("Failed to count {:?} from {}: {}", self.kind, name, e);
+                VecDeque::new()
+            }
+        };
+        let limit = match self.kind {
+            QuotaKind::Messages => self.store.message_quota(name).await,
+            QuotaKind::UploadBytes => self.store.upload_quota(name).await,
+        };
+        let limit = match limit {
+            Ok(limit) => limit.or(self.default_limit),
+            Err(e) => {
+                error!(
+                    "Failed to load the {:?} quota of {}: {}",
+                    self.kind, name, e
+                );
+                self.default_limit
+            }
+        };
+        Sender {
+            limit,
+            total: used.iter().map(|(_, amount)| amount).sum(),
+            used,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn store() -> Store {
+        let store = Store::new("sqlite::memory:".to_string());
+        store.create_tables().await.unwrap();
+        store
+    }
+
+    #[tokio::test]
+    async fn senders_are_refused_past_their_limit() {
+        let quota = Quota::new(store().await, QuotaKind::Messages, Some(2));
+
+        for _ in 0..2 {
+            assert!(quota.check("alice", 1).await.is_ok());
+            quota.record("alice", Utc::now(), 1).await;
+        }
+        let exceeded = quota.check("alice", 1).await.unwrap_err();
+        assert_eq!(exceeded.limit, 2);
+        assert!(exceeded.reset > Duration::from_secs(23 * 60 * 60));
+        assert_eq!(quota.usage("alice").await.used, 2);
+
+        // Other senders have their own quota
+        assert!(quota.check("bob", 1).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn stored_messages_count_after_a_restart() {
+        let store = store().await;
+        for text in ["one", "two"] {
+            store
+                .save_message("main", text, "alice", Utc::now(), None)
+                .await
+                .unwrap();
+        }
+
+        let quota = Quota::new(store, QuotaKind::Messages, Some(2));
+        assert!(quota.check("alice", 1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn checks_alone_count_nothing() {
+        let quota = Quota::new(store().await, QuotaKind::Messages, Some(1));
+        for _ in 0..3 {
+            assert!(quota.check("alice", 1).await.is_ok());
+        }
+        assert_eq!(quota.usage("alice").await.used, 0);
+    }
+
+    #[tokio::test]
+    async fn no_limit_counts_without_refusing() {
+        let quota = Quota::new(store().await, QuotaKind::Messages, None);
+        for _ in 0..5 {
+            assert!(quota.check("alice", 1).await.is_ok());
+            quota.record("alice", Utc::now(), 1).await;
+        }
+        let usage = quota.usage("alice").await;
+        assert_eq!((usage.used, usage.limit), (5, None));
+    }
+
+    #[tokio::test]
+    async fn uploads_count_their_bytes() {
+        let store = store().await;
+        let earlier = Utc::now() - TimeDelta::hours(20);
+        store.record_upload("alice", 600, earlier).await.unwrap();
+        store.record_upload("alice", 300, Utc::now()).await.unwrap();
+        // Too old to count
+        let stale = Utc::now() - TimeDelta::hours(25);
+        store.record_upload("alice", 5000, stale).await.unwrap();
+
+        let quota = Quota::new(store, QuotaKind::UploadBytes, Some(1000));
+        assert_eq!(quota.usage("alice").await.used, 900);
+        assert!(quota.check("alice", 100).await.is_ok());
+
+        // 200 more only fit once the 600 from 20 hours ago age out
+        let exceeded = quota.check("alice", 200).await.unwrap_err();
+        assert_eq!(exceeded.limit, 1000);
+        assert!(exceeded.reset > Duration::from_secs(3 * 60 * 60 + 59 * 60));
+        assert!(exceeded.reset <= Duration::from_secs(4 * 60 * 60));
+    }
+}
@@ -0,0 +1,160 @@
+use crate::config::BidiControls;
+use std::borrow::Cow;
+use unicode_normalization::{UnicodeNormalization, is_nfc};
+
+// Longest run of zero-width characters kept; emoji sequences never need more
+const MAX_ZERO_WIDTH_RUN: usize = 3;
+
+// Control characters other than newline and tab, C0 and C1 alike
+fn is_stripped_control(c: char) -> bool {
+    c.is_control() && c != '\n' && c != '\t'
+}
+
+// Embeddings, overrides and isolates, which can make text display in an
+// order other than the one it was typed in
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+fn is_zero_width(c: char) -> bool {
+    matches!(
+        c,
+        '\u{200B}' | '\u{200C}' | '\u{200D}' | '\u{2060}' | '\u{FEFF}'
+    )
+}
+
+/// Cleans up text typed by a user before the server acts on it: strips
+/// control characters other than newline and tab, strips bidi overrides and
+/// isolates, keeps at most three zero-width characters in a row and
+/// normalizes to NFC, so equivalent spellings compare equal.
+///
+/// Returns None when the text holds bidi controls and `bidi` rejects them.
+pub fn sanitize(text: &str, bidi: BidiControls) -> Option<Cow<'_, str>> {
+    if bidi == BidiControls::Reject && text.chars().any(is_bidi_control) {
+        return None;
+    }
+    // Plain ASCII is already NFC and holds nothing else to strip
+    if text
+        .chars()
+        .all(|c| c.is_ascii() && !is_stripped_control(c))
+    {
+        return Some(Cow::Borrowed(text));
+    }
+
+    let mut cleaned = String::with_capacity(text.len());
+    let mut zero_width_run = 0;
+    for c in text.chars() {
+        if is_stripped_control(c) || is_bidi_control(c) {
+            continue;
+        }
+        if is_zero_width(c) {
+            zero_width_run += 1;
+            if zero_width_run > MAX_ZERO_WIDTH_RUN {
+                continue;
+            }
+        } else {
+            zero_width_run = 0;
+        }
+        cleaned.push(c);
+    }
+
+    if is_nfc(&cleaned) {
+        Some(Cow::Owned(cleaned))
+    } else {
+        Some(Cow::Owned(cleaned.nfc().collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_and_normalizes() {
+        let cases = [
+            ("hello", "hello"),
+            ("", ""),
+            // Null bytes and other C0 controls
+            ("nul\0byte", "nulbyte"),
+            ("bell\u{7}", "bell"),
+            ("esc\u{1B}[31mred", "esc[31mred"),
+            ("crlf\r\nline", "crlf\nline"),
+            ("del\u{7F}ete", "delete"),
+            // Newlines and tabs stay
+            ("two\nlines", "two\nlines"),
+            ("a\tb", "a\tb"),
+            // C1 controls
+            ("c1\u{85}next", "c1next"),
+            ("csi\u{9B}31m", "csi31m"),
+            // Bidi overrides, embeddings and isolates
+            ("evil\u{202E}txt.exe", "eviltxt.exe"),
+            ("\u{202A}ltr\u{202C}", "ltr"),
+            ("\u{2066}isolate\u{2069}", "isolate"),
+            ("\u{2067}\u{2068}both", "both"),
+            // Marks only steer neutral characters, so they stay
+            ("mark\u{200F}", "mark\u{200F}"),
+            // Zero-width runs are capped at three
+            ("a\u{200B}b", "a\u{200B}b"),
+            ("a\u{200B}\u{200B}\u{200B}b", "a\u{200B}\u{200B}\u{200B}b"),
+            (
+                "a\u{200B}\u{200B}\u{200B}\u{200B}b",
+                "a\u{200B}\u{200B}\u{200B}b",
+            ),
+            (
+                "a\u{200D}\u{200C}\u{2060}\u{FEFF}\u{200D}b",
+                "a\u{200D}\u{200C}\u{2060}b",
+            ),
+            // Separate runs are counted separately
+            (
+                "\u{200B}\u{200B}\u{200B}x\u{200B}\u{200B}\u{200B}",
+                "\u{200B}\u{200B}\u{200B}x\u{200B}\u{200B}\u{200B}",
+            ),
+            // A stripped character doesn't break a run
+            (
+                "a\u{200B}\u{200B}\0\u{200B}\u{200B}b",
+                "a\u{200B}\u{200B}\u{200B}b",
+            ),
+            // Emoji sequences joined with ZWJ are untouched
+            ("👩\u{200D}👩\u{200D}👧", "👩\u{200D}👩\u{200D}👧"),
+            // Decomposed letters are composed
+            ("cafe\u{301}", "café"),
+            ("A\u{30A}ngstro\u{308}m", "Ångström"),
+            // Already composed text is left as it is
+            ("café", "café"),
+            ("日本語", "日本語"),
+            // Everything at once
+            (
+                "\u{202E}e\u{301}\0\u{200B}\u{200B}\u{200B}\u{200B}!",
+                "é\u{200B}\u{200B}\u{200B}!",
+            ),
+        ];
+        for (text, expected) in cases {
+            let sanitized = sanitize(text, BidiControls::Strip).unwrap();
+            assert_eq!(sanitized, expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn rejects_bidi_controls_when_told_to() {
+        for text in ["evil\u{202E}txt.exe", "\u{2066}isolate", "\u{202A}"] {
+            assert_eq!(sanitize(text, BidiControls::Reject), None, "{:?}", text);
+        }
+        // Everything else is still cleaned up as usual
+        assert_eq!(
+            sanitize("nul\0 cafe\u{301}", BidiControls::Reject).unwrap(),
+            "nul café"
+        );
+        assert_eq!(
+            sanitize("mark\u{200F}", BidiControls::Reject).unwrap(),
+            "mark\u{200F}"
+        );
+    }
+
+    #[test]
+    fn borrows_clean_ascii() {
+        assert!(matches!(
+            sanitize("plain text\n", BidiControls::Strip),
+            Some(Cow::Borrowed(_))
+        ));
+    }
+}
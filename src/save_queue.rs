@@ -0,0 +1,361 @@
+use crate::db::Store;
+use crate::latency::MessageTrace;
+use crate::quota::Quota;
+use chrono::{DateTime, SubsecRound, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tracing::error;
+
+// Retries of a failed message save, waiting SAVE_RETRY_DELAY before the
+// first and twice as long before each one after
+const SAVE_RETRIES: u32 = 5;
+const SAVE_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// A queued message once its writer got to it.
+#[derive(Clone, Copy, Debug)]
+pub struct Saved {
+    /// None when the message couldn't be stored; it is still delivered, just
+    /// not kept.
+    pub id: Option<i64>,
+    /// Server time the message was stored, with millisecond precision so
+    /// live and replayed copies agree.
+    pub sent_at: DateTime<Utc>,
+}
+
+/// How far saving has fallen behind, across every room.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Lag {
+    /// Messages queued or being saved.
+    pub depth: usize,
+    /// How long the oldest of them has waited; zero when none are.
+    pub oldest: Duration,
+}
+
+/// Whether persistence is keeping up, with hysteresis: it only counts as
+/// recovered once the lag is back under half of both thresholds, so a queue
+/// hovering at a threshold doesn't flap.
+pub struct LagMonitor {
+    max_depth: usize,
+    max_delay: Duration,
+    degraded: bool,
+}
+
+/// A change in whether persistence is keeping up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LagChange {
+    Degraded,
+    Recovered,
+}
+
+impl LagMonitor {
+    pub fn new(max_depth: usize, max_delay: Duration) -> Self {
+        Self {
+            max_depth,
+            max_delay,
+            degraded: false,
+        }
+    }
+
+    /// Takes the latest lag and reports whether that changed the state.
+    pub fn observe(&mut self, lag: Lag) -> Option<LagChange> {
+        if !self.degraded && (lag.depth >= self.max_depth || lag.oldest >= self.max_delay) {
+            self.degraded = true;
+            return Some(LagChange::Degraded);
+        }
+        if self.degraded && lag.depth <= self.max_depth / 2 && lag.oldest <= self.max_delay / 2 {
+            self.degraded = false;
+            return Some(LagChange::Recovered);
+        }
+        None
+    }
+}
+
+// Every unsaved message by queue order, with when it was queued
+#[derive(Default)]
+struct Pending {
+    next: u64,
+    queued: BTreeMap<u64, Instant>,
+}
+
+struct Job {
+    seq: u64,
+    sender: String,
+    text: String,
+    expires_at: Option<i64>,
+    done: oneshot::Sender<Saved>,
+}
+
+/// Saves chat messages through one writer task per room, so a room's rows
+/// are inserted, and stamped, in the order they were queued even when
+/// several connections post at once. Each stored message counts against its
+/// sender's message quota; ones that fail to save don't.
+#[derive(Clone)]
+pub struct SaveQueue {
+    store: Store,
+    quota: Quota,
+    // Each room's writer, started on the room's first message
+    writers: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<Job>>>>,
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl SaveQueue {
+    pub fn new(store: Store, quota: Quota) -> Self {
+        Self {
+            store,
+            quota,
+            writers: Arc::new(Mutex::new(HashMap::new())),
+            pending: Arc::new(Mutex::new(Pending::default())),
+        }
+    }
+
+    /// How far behind saving is right now.
+    pub fn lag(&self) -> Lag {
+        let pending = self.pending.lock().unwrap();
+        Lag {
+            depth: pending.queued.len(),
+            oldest: pending
+                .queued
+                .values()
+                .next()
+                .map(|queued| queued.elapsed())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Queues a message behind the room's earlier ones. The returned
+    /// receiver resolves once it is stored, or given up on.
+    pub fn submit(
+        &self,
+        room: &str,
+        sender: &str,
+        text: &str,
+        expires_at: Option<i64>,
+    ) -> oneshot::Receiver<Saved> {
+        let (done, saved) = oneshot::channel();
+        let seq = {
+            let mut pending = self.pending.lock().unwrap();
+            let seq = pending.next;
+            pending.next += 1;
+            pending.queued.insert(seq, Instant::now());
+            seq
+        };
+        let job = Job {
+            seq,
+            sender: sender.to_string(),
+            text: text.to_string(),
+            expires_at,
+            done,
+        };
+
+        let mut writers = self.writers.lock().unwrap();
+        let writer = writers
+            .entry(room.to_string())
+            .or_insert_with(|| self.spawn_writer(room));
+        if let Err(mpsc::error::SendError(job)) = writer.send(job) {
+            // The writer only stops by panicking; start over with a new one
+            let writer = self.spawn_writer(room);
+            let _ = writer.send(job);
+            writers.insert(room.to_string(), writer);
+        }
+        saved
+    }
+
+    /// Queues a message and waits until it is stored, marking both on the
+    /// message's trace if it has one.
+    pub async fn save(
+        &self,
+        room: &str,
+        sender: &str,
+        text: &str,
+        expires_at: Option<i64>,
+        mut trace: Option<&mut MessageTrace>,
+    ) -> Saved {
+        let saved = self.submit(room, sender, text, expires_at);
+        if let Some(trace) = trace.as_deref_mut() {
+            trace.enqueued();
+        }
+        let saved = saved.await.unwrap_or_else(|_| Saved {
+            id: None,
+            sent_at: Utc::now().trunc_subsecs(3),
+        });
+        if let Some(trace) = trace {
+            trace.acked(saved.id);
+        }
+        saved
+    }
+
+    fn spawn_writer(&self, room: &str) -> mpsc::UnboundedSender<Job> {
+        let (jobs, mut queue) = mpsc::unbounded_channel::<Job>();
+        let store = self.store.clone();
+        let quota = self.quota.clone();
+        let pending = self.pending.clone();
+        let room = room.to_string();
+        tokio::spawn(async move {
+            while let Some(job) = queue.recv().await {
+                // Stamped here rather than when queued, so times never run
+                // backwards against the order rows are inserted in
+                let sent_at = Utc::now().trunc_subsecs(3);
+                let id = save_with_retry(&store, &room, &job, sent_at).await;
+                if id.is_some() {
+                    quota.record(&job.sender, sent_at, 1).await;
+                }
+                pending.lock().unwrap().queued.remove(&job.seq);
+                let _ = job.done.send(Saved { id, sent_at });
+            }
+        });
+        jobs
+    }
+}
+
+// Retries with exponential backoff while the database fails. Gives up with
+// `None` after `SAVE_RETRIES` retries
+async fn save_with_retry(
+    store: &Store,
+    room: &str,
+    job: &Job,
+    sent_at: DateTime<Utc>,
+) -> Option<i64> {
+    let mut retries = 0;
+    loop {
+        match store
+            .save_message(room, &job.text, &job.sender, sent_at, job.expires_at)
+            .await
+        {
+            Ok(id) => return Some(id),
+            Err(e) if retries == SAVE_RETRIES => {
+                error!(
+                    "Failed to save message from {} after {} retries: {}",
+                    job.sender, retries, e
+                );
+                return None;
+            }
+            Err(_) => {
+                tokio::time::sleep(SAVE_RETRY_DELAY * 2u32.pow(retries)).await;
+                retries += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_store::MemoryStore;
+    use crate::quota::QuotaKind;
+
+    #[tokio::test]
+    async fn rooms_store_messages_in_queue_order() {
+        let store = Store::new("sqlite::memory:".to_string());
+        store.create_tables().await.unwrap();
+        let queue = SaveQueue::new(
+            store.clone(),
+            Quota::new(store.clone(), QuotaKind::Messages, None),
+        );
+
+        let texts: Vec<String> = (0..100).map(|n| n.to_string()).collect();
+        let pending: Vec<_> = texts
+            .iter()
+            .map(|text| queue.submit("main", "alice", text, None))
+            .collect();
+        let mut saved = Vec::new();
+        for pending in pending {
+            saved.push(pending.await.unwrap());
+        }
+
+        let ids: Vec<i64> = saved.iter().map(|saved| saved.id.unwrap()).collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(
+            saved
+                .windows(2)
+                .all(|pair| pair[0].sent_at <= pair[1].sent_at)
+        );
+
+        let stored = store.get_messages("main").await.unwrap();
+        let stored: Vec<&str> = stored.iter().map(|message| message.text.as_str()).collect();
+        assert_eq!(stored, texts);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_saves_show_up_as_lag() {
+        let store = MemoryStore::new();
+        store.delay_saves(Duration::from_secs(1));
+        let store = Store::Memory(store);
+        let queue = SaveQueue::new(store.clone(), Quota::new(store, QuotaKind::Messages, None));
+        let mut monitor = LagMonitor::new(4, Duration::from_secs(2));
+
+        let pending: Vec<_> = (0..5)
+            .map(|n| queue.submit("main", "alice", &n.to_string(), None))
+            .collect();
+        assert_eq!(queue.lag().depth, 5);
+        assert_eq!(monitor.observe(queue.lag()), Some(LagChange::Degraded));
+
+        // Two saves later the oldest unsaved message has waited two seconds
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        let lag = queue.lag();
+        assert_eq!(lag.depth, 3);
+        assert_eq!(lag.oldest, Duration::from_millis(2500));
+        assert_eq!(monitor.observe(lag), None);
+
+        for pending in pending {
+            pending.await.unwrap();
+        }
+        assert_eq!(queue.lag(), Lag::default());
+        assert_eq!(monitor.observe(queue.lag()), Some(LagChange::Recovered));
+    }
+
+    #[test]
+    fn lag_only_recovers_under_half_the_thresholds() {
+        let mut monitor = LagMonitor::new(10, Duration::from_secs(10));
+        let lag = |depth, secs| Lag {
+            depth,
+            oldest: Duration::from_secs(secs),
+        };
+
+        assert_eq!(monitor.observe(lag(9, 9)), None);
+        assert_eq!(monitor.observe(lag(1, 10)), Some(LagChange::Degraded));
+        assert_eq!(monitor.observe(lag(12, 20)), None);
+        assert_eq!(monitor.observe(lag(9, 1)), None);
+        assert_eq!(monitor.observe(lag(5, 6)), None);
+        assert_eq!(monitor.observe(lag(5, 5)), Some(LagChange::Recovered));
+        assert_eq!(monitor.observe(lag(10, 0)), Some(LagChange::Degraded));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_saves_are_retried_then_given_up() {
+        let store = Store::new("sqlite:///nonexistent/chat.sqlite".to_string());
+        let quota = Quota::new(store.clone(), QuotaKind::Messages, Some(1));
+        let queue = SaveQueue::new(store, quota.clone());
+
+        let started = tokio::time::Instant::now();
+        assert_eq!(
+            queue.save("main", "alice", "hello", None, None).await.id,
+            None
+        );
+        // 100 + 200 + 400 + 800 + 1600 ms of backoff, on top of however long
+        // each attempt took to fail
+        assert!(started.elapsed() >= Duration::from_millis(3100));
+
+        // A message that was never stored doesn't use up the quota
+        assert_eq!(quota.usage("alice").await.used, 0);
+    }
+
+    #[tokio::test]
+    async fn stored_messages_count_against_the_quota() {
+        let store = Store::Memory(MemoryStore::new());
+        let quota = Quota::new(store.clone(), QuotaKind::Messages, Some(1));
+        let queue = SaveQueue::new(store, quota.clone());
+
+        assert!(quota.check("alice", 1).await.is_ok());
+        assert!(
+            queue
+                .save("main", "alice", "hi", None, None)
+                .await
+                .id
+                .is_some()
+        );
+        assert!(quota.check("alice", 1).await.is_err());
+    }
+}
@@ -0,0 +1,95 @@
+use std::borrow::Cow;
+
+const SHRUG: &str = r"¯\_(ツ)_/¯";
+const TABLEFLIP: &str = "(╯°□°）╯︵ ┻━┻";
+const UNFLIP: &str = "┬─┬ノ(º _ ºノ)";
+
+// `/command <text>` shorthands and the face each appends
+const COMMANDS: &[(&str, &str)] = &[
+    ("/shrug", SHRUG),
+    ("/tableflip", TABLEFLIP),
+    ("/unflip", UNFLIP),
+];
+
+// Trailing marker that works like `/shrug` at the end of a message
+const SHRUG_MARKER: &str = "[shrug]";
+
+/// Rewrites `/shrug`, `/tableflip` and `/unflip` messages, and messages
+/// ending in `[shrug]`, into the text followed by the matching face.
+pub fn expand(text: &str) -> Cow<'_, str> {
+    for (command, face) in COMMANDS {
+        if let Some(rest) = strip_command(text, command) {
+            return Cow::Owned(append(rest, face));
+        }
+    }
+
+    // Only as a word of its own, so a URL or code ending in it stays intact
+    match text.strip_suffix(SHRUG_MARKER) {
+        Some(rest) if rest.is_empty() || rest.ends_with(char::is_whitespace) => {
+            Cow::Owned(append(rest.trim_end(), SHRUG))
+        }
+        _ => Cow::Borrowed(text),
+    }
+}
+
+// The text after `command`, as long as the command stands on its own
+fn strip_command<'a>(text: &'a str, command: &str) -> Option<&'a str> {
+    let rest = text.strip_prefix(command)?;
+    if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+        Some(rest.trim())
+    } else {
+        None
+    }
+}
+
+fn append(text: &str, face: &str) -> String {
+    if text.is_empty() {
+        face.to_string()
+    } else {
+        format!("{} {}", text, face)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_append_their_face() {
+        assert_eq!(expand("/shrug"), SHRUG);
+        assert_eq!(expand("/shrug fine"), format!("fine {}", SHRUG));
+        assert_eq!(
+            expand("/tableflip  not again "),
+            format!("not again {}", TABLEFLIP)
+        );
+        assert_eq!(expand("/unflip\tsorry"), format!("sorry {}", UNFLIP));
+    }
+
+    #[test]
+    fn trailing_marker_works_like_shrug() {
+        assert_eq!(expand("[shrug]"), SHRUG);
+        assert_eq!(expand("who knows  [shrug]"), format!("who knows {}", SHRUG));
+    }
+
+    #[test]
+    fn commands_only_match_whole_words() {
+        assert_eq!(expand("/shrugging it off"), "/shrugging it off");
+        assert_eq!(expand("/tableflipper"), "/tableflipper");
+        assert_eq!(expand("me /shrug"), "me /shrug");
+        assert_eq!(expand("who knows[shrug]"), "who knows[shrug]");
+    }
+
+    #[test]
+    fn urls_and_code_are_left_alone() {
+        for text in [
+            "https://example.com/shrug",
+            "https://example.com/search?q=[shrug]",
+            "`/shrug`",
+            "`/tableflip` flips a table",
+            "run `echo [shrug]`",
+            "```\n/shrug\n```",
+        ] {
+            assert!(matches!(expand(text), Cow::Borrowed(_)), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+/// Limits how often something may happen per key, over a sliding window.
+pub struct Throttle<K> {
+    limit: usize,
+    window: Duration,
+    attempts: Arc<RwLock<HashMap<K, VecDeque<Instant>>>>,
+}
+
+// Clones share the attempts, whether or not keys can be cloned
+impl<K> Clone for Throttle<K> {
+    fn clone(&self) -> Self {
+        Self {
+            limit: self.limit,
+            window: self.window,
+            attempts: Arc::clone(&self.attempts),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Send + Sync + 'static> Throttle<K> {
+    /// `limit` must be at least 1.
+    pub fn new(limit: usize, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            attempts: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Records an attempt by `key`. Fails with the time until it may try
+    /// again once it has used up its attempts for the window. Refused
+    /// attempts count too, so a client that keeps hammering stays locked out.
+    pub async fn attempt(&self, key: K) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut attempts = self.attempts.write().await;
+        let recent = attempts.entry(key).or_default();
+        self.forget_old(recent, now);
+        recent.push_back(now);
+
+        if recent.len() <= self.limit {
+            return Ok(());
+        }
+        // Coming back, the key makes one more attempt, so all but the newest
+        // `limit - 1` attempts must have aged out by then
+        let oldest = recent[recent.len() - self.limit];
+        Err(self.window.saturating_sub(now.duration_since(oldest)))
+    }
+
+    /// The time until `key` may try again, when it has used up its attempts
+    /// for the window, without counting this as one.
+    pub async fn retry_after(&self, key: &K) -> Option<Duration> {
+        let now = Instant::now();
+        let mut attempts = self.attempts.write().await;
+        let recent = attempts.get_mut(key)?;
+        self.forget_old(recent, now);
+        if recent.len() < self.limit {
+            return None;
+        }
+        let oldest = recent[recent.len() - self.limit];
+        Some(self.window.saturating_sub(now.duration_since(oldest)))
+    }
+
+    fn forget_old(&self, recent: &mut VecDeque<Instant>, now: Instant) {
+        while recent
+            .front()
+            .is_some_and(|at| now.duration_since(*at) > self.window)
+        {
+            recent.pop_front();
+        }
+    }
+
+    /// Spawns a task that forgets keys with no attempts left in the window.
+    pub fn spawn_pruner(&self) {
+        let throttle = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(throttle.window);
+            loop {
+                interval.tick().await;
+                let mut attempts = throttle.attempts.write().await;
+                attempts.retain(|_, recent| {
+                    recent
+                        .back()
+                        .is_some_and(|at| at.elapsed() <= throttle.window)
+                });
+            }
+        });
+    }
+}
+
+/// Limits how often a single IP address may open connections.
+#[derive(Clone)]
+pub struct ConnectThrottle {
+    // Addresses never throttled; loopback is always exempt
+    allowlist: Arc<Vec<IpAddr>>,
+    attempts: Throttle<IpAddr>,
+}
+
+impl ConnectThrottle {
+    /// `limit` must be at least 1.
+    pub fn new(limit: usize, window: Duration, allowlist: Vec<IpAddr>) -> Self {
+        Self {
+            allowlist: Arc::new(allowlist),
+            attempts: Throttle::new(limit, window),
+        }
+    }
+
+    /// Records a connection attempt from `ip`, as [`Throttle::attempt`] does.
+    pub async fn attempt(&self, ip: IpAddr) -> Result<(), Duration> {
+        if ip.is_loopback() || self.allowlist.contains(&ip) {
+            return Ok(());
+        }
+        self.attempts.attempt(ip).await
+    }
+
+    /// Spawns a task that forgets addresses with no attempts left in the window.
+    pub fn spawn_pruner(&self) {
+        self.attempts.spawn_pruner();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 7));
+    const OTHER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 8));
+
+    #[tokio::test]
+    async fn rapid_connects_from_one_address_are_refused() {
+        let throttle = ConnectThrottle::new(3, Duration::from_secs(60), Vec::new());
+
+        for _ in 0..3 {
+            assert!(throttle.attempt(CLIENT).await.is_ok());
+        }
+        let retry_after = throttle.attempt(CLIENT).await.unwrap_err();
+        assert!(retry_after > Duration::from_secs(59));
+        assert!(retry_after <= Duration::from_secs(60));
+
+        // Other addresses have their own budget
+        assert!(throttle.attempt(OTHER).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn returning_when_told_is_allowed() {
+        let window = Duration::from_millis(200);
+        let throttle = ConnectThrottle::new(2, window, Vec::new());
+
+        assert!(throttle.attempt(CLIENT).await.is_ok());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(throttle.attempt(CLIENT).await.is_ok());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let retry_after = throttle.attempt(CLIENT).await.unwrap_err();
+
+        tokio::time::sleep(retry_after + Duration::from_millis(10)).await;
+        assert!(throttle.attempt(CLIENT).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn exempt_addresses_are_never_refused() {
+        let throttle = ConnectThrottle::new(1, Duration::from_secs(60), vec![CLIENT]);
+        let loopback = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+        for _ in 0..10 {
+            assert!(throttle.attempt(CLIENT).await.is_ok());
+            assert!(throttle.attempt(loopback).await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_after_looks_without_counting() {
+        let throttle = Throttle::new(2, Duration::from_secs(60));
+        assert_eq!(throttle.retry_after(&7).await, None);
+        assert!(throttle.attempt(7).await.is_ok());
+        // Looking doesn't use up an attempt
+        for _ in 0..5 {
+            assert_eq!(throttle.retry_after(&7).await, None);
+        }
+        assert!(throttle.attempt(7).await.is_ok());
+
+        let retry_after = throttle.retry_after(&7).await.unwrap();
+        assert!(retry_after > Duration::from_secs(59));
+        assert!(retry_after <= Duration::from_secs(60));
+        assert_eq!(throttle.retry_after(&8).await, None);
+    }
+}
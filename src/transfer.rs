@@ -0,0 +1,222 @@
+use std::fmt;
+use tokio::time::Instant;
+
+/// Bytes at the start of every chunk frame: its 0-based chunk number,
+/// big-endian.
+pub const CHUNK_HEADER: usize = 4;
+
+// Longest file name a transfer may declare, in bytes
+const MAX_FILENAME_LEN: usize = 255;
+
+/// A file being received in numbered chunks after a `SendFile` frame
+/// declared it.
+pub struct Transfer {
+    pub filename: String,
+    pub size: u64,
+    /// Name of the user the file is for; the whole room when None.
+    pub to: Option<String>,
+    chunks: u32,
+    received: u32,
+    data: Vec<u8>,
+    /// When the transfer started or last got a chunk, for the stall timeout.
+    pub last_chunk: Instant,
+}
+
+/// Why a transfer was refused or abandoned.
+#[derive(Debug, PartialEq)]
+pub enum TransferError {
+    BadFilename,
+    /// The declared size is over the server's limit.
+    TooLarge {
+        max: u64,
+    },
+    /// More chunks than bytes, or none at all.
+    BadChunkCount,
+    /// A frame too short to carry a chunk number.
+    BadChunk,
+    OutOfOrder {
+        expected: u32,
+        got: u32,
+    },
+    /// The chunks carried more, or fewer, bytes than declared.
+    SizeMismatch {
+        declared: u64,
+        received: u64,
+    },
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::BadFilename => write!(
+                f,
+                "file names must be 1 to {} bytes without path separators",
+                MAX_FILENAME_LEN
+            ),
+            TransferError::TooLarge { max } => write!(f, "files can be at most {} bytes", max),
+            TransferError::BadChunkCount => {
+                write!(f, "a file needs between 1 chunk and one per byte")
+            }
+            TransferError::BadChunk => write!(
+                f,
+                "chunks must start with a {}-byte chunk number",
+                CHUNK_HEADER
+            ),
+            TransferError::OutOfOrder { expected, got } => {
+                write!(f, "expected chunk {}, got chunk {}", expected, got)
+            }
+            TransferError::SizeMismatch { declared, received } => write!(
+                f,
+                "{} bytes were declared but {} were sent",
+                declared, received
+            ),
+        }
+    }
+}
+
+impl Transfer {
+    /// Checks a declared transfer against the server's limit and starts it.
+    pub fn begin(
+        filename: &str,
+        size: u64,
+        chunks: u32,
+        to: Option<String>,
+        max_size: u64,
+    ) -> Result<Self, TransferError> {
+        if filename.is_empty()
+            || filename.len() > MAX_FILENAME_LEN
+            || filename.contains(['/', '\\'])
+        {
+            return Err(TransferError::BadFilename);
+        }
+        if size > max_size {
+            return Err(TransferError::TooLarge { max: max_size });
+        }
+        if chunks == 0 || u64::from(chunks) > size.max(1) {
+            return Err(TransferError::BadChunkCount);
+        }
+        Ok(Self {
+            filename: filename.to_string(),
+            size,
+            to,
+            chunks,
+            received: 0,
+            data: Vec::new(),
+            last_chunk: Instant::now(),
+        })
+    }
+
+    /// Takes the next chunk frame. Returns the whole file once the last
+    /// chunk is in; chunks must arrive in order.
+    pub fn push(&mut self, frame: &[u8]) -> Result<Option<Vec<u8>>, TransferError> {
+        let Some((number, bytes)) = frame.split_first_chunk::<CHUNK_HEADER>() else {
+            return Err(TransferError::BadChunk);
+        };
+        let number = u32::from_be_bytes(*number);
+        if number != self.received {
+            return Err(TransferError::OutOfOrder {
+                expected: self.received,
+                got: number,
+            });
+        }
+
+        let received = (self.data.len() + bytes.len()) as u64;
+        if received > self.size {
+            return Err(TransferError::SizeMismatch {
+                declared: self.size,
+                received,
+            });
+        }
+        self.data.extend_from_slice(bytes);
+        self.received += 1;
+        self.last_chunk = Instant::now();
+
+        if self.received < self.chunks {
+            return Ok(None);
+        }
+        if received != self.size {
+            return Err(TransferError::SizeMismatch {
+                declared: self.size,
+                received,
+            });
+        }
+        Ok(Some(std::mem::take(&mut self.data)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(number: u32, bytes: &[u8]) -> Vec<u8> {
+        let mut frame = number.to_be_bytes().to_vec();
+        frame.extend_from_slice(bytes);
+        frame
+    }
+
+    #[tokio::test]
+    async fn chunks_are_reassembled_in_order() {
+        let mut transfer = Transfer::begin("notes.txt", 11, 3, None, 100).unwrap();
+        assert_eq!(transfer.push(&chunk(0, b"hello")), Ok(None));
+        assert_eq!(transfer.push(&chunk(1, b" ")), Ok(None));
+        assert_eq!(
+            transfer.push(&chunk(2, b"world")),
+            Ok(Some(b"hello world".to_vec()))
+        );
+    }
+
+    #[tokio::test]
+    async fn declarations_are_checked() {
+        let begin = |filename, size, chunks| Transfer::begin(filename, size, chunks, None, 100);
+        assert_eq!(
+            begin("big.bin", 101, 1).err(),
+            Some(TransferError::TooLarge { max: 100 })
+        );
+        assert_eq!(
+            begin("../etc/passwd", 10, 1).err(),
+            Some(TransferError::BadFilename)
+        );
+        assert_eq!(begin("", 10, 1).err(), Some(TransferError::BadFilename));
+        assert_eq!(
+            begin("a.txt", 10, 0).err(),
+            Some(TransferError::BadChunkCount)
+        );
+        assert_eq!(
+            begin("a.txt", 10, 11).err(),
+            Some(TransferError::BadChunkCount)
+        );
+        // An empty file still takes one, empty, chunk
+        let mut empty = begin("empty.txt", 0, 1).unwrap();
+        assert_eq!(empty.push(&chunk(0, b"")), Ok(Some(Vec::new())));
+    }
+
+    #[tokio::test]
+    async fn bad_chunks_abandon_the_transfer() {
+        let mut transfer = Transfer::begin("a.txt", 4, 2, None, 100).unwrap();
+        assert_eq!(transfer.push(&[0, 0]), Err(TransferError::BadChunk));
+        assert_eq!(
+            transfer.push(&chunk(1, b"ab")),
+            Err(TransferError::OutOfOrder {
+                expected: 0,
+                got: 1
+            })
+        );
+        assert_eq!(
+            transfer.push(&chunk(0, b"abcde")),
+            Err(TransferError::SizeMismatch {
+                declared: 4,
+                received: 5
+            })
+        );
+
+        let mut short = Transfer::begin("a.txt", 4, 2, None, 100).unwrap();
+        assert_eq!(short.push(&chunk(0, b"a")), Ok(None));
+        assert_eq!(
+            short.push(&chunk(1, b"b")),
+            Err(TransferError::SizeMismatch {
+                declared: 4,
+                received: 2
+            })
+        );
+    }
+}
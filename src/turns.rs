@@ -0,0 +1,84 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::watch;
+
+/// Hands a connection's frames turns, so they are handled one at a time in
+/// the order they were read. wynd can run two read loops for one connection,
+/// and without turns two frames sent back to back could be handled at once,
+/// and their messages stored out of order.
+#[derive(Clone)]
+pub struct Turns {
+    next: Arc<AtomicU64>,
+    serving: Arc<watch::Sender<u64>>,
+}
+
+/// A frame's place in line. The next frame's turn comes once this is dropped.
+pub struct Turn {
+    number: u64,
+    serving: Arc<watch::Sender<u64>>,
+}
+
+impl Turns {
+    pub fn new() -> Self {
+        Self {
+            next: Arc::new(AtomicU64::new(0)),
+            serving: Arc::new(watch::Sender::new(0)),
+        }
+    }
+
+    /// Puts a frame in line. Call it as wynd hands the frame over, before
+    /// anything awaits, since that is what keeps the line in read order.
+    pub fn take(&self) -> Turn {
+        Turn {
+            number: self.next.fetch_add(1, Ordering::Relaxed),
+            serving: Arc::clone(&self.serving),
+        }
+    }
+}
+
+impl Turn {
+    /// Waits until every frame ahead of this one has been handled.
+    pub async fn wait(&self) {
+        let mut serving = self.serving.subscribe();
+        // The sender lives as long as this turn, so this can't fail
+        let _ = serving.wait_for(|serving| *serving == self.number).await;
+    }
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        self.serving.send_modify(|serving| *serving += 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn frames_are_handled_in_the_order_their_turns_were_taken() {
+        let turns = Turns::new();
+        let handled = Arc::new(Mutex::new(Vec::new()));
+
+        let tasks: Vec<_> = (0..5)
+            .map(|n| {
+                let turn = turns.take();
+                let handled = Arc::clone(&handled);
+                // Later frames are quicker, so they'd finish first unheld
+                let work = Duration::from_millis(50 - 10 * n);
+                tokio::spawn(async move {
+                    turn.wait().await;
+                    tokio::time::sleep(work).await;
+                    handled.lock().unwrap().push(n);
+                })
+            })
+            .collect();
+        for task in tasks.into_iter().rev() {
+            task.await.unwrap();
+        }
+
+        assert_eq!(*handled.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,104 @@
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use std::time::Duration;
+
+/// Spells out a duration in days, hours, minutes and seconds, such as
+/// `3 days, 7 hours, 22 minutes, 15 seconds`. Units that are zero are left
+/// out, except for a duration under a second, which is `0 seconds`.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let units = [
+        (secs / 86_400, "day"),
+        (secs / 3_600 % 24, "hour"),
+        (secs / 60 % 60, "minute"),
+        (secs % 60, "second"),
+    ];
+
+    let parts: Vec<String> = units
+        .iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, unit)| {
+            let plural = if *count == 1 { "" } else { "s" };
+            format!("{} {}{}", count, unit, plural)
+        })
+        .collect();
+    if parts.is_empty() {
+        return "0 seconds".to_string();
+    }
+    parts.join(", ")
+}
+
+/// Hashes a password with Argon2 and a fresh salt, into a PHC string that
+/// `verify_password` checks against. Argon2 is slow on purpose, so this runs
+/// on the blocking pool rather than holding up a runtime worker.
+pub async fn hash_password(password: String) -> Result<String, argon2::password_hash::Error> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>())?;
+        let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+        Ok(hash.to_string())
+    })
+    .await
+    .expect("password hashing panicked")
+}
+
+/// Whether `password` is the one `hash` was made from. A malformed hash
+/// matches nothing. Runs on the blocking pool, like `hash_password`.
+pub async fn verify_password(password: String, hash: String) -> bool {
+    tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    })
+    .await
+    .expect("password verification panicked")
+}
+
+/// Whether `a` and `b` are equal, taking as long whatever they hold, so the
+/// time a wrong guess takes doesn't give away how much of it was right. Only
+/// the lengths can be told apart.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_are_spelled_out_without_zero_units() {
+        let uptime = Duration::from_secs(3 * 86_400 + 7 * 3_600 + 22 * 60 + 15);
+        assert_eq!(
+            format_duration(uptime),
+            "3 days, 7 hours, 22 minutes, 15 seconds"
+        );
+        assert_eq!(
+            format_duration(Duration::from_secs(3_601)),
+            "1 hour, 1 second"
+        );
+        assert_eq!(format_duration(Duration::from_millis(59_900)), "59 seconds");
+        assert_eq!(format_duration(Duration::from_millis(400)), "0 seconds");
+    }
+
+    #[tokio::test]
+    async fn passwords_verify_against_their_salted_hash() {
+        let password = || "open sesame".to_string();
+        let hash = hash_password(password()).await.unwrap();
+        assert!(!hash.contains("open sesame"));
+        assert!(verify_password(password(), hash.clone()).await);
+        assert!(!verify_password("open sesame!".to_string(), hash.clone()).await);
+        assert!(!verify_password(password(), "not a hash".to_string()).await);
+
+        // Salted, so the same password never hashes the same way twice
+        assert_ne!(hash_password(password()).await.unwrap(), hash);
+    }
+
+    #[test]
+    fn constant_time_eq_compares_whole_strings() {
+        assert!(constant_time_eq(b"correct horse", b"correct horse"));
+        assert!(!constant_time_eq(b"correct horse", b"correct horsf"));
+        assert!(!constant_time_eq(b"correct horse", b"correct"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
@@ -0,0 +1,70 @@
+use serde::Serialize;
+use std::time::Duration;
+use tracing::{error, warn};
+
+// Retries after the first failed POST, doubling the wait each time
+const MAX_RETRIES: u32 = 3;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+// Upper bound on a single POST so a stalled endpoint can't pin the task forever
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Body POSTed to the webhook for every saved chat message.
+#[derive(Serialize)]
+pub struct ChatEvent {
+    pub namespace: String,
+    pub room: String,
+    pub sender: String,
+    pub text: String,
+    pub timestamp: String,
+}
+
+/// Forwards chat messages to an external URL.
+#[derive(Clone)]
+pub struct Webhook {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl Webhook {
+    pub fn new(url: String) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .unwrap();
+        Self { client, url }
+    }
+
+    /// Delivers the event in the background; failures are only logged so they
+    /// never hold up the chat itself.
+    pub fn deliver(&self, event: ChatEvent) {
+        let webhook = self.clone();
+        tokio::spawn(async move { webhook.post(&event).await });
+    }
+
+    async fn post(&self, event: &ChatEvent) {
+        let mut backoff = INITIAL_BACKOFF;
+
+        for attempt in 0..=MAX_RETRIES {
+            let result = self
+                .client
+                .post(&self.url)
+                .json(event)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+
+            match result {
+                Ok(_) => return,
+                Err(e) => warn!("Webhook delivery failed (attempt {}): {}", attempt + 1, e),
+            }
+
+            if attempt < MAX_RETRIES {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+
+        error!("Giving up on webhook delivery to {}", self.url);
+    }
+}
@@ -0,0 +1,104 @@
+mod integration;
+
+use integration::{TestClient, spawn_test_server_with};
+
+const ADMIN_PASSWORD: &str = "correct horse";
+
+async fn spawn_server() -> u16 {
+    let (port, _server) = spawn_test_server_with(&["--admin-password", ADMIN_PASSWORD]).await;
+    port
+}
+
+async fn admin(port: u16, name: &str) -> TestClient {
+    let mut client = TestClient::connect(port).await;
+    client.register(name).await;
+    client
+        .send_text(&format!("/admin {}", ADMIN_PASSWORD))
+        .await;
+    client.recv_data("You are now an admin").await;
+    client
+}
+
+// Reads the next audit entry, without its timestamp
+async fn recv_entry(client: &mut TestClient) -> String {
+    let message = client.recv_message().await;
+    let data = message["data"].as_str().unwrap();
+    // `YYYY-MM-DD HH:MM:SS ` comes first
+    data.splitn(3, ' ').nth(2).unwrap().to_string()
+}
+
+#[tokio::test]
+async fn pinning_is_audited_with_actor_and_target() {
+    let port = spawn_server().await;
+    let mut alice = admin(port, "alice").await;
+    alice.send_text("/audit").await;
+    alice.recv_data("The audit log is empty").await;
+
+    alice.send_text("read the rules").await;
+    let echo = alice.recv_data("Me: read the rules").await;
+    let id = echo["id"].as_i64().unwrap();
+    alice.send_text(&format!("/pin {}", id)).await;
+    alice.recv_data("alice: read the rules").await;
+    alice.send_text("/topic lock").await;
+    alice.recv_data("The topic of main is now locked").await;
+
+    alice.send_text("/audit").await;
+    assert_eq!(
+        recv_entry(&mut alice).await,
+        format!("alice pin message {}", id)
+    );
+    assert_eq!(recv_entry(&mut alice).await, "alice lock topic main");
+
+    alice.send_text("/audit 1").await;
+    assert_eq!(recv_entry(&mut alice).await, "alice lock topic main");
+}
+
+#[tokio::test]
+async fn only_admins_can_read_the_audit_log() {
+    let port = spawn_server().await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    bob.send_text("/audit").await;
+    let error = bob.recv_data("Only admins can use /audit").await;
+    assert_eq!(error["message_type"]["Error"]["code"], "Unauthorized");
+}
+
+// Asks for the room's events and returns them without their timestamps
+async fn events(client: &mut TestClient, command: &str) -> Vec<String> {
+    client.send_text(command).await;
+    let message = client.recv_message().await;
+    let data = message["data"].as_str().unwrap();
+    let lines = data.strip_prefix("Events in main:\n").unwrap();
+    lines
+        .lines()
+        .map(|line| line.splitn(3, ' ').nth(2).unwrap().to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn room_events_record_joins_leaves_and_topic_changes() {
+    let port = spawn_server().await;
+    let mut alice = admin(port, "alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+    bob.send_text("/events").await;
+    bob.recv_data("Only admins can use /events").await;
+    bob.send_text("/topic release day").await;
+    alice
+        .recv_data("bob set the topic of main to: release day")
+        .await;
+    bob.close().await;
+    alice.recv_data("bob left the chat!").await;
+
+    assert_eq!(
+        events(&mut alice, "/events").await,
+        [
+            "alice joined",
+            "bob joined",
+            "bob changed the topic",
+            "bob left"
+        ]
+    );
+    assert_eq!(events(&mut alice, "/events 1").await, ["bob left"]);
+}
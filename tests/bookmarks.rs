@@ -0,0 +1,90 @@
+mod integration;
+
+use integration::{TestClient, spawn_test_server, spawn_test_server_with};
+use serde_json::Value;
+
+// Reads the next frame as a Bookmarks list
+async fn recv_bookmarks(client: &mut TestClient) -> Vec<Value> {
+    let message = client.recv_message().await;
+    message["message_type"]["Bookmarks"]["bookmarks"]
+        .as_array()
+        .unwrap()
+        .clone()
+}
+
+#[tokio::test]
+async fn bookmarks_are_shared_with_the_room_and_sent_on_join() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+    alice.recv_data("bob joined the chat!").await;
+
+    alice
+        .send_text("/bookmark add https://docs.example.com API docs")
+        .await;
+    let bookmarks = recv_bookmarks(&mut bob).await;
+    assert_eq!(bookmarks.len(), 1);
+    assert_eq!(bookmarks[0]["url"], "https://docs.example.com");
+    assert_eq!(bookmarks[0]["title"], "API docs");
+    assert_eq!(bookmarks[0]["added_by"], "alice");
+    assert_eq!(recv_bookmarks(&mut alice).await.len(), 1);
+
+    // Without a title the URL stands in for one
+    bob.send_text("/bookmark add https://example.com").await;
+    let bookmarks = recv_bookmarks(&mut bob).await;
+    assert_eq!(bookmarks[1]["title"], "https://example.com");
+
+    let mut carol = TestClient::connect(port).await;
+    assert_eq!(recv_bookmarks(&mut carol).await.len(), 2);
+    let history = carol.recv_message().await;
+    assert!(history["message_type"]["PastMessages"].is_object());
+
+    let id = bookmarks[0]["id"].as_i64().unwrap();
+    bob.send_text(&format!("/bookmark remove {}", id)).await;
+    let bookmarks = recv_bookmarks(&mut bob).await;
+    assert_eq!(bookmarks.len(), 1);
+    assert_eq!(bookmarks[0]["url"], "https://example.com");
+
+    bob.send_text(&format!("/bookmark remove {}", id)).await;
+    bob.recv_data(&format!("No bookmark {} in main", id)).await;
+    bob.send_text("/bookmark list").await;
+    assert_eq!(recv_bookmarks(&mut bob).await.len(), 1);
+}
+
+#[tokio::test]
+async fn bookmark_urls_are_validated() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+
+    let refusal = "Bookmarks must be http or https URLs of at most 2048 characters";
+    alice.send_text("/bookmark add ftp://example.com").await;
+    alice.recv_data(refusal).await;
+    alice.send_text("/bookmark add https://").await;
+    alice.recv_data(refusal).await;
+    let long = format!("https://example.com/{}", "a".repeat(2048));
+    alice.send_text(&format!("/bookmark add {}", long)).await;
+    alice.recv_data(refusal).await;
+
+    alice.send_text("/bookmark add").await;
+    alice.recv_data(refusal).await;
+    alice.send_text("/bookmark").await;
+    alice
+        .recv_data("Usage: /bookmark add <url> [title], /bookmark list or /bookmark remove <id>")
+        .await;
+}
+
+#[tokio::test]
+async fn bookmarks_stop_at_the_room_cap() {
+    let (port, _server) = spawn_test_server_with(&["--max-bookmarks", "1"]).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+
+    alice.send_text("/bookmark add https://example.com").await;
+    recv_bookmarks(&mut alice).await;
+    alice.send_text("/bookmark add https://example.org").await;
+    let refused = alice.recv_data("main already has 1 bookmarks").await;
+    assert_eq!(refused["message_type"]["Error"]["code"], "CapacityReached");
+}
@@ -0,0 +1,897 @@
+mod integration;
+
+use integration::{TestClient, spawn_test_server, spawn_test_server_with};
+use std::time::Duration;
+
+// How long a vanished connection may take to be noticed
+const CLEANUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[tokio::test]
+async fn first_message_registers_the_name() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+
+    let history = alice.recv_message().await;
+    assert_eq!(
+        history["message_type"]["PastMessages"]["days"],
+        serde_json::json!([])
+    );
+    let prompt = alice.recv_message().await;
+    assert_eq!(prompt["message_type"], "Welcome");
+    assert_eq!(prompt["data"], "Welcome! Please enter your name:");
+
+    alice.send_text("alice").await;
+    let welcome = alice.recv_message().await;
+    assert_eq!(welcome["message_type"], "Welcome");
+    assert_eq!(
+        welcome["data"],
+        "Welcome, alice! You can start chatting now."
+    );
+}
+
+#[tokio::test]
+async fn taken_names_are_rejected() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut impostor = TestClient::connect(port).await;
+
+    impostor.send_text("alice").await;
+    let error = impostor
+        .recv_data("The name alice is already taken. Please enter another name:")
+        .await;
+    assert_eq!(error["message_type"]["Error"]["code"], "NameTaken");
+
+    // The rejected connection can still pick a free name
+    impostor.register("bob").await;
+}
+
+#[tokio::test]
+async fn renaming_to_a_taken_name_is_rejected() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    bob.send_text("/nick alice").await;
+    let error = bob.recv_data("The name alice is already taken").await;
+    assert_eq!(error["message_type"]["Error"]["code"], "NameTaken");
+}
+
+#[tokio::test]
+async fn chat_reaches_room_peers() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    alice.send_text("hello bob").await;
+
+    let echo = alice.recv_data("Me: hello bob").await;
+    let received = bob.recv_data("alice: hello bob").await;
+    assert_eq!(received["message_type"], "Chat");
+    assert_eq!(received["id"], echo["id"]);
+}
+
+#[tokio::test]
+async fn closing_frees_the_name() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    alice.close().await;
+
+    // The leave notice goes out once the server has forgotten alice
+    bob.recv_data("alice left the chat!").await;
+    bob.send_text("/whois alice").await;
+    bob.recv_data("alice is offline. No previous names.").await;
+    bob.send_text("/nick alice").await;
+    bob.recv_data("bob is now known as alice").await;
+}
+
+#[tokio::test]
+async fn vanished_connections_are_cleaned_up() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+    // Gone without a Close frame
+    drop(bob);
+
+    // Only writing to bob reveals that he is gone
+    tokio::time::timeout(CLEANUP_TIMEOUT, async {
+        for n in 0.. {
+            alice.send_text(&format!("anyone there? {}", n)).await;
+            let message = alice.recv_message().await;
+            if message["data"] == "bob left the chat!" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("bob was never cleaned up");
+
+    alice.send_text("/nick bob").await;
+    alice.recv_data("alice is now known as bob").await;
+}
+
+#[tokio::test]
+async fn observers_skip_the_name_prompt() {
+    let (port, _server) = spawn_test_server().await;
+    let mut observer = TestClient::connect(port).await;
+    observer.send_text(r#"{"Observe":{"room":null}}"#).await;
+
+    observer.recv_data("Observing main (read-only)").await;
+    let history = observer.recv_message().await;
+    assert!(history["message_type"]["PastMessages"].is_object());
+
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    alice.send_text("hello").await;
+
+    // The chat arrives with no name prompt or join notice before it; observers
+    // aren't members, so nobody announces them
+    let next = observer.recv_message().await;
+    assert_eq!(next["data"], "alice joined the chat!");
+    let next = observer.recv_message().await;
+    assert_eq!(next["data"], "alice: hello");
+}
+
+#[tokio::test]
+async fn hello_shapes_the_only_history_replay() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice
+        .send_text(r#"{"Hello":{"utc_offset_minutes":120}}"#)
+        .await;
+
+    let history = alice.recv_message().await;
+    assert!(history["message_type"]["PastMessages"].is_object());
+    let prompt = alice.recv_message().await;
+    assert_eq!(prompt["data"], "Welcome! Please enter your name:");
+
+    alice
+        .send_text(r#"{"Hello":{"utc_offset_minutes":-60}}"#)
+        .await;
+    alice.send_text("alice").await;
+    let welcome = alice.recv_message().await;
+    assert_eq!(
+        welcome["data"],
+        "Welcome, alice! You can start chatting now."
+    );
+}
+
+// Connects with `hello` and returns the texts of the history it replays, or
+// None when it replays none
+async fn replayed(port: u16, hello: &str) -> Option<Vec<String>> {
+    let mut client = TestClient::connect(port).await;
+    client.send_text(hello).await;
+    let first = client.recv_message().await;
+    let days = first["message_type"]["PastMessages"]["days"].as_array()?;
+    let texts = days
+        .iter()
+        .flat_map(|day| day["messages"].as_array().unwrap())
+        .map(|message| message["data"].as_str().unwrap().to_string())
+        .collect();
+    Some(texts)
+}
+
+#[tokio::test]
+async fn hello_picks_how_much_history_is_replayed() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut ids = Vec::new();
+    for text in ["one", "two", "three"] {
+        alice.send_text(text).await;
+        let sent = alice.recv_data(&format!("Me: {}", text)).await;
+        ids.push(sent["id"].as_i64().unwrap());
+    }
+
+    let all = replayed(port, r#"{"Hello":{"utc_offset_minutes":0}}"#).await;
+    assert_eq!(all.unwrap().len(), 3);
+    let none = r#"{"Hello":{"utc_offset_minutes":0,"history":{"mode":"none"}}}"#;
+    assert_eq!(replayed(port, none).await, None);
+    let recent = r#"{"Hello":{"utc_offset_minutes":0,"history":{"mode":"recent","limit":1}}}"#;
+    assert_eq!(replayed(port, recent).await.unwrap(), ["alice: three"]);
+
+    let since = format!(
+        r#"{{"Hello":{{"utc_offset_minutes":0,"history":{{"mode":"since","since_id":{}}}}}}}"#,
+        ids[0]
+    );
+    let since = replayed(port, &since).await.unwrap();
+    assert_eq!(since, ["alice: two", "alice: three"]);
+    // An id the room doesn't have replays the most recent messages instead
+    let unknown =
+        r#"{"Hello":{"utc_offset_minutes":0,"history":{"mode":"since","since_id":999,"limit":2}}}"#;
+    let unknown = replayed(port, unknown).await.unwrap();
+    assert_eq!(unknown, ["alice: two", "alice: three"]);
+
+    // Only the messages missing from a scattered set are replayed
+    let unseen = format!(
+        r#"{{"Hello":{{"utc_offset_minutes":0,"history":{{"mode":"unseen","seen_ids":[{},{}]}}}}}}"#,
+        ids[0], ids[2]
+    );
+    assert_eq!(replayed(port, &unseen).await.unwrap(), ["alice: two"]);
+}
+
+#[tokio::test]
+async fn replays_stop_at_the_servers_maximum() {
+    let (port, _server) = spawn_test_server_with(&["--max-replay", "2"]).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    for text in ["one", "two", "three"] {
+        alice.send_text(text).await;
+        alice.recv_data(&format!("Me: {}", text)).await;
+    }
+
+    let greedy = r#"{"Hello":{"utc_offset_minutes":0,"history":{"mode":"recent","limit":50}}}"#;
+    let mut bob = TestClient::connect(port).await;
+    bob.send_text(greedy).await;
+    let history = bob.recv_message().await;
+    let days = history["message_type"]["PastMessages"]["days"]
+        .as_array()
+        .unwrap();
+    let texts: Vec<_> = days
+        .iter()
+        .flat_map(|day| day["messages"].as_array().unwrap())
+        .map(|message| message["data"].as_str().unwrap())
+        .collect();
+    assert_eq!(texts, ["alice: two", "alice: three"]);
+    let notice = bob.recv_message().await;
+    assert_eq!(
+        notice["data"],
+        "History truncated to the newest 2 messages; 1 older ones were not sent"
+    );
+
+    // Commands that replay history are held to it too
+    alice.send_text("/from alice 3").await;
+    alice
+        .recv_data("History truncated to the newest 2 messages; 1 older ones were not sent")
+        .await;
+}
+
+#[tokio::test]
+async fn joining_mid_conversation_sees_each_message_once_in_order() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let texts: Vec<String> = (0..300).map(|n| format!("m{}", n)).collect();
+
+    let writer = tokio::spawn({
+        let texts = texts.clone();
+        async move {
+            for text in &texts {
+                alice.send_text(text).await;
+                alice.recv_data(&format!("Me: {}", text)).await;
+            }
+        }
+    });
+    // Bob joins while alice is still writing, so some of her messages are
+    // broadcast while his history is being replayed
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let mut bob = TestClient::connect(port).await;
+
+    let last = "alice: m299";
+    let mut seen: Vec<String> = Vec::new();
+    while seen.last().map(String::as_str) != Some(last) {
+        let message = bob.recv_message().await;
+        if let Some(days) = message["message_type"]["PastMessages"]["days"].as_array() {
+            let replayed = days
+                .iter()
+                .flat_map(|day| day["messages"].as_array().unwrap())
+                .map(|message| message["data"].as_str().unwrap().to_string());
+            seen.extend(replayed);
+        } else if message["message_type"] == "Chat" {
+            seen.push(message["data"].as_str().unwrap().to_string());
+        }
+    }
+    writer.await.unwrap();
+
+    let expected: Vec<String> = texts
+        .iter()
+        .map(|text| format!("alice: {}", text))
+        .collect();
+    assert_eq!(seen, expected);
+}
+
+#[tokio::test]
+async fn oversized_lifetimes_are_refused() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+
+    alice.send_text("/ephemeral 106751991167300d boom").await;
+    alice
+        .recv_data("Usage: /ephemeral <ttl> <message>, e.g. /ephemeral 1h hello (at most 365d)")
+        .await;
+
+    // The handler survived and still accepts a sane lifetime
+    alice.send_text("/ephemeral 365d still here").await;
+    let echo = alice.recv_data("Me: still here").await;
+    assert!(echo["expires_at"].is_i64());
+}
+
+#[tokio::test]
+async fn reconnecting_under_the_old_name_keeps_the_cooldown() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    alice.send_text("/nick bob").await;
+    alice.recv_data("alice is now known as bob").await;
+
+    let mut again = TestClient::connect(port).await;
+    again.register_again("alice").await;
+    again.send_text("/nick carol").await;
+    let error = again.recv_message().await;
+    assert_eq!(error["message_type"]["Error"]["code"], "NameChangeCooldown");
+}
+
+#[tokio::test]
+async fn third_identical_message_is_suppressed() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    for _ in 0..3 {
+        alice.send_text("spam").await;
+    }
+    alice.recv_data("bob joined the chat!").await;
+
+    // Frames are handled concurrently, so the notice may overtake an echo
+    let mut replies = Vec::new();
+    for _ in 0..3 {
+        replies.push(alice.recv_message().await["data"].clone());
+    }
+    replies.sort_by_key(|data| data.to_string());
+    assert_eq!(
+        replies,
+        ["Duplicate message suppressed", "Me: spam", "Me: spam"]
+    );
+
+    // Bob sees exactly two copies: the next thing after them is new text
+    bob.recv_data("alice: spam").await;
+    bob.recv_data("alice: spam").await;
+    alice.send_text("something else").await;
+    let next = bob.recv_message().await;
+    assert_eq!(next["data"], "alice: something else");
+}
+
+#[tokio::test]
+async fn from_returns_only_that_senders_messages() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    for text in ["first", "second", "third"] {
+        alice.send_text(text).await;
+        alice.recv_data(&format!("Me: {}", text)).await;
+        bob.send_text(&format!("reply to {}", text)).await;
+        bob.recv_data(&format!("Me: reply to {}", text)).await;
+    }
+
+    bob.send_text("/from alice 2").await;
+    let history = bob.recv_message().await;
+    let days = history["message_type"]["PastMessages"]["days"]
+        .as_array()
+        .unwrap();
+    let texts: Vec<_> = days
+        .iter()
+        .flat_map(|day| day["messages"].as_array().unwrap())
+        .map(|message| message["data"].as_str().unwrap())
+        .collect();
+    assert_eq!(texts, ["alice: second", "alice: third"]);
+
+    // Unknown senders have simply said nothing
+    bob.send_text("/from nobody").await;
+    let history = bob.recv_message().await;
+    assert_eq!(
+        history["message_type"]["PastMessages"]["days"],
+        serde_json::json!([])
+    );
+}
+
+#[tokio::test]
+async fn resent_messages_are_acknowledged_but_posted_once() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+    alice.recv_data("bob joined the chat!").await;
+
+    let send = r#"{"Send":{"text":"hello","client_msg_id":"m1"}}"#;
+    alice.send_text(send).await;
+    alice.send_text(send).await;
+
+    // Frames are handled concurrently, so the echo may come between the acks
+    let mut replies = Vec::new();
+    for _ in 0..3 {
+        replies.push(alice.recv_message().await);
+    }
+    let acks: Vec<_> = replies
+        .iter()
+        .filter_map(|reply| reply["message_type"]["Ack"].as_object())
+        .collect();
+    assert_eq!(acks.len(), 2);
+    assert!(acks.iter().all(|ack| ack["client_msg_id"] == "m1"));
+    assert!(replies.iter().any(|reply| reply["data"] == "Me: hello"));
+
+    // Bob sees it once: the next thing after it is new text
+    bob.recv_data("alice: hello").await;
+    alice.send_text("something else").await;
+    let next = bob.recv_message().await;
+    assert_eq!(next["data"], "alice: something else");
+}
+
+#[tokio::test]
+async fn repeats_without_a_client_msg_id_are_posted_again() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+    alice.recv_data("bob joined the chat!").await;
+
+    // Nothing says the second is a resend, so both are posted
+    let send = r#"{"Send":{"text":"lol"}}"#;
+    alice.send_text(send).await;
+    bob.recv_data("alice: lol").await;
+    alice.send_text(send).await;
+    bob.recv_data("alice: lol").await;
+}
+
+#[tokio::test]
+async fn skewed_client_clocks_are_clamped_and_server_time_is_sent() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+
+    // A clock a year fast
+    let send = r#"{"Send":{"text":"hello","client_msg_id":"m1","sent_at":"2999-01-01T00:00:00Z"}}"#;
+    alice.send_text(send).await;
+
+    let mut replies = Vec::new();
+    for _ in 0..2 {
+        replies.push(alice.recv_message().await);
+    }
+    let ack = replies
+        .iter()
+        .find(|reply| reply["message_type"]["Ack"].is_object())
+        .unwrap();
+    let parse = |at: &serde_json::Value| {
+        chrono::DateTime::parse_from_rfc3339(at.as_str().unwrap()).unwrap()
+    };
+    let server_time = parse(&ack["sent_at"]);
+    let client_time = parse(&ack["message_type"]["Ack"]["client_sent_at"]);
+    assert_eq!(client_time - server_time, chrono::TimeDelta::minutes(5));
+
+    let echo = replies
+        .iter()
+        .find(|reply| reply["data"] == "Me: hello")
+        .unwrap();
+    assert!(parse(&echo["sent_at"]) <= server_time);
+
+    // Replayed history carries the same server time
+    let mut bob = TestClient::connect(port).await;
+    let history = bob.recv_message().await;
+    let replayed = &history["message_type"]["PastMessages"]["days"][0]["messages"][0];
+    assert_eq!(replayed["sent_at"], echo["sent_at"]);
+}
+
+#[tokio::test]
+async fn pings_are_answered_with_the_same_token() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.recv_data("Welcome! Please enter your name:").await;
+
+    // The frame works before naming, where /ping would be taken as the name
+    alice
+        .send_text(r#"{"Ping":{"token":"1718000000123"}}"#)
+        .await;
+    let pong = alice.recv_message().await;
+    assert_eq!(pong["message_type"]["Pong"]["token"], "1718000000123");
+    assert!(pong["message_type"]["Pong"]["server_time"].is_string());
+    // Pongs sit outside the sequence, so they are never resent
+    assert!(pong.get("seq").is_none());
+
+    // A second ping within two seconds goes unanswered
+    alice
+        .send_text(r#"{"Ping":{"nonce":"again","sent_at":"2024-06-10T06:13:20Z"}}"#)
+        .await;
+    alice.send_text("alice").await;
+    loop {
+        let message = alice.recv_message().await;
+        assert!(message["message_type"].get("Pong").is_none());
+        if message["data"] == "Welcome, alice! You can start chatting now." {
+            break;
+        }
+    }
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+    alice.recv_data("bob joined the chat!").await;
+    alice.send_text("/ping abc").await;
+    let pong = alice.recv_message().await;
+    assert_eq!(pong["message_type"]["Pong"]["token"], "abc");
+
+    // Nobody else saw it: bob's next message is new chat
+    alice.send_text("after the ping").await;
+    let next = bob.recv_message().await;
+    assert_eq!(next["data"], "alice: after the ping");
+}
+
+#[tokio::test]
+async fn markdown_clients_get_chat_text_escaped() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice
+        .send_text(r#"{"Hello":{"utc_offset_minutes":0,"markdown":true}}"#)
+        .await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+    alice.recv_data("bob joined the chat!").await;
+
+    bob.send_text("*bold* <b>").await;
+    bob.recv_data("Me: *bold* <b>").await;
+    alice.recv_data(r"bob: \*bold\* &lt;b&gt;").await;
+
+    // Replays are escaped for markdown clients and stored text stays as typed
+    let mut carol = TestClient::connect(port).await;
+    carol
+        .send_text(r#"{"Hello":{"utc_offset_minutes":0,"markdown":true}}"#)
+        .await;
+    let history = carol.recv_message().await;
+    let days = history["message_type"]["PastMessages"]["days"]
+        .as_array()
+        .unwrap();
+    let replayed = days.last().unwrap()["messages"].as_array().unwrap();
+    assert_eq!(replayed.last().unwrap()["data"], r"bob: \*bold\* &lt;b&gt;");
+
+    let mut dave = TestClient::connect(port).await;
+    let history = dave.recv_message().await;
+    let days = history["message_type"]["PastMessages"]["days"]
+        .as_array()
+        .unwrap();
+    let replayed = days.last().unwrap()["messages"].as_array().unwrap();
+    assert_eq!(replayed.last().unwrap()["data"], "bob: *bold* <b>");
+}
+
+#[tokio::test]
+async fn wordcount_ranks_senders_by_words() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    alice.send_text("one two three").await;
+    alice.recv_data("Me: one two three").await;
+    bob.send_text("four five").await;
+    bob.recv_data("Me: four five").await;
+
+    bob.send_text("/wordcount").await;
+    bob.recv_data("Most words sent:\n1. alice: 3 words\n2. bob: 2 words")
+        .await;
+    bob.send_text("/wordcount 1").await;
+    bob.recv_data("Most words sent:\n1. alice: 3 words").await;
+    bob.send_text("/wordcount none").await;
+    bob.recv_data("Usage: /wordcount [limit]").await;
+}
+
+#[tokio::test]
+async fn rapid_messages_are_stored_in_the_order_they_were_sent() {
+    const BURST: usize = 30;
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    // Both send their whole burst before reading anything back
+    for n in 0..BURST {
+        alice.send_text(&format!("a{}", n)).await;
+        bob.send_text(&format!("b{}", n)).await;
+    }
+    alice.recv_data(&format!("Me: a{}", BURST - 1)).await;
+    bob.recv_data(&format!("Me: b{}", BURST - 1)).await;
+
+    let mut carol = TestClient::connect(port).await;
+    let history = carol.recv_message().await;
+    let messages: Vec<_> = history["message_type"]["PastMessages"]["days"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .flat_map(|day| day["messages"].as_array().unwrap().clone())
+        .collect();
+    assert_eq!(messages.len(), 2 * BURST);
+
+    // History is sorted by server time, so rising ids mean the stamps
+    // followed the order rows were inserted in
+    let ids: Vec<i64> = messages
+        .iter()
+        .map(|message| message["id"].as_i64().unwrap())
+        .collect();
+    assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+
+    for (sender, prefix) in [("alice", "a"), ("bob", "b")] {
+        let sent: Vec<String> = (0..BURST)
+            .map(|n| format!("{}: {}{}", sender, prefix, n))
+            .collect();
+        let stored: Vec<&str> = messages
+            .iter()
+            .filter_map(|message| message["data"].as_str())
+            .filter(|data| data.starts_with(&format!("{}: ", sender)))
+            .collect();
+        assert_eq!(stored, sent);
+    }
+}
+
+#[tokio::test]
+async fn uptime_reports_how_long_the_server_has_run() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+
+    alice.send_text("/uptime").await;
+    let reply = alice.recv_message().await;
+    assert_eq!(reply["message_type"], "System");
+    let uptime = reply["data"].as_str().unwrap();
+    assert!(uptime.starts_with("Server uptime: "), "{}", uptime);
+    assert!(uptime.ends_with("second") || uptime.ends_with("seconds"));
+}
+
+#[tokio::test]
+async fn quiet_users_miss_join_notices_but_not_chat() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+    alice.recv_data("bob joined the chat!").await;
+
+    alice.send_text("/quiet").await;
+    alice
+        .recv_data(
+            "Quiet mode on: join, leave and other notices are hidden. /quiet shows them again",
+        )
+        .await;
+
+    let mut carol = TestClient::connect(port).await;
+    carol.register("carol").await;
+    bob.recv_data("carol joined the chat!").await;
+    carol.send_text("hi").await;
+    bob.recv_data("carol: hi").await;
+    let next = alice.recv_message().await;
+    assert_eq!(next["data"], "carol: hi");
+
+    alice.send_text("/quiet").await;
+    alice.recv_data("Quiet mode off").await;
+}
+
+#[tokio::test]
+async fn runs_of_messages_from_one_sender_are_flagged_as_continuations() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+    alice.recv_data("bob joined the chat!").await;
+
+    for text in ["one", "two"] {
+        alice.send_text(text).await;
+        alice.recv_data(&format!("Me: {}", text)).await;
+    }
+    let one = bob.recv_data("alice: one").await;
+    assert!(one.get("continuation").is_none());
+    let two = bob.recv_data("alice: two").await;
+    assert_eq!(two["continuation"], true);
+
+    // Another sender breaks the run, and so does a system notice
+    bob.send_text("hey").await;
+    alice.recv_data("bob: hey").await;
+    alice.send_text("three").await;
+    let three = bob.recv_data("alice: three").await;
+    assert!(three.get("continuation").is_none());
+    let mut carol = TestClient::connect(port).await;
+    carol.register("carol").await;
+    bob.recv_data("carol joined the chat!").await;
+    alice.send_text("four").await;
+    let four = bob.recv_data("alice: four").await;
+    assert!(four.get("continuation").is_none());
+
+    // Replays derive the flag from the stored rows alone
+    let mut dave = TestClient::connect(port).await;
+    let history = dave.recv_message().await;
+    let replayed = history["message_type"]["PastMessages"]["days"][0]["messages"]
+        .as_array()
+        .unwrap();
+    let flags: Vec<bool> = replayed
+        .iter()
+        .map(|message| message["continuation"] == true)
+        .collect();
+    assert_eq!(flags, [false, true, false, false, true]);
+}
+
+#[tokio::test]
+async fn cls_only_clears_the_asking_client() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+    alice.recv_data("bob joined the chat!").await;
+    alice.send_text("hello").await;
+    alice.recv_data("Me: hello").await;
+    bob.recv_data("alice: hello").await;
+
+    alice.send_text("/cls").await;
+    let clear = alice.recv_message().await;
+    assert_eq!(clear["message_type"], "ClearScreen");
+
+    alice.send_text("still here").await;
+    let next = bob.recv_message().await;
+    assert_eq!(next["data"], "alice: still here");
+    let mut carol = TestClient::connect(port).await;
+    let history = carol.recv_message().await;
+    let replayed = &history["message_type"]["PastMessages"]["days"][0]["messages"];
+    assert_eq!(replayed.as_array().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn aliases_expand_to_commands_and_cannot_chain() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+
+    alice.send_text(r#"/alias set p "/ping hello""#).await;
+    alice.recv_data("/p now runs /ping hello").await;
+    alice.send_text("/p there").await;
+    let pong = alice.recv_message().await;
+    assert_eq!(pong["message_type"]["Pong"]["token"], "hello there");
+
+    // Built-ins win over aliases of the same name
+    alice.send_text("/alias set j /ping").await;
+    alice.recv_data("/j now runs /ping").await;
+    alice
+        .recv_data("/j is also a built-in command, which runs instead of your alias")
+        .await;
+    alice.send_text("/j main").await;
+    alice.recv_data("Already in room main").await;
+
+    alice.send_text("/alias set a /p").await;
+    let error = alice.recv_message().await;
+    assert_eq!(error["message_type"]["Error"]["code"], "CircularAlias");
+    assert_eq!(
+        error["data"],
+        "/p is an alias, and aliases can't run other aliases"
+    );
+    alice.send_text("/alias set b /c").await;
+    alice.recv_data("/b now runs /c").await;
+    alice.send_text("/alias set c /ping").await;
+    alice.recv_data("/b runs /c, so it can't be an alias").await;
+
+    alice.send_text("/alias remove p").await;
+    alice.recv_data("Removed the alias /p").await;
+    alice.close().await;
+
+    // Aliases are saved under the name
+    let mut alice = TestClient::connect(port).await;
+    alice.register_again("alice").await;
+    alice.send_text("/alias list").await;
+    alice
+        .recv_data("Your aliases: /b = /c, /j = /ping (the built-in runs instead)")
+        .await;
+}
+
+#[tokio::test]
+#[cfg(feature = "snake_case_types")]
+async fn message_types_can_be_sent_in_snake_case() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+
+    let history = alice.recv_message().await;
+    assert!(history["message_type"]["past_messages"].is_object());
+    let prompt = alice.recv_message().await;
+    assert_eq!(prompt["message_type"], "welcome");
+    alice.send_text("alice").await;
+    alice.send_text("hi").await;
+    let echo = alice.recv_data("Me: hi").await;
+    assert_eq!(echo["message_type"], "chat");
+}
+
+#[tokio::test]
+async fn idle_connections_are_disconnected() {
+    let (port, _server) = spawn_test_server_with(&["--idle-timeout", "1"]).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+
+    // Chat and commands keep the connection open past the timeout
+    for n in 0..4 {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        if n % 2 == 0 {
+            alice.send_text(&format!("still here {}", n)).await;
+            alice.recv_data(&format!("Me: still here {}", n)).await;
+        } else {
+            alice.send_text("/notify list").await;
+            alice
+                .recv_data("You have no keywords. Add one with /notify add <word>")
+                .await;
+        }
+    }
+
+    let notice = alice.recv_data("Disconnected due to inactivity").await;
+    let disconnected = &notice["message_type"]["Disconnected"];
+    assert_eq!(disconnected["code"], 4003);
+    assert_eq!(disconnected["reconnect"], false);
+    alice.expect_closed().await;
+}
+
+#[tokio::test]
+async fn negotiation_picks_the_first_supported_subprotocol() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice
+        .send_text(r#"{"Negotiate":{"subprotocol":"chat.v2, chat.json"}}"#)
+        .await;
+    alice.recv_data("Using subprotocol chat.json").await;
+    alice.register("alice").await;
+
+    let mut bob = TestClient::connect(port).await;
+    bob.send_text(r#"{"Negotiate":{"subprotocol":"chat.v1"}}"#)
+        .await;
+    let error = bob.recv_message().await;
+    assert_eq!(
+        error["message_type"]["Error"]["code"],
+        "UnsupportedProtocol"
+    );
+    assert_eq!(
+        error["data"],
+        "Unsupported subprotocol: chat.v1. This server speaks chat.json and chat.msgpack"
+    );
+    bob.expect_closed().await;
+}
+
+#[tokio::test]
+async fn control_and_bidi_characters_are_stripped_from_names_and_chat() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.send_text("ali\u{202E}ce\0").await;
+    alice
+        .recv_data("Welcome, alice! You can start chatting now.")
+        .await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    alice
+        .send_text("evil\u{202E}txt.exe cafe\u{301}\u{7}")
+        .await;
+    bob.recv_data("alice: eviltxt.exe café").await;
+}
+
+#[tokio::test]
+async fn bidi_characters_can_be_refused_instead() {
+    let (port, _server) = spawn_test_server_with(&["--bidi-controls", "reject"]).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+
+    alice.send_text("evil\u{202E}txt.exe").await;
+    let error = alice
+        .recv_data("Text can't contain bidi override or isolate characters")
+        .await;
+    assert_eq!(error["message_type"]["Error"]["code"], "InvalidText");
+
+    // Other control characters are still just stripped
+    alice.send_text("be\u{7}ll").await;
+    alice.recv_data("Me: bell").await;
+}
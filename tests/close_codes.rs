@@ -0,0 +1,86 @@
+mod integration;
+
+use backend::metrics;
+use integration::{TestClient, spawn_test_server_with};
+use serde_json::Value;
+
+const ADMIN_PASSWORD: &str = "correct horse";
+
+// Skips messages until the closing notice, and returns its code and whether
+// it invites a reconnect
+async fn recv_disconnected(client: &mut TestClient) -> (Value, Value) {
+    loop {
+        let message = client.recv_message().await;
+        let disconnected = &message["message_type"]["Disconnected"];
+        if disconnected.is_object() {
+            return (
+                disconnected["code"].clone(),
+                disconnected["reconnect"].clone(),
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn kicked_clients_are_told_not_to_reconnect() {
+    let args = [
+        "--admin-password",
+        ADMIN_PASSWORD,
+        "--namespace",
+        "kicks=sqlite::memory:",
+    ];
+    let (port, _server) = spawn_test_server_with(&args).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    alice.send_text(&format!("/admin {}", ADMIN_PASSWORD)).await;
+    alice.recv_data("You are now an admin").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    bob.send_text("/kick alice").await;
+    bob.recv_data("Only admins can use /kick").await;
+
+    alice.send_text("/kick bob").await;
+    let (code, reconnect) = recv_disconnected(&mut bob).await;
+    assert_eq!(code, 4005);
+    assert_eq!(reconnect, false);
+    bob.expect_closed().await;
+
+    alice.recv_data("alice kicked bob").await;
+    alice.recv_data("bob left the chat!").await;
+    assert!(metrics::closes("kicks", 4005) > 0);
+
+    alice.send_text("/kick bob").await;
+    alice.recv_data("bob is not online").await;
+}
+
+#[tokio::test]
+async fn full_servers_invite_a_later_reconnect() {
+    let (port, _server) = spawn_test_server_with(&["--max-connections", "1"]).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+
+    let mut bob = TestClient::connect(port).await;
+    let (code, reconnect) = recv_disconnected(&mut bob).await;
+    assert_eq!(code, 4001);
+    assert_eq!(reconnect, true);
+    bob.expect_closed().await;
+}
+
+#[tokio::test]
+async fn clients_that_never_choose_a_name_are_disconnected() {
+    let (port, _server) = spawn_test_server_with(&["--naming-timeout", "1"]).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut silent = TestClient::connect(port).await;
+
+    let notice = silent.recv_data("No name was chosen in time").await;
+    let disconnected = &notice["message_type"]["Disconnected"];
+    assert_eq!(disconnected["code"], 4008);
+    assert_eq!(disconnected["reconnect"], false);
+    silent.expect_closed().await;
+
+    // Choosing a name in time keeps the connection open past the timeout
+    alice.send_text("still here").await;
+    alice.recv_data("Me: still here").await;
+}
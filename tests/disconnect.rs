@@ -0,0 +1,75 @@
+mod integration;
+
+use backend::metrics;
+use integration::{TestClient, spawn_test_server_with};
+
+const BURST: usize = 200;
+
+#[tokio::test]
+async fn disconnecting_mid_burst_drops_the_rest_quietly() {
+    let (port, _server) = spawn_test_server_with(&["--namespace", "burst=sqlite::memory:"]).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    // Large enough that bob's socket buffers fill and frames are still queued
+    // for him when he goes
+    let padding = "x".repeat(64 * 1024);
+    for n in 0..BURST {
+        alice.send_text(&format!("burst {} {}", n, padding)).await;
+    }
+    // Bob vanishes as soon as the burst reaches him, without reading the rest
+    // or saying goodbye, so the server only notices when writing to him fails
+    bob.recv_data(&format!("alice: burst 0 {}", padding)).await;
+    drop(bob);
+
+    // Alice is unaffected and gets every echo
+    let mut echoes = 0;
+    while echoes < BURST {
+        let message = alice.recv_message().await;
+        if message["data"]
+            .as_str()
+            .is_some_and(|data| data.starts_with("Me: burst "))
+        {
+            echoes += 1;
+        }
+    }
+
+    // After the first failed write, the rest of bob's frames were dropped
+    // rather than written
+    assert!(metrics::frames_dropped_after_close("burst") > 0);
+
+    // The server still serves everyone else
+    let mut carol = TestClient::connect(port).await;
+    carol.register("carol").await;
+    alice.send_text("still here").await;
+    carol.recv_data("alice: still here").await;
+}
+
+#[tokio::test]
+async fn stuck_readers_time_out_and_are_cleaned_up() {
+    let args = [
+        "--send-timeout",
+        "1",
+        "--namespace",
+        "stuck=sqlite::memory:",
+    ];
+    let (port, _server) = spawn_test_server_with(&args).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+    alice.recv_data("bob joined the chat!").await;
+
+    // Bob stays connected but stops reading, so once his socket buffers fill
+    // every write to him stalls
+    let padding = "x".repeat(64 * 1024);
+    for n in 0..BURST {
+        alice.send_text(&format!("burst {} {}", n, padding)).await;
+    }
+
+    alice.recv_data("bob left the chat!").await;
+    assert!(metrics::send_timeouts("stuck") > 0);
+    drop(bob);
+}
@@ -0,0 +1,62 @@
+mod integration;
+
+use integration::{TestClient, spawn_test_server_with};
+use serde_json::Value;
+
+const ADMIN_PASSWORD: &str = "correct horse";
+
+async fn spawn_server() -> u16 {
+    let (port, _server) = spawn_test_server_with(&["--admin-password", ADMIN_PASSWORD]).await;
+    port
+}
+
+// Skips messages until the restart notice arrives
+async fn recv_restart(client: &mut TestClient) -> Value {
+    loop {
+        let message = client.recv_message().await;
+        if message["message_type"]["ServerRestart"].is_object() {
+            return message["message_type"]["ServerRestart"].clone();
+        }
+    }
+}
+
+#[tokio::test]
+async fn draining_moves_everyone_and_turns_away_newcomers() {
+    let port = spawn_server().await;
+    let mut root = TestClient::connect(port).await;
+    root.register("root").await;
+    root.send_text(&format!("/admin {}", ADMIN_PASSWORD)).await;
+    root.recv_data("You are now an admin").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    root.send_text("/drain ws://other.example:3000").await;
+
+    for client in [&mut root, &mut bob] {
+        let restart = recv_restart(client).await;
+        assert_eq!(restart["reason"], "Server is draining for maintenance");
+        assert_eq!(restart["new_url"], "ws://other.example:3000");
+        assert!(restart["reconnect_after_ms"].as_u64().unwrap() > 0);
+        client.expect_closed().await;
+    }
+
+    let mut late = TestClient::connect(port).await;
+    let restart = recv_restart(&mut late).await;
+    assert_eq!(restart["new_url"], "ws://other.example:3000");
+    late.expect_closed().await;
+}
+
+#[tokio::test]
+async fn only_admins_can_drain() {
+    let port = spawn_server().await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    bob.send_text("/drain").await;
+    let error = bob.recv_data("Only admins can use /drain").await;
+    assert_eq!(error["message_type"]["Error"]["code"], "Unauthorized");
+
+    // Still serving
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+}
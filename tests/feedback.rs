@@ -0,0 +1,42 @@
+mod integration;
+
+use integration::{TestClient, spawn_test_server_with};
+use serde_json::Value;
+
+#[tokio::test]
+async fn feedback_is_logged_without_being_broadcast() {
+    let path = std::env::temp_dir().join(format!("feedback-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let (port, _server) =
+        spawn_test_server_with(&["--feedback-file", path.to_str().unwrap()]).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+    alice.recv_data("bob joined the chat!").await;
+
+    alice
+        .send_text("/feedback The \"dark\" theme is hard to read")
+        .await;
+    alice.recv_data("Thank you for your feedback!").await;
+    // Bob hears nothing about it
+    alice.send_text("hi").await;
+    assert_eq!(bob.recv_message().await["data"], "alice: hi");
+
+    bob.send_text("/feedback").await;
+    bob.recv_data("Usage: /feedback <text>").await;
+    bob.send_text("/feedback Love it").await;
+    bob.recv_data("Thank you for your feedback!").await;
+
+    let log = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<Value> = log
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["user"], "alice");
+    assert_eq!(lines[0]["text"], "The \"dark\" theme is hard to read");
+    assert!(lines[0]["timestamp"].is_string());
+    assert_eq!(lines[1]["user"], "bob");
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,99 @@
+mod integration;
+
+use integration::{TestClient, spawn_test_server, spawn_test_server_with};
+use serde_json::Value;
+
+// A chunk frame: the chunk number, big-endian, then its bytes
+fn chunk(number: u32, bytes: &[u8]) -> Vec<u8> {
+    let mut frame = number.to_be_bytes().to_vec();
+    frame.extend_from_slice(bytes);
+    frame
+}
+
+async fn send_file(client: &mut TestClient, filename: &str, size: usize, chunks: u32) {
+    let frame = format!(
+        r#"{{"SendFile":{{"filename":"{}","size":{},"chunks":{}}}}}"#,
+        filename, size, chunks
+    );
+    client.send_text(&frame).await;
+    client
+        .recv_data(&format!("Ready for the {} chunks of {}", chunks, filename))
+        .await;
+}
+
+// Skips frames up to the next File message and returns it
+async fn recv_file(client: &mut TestClient) -> Value {
+    loop {
+        let message = client.recv_message().await;
+        if message["message_type"]["File"].is_object() {
+            return message;
+        }
+    }
+}
+
+#[tokio::test]
+async fn chunked_files_are_reassembled_and_relayed_to_the_room() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    let contents: Vec<u8> = (0..=255).cycle().take(3000).collect();
+    send_file(&mut alice, "photo.png", contents.len(), 3).await;
+    for (number, bytes) in contents.chunks(1000).enumerate() {
+        alice.send_binary(chunk(number as u32, bytes)).await;
+    }
+    alice.recv_data("Sent photo.png (3000 bytes) to main").await;
+
+    let file = recv_file(&mut bob).await;
+    assert_eq!(file["message_type"]["File"]["sender"], "alice");
+    assert_eq!(file["message_type"]["File"]["filename"], "photo.png");
+    assert_eq!(file["message_type"]["File"]["size"], 3000);
+    assert_eq!(bob.recv_binary().await, contents);
+
+    // Binary frames outside a transfer are announced as before
+    alice.send_binary(vec![1, 2, 3]).await;
+    bob.recv_data("alice sent binary data (3 bytes)").await;
+}
+
+#[tokio::test]
+async fn oversized_and_broken_transfers_are_refused() {
+    let (port, _server) = spawn_test_server_with(&["--max-transfer-size", "100"]).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+
+    alice
+        .send_text(r#"{"SendFile":{"filename":"big.bin","size":101,"chunks":1}}"#)
+        .await;
+    let refused = alice
+        .recv_data("Cannot send big.bin: files can be at most 100 bytes")
+        .await;
+    assert_eq!(refused["message_type"]["Error"]["code"], "TransferFailed");
+
+    send_file(&mut alice, "notes.txt", 4, 2).await;
+    alice.send_binary(chunk(1, b"ab")).await;
+    alice
+        .recv_data("Sending notes.txt failed: expected chunk 0, got chunk 1")
+        .await;
+}
+
+#[tokio::test]
+async fn stalled_transfers_are_abandoned() {
+    let (port, _server) = spawn_test_server_with(&["--transfer-stall-secs", "1"]).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    send_file(&mut alice, "notes.txt", 4, 2).await;
+    alice.send_binary(chunk(0, b"ab")).await;
+    let abandoned = alice
+        .recv_data("Sending notes.txt failed: no chunk for 1s")
+        .await;
+    assert_eq!(abandoned["message_type"]["Error"]["code"], "TransferFailed");
+
+    // The late chunk is no longer part of a transfer
+    alice.send_binary(chunk(1, b"cd")).await;
+    bob.recv_data("alice sent binary data (6 bytes)").await;
+}
@@ -0,0 +1,68 @@
+mod integration;
+
+use integration::{TestClient, spawn_test_server, spawn_test_server_with};
+use std::path::PathBuf;
+
+// A greeting file of the test's own, removed when it ends
+struct GreetingFile(PathBuf);
+
+impl GreetingFile {
+    fn new(greeting: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("greeting-{}.txt", rand::random::<u64>()));
+        std::fs::write(&path, greeting).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for GreetingFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+// Reads frames up to the answer to `/uptime` and returns the bot's messages
+// among them
+async fn bot_messages_until_reply(client: &mut TestClient) -> Vec<serde_json::Value> {
+    client.send_text("/uptime").await;
+    let mut direct = Vec::new();
+    loop {
+        let message = client.recv_message().await;
+        if message["message_type"]["Direct"].is_object() {
+            direct.push(message);
+        } else if message["data"]
+            .as_str()
+            .is_some_and(|data| data.starts_with("Server uptime: "))
+        {
+            return direct;
+        }
+    }
+}
+
+#[tokio::test]
+async fn newly_named_users_get_one_bot_message() {
+    let greeting = GreetingFile::new("Hi {name}! Be nice, and try /rooms.\n");
+    let path = greeting.0.to_str().unwrap();
+    let (port, _server) = spawn_test_server_with(&["--greeting-file", path]).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    let direct = bot_messages_until_reply(&mut alice).await;
+    assert_eq!(direct.len(), 1);
+    assert_eq!(direct[0]["message_type"]["Direct"]["sender"], "bot");
+    assert_eq!(direct[0]["data"], "Hi alice! Be nice, and try /rooms.");
+
+    // Renaming isn't a first name
+    alice.send_text("/nick carol").await;
+    alice.recv_data("alice is now known as carol").await;
+    assert!(bot_messages_until_reply(&mut alice).await.is_empty());
+}
+
+#[tokio::test]
+async fn there_is_no_bot_by_default() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    assert!(bot_messages_until_reply(&mut alice).await.is_empty());
+}
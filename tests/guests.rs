@@ -0,0 +1,85 @@
+mod integration;
+
+use integration::{TestClient, spawn_test_server_with};
+
+async fn spawn_server(mode: &str) -> u16 {
+    let (port, _server) = spawn_test_server_with(&["--guest-names", mode]).await;
+    port
+}
+
+// Skips the history replay and returns the guest name from the welcome. An
+// earlier guest's join notice can get in between, since it goes out to every
+// connection once that guest is welcomed.
+async fn recv_guest_welcome(client: &mut TestClient) -> String {
+    let history = client.recv_message().await;
+    assert!(history["message_type"]["PastMessages"].is_object());
+    let welcome = loop {
+        let message = client.recv_message().await;
+        if message["message_type"]["GuestWelcome"].is_object() {
+            break message;
+        }
+        assert_eq!(message["message_type"], "System");
+    };
+    let name = welcome["message_type"]["GuestWelcome"]["name"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    assert_eq!(
+        welcome["data"],
+        format!(
+            "Welcome, {}! You can start chatting now, or pick a name with /nick <name>.",
+            name
+        )
+    );
+    name
+}
+
+#[tokio::test]
+async fn auto_mode_names_connections_without_a_prompt() {
+    let port = spawn_server("auto").await;
+    let mut first = TestClient::connect(port).await;
+    let first_name = recv_guest_welcome(&mut first).await;
+    assert!(first_name.starts_with("Guest-"));
+
+    let mut second = TestClient::connect(port).await;
+    let second_name = recv_guest_welcome(&mut second).await;
+    assert_ne!(first_name, second_name);
+    first
+        .recv_data(&format!("{} joined the chat!", second_name))
+        .await;
+
+    // The first message is chat, not a name
+    second.send_text("alice").await;
+    second.recv_data("Me: alice").await;
+    first.recv_data(&format!("{}: alice", second_name)).await;
+
+    second.send_text("/nick bob").await;
+    second
+        .recv_data(&format!("{} is now known as bob", second_name))
+        .await;
+}
+
+#[tokio::test]
+async fn allow_mode_names_guests_once_they_chat() {
+    let port = spawn_server("allow").await;
+    let mut guest = TestClient::connect(port).await;
+    guest
+        .recv_data("Welcome! Start chatting as a guest, or pick a name with /nick <name>:")
+        .await;
+
+    guest.send_text("hello").await;
+    let welcome = guest.recv_message().await;
+    let name = welcome["message_type"]["GuestWelcome"]["name"]
+        .as_str()
+        .unwrap();
+    assert!(name.starts_with("Guest-"));
+    guest.recv_data("Me: hello").await;
+
+    // Naming first skips the guest name entirely
+    let mut alice = TestClient::connect(port).await;
+    alice.send_text("/nick alice").await;
+    let welcome = alice
+        .recv_data("Welcome, alice! You can start chatting now.")
+        .await;
+    assert_eq!(welcome["message_type"], "Welcome");
+}
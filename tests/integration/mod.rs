@@ -0,0 +1,179 @@
+//! Helpers for running the chat server in-process and talking to it like a
+//! real client would.
+
+// Each test crate uses its own subset of the helpers
+#![allow(dead_code)]
+
+use backend::config::ServerConfig;
+use clap::Parser;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::Value;
+use std::net::TcpListener;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tokio::net::{TcpSocket, TcpStream};
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, client_async, connect_async};
+
+// How long a client waits for a frame before the test fails
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long the server gets to start accepting connections
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts a server backed by an in-memory database and returns its port once
+/// it accepts connections.
+///
+/// wynd binds the listener itself and never reports the address it got, so
+/// a free port is found by binding port 0 first and handing that port over.
+/// The server runs on a thread of its own because wynd's listen future isn't
+/// `Send`; the thread goes away with the test process.
+pub async fn spawn_test_server() -> (u16, JoinHandle<()>) {
+    spawn_test_server_with(&[]).await
+}
+
+/// Like [`spawn_test_server`], with extra command line arguments. Passing a
+/// `--namespace` replaces the in-memory default one.
+pub async fn spawn_test_server_with(args: &[&str]) -> (u16, JoinHandle<()>) {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let port_arg = port.to_string();
+    let mut argv = vec!["backend", "--port", &port_arg];
+    if !args.contains(&"--namespace") {
+        argv.extend(["--namespace", "default=sqlite::memory:"]);
+    }
+    argv.extend(args);
+    let config = ServerConfig::parse_from(argv);
+    let server = thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(backend::serve(config));
+    });
+
+    tokio::time::timeout(STARTUP_TIMEOUT, async {
+        while TcpStream::connect(("127.0.0.1", port)).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("server did not start");
+
+    (port, server)
+}
+
+/// A WebSocket connection to a test server.
+pub struct TestClient {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl TestClient {
+    pub async fn connect(port: u16) -> Self {
+        let (ws, _) = connect_async(format!("ws://127.0.0.1:{}", port))
+            .await
+            .unwrap();
+        Self { ws }
+    }
+
+    /// Connects with a receive buffer of `bytes`, for tests that stop reading
+    /// and need the server to notice. Left to itself, the kernel grows the
+    /// buffer until it swallows megabytes of backlog.
+    pub async fn connect_with_recv_buffer(port: u16, bytes: u32) -> Self {
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(bytes).unwrap();
+        let stream = socket.connect(([127, 0, 0, 1], port).into()).await.unwrap();
+        let (ws, _) = client_async(
+            format!("ws://127.0.0.1:{}", port),
+            MaybeTlsStream::Plain(stream),
+        )
+        .await
+        .unwrap();
+        Self { ws }
+    }
+
+    pub async fn send_text(&mut self, text: &str) {
+        self.ws.send(WsMessage::text(text)).await.unwrap();
+    }
+
+    pub async fn send_binary(&mut self, bytes: Vec<u8>) {
+        self.ws.send(WsMessage::binary(bytes)).await.unwrap();
+    }
+
+    /// The next frame from the server, which must be a binary one.
+    pub async fn recv_binary(&mut self) -> Vec<u8> {
+        let frame = tokio::time::timeout(RECV_TIMEOUT, self.ws.next())
+            .await
+            .expect("timed out waiting for a binary frame")
+            .expect("connection closed")
+            .unwrap();
+        match frame {
+            WsMessage::Binary(bytes) => bytes.to_vec(),
+            other => panic!("expected a binary frame, got {:?}", other),
+        }
+    }
+
+    /// The next message from the server, failing the test if none arrives.
+    pub async fn recv_message(&mut self) -> Value {
+        loop {
+            let frame = tokio::time::timeout(RECV_TIMEOUT, self.ws.next())
+                .await
+                .expect("timed out waiting for a message")
+                .expect("connection closed")
+                .unwrap();
+            match frame {
+                WsMessage::Text(text) => return serde_json::from_str(&text).unwrap(),
+                WsMessage::Close(_) => panic!("connection closed"),
+                _ => continue,
+            }
+        }
+    }
+
+    /// Skips messages until the server closes the connection, failing the
+    /// test if it stays open.
+    pub async fn expect_closed(&mut self) {
+        loop {
+            let frame = tokio::time::timeout(RECV_TIMEOUT, self.ws.next())
+                .await
+                .expect("timed out waiting for the connection to close");
+            match frame {
+                None | Some(Err(_)) | Some(Ok(WsMessage::Close(_))) => return,
+                Some(Ok(_)) => continue,
+            }
+        }
+    }
+
+    /// Skips messages until one carries `data`, and returns it.
+    pub async fn recv_data(&mut self, data: &str) -> Value {
+        loop {
+            let message = self.recv_message().await;
+            if message["data"] == data {
+                return message;
+            }
+        }
+    }
+
+    /// Joins the chat as `name`, consuming the history and greeting.
+    pub async fn register(&mut self, name: &str) {
+        self.send_text(name).await;
+        let welcome = format!("Welcome, {}! You can start chatting now.", name);
+        self.recv_data(&welcome).await;
+    }
+
+    /// Like [`TestClient::register`], for a name the server has seen before.
+    pub async fn register_again(&mut self, name: &str) {
+        self.send_text(name).await;
+        let welcome = format!("Welcome back, {}! You can start chatting now.", name);
+        self.recv_data(&welcome).await;
+    }
+
+    /// Sends a Close frame. wynd never answers it once the server installs
+    /// its own close handler, so this doesn't wait for the handshake.
+    pub async fn close(mut self) {
+        self.ws.close(None).await.unwrap();
+    }
+}
@@ -0,0 +1,37 @@
+mod integration;
+
+use backend::metrics::{self, Stage};
+use integration::{TestClient, spawn_test_server_with};
+use std::time::Duration;
+
+#[tokio::test]
+async fn traced_messages_feed_every_stage_percentile() {
+    let args = ["--trace-messages", "--namespace", "traced=sqlite::memory:"];
+    let (port, _server) = spawn_test_server_with(&args).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    // Naming isn't a chat message, so nothing has been traced yet
+    assert_eq!(metrics::stage_p95("traced", Stage::Total), None);
+
+    alice.send_text("hello bob").await;
+    alice.recv_data("Me: hello bob").await;
+    bob.recv_data("alice: hello bob").await;
+
+    // The trace ends just after the echo is queued, so it may still be
+    // finishing as the echo arrives
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while metrics::stage_p95("traced", Stage::Total).is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the message was never traced");
+    for stage in Stage::ALL {
+        assert!(metrics::stage_p95("traced", stage).is_some(), "{:?}", stage);
+    }
+    // Each namespace keeps its own
+    assert_eq!(metrics::stage_p95("other", Stage::Total), None);
+}
@@ -0,0 +1,42 @@
+mod integration;
+
+use integration::{TestClient, spawn_test_server_with};
+
+async fn spawn_server() -> u16 {
+    let (port, _server) = spawn_test_server_with(&["--namespace", "default=memory://"]).await;
+    port
+}
+
+#[tokio::test]
+async fn history_is_replayed_from_memory() {
+    let port = spawn_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    alice.send_text("kept in memory").await;
+    let echo = alice.recv_data("Me: kept in memory").await;
+
+    let mut bob = TestClient::connect(port).await;
+    let history = bob.recv_message().await;
+    let days = history["message_type"]["PastMessages"]["days"]
+        .as_array()
+        .unwrap();
+    assert_eq!(days.len(), 1);
+    let messages = days[0]["messages"].as_array().unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0]["data"], "alice: kept in memory");
+    assert_eq!(messages[0]["id"], echo["id"]);
+}
+
+#[tokio::test]
+async fn renames_are_remembered_in_memory() {
+    let port = spawn_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    alice.send_text("/nick bob").await;
+    alice.recv_data("alice is now known as bob").await;
+
+    alice.send_text("/whois bob").await;
+    alice
+        .recv_data("bob is online. Previously known as: alice")
+        .await;
+}
@@ -0,0 +1,90 @@
+mod integration;
+
+use integration::{TestClient, spawn_test_server_with};
+use std::path::PathBuf;
+
+const ADMIN_PASSWORD: &str = "correct horse";
+
+// A MOTD file of the test's own, removed when it ends
+struct MotdFile(PathBuf);
+
+impl MotdFile {
+    fn new(motd: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("motd-{}.txt", rand::random::<u64>()));
+        std::fs::write(&path, motd).unwrap();
+        Self(path)
+    }
+
+    fn read(&self) -> String {
+        std::fs::read_to_string(&self.0).unwrap()
+    }
+}
+
+impl Drop for MotdFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+async fn spawn_server(motd_file: &MotdFile) -> u16 {
+    let path = motd_file.0.to_str().unwrap();
+    let args = ["--admin-password", ADMIN_PASSWORD, "--motd-file", path];
+    let (port, _server) = spawn_test_server_with(&args).await;
+    port
+}
+
+#[tokio::test]
+async fn new_users_get_the_motd_from_the_file() {
+    let motd_file = MotdFile::new("Be nice\n");
+    let port = spawn_server(&motd_file).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+
+    let motd = alice.recv_message().await;
+    assert_eq!(motd["message_type"], "System");
+    assert_eq!(motd["data"], "Be nice");
+}
+
+#[tokio::test]
+async fn admins_change_the_motd_for_later_joins() {
+    let motd_file = MotdFile::new("");
+    let port = spawn_server(&motd_file).await;
+    let mut root = TestClient::connect(port).await;
+    root.register("root").await;
+    root.send_text(&format!("/admin {}", ADMIN_PASSWORD)).await;
+    root.recv_data("You are now an admin").await;
+
+    root.send_text("/motd set Release at noon").await;
+    root.recv_data("Message of the day updated").await;
+    assert_eq!(motd_file.read(), "Release at noon");
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    alice.recv_data("Release at noon").await;
+
+    root.send_text("/motd clear").await;
+    root.recv_data("Message of the day cleared").await;
+    assert_eq!(motd_file.read(), "");
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+    bob.send_text("/motd").await;
+    let next = bob.recv_message().await;
+    assert_eq!(next["data"], "There is no message of the day");
+}
+
+#[tokio::test]
+async fn only_admins_change_the_motd() {
+    let motd_file = MotdFile::new("Be nice");
+    let port = spawn_server(&motd_file).await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+    bob.recv_data("Be nice").await;
+
+    bob.send_text("/motd set vandalised").await;
+    let error = bob
+        .recv_data("Only admins can change the message of the day")
+        .await;
+    assert_eq!(error["message_type"]["Error"]["code"], "Unauthorized");
+    bob.send_text("/motd").await;
+    bob.recv_data("Message of the day: Be nice").await;
+    assert_eq!(motd_file.read(), "Be nice");
+}
@@ -0,0 +1,70 @@
+mod integration;
+
+use integration::{TestClient, spawn_test_server};
+
+#[tokio::test]
+async fn keywords_alert_everyone_but_the_sender() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+    alice.recv_data("bob joined the chat!").await;
+
+    for client in [&mut alice, &mut bob] {
+        client.send_text("/notify add Deploy").await;
+        client
+            .recv_data("You will be alerted when someone mentions deploy")
+            .await;
+    }
+
+    alice.send_text("The DEPLOY is done").await;
+    bob.recv_data("alice: The DEPLOY is done").await;
+    let alert = bob.recv_message().await;
+    assert_eq!(alert["data"], "alice mentioned deploy");
+    let alert = &alert["message_type"]["KeywordAlert"];
+    assert_eq!(alert["keyword"], "deploy");
+    assert_eq!(alert["message"]["data"], "alice: The DEPLOY is done");
+
+    // Only whole words count, and the sender's own messages never do
+    alice.recv_data("Me: The DEPLOY is done").await;
+    alice.send_text("redeploying now").await;
+    bob.recv_data("alice: redeploying now").await;
+    bob.send_text("next").await;
+    assert_eq!(bob.recv_message().await["data"], "Me: next");
+    alice.recv_data("Me: redeploying now").await;
+    assert_eq!(alice.recv_message().await["data"], "bob: next");
+
+    bob.send_text("/notify remove deploy").await;
+    bob.recv_data("Removed the keyword deploy").await;
+    alice.send_text("deploy again").await;
+    bob.recv_data("alice: deploy again").await;
+    bob.send_text("/notify list").await;
+    bob.recv_data("You have no keywords. Add one with /notify add <word>")
+        .await;
+}
+
+#[tokio::test]
+async fn keywords_come_back_with_the_name() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    alice.send_text("/notify add deploy").await;
+    alice
+        .recv_data("You will be alerted when someone mentions deploy")
+        .await;
+    alice.send_text("/notify add release").await;
+    alice
+        .recv_data("You will be alerted when someone mentions release")
+        .await;
+    alice.send_text("/notify add two words").await;
+    alice
+        .recv_data("Keywords are single words of at most 50 characters")
+        .await;
+    alice.close().await;
+
+    let mut alice = TestClient::connect(port).await;
+    alice.register_again("alice").await;
+    alice.send_text("/notify list").await;
+    alice.recv_data("Your keywords: deploy, release").await;
+}
@@ -0,0 +1,155 @@
+mod integration;
+
+use integration::{TestClient, spawn_test_server_with};
+use serde_json::Value;
+
+const ADMIN_PASSWORD: &str = "correct horse";
+
+async fn spawn_server() -> u16 {
+    let (port, _server) = spawn_test_server_with(&["--admin-password", ADMIN_PASSWORD]).await;
+    port
+}
+
+async fn admin(port: u16, name: &str) -> TestClient {
+    let mut client = TestClient::connect(port).await;
+    client.register(name).await;
+    client
+        .send_text(&format!("/admin {}", ADMIN_PASSWORD))
+        .await;
+    client.recv_data("You are now an admin").await;
+    client
+}
+
+// Posts a chat message and returns its id
+async fn post(client: &mut TestClient, text: &str) -> i64 {
+    client.send_text(text).await;
+    let echo = client.recv_data(&format!("Me: {}", text)).await;
+    echo["id"].as_i64().unwrap()
+}
+
+// Skips messages until one of the given type arrives
+async fn recv_type(client: &mut TestClient, message_type: &str) -> Value {
+    loop {
+        let message = client.recv_message().await;
+        if message["message_type"] == message_type {
+            return message;
+        }
+    }
+}
+
+#[tokio::test]
+async fn pinning_notifies_everyone() {
+    let port = spawn_server().await;
+    let mut alice = admin(port, "alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+    let id = post(&mut alice, "read the rules").await;
+
+    alice.send_text(&format!("/pin {}", id)).await;
+
+    for client in [&mut alice, &mut bob] {
+        let pinned = recv_type(client, "Pinned").await;
+        assert_eq!(pinned["id"], id);
+        assert_eq!(pinned["data"], "alice: read the rules");
+    }
+}
+
+#[tokio::test]
+async fn pins_lists_pinned_messages_only() {
+    let port = spawn_server().await;
+    let mut alice = admin(port, "alice").await;
+    let first = post(&mut alice, "first").await;
+    post(&mut alice, "not pinned").await;
+    let third = post(&mut alice, "third").await;
+    for id in [first, third] {
+        alice.send_text(&format!("/pin {}", id)).await;
+        recv_type(&mut alice, "Pinned").await;
+    }
+
+    alice.send_text("/pins").await;
+    let listed = [
+        recv_type(&mut alice, "Pinned").await,
+        recv_type(&mut alice, "Pinned").await,
+    ];
+    assert_eq!(listed.map(|m| m["id"].as_i64().unwrap()), [first, third]);
+}
+
+#[tokio::test]
+async fn unpinning_removes_the_pin() {
+    let port = spawn_server().await;
+    let mut alice = admin(port, "alice").await;
+    let id = post(&mut alice, "temporary").await;
+    alice.send_text(&format!("/pin {}", id)).await;
+    recv_type(&mut alice, "Pinned").await;
+
+    alice.send_text(&format!("/unpin {}", id)).await;
+    let unpinned = recv_type(&mut alice, "Unpinned").await;
+    assert_eq!(unpinned["id"], id);
+
+    alice.send_text("/pins").await;
+    alice.recv_data("No pinned messages").await;
+}
+
+#[tokio::test]
+async fn newcomers_get_pinned_messages_before_history() {
+    let port = spawn_server().await;
+    let mut alice = admin(port, "alice").await;
+    let id = post(&mut alice, "welcome all").await;
+    alice.send_text(&format!("/pin {}", id)).await;
+    recv_type(&mut alice, "Pinned").await;
+
+    let mut bob = TestClient::connect(port).await;
+    bob.send_text("bob").await;
+    let pinned = bob.recv_message().await;
+    assert_eq!(pinned["message_type"], "Pinned");
+    assert_eq!(pinned["id"], id);
+    let history = bob.recv_message().await;
+    assert!(history["message_type"]["PastMessages"].is_object());
+}
+
+#[tokio::test]
+async fn only_admins_can_pin() {
+    let port = spawn_server().await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+    let id = post(&mut bob, "pin me").await;
+
+    bob.send_text(&format!("/pin {}", id)).await;
+    let error = bob.recv_data("Only admins can use /pin").await;
+    assert_eq!(error["message_type"]["Error"]["code"], "Unauthorized");
+}
+
+#[tokio::test]
+async fn pin_list_sums_up_pinned_messages_for_anyone() {
+    let port = spawn_server().await;
+    let mut alice = admin(port, "alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+    bob.send_text("/pin list").await;
+    bob.recv_data("No pinned messages in this room.").await;
+
+    let short = post(&mut alice, "read the rules").await;
+    let long = post(&mut alice, &"x".repeat(150)).await;
+    for id in [short, long] {
+        alice.send_text(&format!("/pin {}", id)).await;
+        recv_type(&mut alice, "Pinned").await;
+    }
+
+    bob.send_text("/pin list").await;
+    let list = loop {
+        let message = recv_type(&mut bob, "System").await;
+        if message["data"]
+            .as_str()
+            .unwrap()
+            .starts_with("Pinned in main:")
+        {
+            break message;
+        }
+    };
+    let lines: Vec<&str> = list["data"].as_str().unwrap().lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[1].starts_with("1. "));
+    assert!(lines[1].ends_with(" alice: read the rules"));
+    assert!(lines[2].starts_with("2. "));
+    assert!(lines[2].ends_with(&format!(" alice: {}…", "x".repeat(100))));
+}
@@ -0,0 +1,109 @@
+mod integration;
+
+use integration::{TestClient, spawn_test_server_with};
+use serde_json::Value;
+
+const ADMIN_PASSWORD: &str = "correct horse";
+
+async fn admin(port: u16, name: &str) -> TestClient {
+    let mut client = TestClient::connect(port).await;
+    client.register(name).await;
+    client
+        .send_text(&format!("/admin {}", ADMIN_PASSWORD))
+        .await;
+    client.recv_data("You are now an admin").await;
+    client
+}
+
+// Posts a chat message and returns its id
+async fn post(client: &mut TestClient, text: &str) -> i64 {
+    client.send_text(text).await;
+    let echo = client.recv_data(&format!("Me: {}", text)).await;
+    echo["id"].as_i64().unwrap()
+}
+
+// Skips messages until one of the given type arrives
+async fn recv_bulk_deleted(client: &mut TestClient) -> Value {
+    loop {
+        let message = client.recv_message().await;
+        if message["message_type"]["BulkDeleted"].is_object() {
+            return message;
+        }
+    }
+}
+
+#[tokio::test]
+async fn purging_deletes_a_users_newest_messages_at_once() {
+    let (port, _server) = spawn_test_server_with(&["--admin-password", ADMIN_PASSWORD]).await;
+    let mut alice = admin(port, "alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+    let first = post(&mut bob, "buy now").await;
+    let second = post(&mut bob, "cheap pills").await;
+
+    bob.send_text("/purge bob 1").await;
+    bob.recv_data("Only admins can use /purge").await;
+
+    alice.send_text("/purge bob 1 --preview").await;
+    alice
+        .recv_data(&format!(
+            "/purge would delete 1 of bob's messages in main:\n{}: cheap pills",
+            second
+        ))
+        .await;
+
+    // More than bob sent deletes what is there
+    alice.send_text("/purge bob 50").await;
+    for client in [&mut alice, &mut bob] {
+        let purged = recv_bulk_deleted(client).await;
+        assert_eq!(
+            purged["message_type"]["BulkDeleted"]["message_ids"],
+            serde_json::json!([first, second])
+        );
+        assert_eq!(purged["data"], "alice purged 2 of bob's messages");
+    }
+
+    alice.send_text("/purge bob 50 --preview").await;
+    alice.recv_data("bob has no messages in main").await;
+    alice.send_text("/purge bob").await;
+    alice
+        .recv_data("Usage: /purge <name> <count> [--preview]")
+        .await;
+}
+
+#[tokio::test]
+async fn purged_messages_can_be_restored_until_removed_for_good() {
+    let (port, _server) = spawn_test_server_with(&["--admin-password", ADMIN_PASSWORD]).await;
+    let mut alice = admin(port, "alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+    let first = post(&mut bob, "not spam").await;
+    let second = post(&mut bob, "cheap pills").await;
+
+    alice.send_text("/purge bob 2").await;
+    recv_bulk_deleted(&mut alice).await;
+    recv_bulk_deleted(&mut bob).await;
+
+    bob.send_text(&format!("/restore {}", first)).await;
+    bob.recv_data("Only admins can use /restore").await;
+
+    alice.send_text(&format!("/restore {}", first)).await;
+    for client in [&mut alice, &mut bob] {
+        let restored = client.recv_data("bob: not spam").await;
+        assert_eq!(restored["message_type"], "Restore");
+        assert_eq!(restored["id"], first);
+    }
+    alice.send_text(&format!("/restore {}", first)).await;
+    alice
+        .recv_data(&format!("No deleted message with id {}", first))
+        .await;
+
+    alice.send_text("/purge-deleted").await;
+    alice
+        .recv_data("Removed 1 deleted messages from main for good")
+        .await;
+    alice.send_text(&format!("/restore {}", second)).await;
+    alice
+        .recv_data(&format!("No deleted message with id {}", second))
+        .await;
+}
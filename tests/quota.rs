@@ -0,0 +1,119 @@
+mod integration;
+
+use integration::{TestClient, spawn_test_server_with};
+
+const ADMIN_PASSWORD: &str = "correct horse";
+
+async fn spawn_server() -> u16 {
+    let args = [
+        "--admin-password",
+        ADMIN_PASSWORD,
+        "--message-quota",
+        "2",
+        "--upload-quota",
+        "10",
+    ];
+    let (port, _server) = spawn_test_server_with(&args).await;
+    port
+}
+
+#[tokio::test]
+async fn messages_past_the_quota_are_refused() {
+    let port = spawn_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+
+    for text in ["one", "two"] {
+        alice.send_text(text).await;
+        alice.recv_data(&format!("Me: {}", text)).await;
+    }
+    alice.send_text("three").await;
+    let error = alice
+        .recv_data("You have used your quota of 2 messages per 24 hours")
+        .await;
+    let refusal = &error["message_type"]["Error"];
+    assert_eq!(refusal["code"], "MessageQuotaExceeded");
+    assert!(refusal["retry_after"].as_u64().unwrap() > 23 * 60 * 60);
+
+    alice.send_text("/quota").await;
+    alice
+        .recv_data("You have stored 2 of 2 messages allowed per 24 hours")
+        .await;
+
+    // Nothing refused was stored
+    let mut bob = TestClient::connect(port).await;
+    let history = bob.recv_message().await;
+    let days = history["message_type"]["PastMessages"]["days"]
+        .as_array()
+        .unwrap();
+    assert_eq!(days[0]["messages"].as_array().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn admins_are_exempt() {
+    let port = spawn_server().await;
+    let mut root = TestClient::connect(port).await;
+    root.register("root").await;
+    root.send_text(&format!("/admin {}", ADMIN_PASSWORD)).await;
+    root.recv_data("You are now an admin").await;
+
+    for n in 0..3 {
+        root.send_text(&format!("message {}", n)).await;
+        root.recv_data(&format!("Me: message {}", n)).await;
+    }
+    root.send_text("/quota").await;
+    root.recv_data("Admins have no quotas").await;
+}
+
+#[tokio::test]
+async fn unsaved_messages_do_not_count() {
+    let port = spawn_server().await;
+    let mut root = TestClient::connect(port).await;
+    root.register("root").await;
+    root.send_text(&format!("/admin {}", ADMIN_PASSWORD)).await;
+    root.recv_data("You are now an admin").await;
+    root.send_text("/roomconfig main persist off").await;
+    root.recv_data("persist of main is now off").await;
+
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    for n in 0..3 {
+        alice.send_text(&format!("message {}", n)).await;
+        alice.recv_data(&format!("Me: message {}", n)).await;
+    }
+    alice.send_text("/quota").await;
+    alice
+        .recv_data("You have stored 0 of 2 messages allowed per 24 hours")
+        .await;
+}
+
+#[tokio::test]
+async fn uploads_past_the_byte_quota_are_refused() {
+    let port = spawn_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+
+    alice
+        .send_text(r#"{"SendFile":{"filename":"notes.txt","size":6,"chunks":1}}"#)
+        .await;
+    alice.recv_data("Ready for the 1 chunks of notes.txt").await;
+    let mut frame = 0u32.to_be_bytes().to_vec();
+    frame.extend_from_slice(b"abcdef");
+    alice.send_binary(frame).await;
+    alice.recv_data("Sent notes.txt (6 bytes) to main").await;
+
+    alice
+        .send_text(r#"{"SendFile":{"filename":"more.txt","size":5,"chunks":1}}"#)
+        .await;
+    let error = alice
+        .recv_data("Cannot send more.txt: you may send 10 bytes of files per 24 hours")
+        .await;
+    let refusal = &error["message_type"]["Error"];
+    assert_eq!(refusal["code"], "UploadQuotaExceeded");
+    assert!(refusal["retry_after"].as_u64().unwrap() > 23 * 60 * 60);
+
+    alice.send_text("/quota").await;
+    alice
+        .recv_data("You have sent 6 of 10 bytes of files allowed per 24 hours")
+        .await;
+}
@@ -0,0 +1,488 @@
+mod integration;
+
+use integration::{TestClient, spawn_test_server, spawn_test_server_with};
+use serde_json::Value;
+
+const ADMIN_PASSWORD: &str = "correct horse";
+
+// Asks for /rooms with `args` and returns the RoomList that answers it
+async fn room_list(client: &mut TestClient, args: &str) -> Value {
+    client.send_text(&format!("/rooms {}", args)).await;
+    let message = client.recv_message().await;
+    message["message_type"]["RoomList"].clone()
+}
+
+#[tokio::test]
+async fn auto_mode_joins_the_main_room() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+
+    let list = room_list(&mut alice, "").await;
+    assert_eq!(list["rooms"][0]["name"], "main");
+    assert_eq!(list["rooms"][0]["members"], 1);
+    assert_eq!(list["rooms"][0]["is_member"], true);
+
+    alice.send_text("/join main").await;
+    alice.recv_data("Already in room main").await;
+}
+
+#[tokio::test]
+async fn lobby_mode_waits_for_an_explicit_join() {
+    let (port, _server) = spawn_test_server_with(&["--room-join", "lobby"]).await;
+    let mut bob = TestClient::connect(port).await;
+    let greeting = bob.recv_message().await;
+    let list = &greeting["message_type"]["RoomList"];
+    assert_eq!(list["rooms"][0]["members"], 0);
+    assert_eq!(list["rooms"][0]["is_member"], false);
+    bob.recv_data("Welcome! Pick a room with /join <room>")
+        .await;
+    bob.send_text("/join main").await;
+    let history = bob.recv_message().await;
+    assert!(history["message_type"]["PastMessages"].is_object());
+    bob.recv_data("Welcome! Please enter your name:").await;
+    bob.register("bob").await;
+
+    let mut alice = TestClient::connect(port).await;
+    let greeting = alice.recv_message().await;
+    assert_eq!(
+        greeting["message_type"]["RoomList"]["rooms"][0]["members"],
+        1
+    );
+    alice
+        .recv_data("Welcome! Pick a room with /join <room>")
+        .await;
+    alice.send_text("hello").await;
+    alice.recv_data("Join a room first with /join <room>").await;
+    alice.send_text("/join elsewhere").await;
+    alice.recv_data("No such room: elsewhere").await;
+
+    // Room chat doesn't reach the lobby
+    bob.send_text("anyone here?").await;
+    bob.recv_data("Me: anyone here?").await;
+    let list = room_list(&mut alice, "").await;
+    assert_eq!(list["rooms"][0]["messages_last_hour"], 1);
+
+    alice.send_text("/join main").await;
+    alice.recv_data("Welcome! Please enter your name:").await;
+    alice.register("alice").await;
+    bob.recv_data("alice joined the chat!").await;
+}
+
+#[tokio::test]
+async fn rooms_with_a_password_only_let_in_those_who_give_it() {
+    let (port, _server) =
+        spawn_test_server_with(&["--room-join", "lobby", "--admin-password", ADMIN_PASSWORD]).await;
+
+    // Public rooms are joined as before
+    let mut alice = TestClient::connect(port).await;
+    alice
+        .recv_data("Welcome! Pick a room with /join <room>")
+        .await;
+    alice.send_text("/join main").await;
+    alice.recv_data("Welcome! Please enter your name:").await;
+    alice.register("alice").await;
+
+    alice.send_text("/roompassword set hunter22").await;
+    let refused = alice
+        .recv_data("Only admins can change the room password")
+        .await;
+    assert_eq!(refused["message_type"]["Error"]["code"], "Unauthorized");
+    alice.send_text(&format!("/admin {}", ADMIN_PASSWORD)).await;
+    alice.recv_data("You are now an admin").await;
+    alice.send_text("/roompassword set hunter22").await;
+    alice.recv_data("Joining main now takes a password").await;
+
+    let mut bob = TestClient::connect(port).await;
+    let greeting = bob.recv_message().await;
+    assert_eq!(
+        greeting["message_type"]["RoomList"]["rooms"][0]["has_password"],
+        true
+    );
+    bob.send_text("/join main").await;
+    let refused = bob
+        .recv_data("main needs a password: /join main <password>")
+        .await;
+    assert_eq!(refused["message_type"]["Error"]["code"], "Unauthorized");
+    bob.send_text("/join main hunter2").await;
+    let refused = bob.recv_data("Incorrect password for main").await;
+    assert_eq!(refused["message_type"]["Error"]["code"], "Unauthorized");
+    bob.send_text("hello?").await;
+    bob.recv_data("Join a room first with /join <room>").await;
+
+    bob.send_text("/join main hunter22").await;
+    bob.recv_data("Welcome! Please enter your name:").await;
+    bob.register("bob").await;
+    alice.recv_data("bob joined the chat!").await;
+
+    // Clearing the password makes the room public again
+    alice.send_text("/roompassword clear").await;
+    alice.recv_data("main no longer needs a password").await;
+    let mut carol = TestClient::connect(port).await;
+    let greeting = carol.recv_message().await;
+    assert_eq!(
+        greeting["message_type"]["RoomList"]["rooms"][0]["has_password"],
+        false
+    );
+    carol.send_text("/join main").await;
+    carol.recv_data("Welcome! Please enter your name:").await;
+}
+
+#[tokio::test]
+async fn a_room_password_keeps_newcomers_in_the_lobby() {
+    let (port, _server) = spawn_test_server_with(&["--admin-password", ADMIN_PASSWORD]).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    alice.send_text(&format!("/admin {}", ADMIN_PASSWORD)).await;
+    alice.recv_data("You are now an admin").await;
+    alice.send_text("/roompassword set hunter22").await;
+    alice.recv_data("Joining main now takes a password").await;
+
+    // Auto mode would have put bob in the room
+    let mut bob = TestClient::connect(port).await;
+    bob.recv_data("Welcome! Pick a room with /join <room>")
+        .await;
+    bob.send_text("/join main hunter22").await;
+    bob.recv_data("Welcome! Please enter your name:").await;
+}
+
+#[tokio::test]
+async fn wrong_room_passwords_lock_a_connection_out() {
+    let (port, _server) = spawn_test_server_with(&["--admin-password", ADMIN_PASSWORD]).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    alice.send_text(&format!("/admin {}", ADMIN_PASSWORD)).await;
+    alice.recv_data("You are now an admin").await;
+    alice.send_text("/roompassword set hunter22").await;
+    alice.recv_data("Joining main now takes a password").await;
+
+    let mut bob = TestClient::connect(port).await;
+    bob.recv_data("Welcome! Pick a room with /join <room>")
+        .await;
+    for guess in ["a", "b", "c", "d", "e"] {
+        bob.send_text(&format!("/join main {}", guess)).await;
+        bob.recv_data("Incorrect password for main").await;
+    }
+    // Even the right password waits until the window moves on
+    bob.send_text("/join main hunter22").await;
+    let refused = bob.recv_message().await;
+    assert_eq!(refused["message_type"]["Error"]["code"], "TooManyAttempts");
+    let retry_after = refused["message_type"]["Error"]["retry_after"]
+        .as_u64()
+        .unwrap();
+    assert!((1..=60).contains(&retry_after));
+    assert!(
+        refused["data"]
+            .as_str()
+            .unwrap()
+            .starts_with("Too many wrong passwords for main, try again in ")
+    );
+
+    // Other connections have their own budget
+    let mut carol = TestClient::connect(port).await;
+    carol
+        .recv_data("Welcome! Pick a room with /join <room>")
+        .await;
+    carol.send_text("/join main hunter22").await;
+    carol.recv_data("Welcome! Please enter your name:").await;
+}
+
+#[tokio::test]
+async fn wrong_admin_passwords_count_against_the_same_lockout() {
+    let (port, _server) = spawn_test_server_with(&["--admin-password", ADMIN_PASSWORD]).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+
+    for guess in ["a", "b", "c", "d", "e"] {
+        alice.send_text(&format!("/admin {}", guess)).await;
+        alice.recv_data("Incorrect admin password").await;
+    }
+    alice.send_text(&format!("/admin {}", ADMIN_PASSWORD)).await;
+    let refused = alice.recv_message().await;
+    assert_eq!(refused["message_type"]["Error"]["code"], "TooManyAttempts");
+    assert!(
+        refused["data"]
+            .as_str()
+            .unwrap()
+            .starts_with("Too many wrong passwords, try again in ")
+    );
+
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+    bob.send_text(&format!("/admin {}", ADMIN_PASSWORD)).await;
+    bob.recv_data("You are now an admin").await;
+}
+
+#[tokio::test]
+async fn room_lists_show_topic_activity_and_pages() {
+    let (port, _server) = spawn_test_server_with(&["--admin-password", ADMIN_PASSWORD]).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    alice.send_text("/topic Planning").await;
+    alice.send_text("one").await;
+    alice.send_text("two").await;
+    alice.recv_data("Me: two").await;
+
+    let list = room_list(&mut alice, "--page 1").await;
+    assert_eq!(list["page"], 1);
+    assert_eq!(list["pages"], 1);
+    let main = &list["rooms"][0];
+    assert_eq!(main["topic"], "Planning");
+    assert_eq!(main["messages_last_hour"], 2);
+
+    alice.send_text("/rooms --page 2").await;
+    alice.recv_data("No such page: 2 (the last is 1)").await;
+    alice.send_text("/rooms --page nope").await;
+    alice.recv_data("Usage: /rooms [--page <n>] [--all]").await;
+
+    alice.send_text("/rooms --all").await;
+    let refused = alice.recv_data("Only admins can list every room").await;
+    assert_eq!(refused["message_type"]["Error"]["code"], "Unauthorized");
+    alice.send_text(&format!("/admin {}", ADMIN_PASSWORD)).await;
+    alice.recv_data("You are now an admin").await;
+    let list = room_list(&mut alice, "--all").await;
+    assert_eq!(list["rooms"][0]["name"], "main");
+}
+
+#[tokio::test]
+async fn join_messages_greet_later_newcomers_first() {
+    let (port, _server) = spawn_test_server_with(&["--admin-password", ADMIN_PASSWORD]).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+
+    alice.send_text("/joinmsg set Read the rules first").await;
+    let refused = alice
+        .recv_data("Only admins can change the join message")
+        .await;
+    assert_eq!(refused["message_type"]["Error"]["code"], "Unauthorized");
+
+    alice.send_text(&format!("/admin {}", ADMIN_PASSWORD)).await;
+    alice.recv_data("You are now an admin").await;
+    let long = "a".repeat(501);
+    alice.send_text(&format!("/joinmsg set {}", long)).await;
+    alice
+        .recv_data("Join messages can be at most 500 characters")
+        .await;
+    alice.send_text("/joinmsg set Read the rules first").await;
+    alice
+        .recv_data("Newcomers to main will now see the new join message")
+        .await;
+
+    // The preview is the frame newcomers get
+    alice.send_text("/joinmsg show").await;
+    let preview = alice.recv_data("Read the rules first").await;
+    assert_eq!(preview["message_type"]["RoomGreeting"]["room"], "main");
+    let mut bob = TestClient::connect(port).await;
+    let greeting = bob.recv_message().await;
+    // Sequence numbers count each connection's own frames
+    assert_eq!(greeting["message_type"], preview["message_type"]);
+    assert_eq!(greeting["data"], preview["data"]);
+    let history = bob.recv_message().await;
+    assert!(history["message_type"]["PastMessages"].is_object());
+
+    alice.send_text("/joinmsg clear").await;
+    alice.recv_data("main no longer has a join message").await;
+    let mut carol = TestClient::connect(port).await;
+    let history = carol.recv_message().await;
+    assert!(history["message_type"]["PastMessages"].is_object());
+}
+
+#[tokio::test]
+async fn room_config_overrides_the_message_length_limit() {
+    let (port, _server) = spawn_test_server_with(&[
+        "--admin-password",
+        ADMIN_PASSWORD,
+        "--max-message-length",
+        "100",
+    ])
+    .await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+
+    alice.send_text("/roomconfig main max_length 5").await;
+    let refused = alice
+        .recv_data("Only admins can change room settings")
+        .await;
+    assert_eq!(refused["message_type"]["Error"]["code"], "Unauthorized");
+    alice.send_text(&format!("/admin {}", ADMIN_PASSWORD)).await;
+    alice.recv_data("You are now an admin").await;
+
+    alice.send_text("/roomconfig main colour blue").await;
+    let unknown = alice.recv_message().await;
+    assert_eq!(unknown["message_type"]["Error"]["code"], "UnknownSetting");
+    alice.send_text("/roomconfig main max_length 5").await;
+    alice.recv_data("max_length of main is now 5").await;
+    alice.send_text("/roomconfig main").await;
+    alice
+        .recv_data("Settings of main: max_length 5, ttl off, persist on")
+        .await;
+
+    alice.send_text("toolong").await;
+    let refused = alice
+        .recv_data("Messages in main can be at most 5 characters")
+        .await;
+    assert_eq!(refused["message_type"]["Error"]["code"], "MessageTooLong");
+    alice.send_text("short").await;
+    alice.recv_data("Me: short").await;
+
+    // Back to the server's limit
+    alice.send_text("/roomconfig main max_length default").await;
+    alice.recv_data("max_length of main is now default").await;
+    alice.send_text("toolong").await;
+    alice.recv_data("Me: toolong").await;
+    alice.send_text(&"a".repeat(101)).await;
+    alice
+        .recv_data("Messages in main can be at most 100 characters")
+        .await;
+}
+
+#[tokio::test]
+async fn length_limits_hold_for_expanded_messages() {
+    let (port, _server) = spawn_test_server_with(&["--max-message-length", "10"]).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+
+    // Ten characters typed, twelve once the shrug is expanded
+    alice.send_text("hi [shrug]").await;
+    let refused = alice
+        .recv_data("Messages in main can be at most 10 characters")
+        .await;
+    assert_eq!(refused["message_type"]["Error"]["code"], "MessageTooLong");
+    alice.send_text("[shrug]").await;
+    alice.recv_data(r"Me: ¯\_(ツ)_/¯").await;
+}
+
+#[tokio::test]
+async fn rooms_can_stop_saving_messages() {
+    let (port, _server) = spawn_test_server_with(&["--admin-password", ADMIN_PASSWORD]).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    alice.send_text(&format!("/admin {}", ADMIN_PASSWORD)).await;
+    alice.recv_data("You are now an admin").await;
+
+    alice.send_text("/roomconfig main persist off").await;
+    alice.recv_data("persist of main is now off").await;
+    alice.send_text("gone").await;
+    let echo = alice.recv_data("Me: gone").await;
+    assert!(echo.get("id").is_none());
+    alice.send_text("/roomconfig main persist on").await;
+    alice.recv_data("persist of main is now on").await;
+    alice.send_text("kept").await;
+    alice.recv_data("Me: kept").await;
+
+    let mut bob = TestClient::connect(port).await;
+    let history = bob.recv_message().await;
+    let replayed = &history["message_type"]["PastMessages"]["days"][0]["messages"];
+    let texts: Vec<&str> = replayed
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|message| message["data"].as_str().unwrap())
+        .collect();
+    assert_eq!(texts, ["alice: kept"]);
+}
+
+#[tokio::test]
+async fn room_settings_turn_links_and_uploads_off() {
+    let (port, _server) = spawn_test_server_with(&["--admin-password", ADMIN_PASSWORD]).await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+    alice.send_text(&format!("/admin {}", ADMIN_PASSWORD)).await;
+    alice.recv_data("You are now an admin").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    bob.send_text("/roomsettings links=off").await;
+    let refused = bob.recv_data("Only admins can change room settings").await;
+    assert_eq!(refused["message_type"]["Error"]["code"], "Unauthorized");
+
+    // Every bad pair is reported, and none of the good ones applied
+    alice
+        .send_text("/roomsettings filter=strict links=off uploads=maybe")
+        .await;
+    let invalid = alice
+        .recv_data(
+            "Nothing changed: filter is not a setting, uploads=maybe is not on or off. \
+             Rooms have links and uploads, each on or off",
+        )
+        .await;
+    assert_eq!(invalid["message_type"]["Error"]["code"], "UnknownSetting");
+    bob.send_text("/roomsettings").await;
+    bob.recv_data("Content settings of main: links=on uploads=on")
+        .await;
+
+    alice.send_text("/roomsettings links=off uploads=off").await;
+    bob.recv_data("alice changed the settings of main: links=off uploads=off")
+        .await;
+
+    bob.send_text("look at https://example.com").await;
+    let refused = bob.recv_data("Links are off in main").await;
+    assert_eq!(refused["message_type"]["Error"]["code"], "RoomPolicy");
+    bob.send_text(r#"{"SendFile":{"filename":"a.txt","size":1,"chunks":1}}"#)
+        .await;
+    let refused = bob.recv_data("Uploads are off in main").await;
+    assert_eq!(refused["message_type"]["Error"]["code"], "RoomPolicy");
+    bob.send_text("no links here").await;
+    bob.recv_data("Me: no links here").await;
+}
+
+#[tokio::test]
+async fn invitations_can_be_accepted_or_declined_from_the_lobby() {
+    let (port, _server) = spawn_test_server_with(&["--room-join", "lobby"]).await;
+
+    // Resuming a session names bob while he is still in the lobby
+    let mut bob = TestClient::connect(port).await;
+    bob.send_text(r#"{"kind":"new"}"#).await;
+    bob.recv_data("Welcome! Pick a room with /join <room>")
+        .await;
+    bob.send_text("/join main").await;
+    bob.recv_data("Welcome! Please enter your name:").await;
+    bob.register("bob").await;
+    let session = bob.recv_message().await;
+    let token = session["message_type"]["Session"]["token"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    bob.close().await;
+
+    let mut alice = TestClient::connect(port).await;
+    alice.send_text("/join main").await;
+    alice.recv_data("Welcome! Please enter your name:").await;
+    alice.register("alice").await;
+
+    let mut bob = TestClient::connect(port).await;
+    bob.send_text(&format!(r#"{{"kind":"resume","token":"{}"}}"#, token))
+        .await;
+    bob.recv_data("Welcome! Pick a room with /join <room>")
+        .await;
+
+    alice.send_text("/invite carol main").await;
+    alice.recv_data("carol is not online").await;
+    alice.send_text("/invite bob elsewhere").await;
+    alice.recv_data("No such room: elsewhere").await;
+
+    alice.send_text("/invite bob main").await;
+    alice.recv_data("Invited bob to main").await;
+    let invite = bob
+        .recv_data("alice invited you to main. /accept main or /decline main")
+        .await;
+    assert_eq!(invite["message_type"]["Invite"]["room"], "main");
+    assert_eq!(invite["message_type"]["Invite"]["from"], "alice");
+
+    bob.send_text("/decline main").await;
+    bob.recv_data("You declined the invitation to main").await;
+    alice
+        .recv_data("bob declined your invitation to main")
+        .await;
+    bob.send_text("/accept main").await;
+    bob.recv_data("You have no invitation to main").await;
+
+    alice.send_text("/invite bob main").await;
+    alice.recv_data("Invited bob to main").await;
+    bob.send_text("/accept main").await;
+
+    bob.send_text("made it").await;
+    alice.recv_data("bob: made it").await;
+    alice.send_text("/invite bob main").await;
+    alice.recv_data("bob is already in main").await;
+}
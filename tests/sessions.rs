@@ -0,0 +1,197 @@
+mod integration;
+
+use integration::{TestClient, spawn_test_server, spawn_test_server_with};
+
+// Opens with a `new` handshake, registers as `name` and returns the session
+// token that follows the welcome
+async fn register_new(client: &mut TestClient, name: &str) -> String {
+    client.send_text(r#"{"kind":"new"}"#).await;
+    client.recv_data("Welcome! Please enter your name:").await;
+    client.register(name).await;
+    let session = client.recv_message().await;
+    session["message_type"]["Session"]["token"]
+        .as_str()
+        .unwrap()
+        .to_string()
+}
+
+#[tokio::test]
+async fn new_sessions_get_a_token_after_naming() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    let token = register_new(&mut alice, "alice").await;
+    assert_eq!(token.len(), 32);
+}
+
+#[tokio::test]
+async fn resuming_restores_the_name_without_a_prompt() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    let token = register_new(&mut alice, "alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    alice.close().await;
+    bob.recv_data("alice left the chat!").await;
+
+    let mut alice = TestClient::connect(port).await;
+    alice
+        .send_text(&format!(r#"{{"kind":"resume","token":"{}"}}"#, token))
+        .await;
+    let history = alice.recv_message().await;
+    assert!(history["message_type"]["PastMessages"].is_object());
+    let welcome = alice.recv_message().await;
+    assert_eq!(
+        welcome["data"],
+        "Welcome back, alice! You can start chatting now."
+    );
+    let session = alice.recv_message().await;
+    assert_eq!(session["message_type"]["Session"]["token"], token.as_str());
+    bob.recv_data("alice joined the chat!").await;
+
+    alice.send_text("back again").await;
+    bob.recv_data("alice: back again").await;
+}
+
+#[tokio::test]
+async fn known_identities_get_the_welcome_back_template() {
+    let (port, _server) = spawn_test_server_with(&[
+        "--welcome-template",
+        "Hello {name}",
+        "--welcome-back-template",
+        "Good to see you again, {name}",
+    ])
+    .await;
+    let mut alice = TestClient::connect(port).await;
+    alice.send_text(r#"{"kind":"new"}"#).await;
+    alice.recv_data("Welcome! Please enter your name:").await;
+    alice.send_text("alice").await;
+    alice.recv_data("Hello alice").await;
+    let session = alice.recv_message().await;
+    let token = session["message_type"]["Session"]["token"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    alice.close().await;
+
+    let mut alice = TestClient::connect(port).await;
+    alice
+        .send_text(&format!(r#"{{"kind":"resume","token":"{}"}}"#, token))
+        .await;
+    let welcome = alice.recv_data("Good to see you again, alice").await;
+    assert_eq!(welcome["message_type"], "Welcome");
+}
+
+#[tokio::test]
+async fn renames_carry_over_to_resumed_sessions() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    let token = register_new(&mut alice, "alice").await;
+    alice.send_text("/nick carol").await;
+    alice.recv_data("alice is now known as carol").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    alice.close().await;
+    bob.recv_data("carol left the chat!").await;
+
+    let mut carol = TestClient::connect(port).await;
+    carol
+        .send_text(&format!(r#"{{"kind":"resume","token":"{}"}}"#, token))
+        .await;
+    carol
+        .recv_data("Welcome back, carol! You can start chatting now.")
+        .await;
+}
+
+#[tokio::test]
+async fn unknown_tokens_start_a_new_session() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice
+        .send_text(r#"{"kind":"resume","token":"nonsense"}"#)
+        .await;
+    let error = alice.recv_message().await;
+    assert_eq!(error["message_type"]["Error"]["code"], "UnknownSession");
+    assert_eq!(error["data"], "Unknown session, starting a new session");
+
+    alice.recv_data("Welcome! Please enter your name:").await;
+    alice.register("alice").await;
+    let session = alice.recv_message().await;
+    assert!(session["message_type"]["Session"]["token"].is_string());
+}
+
+#[tokio::test]
+async fn resuming_a_name_in_use_is_refused() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    let token = register_new(&mut alice, "alice").await;
+
+    let mut impostor = TestClient::connect(port).await;
+    impostor
+        .send_text(&format!(r#"{{"kind":"resume","token":"{}"}}"#, token))
+        .await;
+    let error = impostor.recv_message().await;
+    assert_eq!(error["message_type"]["Error"]["code"], "NameTaken");
+    impostor.recv_data("Welcome! Please enter your name:").await;
+}
+
+#[tokio::test]
+async fn plain_text_first_frames_keep_the_legacy_flow() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+
+    // No session token follows the welcome
+    alice.send_text("hello").await;
+    let echo = alice.recv_message().await;
+    assert_eq!(echo["data"], "Me: hello");
+}
+
+#[tokio::test]
+async fn direct_messages_wait_for_an_offline_session_to_resume() {
+    let (port, _server) = spawn_test_server().await;
+    let mut alice = TestClient::connect(port).await;
+    let token = register_new(&mut alice, "alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    bob.send_text("/msg alice are you there?").await;
+    bob.recv_data("Sent to alice").await;
+    let direct = alice.recv_data("are you there?").await;
+    assert_eq!(direct["message_type"]["Direct"]["sender"], "bob");
+
+    alice.close().await;
+    bob.recv_data("alice left the chat!").await;
+    bob.send_text("/msg alice see you tomorrow").await;
+    bob.recv_data("alice is offline and will get your message when they return")
+        .await;
+    bob.send_text("/msg nobody hello?").await;
+    bob.recv_data("nobody is not online").await;
+
+    let mut alice = TestClient::connect(port).await;
+    alice
+        .send_text(&format!(r#"{{"kind":"resume","token":"{}"}}"#, token))
+        .await;
+    let direct = alice.recv_data("see you tomorrow").await;
+    assert_eq!(direct["message_type"]["Direct"]["sender"], "bob");
+    assert!(direct["sent_at"].is_string());
+    alice.close().await;
+
+    // Delivered once only
+    let mut alice = TestClient::connect(port).await;
+    alice
+        .send_text(&format!(r#"{{"kind":"resume","token":"{}"}}"#, token))
+        .await;
+    alice
+        .recv_data("Welcome back, alice! You can start chatting now.")
+        .await;
+    alice.send_text("/cls").await;
+    loop {
+        let message = alice.recv_message().await;
+        assert_ne!(message["data"], "see you tomorrow");
+        if message["message_type"] == "ClearScreen" {
+            break;
+        }
+    }
+}
@@ -0,0 +1,160 @@
+mod integration;
+
+use integration::{TestClient, spawn_test_server_with};
+use serde_json::Value;
+
+const ADMIN_PASSWORD: &str = "correct horse";
+
+// Admins allowed to tail at once
+const MAX_TAILERS: usize = 4;
+
+async fn spawn_server() -> u16 {
+    let (port, _server) = spawn_test_server_with(&["--admin-password", ADMIN_PASSWORD]).await;
+    port
+}
+
+async fn admin(port: u16, name: &str) -> TestClient {
+    login(TestClient::connect(port).await, name).await
+}
+
+async fn login(mut client: TestClient, name: &str) -> TestClient {
+    client.register(name).await;
+    client
+        .send_text(&format!("/admin {}", ADMIN_PASSWORD))
+        .await;
+    client.recv_data("You are now an admin").await;
+    client
+}
+
+// Skips messages until a tail frame arrives
+async fn recv_tail(client: &mut TestClient) -> Value {
+    loop {
+        let message = client.recv_message().await;
+        if message["message_type"]["AdminTail"].is_object() {
+            return message["message_type"]["AdminTail"].clone();
+        }
+    }
+}
+
+#[tokio::test]
+async fn tailing_admins_get_a_copy_of_every_chat() {
+    let port = spawn_server().await;
+    let mut root = admin(port, "root").await;
+    root.send_text("/tailall on").await;
+    root.recv_data("Live tail started").await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+
+    alice.send_text("hello: world").await;
+    let echo = alice.recv_data("Me: hello: world").await;
+
+    let tail = recv_tail(&mut root).await;
+    assert_eq!(tail["room"], "main");
+    assert_eq!(tail["sender"], "alice");
+    assert_eq!(tail["text"], "hello: world");
+    assert_eq!(tail["id"], echo["id"]);
+
+    root.send_text("/tailall off").await;
+    root.recv_data("Live tail stopped").await;
+    alice.send_text("unseen").await;
+    alice.recv_data("Me: unseen").await;
+    root.send_text("/whois alice").await;
+    loop {
+        let message = root.recv_message().await;
+        assert!(message["message_type"]["AdminTail"].is_null());
+        if message["data"] == "alice is online. No previous names." {
+            break;
+        }
+    }
+}
+
+#[tokio::test]
+async fn tails_never_reach_others_or_the_history() {
+    let port = spawn_server().await;
+    let mut root = admin(port, "root").await;
+    root.send_text("/tailall on").await;
+    root.recv_data("Live tail started").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    for text in ["one", "two"] {
+        bob.send_text(text).await;
+        bob.recv_data(&format!("Me: {}", text)).await;
+        recv_tail(&mut root).await;
+    }
+    bob.send_text("/whois bob").await;
+    loop {
+        let message = bob.recv_message().await;
+        assert!(message["message_type"]["AdminTail"].is_null());
+        if message["data"] == "bob is online. No previous names." {
+            break;
+        }
+    }
+
+    // Only the chat itself was stored
+    let mut newcomer = TestClient::connect(port).await;
+    let history = newcomer.recv_message().await;
+    let days = history["message_type"]["PastMessages"]["days"]
+        .as_array()
+        .unwrap();
+    let texts: Vec<_> = days
+        .iter()
+        .flat_map(|day| day["messages"].as_array().unwrap())
+        .map(|message| message["data"].as_str().unwrap())
+        .collect();
+    assert_eq!(texts, ["bob: one", "bob: two"]);
+}
+
+#[tokio::test]
+async fn only_admins_can_tail() {
+    let port = spawn_server().await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    bob.send_text("/tailall on").await;
+    let error = bob.recv_data("Only admins can use /tailall").await;
+    assert_eq!(error["message_type"]["Error"]["code"], "Unauthorized");
+}
+
+#[tokio::test]
+async fn tailers_are_capped() {
+    let port = spawn_server().await;
+    let mut tailers = Vec::new();
+    for n in 0..MAX_TAILERS {
+        let mut tailer = admin(port, &format!("admin{}", n)).await;
+        tailer.send_text("/tailall on").await;
+        tailer.recv_data("Live tail started").await;
+        tailers.push(tailer);
+    }
+
+    let mut late = admin(port, "late").await;
+    late.send_text("/tailall on").await;
+    let error = late
+        .recv_data(&format!("At most {} admins can tail at once", MAX_TAILERS))
+        .await;
+    assert_eq!(error["message_type"]["Error"]["code"], "CapacityReached");
+}
+
+#[tokio::test]
+async fn slow_tailers_are_cut_off() {
+    // Long enough that the backlog, not a stalled write, is what cuts root off
+    let (port, _server) =
+        spawn_test_server_with(&["--admin-password", ADMIN_PASSWORD, "--send-timeout", "60"]).await;
+    let client = TestClient::connect_with_recv_buffer(port, 64 * 1024).await;
+    let mut root = login(client, "root").await;
+    root.send_text("/tailall on").await;
+    root.recv_data("Live tail started").await;
+    let mut alice = TestClient::connect(port).await;
+    alice.register("alice").await;
+
+    // Root stops reading while large messages fill the socket buffers, and
+    // then the queue behind them
+    let padding = "x".repeat(64 * 1024);
+    for n in 0..400 {
+        alice.send_text(&format!("{} {}", n, padding)).await;
+        alice.recv_data(&format!("Me: {} {}", n, padding)).await;
+    }
+
+    root.recv_data("Live tail stopped because you are falling behind")
+        .await;
+}
@@ -0,0 +1,105 @@
+mod integration;
+
+use integration::{TestClient, spawn_test_server_with};
+
+const ADMIN_PASSWORD: &str = "correct horse";
+
+async fn spawn_server() -> u16 {
+    let (port, _server) = spawn_test_server_with(&["--admin-password", ADMIN_PASSWORD]).await;
+    port
+}
+
+async fn admin(port: u16, name: &str) -> TestClient {
+    let mut client = TestClient::connect(port).await;
+    client.register(name).await;
+    client
+        .send_text(&format!("/admin {}", ADMIN_PASSWORD))
+        .await;
+    client.recv_data("You are now an admin").await;
+    client
+}
+
+#[tokio::test]
+async fn anyone_can_set_an_unlocked_topic() {
+    let port = spawn_server().await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    bob.send_text("/topic").await;
+    bob.recv_data("main has no topic").await;
+
+    bob.send_text("/topic release day").await;
+    bob.recv_data("bob set the topic of main to: release day")
+        .await;
+    bob.send_text("/topic").await;
+    bob.recv_data("The topic of main is: release day").await;
+}
+
+#[tokio::test]
+async fn locked_topics_only_change_for_admins() {
+    let port = spawn_server().await;
+    let mut alice = admin(port, "alice").await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    alice.send_text("/topic lock").await;
+    bob.recv_data("The topic of main is now locked").await;
+
+    bob.send_text("/topic vandalised").await;
+    let error = bob.recv_data("The topic of main is locked").await;
+    assert_eq!(error["message_type"]["Error"]["code"], "Unauthorized");
+    bob.send_text("/topic").await;
+    bob.recv_data("main has no topic").await;
+
+    alice.send_text("/topic official").await;
+    bob.recv_data("alice set the topic of main to: official")
+        .await;
+
+    alice.send_text("/topic unlock").await;
+    bob.recv_data("The topic of main is now unlocked").await;
+    bob.send_text("/topic open again").await;
+    bob.recv_data("bob set the topic of main to: open again")
+        .await;
+}
+
+#[tokio::test]
+async fn only_admins_can_lock_the_topic() {
+    let port = spawn_server().await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    for command in ["lock", "unlock"] {
+        bob.send_text(&format!("/topic {}", command)).await;
+        let error = bob
+            .recv_data(&format!("Only admins can {} the topic", command))
+            .await;
+        assert_eq!(error["message_type"]["Error"]["code"], "Unauthorized");
+    }
+}
+
+#[tokio::test]
+async fn topic_history_lists_the_latest_changes() {
+    let port = spawn_server().await;
+    let mut bob = TestClient::connect(port).await;
+    bob.register("bob").await;
+
+    bob.send_text("/topic history").await;
+    bob.recv_data("The topic of main has never been set").await;
+
+    for n in 1..=12 {
+        bob.send_text(&format!("/topic set topic {}", n)).await;
+        bob.recv_data(&format!("bob set the topic of main to: topic {}", n))
+            .await;
+    }
+    bob.send_text("/topic set lock").await;
+    bob.recv_data("bob set the topic of main to: lock").await;
+
+    bob.send_text("/topic history").await;
+    let history = bob.recv_message().await;
+    let text = history["data"].as_str().unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines[0], "Topic history of main:");
+    assert_eq!(lines.len(), 11);
+    assert!(lines[1].starts_with("1. ") && lines[1].ends_with(" bob: lock"));
+    assert!(lines[10].starts_with("10. ") && lines[10].ends_with(" bob: topic 4"));
+}